@@ -0,0 +1,7 @@
+//! Block device drivers.
+
+mod partition;
+mod ram;
+
+pub use partition::{scan_partitions, Partition, PartitionBlock, PartitionType};
+pub use ram::RamDisk;
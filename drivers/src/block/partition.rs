@@ -0,0 +1,417 @@
+//! Partition tables of block devices, in MBR or GPT.
+//!
+//! Reference: <https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html>
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::convert::TryInto;
+
+use crate::scheme::{BlockScheme, EventScheme, Scheme};
+use crate::utils::EventHandler;
+use crate::{DeviceError, DeviceResult};
+
+/// The size of a logical block.
+const SECTOR_SIZE: usize = 512;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRIES: usize = 4;
+/// The partition type of the protective MBR, covering the whole GPT disk.
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The minimal size of the GPT header, up to the CRC32 of the entries.
+const GPT_HEADER_MIN_SIZE: usize = 92;
+/// The minimal size of a GPT entry, up to the name.
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Limit the entries to read, 128 is the usual number.
+const GPT_MAX_ENTRIES: usize = 1024;
+/// Limit the size of the entries to read, as much as the most entries of the
+/// minimal size.
+const GPT_MAX_ENTRIES_LEN: usize = GPT_MAX_ENTRIES * GPT_ENTRY_MIN_SIZE;
+
+/// The type of a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// The system ID in the MBR, e.g. `0x83` for Linux.
+    Mbr(u8),
+    /// The partition type GUID in the GPT, in the on-disk (mixed-endian)
+    /// layout.
+    Gpt([u8; 16]),
+}
+
+/// A partition found in the partition table.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// The index in the partition table, from 0.
+    pub index: usize,
+    /// The first sector of the partition.
+    pub start_lba: u64,
+    /// The number of 512-byte sectors.
+    pub sectors: u64,
+    pub part_type: PartitionType,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// The CRC32 used by GPT, same as zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_sectors(dev: &dyn BlockScheme, lba: u64, count: usize) -> DeviceResult<Vec<u8>> {
+    let mut buf = vec![0; count * SECTOR_SIZE];
+    dev.read_block(lba as usize, &mut buf)?;
+    Ok(buf)
+}
+
+/// Parse the primary partitions in the MBR. The logical partitions in the
+/// extended partitions are not listed.
+fn parse_mbr(mbr: &[u8]) -> Vec<Partition> {
+    (0..MBR_ENTRIES)
+        .filter_map(|index| {
+            let entry = &mbr[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+            let sys_id = entry[4];
+            let start_lba = read_u32(entry, 8) as u64;
+            let sectors = read_u32(entry, 12) as u64;
+            if sys_id == 0 || sectors == 0 {
+                return None;
+            }
+            Some(Partition {
+                index,
+                start_lba,
+                sectors,
+                part_type: PartitionType::Mbr(sys_id),
+            })
+        })
+        .collect()
+}
+
+/// Read and validate the GPT header at `lba`. Returns `None` if there is no
+/// GPT signature.
+fn read_gpt_header(dev: &dyn BlockScheme, lba: u64) -> DeviceResult<Option<Vec<u8>>> {
+    let mut header = read_sectors(dev, lba, 1)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let size = read_u32(&header, 12) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=SECTOR_SIZE).contains(&size) || read_u64(&header, 24) != lba {
+        return Err(DeviceError::InvalidParam);
+    }
+    let crc = read_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..size]) != crc {
+        warn!("GPT header at LBA {} has a bad CRC32", lba);
+        return Err(DeviceError::InvalidParam);
+    }
+    Ok(Some(header))
+}
+
+/// Parse the partition entries specified by the validated GPT `header`.
+fn parse_gpt(dev: &dyn BlockScheme, header: &[u8]) -> DeviceResult<Vec<Partition>> {
+    let entries_lba = read_u64(header, 72);
+    let count = read_u32(header, 80) as usize;
+    let entry_size = read_u32(header, 84) as usize;
+    // the entry size is 128 * 2^n, and an entry does not cross sectors
+    if count > GPT_MAX_ENTRIES
+        || entry_size < GPT_ENTRY_MIN_SIZE
+        || entry_size > SECTOR_SIZE
+        || !entry_size.is_power_of_two()
+    {
+        return Err(DeviceError::InvalidParam);
+    }
+    let len = count * entry_size;
+    if len > GPT_MAX_ENTRIES_LEN {
+        return Err(DeviceError::InvalidParam);
+    }
+    let entries = read_sectors(dev, entries_lba, (len + SECTOR_SIZE - 1) / SECTOR_SIZE)?;
+    if crc32(&entries[..len]) != read_u32(header, 88) {
+        warn!("GPT partition entries have a bad CRC32");
+        return Err(DeviceError::InvalidParam);
+    }
+    Ok(entries[..len]
+        .chunks(entry_size)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let type_guid: [u8; 16] = entry[..16].try_into().unwrap();
+            let (first, last) = (read_u64(entry, 32), read_u64(entry, 40));
+            // unused entries have a zero type GUID
+            if type_guid == [0; 16] || last < first {
+                return None;
+            }
+            Some(Partition {
+                index,
+                start_lba: first,
+                sectors: last - first + 1,
+                part_type: PartitionType::Gpt(type_guid),
+            })
+        })
+        .collect())
+}
+
+/// Scan the partition table of the block device.
+///
+/// A protective MBR means a GPT, whose primary header is validated by its
+/// CRC32, or the backup header at the last sector is used. If there is no
+/// GPT signature, the MBR is used. Returns an empty list if there is no
+/// partition table.
+pub fn scan_partitions(dev: Arc<dyn BlockScheme>) -> DeviceResult<Vec<Partition>> {
+    let dev = dev.as_ref();
+    let mbr = read_sectors(dev, 0, 1)?;
+    if mbr[SECTOR_SIZE - 2..] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let partitions = parse_mbr(&mbr);
+    if !partitions
+        .iter()
+        .any(|p| p.part_type == PartitionType::Mbr(MBR_TYPE_GPT_PROTECTIVE))
+    {
+        return Ok(partitions);
+    }
+    let header = match read_gpt_header(dev, 1) {
+        Ok(Some(header)) => Some(header),
+        Ok(None) => None,
+        Err(err) => match dev.capacity().checked_sub(1) {
+            Some(last) if last > 1 => {
+                warn!("use the backup GPT header at LBA {}", last);
+                read_gpt_header(dev, last)?
+            }
+            _ => return Err(err),
+        },
+    };
+    match header {
+        Some(header) => parse_gpt(dev, &header),
+        None => {
+            warn!("no GPT signature in the protective MBR disk, use the MBR");
+            Ok(partitions)
+        }
+    }
+}
+
+/// A partition of a block device, whose accesses are offset to the start of
+/// the partition and limited to its size.
+///
+/// Only the blocking interface is supported, the events are those of the
+/// whole device.
+pub struct PartitionBlock {
+    dev: Arc<dyn BlockScheme>,
+    start_lba: u64,
+    sectors: u64,
+}
+
+impl PartitionBlock {
+    pub fn new(dev: Arc<dyn BlockScheme>, partition: &Partition) -> Self {
+        Self {
+            dev,
+            start_lba: partition.start_lba,
+            sectors: partition.sectors,
+        }
+    }
+
+    /// Returns the block of the device for the block `block_id` of `len`
+    /// bytes in the partition.
+    fn device_block(&self, block_id: usize, len: usize) -> DeviceResult<usize> {
+        let sectors = ((len + SECTOR_SIZE - 1) / SECTOR_SIZE) as u64;
+        match (block_id as u64).checked_add(sectors) {
+            Some(end) if end <= self.sectors => Ok((self.start_lba + block_id as u64) as usize),
+            _ => Err(DeviceError::InvalidParam),
+        }
+    }
+}
+
+impl Scheme for PartitionBlock {
+    fn name(&self) -> &str {
+        "partition"
+    }
+}
+
+impl EventScheme for PartitionBlock {
+    type Event = ();
+
+    fn trigger(&self, event: ()) {
+        self.dev.trigger(event)
+    }
+
+    fn subscribe(&self, handler: EventHandler, once: bool) {
+        self.dev.subscribe(handler, once)
+    }
+}
+
+impl BlockScheme for PartitionBlock {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
+        let block_id = self.device_block(block_id, buf.len())?;
+        self.dev.read_block(block_id, buf)
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
+        let block_id = self.device_block(block_id, buf.len())?;
+        self.dev.write_block(block_id, buf)
+    }
+
+    fn flush(&self) -> DeviceResult {
+        self.dev.flush()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.dev.is_read_only()
+    }
+
+    fn capacity(&self) -> u64 {
+        self.sectors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const DISK_SECTORS: usize = 64;
+
+    fn set_mbr_entry(disk: &mut [u8], index: usize, sys_id: u8, start: u32, sectors: u32) {
+        let entry = &mut disk[MBR_ENTRIES_OFFSET + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = sys_id;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        disk[SECTOR_SIZE - 2..SECTOR_SIZE].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    /// Write a GPT header at `lba` with 4 entries at `entries_lba`.
+    fn set_gpt_header(disk: &mut [u8], lba: u64, entries_lba: u64) {
+        let entries = &disk[entries_lba as usize * SECTOR_SIZE..][..4 * GPT_ENTRY_MIN_SIZE];
+        let entries_crc = crc32(entries);
+        let header = &mut disk[lba as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_MIN_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_MIN_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header[..GPT_HEADER_MIN_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Change the number and the size of the entries in the GPT header at
+    /// `lba`.
+    fn set_gpt_layout(disk: &mut [u8], lba: u64, count: u32, entry_size: u32) {
+        let header = &mut disk[lba as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        header[80..84].copy_from_slice(&count.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32(&header[..GPT_HEADER_MIN_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn set_gpt_entry(disk: &mut [u8], entries_lba: u64, index: usize, first: u64, last: u64) {
+        let entry = &mut disk[entries_lba as usize * SECTOR_SIZE + index * GPT_ENTRY_MIN_SIZE..]
+            [..GPT_ENTRY_MIN_SIZE];
+        entry[..16].copy_from_slice(&[0xaf; 16]);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_mbr() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        set_mbr_entry(&mut disk, 0, 0x0c, 8, 16);
+        set_mbr_entry(&mut disk, 2, 0x83, 24, 32);
+        disk[24 * SECTOR_SIZE] = 0x42;
+        let dev: Arc<dyn BlockScheme> = Arc::new(RamDisk::from_vec(disk));
+
+        let parts = scan_partitions(dev.clone()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1].index, 2);
+        assert_eq!((parts[1].start_lba, parts[1].sectors), (24, 32));
+        assert_eq!(parts[1].part_type, PartitionType::Mbr(0x83));
+
+        let part = PartitionBlock::new(dev, &parts[1]);
+        assert_eq!(part.capacity(), 32);
+        let mut buf = [0; SECTOR_SIZE];
+        part.read_block(0, &mut buf).unwrap();
+        assert_eq!(buf[0], 0x42);
+        assert!(part.read_block(32, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_gpt() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        set_mbr_entry(
+            &mut disk,
+            0,
+            MBR_TYPE_GPT_PROTECTIVE,
+            1,
+            DISK_SECTORS as u32 - 1,
+        );
+        set_gpt_entry(&mut disk, 2, 1, 34, 47);
+        set_gpt_header(&mut disk, 1, 2);
+        let last = DISK_SECTORS as u64 - 1;
+        set_gpt_header(&mut disk, last, 2);
+        let parts = scan_partitions(Arc::new(RamDisk::from_vec(disk.clone()))).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].index, 1);
+        assert_eq!((parts[0].start_lba, parts[0].sectors), (34, 14));
+        assert_eq!(parts[0].part_type, PartitionType::Gpt([0xaf; 16]));
+
+        // the backup header is used if the primary one is corrupted
+        disk[SECTOR_SIZE + 40] ^= 1;
+        let parts = scan_partitions(Arc::new(RamDisk::from_vec(disk.clone()))).unwrap();
+        assert_eq!(parts.len(), 1);
+        disk[last as usize * SECTOR_SIZE + 40] ^= 1;
+        assert!(scan_partitions(Arc::new(RamDisk::from_vec(disk.clone()))).is_err());
+
+        // no GPT signature, fall back to the MBR
+        disk[SECTOR_SIZE..2 * SECTOR_SIZE].fill(0);
+        let parts = scan_partitions(Arc::new(RamDisk::from_vec(disk))).unwrap();
+        assert_eq!(
+            parts[0].part_type,
+            PartitionType::Mbr(MBR_TYPE_GPT_PROTECTIVE)
+        );
+    }
+
+    #[test]
+    fn test_gpt_bad_layout() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE];
+        set_mbr_entry(
+            &mut disk,
+            0,
+            MBR_TYPE_GPT_PROTECTIVE,
+            1,
+            DISK_SECTORS as u32 - 1,
+        );
+        set_gpt_header(&mut disk, 1, 2);
+        // too small, not 128 * 2^n, larger than a sector, too many in total
+        for &(count, entry_size) in &[(4, 64), (4, 136), (4, 1024), (1024, 256), (2048, 128)] {
+            set_gpt_layout(&mut disk, 1, count, entry_size);
+            let dev = Arc::new(RamDisk::from_vec(disk.clone()));
+            assert!(
+                matches!(scan_partitions(dev), Err(DeviceError::InvalidParam)),
+                "{} entries of {} bytes",
+                count,
+                entry_size
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! A block device in the memory, for tests and initial RAM disks.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use lock::Mutex;
+
+use crate::scheme::{impl_event_scheme, BlockScheme, Scheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult};
+
+/// The size of a block.
+const BLOCK_SIZE: usize = 512;
+
+enum Storage {
+    Borrowed(&'static mut [u8]),
+    Owned(Vec<u8>),
+}
+
+impl Storage {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            Self::Borrowed(buf) => buf,
+            Self::Owned(buf) => buf.as_mut_slice(),
+        }
+    }
+}
+
+/// A block device backed by a memory buffer, of 512-byte blocks.
+///
+/// The bytes after the last whole block are not accessible.
+pub struct RamDisk {
+    storage: Mutex<Storage>,
+    blocks: usize,
+    read_only: bool,
+    listener: EventListener,
+}
+
+impl_event_scheme!(RamDisk);
+
+impl RamDisk {
+    /// Construct a `RamDisk` on the given memory, e.g. an embedded image.
+    pub fn new(buf: &'static mut [u8]) -> Self {
+        Self::with_storage(Storage::Borrowed(buf))
+    }
+
+    /// Construct a `RamDisk` owning the buffer.
+    pub fn from_vec(buf: Vec<u8>) -> Self {
+        Self::with_storage(Storage::Owned(buf))
+    }
+
+    fn with_storage(mut storage: Storage) -> Self {
+        let blocks = storage.as_mut_slice().len() / BLOCK_SIZE;
+        Self {
+            storage: Mutex::new(storage),
+            blocks,
+            read_only: false,
+            listener: EventListener::new(),
+        }
+    }
+
+    /// Reject all writes if `read_only` is true.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Returns the byte range of `len` bytes from the block `block_id`, which
+    /// must be whole blocks in the disk.
+    fn range(&self, block_id: usize, len: usize) -> DeviceResult<Range<usize>> {
+        let end = block_id.checked_add(len / BLOCK_SIZE);
+        if len % BLOCK_SIZE != 0 || end.map_or(true, |end| end > self.blocks) {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok(block_id * BLOCK_SIZE..block_id * BLOCK_SIZE + len)
+    }
+}
+
+impl Scheme for RamDisk {
+    fn name(&self) -> &str {
+        "ram-disk"
+    }
+}
+
+impl BlockScheme for RamDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
+        let range = self.range(block_id, buf.len())?;
+        buf.copy_from_slice(&self.storage.lock().as_mut_slice()[range]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
+        if self.read_only {
+            return Err(DeviceError::NotSupported);
+        }
+        let range = self.range(block_id, buf.len())?;
+        self.storage.lock().as_mut_slice()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// The writes are done in the memory already.
+    fn flush(&self) -> DeviceResult {
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn capacity(&self) -> u64 {
+        self.blocks as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{boxed::Box, vec};
+
+    #[test]
+    fn test_read_write() {
+        let disk = RamDisk::from_vec(vec![0; BLOCK_SIZE * 4 + 100]);
+        assert_eq!(disk.capacity(), 4);
+
+        let data = [0x5a; BLOCK_SIZE * 2];
+        disk.write_block(2, &data).unwrap();
+        let mut buf = [0; BLOCK_SIZE];
+        disk.read_block(3, &mut buf).unwrap();
+        assert_eq!(buf, [0x5a; BLOCK_SIZE]);
+        disk.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf, [0; BLOCK_SIZE]);
+        assert!(disk.flush().is_ok());
+
+        // out of range, or not whole blocks
+        assert!(matches!(
+            disk.read_block(4, &mut buf),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.write_block(3, &data),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.read_block(0, &mut buf[..100]),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            disk.read_block(usize::MAX, &mut buf),
+            Err(DeviceError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let image = Box::leak(Box::new([0x11u8; BLOCK_SIZE * 2]));
+        let disk = RamDisk::new(image).read_only(true);
+        assert!(disk.is_read_only());
+        assert!(matches!(
+            disk.write_block(0, &[0; BLOCK_SIZE]),
+            Err(DeviceError::NotSupported)
+        ));
+        let mut buf = [0; BLOCK_SIZE];
+        disk.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf, [0x11; BLOCK_SIZE]);
+    }
+}
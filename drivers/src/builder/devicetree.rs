@@ -17,125 +17,877 @@
 //!
 //! Specification: <https://github.com/devicetree-org/devicetree-specification/releases/download/v0.3/devicetree-specification-v0.3.pdf>.
 
-use super::IoMapper;
+use super::{IoMapper, MmioAttrs};
 use crate::{
+    bus::{
+        pci_ecam::{PciBar, PciFunction},
+        PAGE_SIZE,
+    },
+    prelude::{IrqPolarity, IrqTriggerMode},
+    scheme::{GpioScheme, IrqScheme},
     utils::devicetree::{
-        parse_interrupts, parse_reg, Devicetree, InheritProps, InterruptsProp, Node, StringList,
+        is_enabled, parse_interrupts, parse_reg, parse_reg_all, CpuInfo, Devicetree, InheritProps,
+        InterruptsProp, Node, StringList, FDT_HEADER_SIZE,
     },
-    Device, DeviceError, DeviceResult, VirtAddr,
+    Device, DeviceError, DeviceResult, PhysAddr, VirtAddr,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
 };
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{cell::RefCell, fmt, ops::Range};
 
 const MODULE: &str = "device-tree";
 
+/// The reference clock of PL011, if not specified (same as QEMU virt machine).
+const PL011_CLOCK_FREQ: u32 = 24_000_000;
+/// The input clock of Allwinner UARTs (APB1), if not specified.
+#[cfg(feature = "board-d1")]
+const ALLWINNER_UART_CLOCK_FREQ: u32 = 24_000_000;
+/// The `GPIO_ACTIVE_LOW` flag in GPIO specifiers.
+const GPIO_ACTIVE_LOW: u32 = 1;
+/// The kernel command line option to skip nodes with the compatible.
+const CMDLINE_DENY: &str = "drivers.deny=";
+/// The kernel command line option to probe only nodes with the compatible.
+const CMDLINE_ALLOW: &str = "drivers.allow=";
+/// The baud rate of UARTs configured at probe, if `current-speed` is not
+/// specified.
+const DEFAULT_BAUD_RATE: u32 = 115200;
+/// The `#size-cells` of PCI host bridges, if not specified.
+const PCI_SIZE_CELLS: u32 = 2;
+
 type DevWithInterrupt = (Device, InterruptsProp);
 
+/// A function to create the driver of a device tree node, registered by
+/// [`DevicetreeDriverBuilder::register_probe`].
+///
+/// It is given the node, the properties inherited from its ancestors, and a
+/// function to map a physical address range to the virtual address. Returns
+/// the device and its interrupts, which are parsed from the node if `None`.
+pub type ProbeFn = dyn Fn(
+    &Node,
+    &InheritProps,
+    &dyn Fn(PhysAddr, usize) -> DeviceResult<VirtAddr>,
+) -> DeviceResult<(Device, Option<InterruptsProp>)>;
+
+/// A function to create the driver of an interrupt controller node,
+/// registered by [`DevicetreeDriverBuilder::register_intc_probe`].
+///
+/// It is given the same arguments as [`ProbeFn`]. The first cell of the
+/// interrupt specifiers referring to the controller is the IRQ number, and the
+/// second one, if any, is the trigger type as `IRQ_TYPE_*` in Linux.
+pub type IntcProbeFn = dyn Fn(
+    &Node,
+    &InheritProps,
+    &dyn Fn(PhysAddr, usize) -> DeviceResult<VirtAddr>,
+) -> DeviceResult<Arc<dyn IrqScheme>>;
+
+/// 内置的设备解析函数，最后一个参数为预先创建的 GPIO 控制器
+type BuiltinProbe<M> = fn(
+    &DevicetreeDriverBuilder<M>,
+    &Node,
+    &StringList,
+    &InheritProps,
+    &BTreeMap<String, GpioCtrl>,
+) -> DeviceResult<Vec<DevWithInterrupt>>;
+
+/// 按兼容字符串查找的设备解析函数
+enum Probe<M: IoMapper> {
+    Builtin(BuiltinProbe<M>),
+    External(Box<ProbeFn>),
+}
+
+/// 兼容字符串为 `comp` 的内置解析函数
+fn builtin<M: IoMapper>(comp: &str, probe: BuiltinProbe<M>) -> (String, Probe<M>) {
+    (String::from(comp), Probe::Builtin(probe))
+}
+
+/// 将中断说明符翻译为中断控制器的中断号，无效时返回 `None`
+type SpecToIrq = fn(&[u32]) -> Option<usize>;
+
+/// 从中断说明符中解码触发方式，说明符中没有指定时返回 `None`
+type SpecToTrigger = fn(&[u32]) -> Option<(IrqTriggerMode, IrqPolarity)>;
+
 /// 设备树中中断控制器特有的属性
 struct IntcProps {
+    irq: Arc<dyn IrqScheme>,
     phandle: u32,
     interrupt_cells: u32,
+    spec_to_irq: SpecToIrq,
+    spec_to_trigger: SpecToTrigger,
 }
 
 /// 查找表保存的中断控制器信息
 struct Intc {
-    index: usize,
+    irq: Arc<dyn IrqScheme>,
+    cells: usize,
+    spec_to_irq: SpecToIrq,
+    spec_to_trigger: SpecToTrigger,
+}
+
+/// 中断说明符的第一个参数即为中断号，`0xffffffff` 表示没有中断
+fn first_cell_to_irq(spec: &[u32]) -> Option<usize> {
+    match spec.first() {
+        Some(&irq_num) if irq_num != 0xffff_ffff => Some(irq_num as _),
+        _ => None,
+    }
+}
+
+/// 按照 `IRQ_TYPE_*` 解码触发方式，`IRQ_TYPE_NONE` 或无效的值返回 `None`
+fn irq_type_to_trigger(flags: u32) -> Option<(IrqTriggerMode, IrqPolarity)> {
+    match flags & 0xf {
+        1 => Some((IrqTriggerMode::Edge, IrqPolarity::ActiveHigh)),
+        2 => Some((IrqTriggerMode::Edge, IrqPolarity::ActiveLow)),
+        4 => Some((IrqTriggerMode::Level, IrqPolarity::ActiveHigh)),
+        8 => Some((IrqTriggerMode::Level, IrqPolarity::ActiveLow)),
+        _ => None,
+    }
+}
+
+/// 中断说明符中没有触发方式
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+fn no_trigger(_spec: &[u32]) -> Option<(IrqTriggerMode, IrqPolarity)> {
+    None
+}
+
+/// 中断说明符的第二个参数为触发方式
+fn second_cell_to_trigger(spec: &[u32]) -> Option<(IrqTriggerMode, IrqPolarity)> {
+    irq_type_to_trigger(*spec.get(1)?)
+}
+
+/// 中断说明符的第三个参数为触发方式，如 GIC
+#[cfg(target_arch = "aarch64")]
+fn third_cell_to_trigger(spec: &[u32]) -> Option<(IrqTriggerMode, IrqPolarity)> {
+    irq_type_to_trigger(*spec.get(2)?)
+}
+
+/// 将 GPIO 说明符翻译为控制器的引脚号和标志，无效时返回 `None`
+type SpecToPin = fn(&[u32]) -> Option<(usize, u32)>;
+
+/// 预先创建的 GPIO 控制器
+struct GpioCtrl {
+    gpio: Arc<dyn GpioScheme>,
+    phandle: Option<u32>,
     cells: usize,
+    spec_to_pin: SpecToPin,
+    /// 创建控制器时映射的区域
+    mappings: Vec<(VirtAddr, usize)>,
+}
+
+/// GPIO 说明符为 `<bank pin flags>`，每组 32 个引脚
+fn bank_pin_to_pin(spec: &[u32]) -> Option<(usize, u32)> {
+    match *spec {
+        [bank, pin, flags] if pin < 32 => Some((bank as usize * 32 + pin as usize, flags)),
+        _ => None,
+    }
+}
+
+/// M 级 APLIC 域由固件管理，返回其中断源被委托到的子域的 phandle，不是 M 级
+/// 域时返回 `None`
+fn aplic_delegate(node: &Node) -> Option<u32> {
+    ["riscv,delegation", "riscv,delegate", "riscv,children"]
+        .iter()
+        .find_map(|name| node.prop_cells(name).ok()?.first().copied())
+}
+
+/// A probed device with its names and the node it came from.
+pub struct NamedDevice {
+    /// The aliases of the device node in `/aliases`, or the node name with
+    /// unit address (e.g. `serial@10000000`) if there is no alias.
+    pub names: Vec<String>,
+    /// The full path of the device node, e.g. `/soc/serial@10000000`.
+    pub path: String,
+    /// The first (most specific) string of the `compatible` property.
+    pub compatible: String,
+    /// The physical address range of the first `reg` window, if any.
+    pub mmio: Option<Range<PhysAddr>>,
+    /// The virtual address ranges mapped for the device, which are unmapped
+    /// by [`IoMapper::unmap`] when the device is removed. Empty if they are
+    /// shared with other devices of the node, e.g. the PCI functions.
+    pub mappings: Vec<(VirtAddr, usize)>,
+    /// The interrupts registered for the device, with the controllers they are
+    /// registered to.
+    pub irqs: Vec<(Arc<dyn IrqScheme>, usize)>,
+    /// The device.
+    pub device: Device,
+}
+
+/// What happened to a device tree node in [`DevicetreeDriverBuilder::build`].
+#[derive(Debug)]
+pub enum ProbeStatus {
+    /// Devices are created from the node.
+    Probed,
+    /// The node is disabled by its `status` property.
+    Disabled,
+    /// The node is skipped by the compatible filters.
+    Filtered,
+    /// No driver supports the node.
+    NotSupported,
+    /// The node is skipped since it has been probed, or its MMIO region is
+    /// used by another device.
+    Duplicated,
+    /// Failed to create the devices.
+    Failed(DeviceError),
+}
+
+/// The probe result of a device tree node.
+#[derive(Debug)]
+pub struct ProbeRecord {
+    /// The full path of the node.
+    pub path: String,
+    /// All strings of the `compatible` property.
+    pub compatible: Vec<String>,
+    /// What happened to the node.
+    pub status: ProbeStatus,
+    /// The physical address range of the first `reg` window of the probed
+    /// node, if any.
+    pub mmio: Option<Range<PhysAddr>>,
+    /// The IRQ numbers registered for the devices of the probed node.
+    pub irqs: Vec<usize>,
+}
+
+impl ProbeRecord {
+    fn new(node: &Node, props: &InheritProps, status: ProbeStatus) -> Self {
+        let compatible = node
+            .prop_str("compatible")
+            .map(|c| {
+                c.split('\0')
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            path: props.path.clone(),
+            compatible,
+            status,
+            mmio: None,
+            irqs: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ProbeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}: ", self.path, self.compatible)?;
+        match &self.status {
+            ProbeStatus::Probed => write!(f, "probed")?,
+            ProbeStatus::Disabled => write!(f, "disabled")?,
+            ProbeStatus::Filtered => write!(f, "filtered")?,
+            ProbeStatus::NotSupported => write!(f, "not supported")?,
+            ProbeStatus::Duplicated => write!(f, "duplicated")?,
+            ProbeStatus::Failed(err) => write!(f, "failed ({err:?})")?,
+        }
+        if let Some(mmio) = &self.mmio {
+            write!(f, ", mmio={mmio:#x?}")?;
+        }
+        if !self.irqs.is_empty() {
+            write!(f, ", irqs={:?}", self.irqs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Devices probed from the device tree.
+pub struct ProbedDevices {
+    /// All probed devices, in the order of the device tree.
+    pub devices: Vec<NamedDevice>,
+    /// Index of the UART in `devices` specified by `/chosen/stdout-path`, as
+    /// the boot console.
+    pub console: Option<usize>,
+    /// Interrupts failed to register, with the full paths of the device nodes
+    /// they belong to.
+    pub irq_errors: Vec<(String, DeviceError)>,
+    /// What happened to each node of the device tree, in the order they are
+    /// visited.
+    pub records: Vec<ProbeRecord>,
+    /// The kernel command line in `/chosen/bootargs`.
+    pub bootargs: Option<String>,
+    /// The address and size of the init RAM disk in `/chosen`.
+    pub initrd: Option<(PhysAddr, usize)>,
+}
+
+/// The information in the `/chosen` node, returned by
+/// [`DevicetreeDriverBuilder::build_with_chosen`].
+pub struct ChosenInfo {
+    /// The UART specified by `stdout-path`, e.g. `serial0:115200n8`, as the
+    /// boot console.
+    pub stdout: Option<Device>,
+    /// The kernel command line in `bootargs`.
+    pub bootargs: Option<String>,
 }
 
 /// A builder to probe devices and create drivers from device tree.
 pub struct DevicetreeDriverBuilder<M: IoMapper> {
     dt: Devicetree,
     io_mapper: M,
+    probe_disabled: bool,
+    /// Log the probe result of each node after build.
+    dump: bool,
+    /// Skip the nodes with any of these compatibles.
+    deny: Vec<String>,
+    /// Probe only the nodes with any of these compatibles, and the interrupt
+    /// controllers.
+    allow: Option<Vec<String>>,
+    /// The probe functions with the compatibles they handle, the registered
+    /// ones come before the built-in ones.
+    probes: Vec<(String, Probe<M>)>,
+    /// The registered probe functions of interrupt controllers, consulted
+    /// before the built-in ones.
+    intc_probes: Vec<(String, Box<IntcProbeFn>)>,
+    /// The regions mapped for the node being probed, which are unmapped if
+    /// it fails.
+    mappings: RefCell<Vec<(VirtAddr, usize)>>,
 }
 
 impl<M: IoMapper> DevicetreeDriverBuilder<M> {
     /// Prepare to parse DTB from the given virtual address.
     pub fn new(dtb_base_vaddr: VirtAddr, io_mapper: M) -> DeviceResult<Self> {
-        Ok(Self {
-            dt: Devicetree::from(dtb_base_vaddr)?,
+        Ok(Self::with_devicetree(
+            Devicetree::from(dtb_base_vaddr)?,
             io_mapper,
-        })
+        ))
     }
 
-    /// Parse the device tree from root, and returns an array of [`Device`] it found.
-    pub fn build(&self) -> DeviceResult<Vec<Device>> {
+    /// Prepare to parse DTB from the given physical address, which is mapped
+    /// by the `io_mapper` while being loaded, and unmapped after that.
+    pub fn from_phys(dtb_paddr: PhysAddr, io_mapper: M) -> DeviceResult<Self> {
+        let unmap = |vaddr: VirtAddr, len: usize| {
+            if let Err(err) = io_mapper.unmap(vaddr, len) {
+                warn!("{MODULE}: failed to unmap DTB at {vaddr:#x}: {err:?}");
+            }
+        };
+        // 先映射到页尾以读取头部，DTB 不超过这一页时不需要再次映射
+        let mut len = (PAGE_SIZE - dtb_paddr % PAGE_SIZE).max(FDT_HEADER_SIZE);
+        let mut vaddr = io_mapper.map_mmio(dtb_paddr, len, MmioAttrs::Cached)?;
+        let header = unsafe { core::slice::from_raw_parts(vaddr as *const u8, FDT_HEADER_SIZE) };
+        let total_size = Devicetree::blob_size(header).map_err(|err| {
+            unmap(vaddr, len);
+            err
+        })?;
+        if total_size > len {
+            unmap(vaddr, len);
+            len = total_size;
+            vaddr = io_mapper.map_mmio(dtb_paddr, len, MmioAttrs::Cached)?;
+        }
+        let blob = unsafe { core::slice::from_raw_parts(vaddr as *const u8, total_size) };
+        // 加载后的设备树不再引用 DTB
+        let dt = Devicetree::from_bytes(blob);
+        unmap(vaddr, len);
+        Ok(Self::with_devicetree(dt?, io_mapper))
+    }
+
+    fn with_devicetree(dt: Devicetree, io_mapper: M) -> Self {
+        Self {
+            dt,
+            io_mapper,
+            probe_disabled: false,
+            dump: false,
+            deny: Vec::new(),
+            allow: None,
+            probes: Self::builtin_probes(),
+            intc_probes: Vec::new(),
+            mappings: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Also probe nodes whose `status` is not `"okay"`, for debugging.
+    pub fn probe_disabled(mut self, probe_disabled: bool) -> Self {
+        self.probe_disabled = probe_disabled;
+        self
+    }
+
+    /// Log the probe result of each node after [`build`](Self::build), which
+    /// is also returned in [`ProbedDevices::records`].
+    pub fn dump(mut self, dump: bool) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    /// Skip the nodes with the compatible `comp`, including interrupt
+    /// controllers.
+    pub fn deny_compatible(mut self, comp: &str) -> Self {
+        self.deny.push(String::from(comp));
+        self
+    }
+
+    /// Probe only the nodes with any of the compatibles `comps`. Interrupt
+    /// controllers are always probed unless denied.
+    pub fn allow_only(mut self, comps: &[&str]) -> Self {
+        let allow = self.allow.get_or_insert_with(Vec::new);
+        allow.extend(comps.iter().map(|&c| String::from(c)));
+        self
+    }
+
+    /// Configure the compatible filters by the kernel command line, each
+    /// `drivers.deny=<compatible>` or `drivers.allow=<compatible>` option
+    /// adds a compatible, e.g. `drivers.deny=virtio,mmio`.
+    pub fn filter_from_cmdline(mut self, cmdline: &str) -> Self {
+        for opt in cmdline.split_whitespace() {
+            if let Some(comp) = opt.strip_prefix(CMDLINE_DENY) {
+                self = self.deny_compatible(comp);
+            } else if let Some(comp) = opt.strip_prefix(CMDLINE_ALLOW) {
+                self = self.allow_only(&[comp]);
+            }
+        }
+        self
+    }
+
+    /// Create the devices of the nodes with the compatible `comp` by `probe`,
+    /// e.g. for the devices of a board which have no built-in drivers. The
+    /// registered functions are consulted in order, before the built-in ones.
+    pub fn register_probe(
+        mut self,
+        comp: &str,
+        probe: impl Fn(
+                &Node,
+                &InheritProps,
+                &dyn Fn(PhysAddr, usize) -> DeviceResult<VirtAddr>,
+            ) -> DeviceResult<(Device, Option<InterruptsProp>)>
+            + 'static,
+    ) -> Self {
+        let pos = self
+            .probes
+            .iter()
+            .take_while(|(_, p)| matches!(p, Probe::External(_)))
+            .count();
+        self.probes
+            .insert(pos, (String::from(comp), Probe::External(Box::new(probe))));
+        self
+    }
+
+    /// Create the interrupt controllers of the nodes with the compatible `comp`
+    /// by `probe`, see [`register_probe`](Self::register_probe). The
+    /// registered functions are consulted in order, before the built-in ones.
+    pub fn register_intc_probe(
+        mut self,
+        comp: &str,
+        probe: impl Fn(
+                &Node,
+                &InheritProps,
+                &dyn Fn(PhysAddr, usize) -> DeviceResult<VirtAddr>,
+            ) -> DeviceResult<Arc<dyn IrqScheme>>
+            + 'static,
+    ) -> Self {
+        self.intc_probes.push((String::from(comp), Box::new(probe)));
+        self
+    }
+
+    /// Whether the node with compatible `comp` is skipped by the filters.
+    fn is_filtered(&self, comp: &StringList, is_intc: bool) -> bool {
+        if self.deny.iter().any(|c| comp.contains(c.as_str())) {
+            return true;
+        }
+        match &self.allow {
+            Some(allow) if !is_intc => !allow.iter().any(|c| comp.contains(c.as_str())),
+            _ => false,
+        }
+    }
+
+    /// Returns why the node is skipped if it is disabled or filtered, `is_intc`
+    /// is whether it is an interrupt controller.
+    fn skip_status(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+        is_intc: bool,
+    ) -> Option<ProbeStatus> {
+        debug!(
+            "{MODULE}: parsing node {:?} with compatible {comp:?}",
+            node.name
+        );
+        if !self.probe_disabled && (!is_enabled(node) || props.parent_disabled) {
+            debug!(
+                "{MODULE}: skip disabled node {:?} with compatible {comp:?}",
+                props.path
+            );
+            return Some(ProbeStatus::Disabled);
+        }
+        if self.is_filtered(comp, is_intc) {
+            info!(
+                "{MODULE}: skip node {:?} with compatible {comp:?} by the filters",
+                props.path
+            );
+            return Some(ProbeStatus::Filtered);
+        }
+        None
+    }
+
+    /// Merge a device tree overlay blob into the tree before
+    /// [`build`](Self::build), see [`Devicetree::apply_overlay`].
+    pub fn apply_overlay(&mut self, overlay: &[u8]) -> DeviceResult {
+        self.dt.apply_overlay(overlay)
+    }
+
+    /// Parse the device tree from root, and returns all [`Device`]s it found,
+    /// with the nodes they came from and the boot console.
+    pub fn build(&self) -> DeviceResult<ProbedDevices> {
         let mut intc_map = BTreeMap::new(); // phandle -> intc
         let mut dev_list = Vec::new(); // devices
+        let stdout_node = self.dt.stdout_node();
+        let mut console = None;
+        let aliases = self.dt.aliases();
+        let mut infos = Vec::new(); // names, path, compatible and MMIO range of each device
+        let mut dup_phandle = None; // phandle shared by multiple interrupt controllers
+        let mut intc_alias = BTreeMap::new(); // phandle of M-level APLIC -> S-level APLIC
+        let gpio_ctrls = self.probe_gpio_controllers(); // path -> GPIO controller
+        let mut claimed: Vec<Range<usize>> = Vec::new(); // MMIO regions of probed devices
+        let mut probed_phandles = BTreeSet::new(); // phandles of probed nodes
+        let mut records = Vec::new(); // probe result and device indices of each node
 
-        // 解析设备树
+        // 解析节点并记录得到的设备及其来源节点，跳过重复的节点和已被占用的 MMIO 区域，
+        // 返回设备在列表中的下标范围
+        let mut probe =
+            |node: &Node,
+             props: &InheritProps,
+             parse: &mut dyn FnMut() -> DeviceResult<Vec<DevWithInterrupt>>| {
+                let phandle = node.prop_u32("phandle").ok();
+                if let Some(phandle) = phandle.filter(|p| probed_phandles.contains(p)) {
+                    warn!(
+                        "{MODULE}: skip node {:?}, phandle {phandle:#x} already probed",
+                        props.path
+                    );
+                    return Err(ProbeStatus::Duplicated);
+                }
+                let regions: Vec<Range<usize>> = parse_reg_all(node, props)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|&(_, size)| size > 0)
+                    .map(|(paddr, size)| paddr as usize..(paddr + size) as usize)
+                    .collect();
+                if let Some(region) = regions
+                    .iter()
+                    .find(|r| claimed.iter().any(|c| r.start < c.end && c.start < r.end))
+                {
+                    warn!(
+                        "{MODULE}: skip node {:?}, MMIO region {region:#x?} already claimed",
+                        props.path
+                    );
+                    return Err(ProbeStatus::Duplicated);
+                }
+                let devs = match parse() {
+                    Ok(devs) => devs,
+                    Err(err) => {
+                        // 解析失败前已经映射的区域不再使用
+                        self.release_mappings();
+                        if let DeviceError::NotSupported = err {
+                            return Err(ProbeStatus::NotSupported);
+                        }
+                        warn!("{MODULE}: failed to parsing node {:?}: {err:?}", props.path);
+                        return Err(ProbeStatus::Failed(err));
+                    }
+                };
+                // 多个设备共用节点映射的区域时，不随某个设备释放
+                let mappings = self.take_mappings();
+                let mappings = if devs.len() == 1 {
+                    mappings
+                } else {
+                    Vec::new()
+                };
+                claimed.extend(regions);
+                probed_phandles.extend(phandle);
+
+                let mut dev_names: Vec<String> = aliases
+                    .iter()
+                    .filter(|(_, n)| core::ptr::eq(*n, node))
+                    .map(|(alias, _)| String::from(*alias))
+                    .collect();
+                if dev_names.is_empty() {
+                    dev_names.push(node.name.clone());
+                }
+                let compatible = node
+                    .prop_str("compatible")
+                    .ok()
+                    .and_then(|c| c.split('\0').next())
+                    .map(String::from)
+                    .unwrap_or_default();
+                let mmio = parse_reg(node, props)
+                    .ok()
+                    .map(|(paddr, size)| paddr as usize..(paddr + size) as usize);
+                let start = dev_list.len();
+                for mut dev in devs {
+                    // route the interrupts through interrupt nexus nodes
+                    dev.1 = self.dt.resolve_interrupts(node, &dev.1);
+                    if matches!(dev.0, Device::Uart(_))
+                        && stdout_node.map_or(false, |n| core::ptr::eq(n, node))
+                    {
+                        console = Some(dev_list.len());
+                    }
+                    infos.push((
+                        dev_names.clone(),
+                        props.path.clone(),
+                        compatible.clone(),
+                        mappings.clone(),
+                        mmio.clone(),
+                    ));
+                    dev_list.push(dev)
+                }
+                Ok(start..dev_list.len())
+            };
+
+        // 第一遍只解析中断控制器，使其不依赖在设备树中出现的顺序
         self.dt.walk(&mut |node, comp, props| {
-            debug!(
-                "{MODULE}: parsing node {:?} with compatible {comp:?}",
-                node.name
-            );
-            // parse interrupt controller
-            let res = if node.has_prop("interrupt-controller") {
+            // GPIO 控制器已经创建，与其他设备一起解析其中断
+            if !node.has_prop("interrupt-controller") || gpio_ctrls.contains_key(&props.path) {
+                return;
+            }
+            if let Some(status) = self.skip_status(node, comp, props, true) {
+                records.push((ProbeRecord::new(node, props, status), 0..0));
+                return;
+            }
+            // 注册到 M 级 APLIC 域的中断，转而注册到被委托的 S 级子域
+            if comp.contains("riscv,aplic") {
+                if let (Ok(phandle), Some(child)) = (node.prop_u32("phandle"), aplic_delegate(node))
+                {
+                    intc_alias.insert(phandle, child);
+                    let record = ProbeRecord::new(node, props, ProbeStatus::NotSupported);
+                    records.push((record, 0..0));
+                    return;
+                }
+            }
+            // 不同的中断控制器使用了相同的 phandle
+            if let Some(phandle) = node
+                .prop_u32("phandle")
+                .ok()
+                .filter(|p| intc_map.contains_key(p))
+            {
+                dup_phandle = Some(phandle);
+                records.push((ProbeRecord::new(node, props, ProbeStatus::Duplicated), 0..0));
+                return;
+            }
+            let res = probe(node, props, &mut || {
                 self.parse_intc(node, comp, props).map(|(dev, intc)| {
                     intc_map.insert(
                         intc.phandle,
                         Intc {
-                            index: dev_list.len(),
+                            irq: intc.irq,
                             cells: intc.interrupt_cells as _,
+                            spec_to_irq: intc.spec_to_irq,
+                            spec_to_trigger: intc.spec_to_trigger,
                         },
                     );
-                    dev
+                    vec![dev]
                 })
-            } else {
-                // parse other device
-                match comp {
-                    #[cfg(feature = "virtio")]
-                    c if c.contains("virtio,mmio") => self.parse_virtio(node, props),
-                    #[cfg(not(feature = "loopback"))]
-                    c if c.contains("allwinner,sunxi-gmac") => {
-                        self.parse_ethernet(node, comp, props)
-                    }
-                    c if c.contains("ns16550a")
-                        || c.contains("allwinner,sun20i-uart")
-                        || c.contains("snps,dw-apb-uart")
-                        || c.contains("sifive,fu740-c000-uart") =>
-                    {
-                        self.parse_uart(node, comp, props)
-                    }
-                    _ => Err(DeviceError::NotSupported),
-                }
-            };
-            match res {
-                Ok(dev) => dev_list.push(dev),
-                Err(DeviceError::NotSupported) => {}
-                Err(err) => warn!("{MODULE}: failed to parsing node {:?}: {err:?}", node.name),
+            });
+            records.push(match res {
+                Ok(devs) => (ProbeRecord::new(node, props, ProbeStatus::Probed), devs),
+                Err(status) => (ProbeRecord::new(node, props, status), 0..0),
+            });
+        });
+
+        // 第二遍解析其他设备
+        self.dt.walk(&mut |node, comp, props| {
+            let gpio_ctrl = gpio_ctrls.get(&props.path);
+            if node.has_prop("interrupt-controller") && gpio_ctrl.is_none() {
+                return;
+            }
+            if let Some(status) = self.skip_status(node, comp, props, false) {
+                records.push((ProbeRecord::new(node, props, status), 0..0));
+                return;
             }
+            let res = probe(node, props, &mut || {
+                if let Some(ctrl) = gpio_ctrl {
+                    // GPIO 控制器已经创建，只需解析它的中断
+                    parse_interrupts(node, props).map(|irqs| {
+                        // 控制器仍被引用时不能释放其映射的区域
+                        let mut mappings = self.mappings.borrow_mut();
+                        mappings.extend(ctrl.mappings.iter().copied());
+                        vec![(Device::Gpio(ctrl.gpio.clone()), irqs)]
+                    })
+                } else {
+                    self.probe_node(node, comp, props, &gpio_ctrls)
+                }
+            });
+            records.push(match res {
+                Ok(devs) => (ProbeRecord::new(node, props, ProbeStatus::Probed), devs),
+                Err(status) => (ProbeRecord::new(node, props, status), 0..0),
+            });
         });
 
-        // 注册中断
-        for (device, interrupts_extended) in &dev_list {
+        // 中断控制器的查找表不一致时，无法确定中断应该注册到哪里
+        if let Some(phandle) = dup_phandle {
+            warn!("{MODULE}: multiple interrupt controllers with phandle {phandle:#x}");
+            return Err(DeviceError::InvalidDtb);
+        }
+
+        // 注册中断，失败时记录下来并继续注册其他中断
+        let mut irq_errors = Vec::new();
+        let mut dev_irqs = Vec::new();
+        for ((device, interrupts_extended), (_, path, ..)) in dev_list.iter().zip(&infos) {
+            let mut registered = Vec::new();
             let mut extended = interrupts_extended.as_slice();
-            // 分解 interrupts_extended
-            while let [phandle, irq_num, ..] = extended {
-                if let Some(Intc { index, cells }) = intc_map.get(phandle) {
-                    let (intc, _) = &dev_list[*index];
-                    extended = &extended[1 + cells..];
-                    if let Device::Irq(irq) = intc {
-                        if *irq_num != 0xffff_ffff {
-                            info!("{MODULE}: register interrupts for {intc:?}: {device:?}, irq_num={irq_num}");
-                            if irq.register_device(*irq_num as _, device.inner()).is_ok() {
-                                irq.unmask(*irq_num as _)?;
+            // 分解 interrupts_extended，逐个注册其中的中断说明符
+            while let [phandle, rest @ ..] = extended {
+                let Intc {
+                    irq,
+                    cells,
+                    spec_to_irq,
+                    spec_to_trigger,
+                } = match intc_map
+                    .get(phandle)
+                    .or_else(|| intc_map.get(intc_alias.get(phandle)?))
+                {
+                    Some(intc) => intc,
+                    None => {
+                        warn!(
+                            "{MODULE}: no such node with phandle {phandle:#x} as the interrupt-parent of {path:?}"
+                        );
+                        // 无法确定说明符的长度，放弃该设备剩余的中断
+                        irq_errors.push((path.clone(), DeviceError::InvalidParam));
+                        break;
+                    }
+                };
+                if rest.len() < *cells {
+                    warn!("{MODULE}: truncated interrupt specifier {rest:x?} of {path:?}");
+                    irq_errors.push((path.clone(), DeviceError::InvalidParam));
+                    break;
+                }
+                let (spec, next) = rest.split_at(*cells);
+                extended = next;
+                // `0xffffffff` 是占位的说明符，表示没有中断
+                if spec.first() == Some(&0xffff_ffff) {
+                    continue;
+                }
+                let res = match spec_to_irq(spec) {
+                    Some(irq_num) => {
+                        info!(
+                            "{MODULE}: register interrupts for {:?}: {device:?}, irq_num={irq_num}",
+                            irq.name()
+                        );
+                        // 触发方式不被支持时只给出警告，仍然注册中断
+                        if let Some((tm, pol)) = spec_to_trigger(spec) {
+                            if let Err(err) = irq.configure(irq_num, tm, pol) {
+                                warn!(
+                                    "{MODULE}: failed to configure interrupt {irq_num} of {path:?} as {tm:?} {pol:?}: {err:?}"
+                                );
                             }
                         }
-                    } else {
-                        warn!("{MODULE}: node with phandle {phandle:#x} is not an interrupt-controller");
-                        return Err(DeviceError::InvalidParam);
+                        irq.register_device(irq_num, device.inner())
+                            .and_then(|_| irq.unmask(irq_num))
+                            .map(|_| irq_num)
                     }
-                } else {
-                    warn!(
-                        "{MODULE}: no such node with phandle {phandle:#x} as the interrupt-parent"
-                    );
-                    return Err(DeviceError::InvalidParam);
+                    None => Err(DeviceError::InvalidParam),
+                };
+                match res {
+                    Ok(irq_num) => registered.push((irq.clone(), irq_num)),
+                    Err(err) => {
+                        warn!(
+                            "{MODULE}: failed to register interrupt {spec:x?} of {path:?} to {:?}: {err:?}",
+                            irq.name()
+                        );
+                        irq_errors.push((path.clone(), err));
+                    }
+                }
+            }
+            dev_irqs.push(registered);
+        }
+
+        // 补充成功解析的节点的 MMIO 区域和中断号
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|(mut record, devs)| {
+                if let Some((.., mmio)) = infos.get(devs.start).filter(|_| !devs.is_empty()) {
+                    record.mmio = mmio.clone();
                 }
+                record.irqs = dev_irqs[devs]
+                    .iter()
+                    .flatten()
+                    .map(|(_, irq_num)| *irq_num)
+                    .collect();
+                record
+            })
+            .collect();
+        if self.dump {
+            for record in &records {
+                info!("{MODULE}: {record}");
             }
         }
 
         // 丢弃中断信息
-        Ok(dev_list.into_iter().map(|(dev, _)| dev).collect())
+        Ok(ProbedDevices {
+            devices: dev_list
+                .into_iter()
+                .zip(infos)
+                .zip(dev_irqs)
+                .map(
+                    |(((device, _), (names, path, compatible, mappings, mmio)), irqs)| {
+                        NamedDevice {
+                            names,
+                            path,
+                            compatible,
+                            mmio,
+                            mappings,
+                            irqs,
+                            device,
+                        }
+                    },
+                )
+                .collect(),
+            console,
+            irq_errors,
+            records,
+            bootargs: self.bootargs().map(String::from),
+            initrd: self.initrd(),
+        })
+    }
+
+    /// Parse the device tree from root, and returns all [`Device`]s it found,
+    /// without the node information.
+    pub fn build_devices(&self) -> DeviceResult<Vec<Device>> {
+        Ok(self
+            .build()?
+            .devices
+            .into_iter()
+            .map(|d| d.device)
+            .collect())
+    }
+
+    /// Same as [`build_devices`](Self::build_devices), and also returns the
+    /// boot console and the kernel command line in the `/chosen` node.
+    pub fn build_with_chosen(&self) -> DeviceResult<(Vec<Device>, ChosenInfo)> {
+        let probed = self.build()?;
+        let chosen = ChosenInfo {
+            stdout: probed.console.map(|i| probed.devices[i].device.clone()),
+            bootargs: probed.bootargs,
+        };
+        let devices = probed.devices.into_iter().map(|d| d.device).collect();
+        Ok((devices, chosen))
+    }
+
+    /// Returns the usable physical memory regions as sorted and coalesced
+    /// `(address, size)` pairs, i.e. all `/memory` nodes without the
+    /// `/memreserve/` entries and the children of `/reserved-memory`.
+    pub fn memory_regions(&self) -> DeviceResult<Vec<(PhysAddr, usize)>> {
+        self.dt.usable_memory_regions()
+    }
+
+    /// Returns all CPUs in `/cpus`, including the disabled ones.
+    pub fn cpus(&self) -> Vec<CpuInfo> {
+        self.dt.cpus()
+    }
+
+    /// Returns the frequency of the timer, from `/cpus` or the first enabled
+    /// CPU.
+    pub fn timebase_frequency(&self) -> Option<u32> {
+        self.dt.timebase_frequency()
+    }
+
+    /// Returns the kernel command line in `/chosen/bootargs`, `None` if it is
+    /// absent or empty.
+    pub fn bootargs(&self) -> Option<&str> {
+        self.dt.bootargs()
+    }
+
+    /// Returns the address and size of the init RAM disk, passed by the
+    /// loader in `/chosen/linux,initrd-start` and `/chosen/linux,initrd-end`.
+    pub fn initrd(&self) -> Option<(PhysAddr, usize)> {
+        self.dt.initrd()
     }
 }
 
@@ -144,6 +896,151 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
 #[allow(unused_variables)]
 #[allow(unreachable_code)]
 impl<M: IoMapper> DevicetreeDriverBuilder<M> {
+    /// The builtin probe functions, the compatible strings matched first take
+    /// precedence.
+    fn builtin_probes() -> Vec<(String, Probe<M>)> {
+        let mut probes = vec![
+            // 一个 PCI 主桥下可能有多个设备
+            builtin("pci-host-ecam-generic", |b, node, _, props, _| {
+                b.parse_pci_host(node, props)
+            }),
+        ];
+        #[cfg(feature = "virtio")]
+        probes.push(builtin("virtio,mmio", |b, node, _, props, _| {
+            Ok(vec![b.parse_virtio(node, props)?])
+        }));
+        #[cfg(not(feature = "loopback"))]
+        probes.push(builtin(
+            "allwinner,sunxi-gmac",
+            |b, node, comp, props, _| Ok(vec![b.parse_ethernet(node, comp, props)?]),
+        ));
+        for comp in &[
+            "ns16550a",
+            "allwinner,sun20i-uart",
+            "snps,dw-apb-uart",
+            "sifive,uart0",
+            "sifive,fu740-c000-uart",
+            "arm,pl011",
+        ] {
+            probes.push(builtin(comp, |b, node, comp, props, _| {
+                Ok(vec![b.parse_uart(node, comp, props)?])
+            }));
+        }
+        probes.push(builtin("simple-framebuffer", |b, node, comp, props, _| {
+            Ok(vec![b.parse_display(node, comp, props)?])
+        }));
+        probes.push(builtin("gpio-keys", |b, node, _, _, gpio_ctrls| {
+            Ok(vec![b.parse_gpio_keys(node, gpio_ctrls)?])
+        }));
+        for comp in &["google,goldfish-rtc", "arm,pl031"] {
+            probes.push(builtin(comp, |b, node, comp, props, _| {
+                Ok(vec![b.parse_rtc(node, comp, props)?])
+            }));
+        }
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        for comp in &["riscv,clint0", "sifive,clint0"] {
+            probes.push(builtin(comp, |b, node, _, props, _| {
+                Ok(vec![b.parse_clint(node, props)?])
+            }));
+        }
+        #[cfg(target_arch = "aarch64")]
+        let power = [
+            "sifive,test0",
+            "syscon-poweroff",
+            "syscon-reboot",
+            "arm,psci-0.2",
+            "arm,psci-1.0",
+        ];
+        #[cfg(not(target_arch = "aarch64"))]
+        let power = ["sifive,test0", "syscon-poweroff", "syscon-reboot"];
+        for comp in &power {
+            probes.push(builtin(comp, |b, node, comp, props, _| {
+                Ok(vec![b.parse_power(node, comp, props)?])
+            }));
+        }
+        probes
+    }
+
+    /// Create the devices of the node by the probe function matched by the
+    /// first compatible string.
+    fn probe_node(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+        gpio_ctrls: &BTreeMap<String, GpioCtrl>,
+    ) -> DeviceResult<Vec<DevWithInterrupt>> {
+        let probe = self
+            .probes
+            .iter()
+            .find(|(c, _)| comp.contains(c.as_str()))
+            .map(|(_, probe)| probe)
+            .ok_or(DeviceError::NotSupported)?;
+        match probe {
+            Probe::Builtin(probe) => probe(self, node, comp, props, gpio_ctrls),
+            Probe::External(probe) => {
+                let mmap = |paddr, size| {
+                    self.query_or_map(paddr, size)
+                        .ok_or(DeviceError::NoResources)
+                };
+                let (dev, irqs) = probe(node, props, &mmap)?;
+                // 与内置的设备一样解析中断
+                let irqs = match irqs {
+                    Some(irqs) => irqs,
+                    None => parse_interrupts(node, props)?,
+                };
+                Ok(vec![(dev, irqs)])
+            }
+        }
+    }
+
+    /// Map the region by the [`IoMapper`], and remember it to unmap if the
+    /// node being probed fails.
+    fn map_mmio(&self, paddr: PhysAddr, size: usize, attrs: MmioAttrs) -> DeviceResult<VirtAddr> {
+        let vaddr = self.io_mapper.map_mmio(paddr, size, attrs)?;
+        self.mappings.borrow_mut().push((vaddr, size));
+        Ok(vaddr)
+    }
+
+    /// Map the region as device memory, see [`map_mmio`](Self::map_mmio).
+    fn query_or_map(&self, paddr: PhysAddr, size: usize) -> Option<VirtAddr> {
+        self.map_mmio(paddr, size, MmioAttrs::Device).ok()
+    }
+
+    /// Returns the regions mapped since the last call.
+    fn take_mappings(&self) -> Vec<(VirtAddr, usize)> {
+        core::mem::take(&mut *self.mappings.borrow_mut())
+    }
+
+    /// Unmap the regions mapped since the last call.
+    fn release_mappings(&self) {
+        for (vaddr, size) in self.take_mappings() {
+            if let Err(err) = self.io_mapper.unmap(vaddr, size) {
+                warn!(
+                    "{MODULE}: failed to unmap {:#x?}: {err:?}",
+                    vaddr..vaddr + size
+                );
+            }
+        }
+    }
+
+    /// Map all `reg` windows of the node, and returns their virtual addresses
+    /// and sizes in order.
+    fn map_reg_all(
+        &self,
+        node: &Node,
+        props: &InheritProps,
+    ) -> DeviceResult<Vec<(VirtAddr, usize)>> {
+        parse_reg_all(node, props)?
+            .into_iter()
+            .map(|(paddr, size)| {
+                self.query_or_map(paddr as usize, size as usize)
+                    .map(|vaddr| (vaddr, size as usize))
+                    .ok_or(DeviceError::NoResources)
+            })
+            .collect()
+    }
+
     /// Parse nodes for interrupt controllers.
     fn parse_intc(
         &self,
@@ -158,27 +1055,144 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
             .prop_u32("#interrupt-cells")
             .map_err(|_| DeviceError::InvalidParam)?;
         let interrupts_extended = parse_interrupts(node, props)?;
+        if let Some((_, probe)) = self
+            .intc_probes
+            .iter()
+            .find(|(c, _)| comp.contains(c.as_str()))
+        {
+            let mmap = |paddr, size| {
+                self.query_or_map(paddr, size)
+                    .ok_or(DeviceError::NoResources)
+            };
+            let dev = probe(node, props, &mmap)?;
+            return Ok((
+                (Device::Irq(dev.clone()), interrupts_extended),
+                IntcProps {
+                    irq: dev,
+                    phandle,
+                    interrupt_cells,
+                    spec_to_irq: first_cell_to_irq,
+                    spec_to_trigger: second_cell_to_trigger,
+                },
+            ));
+        }
         let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
-            self.io_mapper
-                .query_or_map(paddr as usize, size as usize)
+            self.query_or_map(paddr as usize, size as usize)
                 .ok_or(DeviceError::NoResources)
         });
         use crate::irq::*;
-        let dev = Device::Irq(match comp {
-            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-            c if c.contains("riscv,cpu-intc") => Arc::new(riscv::Intc::new()),
-            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-            c if c.contains("riscv,plic0") => Arc::new(riscv::Plic::new(base_vaddr?)),
-            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-            c if c.contains("sifive,fu540-c000-plic") => Arc::new(riscv::Plic::new(base_vaddr?)),
-            _ => return Err(DeviceError::NotSupported),
-        });
+        let (dev, spec_to_irq, spec_to_trigger): (Arc<dyn IrqScheme>, SpecToIrq, SpecToTrigger) =
+            match comp {
+                #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+                c if c.contains("riscv,cpu-intc") => {
+                    (Arc::new(riscv::Intc::new()), first_cell_to_irq, no_trigger)
+                }
+                #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+                c if c.contains("riscv,plic0")
+                    || c.contains("sifive,plic-1.0.0")
+                    || c.contains("sifive,fu540-c000-plic") =>
+                {
+                    let plic = match node.prop_u32("riscv,ndev") {
+                        // each context is a pair of the CPU intc phandle and the cause
+                        Ok(ndev) => match node.prop_cells("interrupts-extended") {
+                            Ok(cells) if cells.len() >= 2 => riscv::Plic::with_contexts(
+                                base_vaddr?,
+                                ndev as usize,
+                                cells.len() / 2,
+                            ),
+                            _ => riscv::Plic::with_ndev(base_vaddr?, ndev as usize),
+                        },
+                        Err(_) => riscv::Plic::new(base_vaddr?),
+                    };
+                    // 有些 PLIC 使用两个参数，第二个为触发方式
+                    let spec_to_trigger = if interrupt_cells >= 2 {
+                        second_cell_to_trigger
+                    } else {
+                        no_trigger
+                    };
+                    (Arc::new(plic), first_cell_to_irq, spec_to_trigger)
+                }
+                #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+                c if c.contains("thead,c900-plic") || c.contains("allwinner,sun20i-d1-plic") => {
+                    // T-Head 的 PLIC 必须给出中断源数量和上下文
+                    let ndev = node
+                        .prop_u32("riscv,ndev")
+                        .map_err(|_| DeviceError::InvalidParam)?;
+                    let contexts = match node.prop_cells("interrupts-extended") {
+                        Ok(cells) if cells.len() >= 2 => cells.len() / 2,
+                        _ => return Err(DeviceError::InvalidParam),
+                    };
+                    let plic =
+                        riscv::Plic::thead_with_contexts(base_vaddr?, ndev as usize, contexts);
+                    let spec_to_trigger = if interrupt_cells >= 2 {
+                        second_cell_to_trigger
+                    } else {
+                        no_trigger
+                    };
+                    (Arc::new(plic), first_cell_to_irq, spec_to_trigger)
+                }
+                #[cfg(target_arch = "aarch64")]
+                c if c.contains("arm,gic-400") || c.contains("arm,cortex-a15-gic") => {
+                    // the distributor and the CPU interface
+                    let (gicd, gicc) = match self.map_reg_all(node, props)?[..] {
+                        [(gicd, _), (gicc, _), ..] => (gicd, gicc),
+                        _ => return Err(DeviceError::InvalidParam),
+                    };
+                    (
+                        Arc::new(gic_400::init(gicc, gicd)),
+                        gic_400::spec_to_irq,
+                        third_cell_to_trigger,
+                    )
+                }
+                #[cfg(target_arch = "aarch64")]
+                c if c.contains("arm,gic-v3") => {
+                    // the distributor and the redistributors
+                    let (gicd, (gicr, gicr_size)) = match self.map_reg_all(node, props)?[..] {
+                        [(gicd, _), gicr, ..] => (gicd, gicr),
+                        _ => return Err(DeviceError::InvalidParam),
+                    };
+                    // same interrupt specifier as GICv2
+                    (
+                        Arc::new(arm::GicV3::new(gicd, gicr, gicr_size)?),
+                        gic_400::spec_to_irq,
+                        third_cell_to_trigger,
+                    )
+                }
+                #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+                c if c.contains("riscv,aplic") => {
+                    // pairs of the CPU intc phandle and the cause, one for each IDC
+                    let targets = node.prop_cells("interrupts-extended").unwrap_or_default();
+                    if targets.is_empty() {
+                        warn!(
+                            "{MODULE}: APLIC {:?} in MSI mode is not supported",
+                            props.path
+                        );
+                        return Err(DeviceError::NotSupported);
+                    }
+                    // the M-level domain without children delivers to the M-mode
+                    if targets.chunks(2).any(|t| t.get(1) == Some(&11)) {
+                        info!("{MODULE}: skip M-level APLIC {:?}", props.path);
+                        return Err(DeviceError::NotSupported);
+                    }
+                    let num_sources = node
+                        .prop_u32("riscv,num-sources")
+                        .map_err(|_| DeviceError::InvalidParam)?;
+                    let aplic = unsafe {
+                        riscv::Aplic::new(base_vaddr?, num_sources as usize, targets.len() / 2)
+                    };
+                    (Arc::new(aplic), first_cell_to_irq, second_cell_to_trigger)
+                }
+                _ => return Err(DeviceError::NotSupported),
+            };
 
         Ok((
-            (dev, interrupts_extended),
+            (Device::Irq(dev.clone()), interrupts_extended),
             IntcProps {
+                irq: dev,
                 phandle,
                 interrupt_cells,
+                spec_to_irq,
+                spec_to_trigger,
             },
         ))
     }
@@ -186,30 +1200,57 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
     /// Parse nodes for virtio devices over MMIO.
     #[cfg(feature = "virtio")]
     fn parse_virtio(&self, node: &Node, props: &InheritProps) -> DeviceResult<DevWithInterrupt> {
+        use crate::io::MmioRegion;
         use crate::virtio::*;
         use virtio_drivers::{DeviceType, VirtIOHeader};
 
         let interrupts_extended = parse_interrupts(node, props)?;
-        let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
-            self.io_mapper
-                .query_or_map(paddr as usize, size as usize)
-                .ok_or(DeviceError::NoResources)
-        })?;
-        let header = unsafe { &mut *(base_vaddr as *mut VirtIOHeader) };
-        if !header.verify() {
-            return Err(DeviceError::NotSupported);
-        }
+        let (paddr, size) = parse_reg(node, props)?;
+        let base_vaddr = self
+            .query_or_map(paddr as usize, size as usize)
+            .ok_or(DeviceError::NoResources)?;
+        // a `reg` smaller than the header is rejected rather than read beyond
+        let region = unsafe { MmioRegion::new(base_vaddr, size as usize)? };
+        let header = unsafe { region.block_mut_at::<VirtIOHeader>(0)? };
+        let version = mmio_version(base_vaddr).ok_or(DeviceError::NotSupported)?;
         info!(
-            "{MODULE}: detected virtio device: vendor_id={:#X}, type={:?}",
+            "{MODULE}: detected virtio device: vendor_id={:#X}, type={:?}, {version:?}",
             header.vendor_id(),
             header.device_type()
         );
 
+        // `virtio_drivers` only supports the legacy layout, use our own drivers
+        // for the modern devices
+        if version == MmioVersion::Modern {
+            let dev = match header.device_type() {
+                DeviceType::Block => Device::Block(Arc::new(VirtIoBlk::new(header)?)),
+                DeviceType::GPU => Device::Display(Arc::new(VirtIoGpu::new(header)?)),
+                DeviceType::Input => Device::Input(Arc::new(VirtIoInput::new(header)?)),
+                DeviceType::EntropySource => Device::Rng(Arc::new(VirtIoRng::new(header)?)),
+                DeviceType::MemoryBallooning => {
+                    Device::Balloon(Arc::new(VirtIoBalloon::new(header)?))
+                }
+                DeviceType::Socket => Device::Vsock(Arc::new(VirtIoVsock::new(header)?)),
+                ty => {
+                    warn!(
+                        "{MODULE}: modern virtio {ty:?} device at {:?} is not supported, try `-global virtio-mmio.force-legacy=on` in QEMU",
+                        props.path
+                    );
+                    return Err(DeviceError::NotSupported);
+                }
+            };
+            return Ok((dev, interrupts_extended));
+        }
+
         let dev = match header.device_type() {
             DeviceType::Block => Device::Block(Arc::new(VirtIoBlk::new(header)?)),
             DeviceType::GPU => Device::Display(Arc::new(VirtIoGpu::new(header)?)),
             DeviceType::Input => Device::Input(Arc::new(VirtIoInput::new(header)?)),
             DeviceType::Console => Device::Uart(Arc::new(VirtIoConsole::new(header)?)),
+            DeviceType::Network => Device::Net(Arc::new(VirtIoNet::new(header)?)),
+            DeviceType::EntropySource => Device::Rng(Arc::new(VirtIoRng::new(header)?)),
+            DeviceType::MemoryBallooning => Device::Balloon(Arc::new(VirtIoBalloon::new(header)?)),
+            DeviceType::Socket => Device::Vsock(Arc::new(VirtIoVsock::new(header)?)),
             _ => return Err(DeviceError::NotSupported),
         };
 
@@ -225,19 +1266,25 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
     ) -> DeviceResult<DevWithInterrupt> {
         let interrupts_extended = parse_interrupts(node, props)?;
         let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
-            self.io_mapper
-                .query_or_map(paddr as usize, size as usize)
+            self.query_or_map(paddr as usize, size as usize)
                 .ok_or(DeviceError::NoResources)
         });
         info!("Ethernet gmac init ...");
 
-        let irq_num = interrupts_extended[1];
+        // the first interrupt specifier after the phandle
+        let irq_num = *interrupts_extended
+            .get(1)
+            .ok_or(DeviceError::InvalidParam)?;
         use crate::net::*;
         let dev = Device::Net(match comp {
             #[cfg(target_arch = "riscv64")]
             c if c.contains("allwinner,sunxi-gmac") => {
-                Arc::new(rtlx_init(irq_num as usize, |paddr, size| {
-                    self.io_mapper.query_or_map(paddr, size)
+                // the second window is the EPHY clock register in syscon
+                let ephy_clk_reg = parse_reg_all(node, props)?
+                    .get(1)
+                    .map(|&(paddr, _)| paddr as usize);
+                Arc::new(rtlx_init(irq_num as usize, ephy_clk_reg, |paddr, size| {
+                    self.query_or_map(paddr, size)
                 })?)
             }
             _ => return Err(DeviceError::NotSupported),
@@ -246,6 +1293,387 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
         Ok((dev, interrupts_extended))
     }
 
+    /// Parse nodes for display devices.
+    fn parse_display(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+    ) -> DeviceResult<DevWithInterrupt> {
+        let (paddr, size) = parse_reg(node, props)?;
+        // the framebuffer is memory rather than registers
+        let base_vaddr = self.map_mmio(paddr as usize, size as usize, MmioAttrs::WriteCombining);
+
+        use crate::display::*;
+        use crate::prelude::ColorFormat;
+        let dev = Device::Display(match comp {
+            c if c.contains("simple-framebuffer") => {
+                let format = match node.prop_str("format")? {
+                    "a8r8g8b8" | "x8r8g8b8" => ColorFormat::ARGB8888,
+                    "r8g8b8" => ColorFormat::RGB888,
+                    "r5g6b5" => ColorFormat::RGB565,
+                    f => {
+                        warn!("{MODULE}: unsupported framebuffer format {:?}", f);
+                        return Err(DeviceError::NotSupported);
+                    }
+                };
+                Arc::new(SimpleFramebuffer::new(
+                    base_vaddr?,
+                    size as usize,
+                    node.prop_u32("width")?,
+                    node.prop_u32("height")?,
+                    node.prop_u32("stride")?,
+                    format,
+                )?)
+            }
+            _ => return Err(DeviceError::NotSupported),
+        });
+
+        Ok((dev, Vec::new()))
+    }
+
+    /// Parse nodes for RTC devices.
+    fn parse_rtc(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+    ) -> DeviceResult<DevWithInterrupt> {
+        let interrupts_extended = parse_interrupts(node, props)?;
+        // the whole window is mapped, PL031 has ID registers at its end
+        let (paddr, size) = parse_reg(node, props)?;
+        let base_vaddr = self
+            .query_or_map(paddr as usize, size as usize)
+            .ok_or(DeviceError::NoResources);
+
+        use crate::rtc::*;
+        let dev = Device::Rtc(match comp {
+            c if c.contains("google,goldfish-rtc") => {
+                Arc::new(unsafe { GoldfishRtc::new(base_vaddr?) })
+            }
+            c if c.contains("arm,pl031") => {
+                Arc::new(unsafe { Pl031::new(base_vaddr?, size as usize)? })
+            }
+            _ => return Err(DeviceError::NotSupported),
+        });
+
+        Ok((dev, interrupts_extended))
+    }
+
+    /// Parse nodes for the RISC-V CLINT.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    fn parse_clint(&self, node: &Node, props: &InheritProps) -> DeviceResult<DevWithInterrupt> {
+        let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
+            self.query_or_map(paddr as usize, size as usize)
+                .ok_or(DeviceError::NoResources)
+        })?;
+        // the registers of each hart are indexed by the hart ID
+        let num_harts = match self.dt.cpus().iter().map(|cpu| cpu.id + 1).max() {
+            Some(n) => n,
+            // the M-mode software and timer interrupts of each hart
+            None => node.prop_cells("interrupts-extended")?.len() / 4,
+        };
+        let clint = unsafe { crate::irq::riscv::Clint::new(base_vaddr, num_harts) };
+        // its interrupts are delivered to the M-mode, not registered here
+        Ok((Device::Timer(Arc::new(clint)), Vec::new()))
+    }
+
+    /// Create all GPIO controllers first, so that the devices referring to
+    /// them do not depend on the node order.
+    fn probe_gpio_controllers(&self) -> BTreeMap<String, GpioCtrl> {
+        let mut ctrls = BTreeMap::new();
+        self.dt.walk(&mut |node, comp, props| {
+            if !node.has_prop("gpio-controller")
+                || (!self.probe_disabled && (!is_enabled(node) || props.parent_disabled))
+                || self.is_filtered(comp, node.has_prop("interrupt-controller"))
+            {
+                return;
+            }
+            match self.parse_gpio(node, comp, props) {
+                Ok(mut ctrl) => {
+                    ctrl.mappings = self.take_mappings();
+                    ctrls.insert(props.path.clone(), ctrl);
+                }
+                Err(err) => {
+                    self.release_mappings();
+                    if !matches!(err, DeviceError::NotSupported) {
+                        warn!("{MODULE}: failed to parsing node {:?}: {err:?}", props.path);
+                    }
+                }
+            }
+        });
+        ctrls
+    }
+
+    /// Parse nodes for GPIO controllers.
+    fn parse_gpio(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+    ) -> DeviceResult<GpioCtrl> {
+        use crate::gpio::*;
+        let (gpio, spec_to_pin): (Arc<dyn GpioScheme>, SpecToPin) = match comp {
+            c if c.contains("allwinner,sun20i-d1-pinctrl") => {
+                let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
+                    self.query_or_map(paddr as usize, size as usize)
+                        .ok_or(DeviceError::NoResources)
+                })?;
+                (
+                    Arc::new(unsafe { GpioAllwinner::new(base_vaddr) }),
+                    bank_pin_to_pin,
+                )
+            }
+            _ => return Err(DeviceError::NotSupported),
+        };
+        Ok(GpioCtrl {
+            gpio,
+            phandle: node.prop_u32("phandle").ok(),
+            cells: node.prop_u32("#gpio-cells")? as usize,
+            spec_to_pin,
+            mappings: Vec::new(),
+        })
+    }
+
+    /// Parse the `gpio-keys` node, whose children are the keys.
+    fn parse_gpio_keys(
+        &self,
+        node: &Node,
+        gpio_ctrls: &BTreeMap<String, GpioCtrl>,
+    ) -> DeviceResult<DevWithInterrupt> {
+        use crate::input::{GpioKey, GpioKeys};
+        let mut keys = Vec::new();
+        for child in node.children.iter().filter(|n| is_enabled(n)) {
+            // 按键引用 GPIO 控制器，如 `gpios = <&pio 4 14 GPIO_ACTIVE_LOW>`
+            let gpios = match child.prop_cells("gpios") {
+                Ok(gpios) => gpios,
+                Err(_) => {
+                    warn!(
+                        "{MODULE}: key {:?} without `gpios` is not supported",
+                        child.name
+                    );
+                    continue;
+                }
+            };
+            let (phandle, spec) = gpios.split_first().ok_or(DeviceError::InvalidParam)?;
+            let ctrl = gpio_ctrls
+                .values()
+                .find(|c| c.phandle == Some(*phandle))
+                .ok_or_else(|| {
+                    warn!(
+                        "{MODULE}: no GPIO controller with phandle {phandle:#x} for key {:?}",
+                        child.name
+                    );
+                    DeviceError::InvalidParam
+                })?;
+            let (pin, flags) = spec
+                .get(..ctrl.cells)
+                .and_then(ctrl.spec_to_pin)
+                .ok_or(DeviceError::InvalidParam)?;
+            keys.push(GpioKey {
+                gpio: ctrl.gpio.clone(),
+                pin,
+                active_low: flags & GPIO_ACTIVE_LOW != 0,
+                code: child.prop_u32("linux,code")? as u16,
+            });
+        }
+        if keys.is_empty() {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok((Device::Input(GpioKeys::new(keys)?), Vec::new()))
+    }
+
+    /// Parse the generic PCI host bridge with ECAM, enumerate the functions
+    /// behind it, and create drivers for the known ones.
+    fn parse_pci_host(
+        &self,
+        node: &Node,
+        props: &InheritProps,
+    ) -> DeviceResult<Vec<DevWithInterrupt>> {
+        use crate::bus::pci_ecam::{EcamHost, PciRange};
+        let (paddr, size) = parse_reg(node, props)?;
+        let base_vaddr = self
+            .query_or_map(paddr as usize, size as usize)
+            .ok_or(DeviceError::NoResources)?;
+        let (start, end) = match node.prop_cells("bus-range").as_deref() {
+            Ok(&[start, end]) if start <= end && end <= 255 => (start as u64, end as u64),
+            _ => (0, 255),
+        };
+        // 每条总线占 1MB 的配置空间，只探测映射了的总线
+        let end = end.min(start + (size >> 20).max(1) - 1);
+        let buses = start as u8..=end as u8;
+        let ranges = PciRange::parse(
+            &node.prop_cells("ranges")?,
+            props.parent_address_cells as usize,
+            node.prop_u32("#size-cells").unwrap_or(PCI_SIZE_CELLS) as usize,
+        )?;
+        let interrupt_map = self.dt.parse_interrupt_map(node).ok();
+
+        let mut host = unsafe { EcamHost::new(base_vaddr, buses, ranges) };
+        let mut devs = Vec::new();
+        for func in host.enumerate() {
+            let name = format!("enp{}s{}f{}", func.bus, func.device, func.function);
+            info!(
+                "{MODULE}: PCI {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x} BARs {:x?}",
+                func.bus,
+                func.device,
+                func.function,
+                func.vendor_id,
+                func.device_id,
+                func.class,
+                func.subclass,
+                func.bars
+            );
+            // INTx 经过主桥的 `interrupt-map` 翻译为中断控制器的说明符
+            let mut interrupts_extended = Vec::new();
+            if func.interrupt_pin != 0 {
+                let pin = func.interrupt_pin as u32;
+                match interrupt_map
+                    .as_ref()
+                    .and_then(|map| map.translate(&func.unit_addr(), &[pin]))
+                {
+                    Some((parent, spec)) => {
+                        interrupts_extended.push(parent);
+                        interrupts_extended.extend_from_slice(spec);
+                    }
+                    None => warn!("{MODULE}: INTx of PCI function {name} is not routed"),
+                }
+            }
+            match self.parse_pci_function(&func, &name, &interrupts_extended, devs.len()) {
+                Ok(dev) => devs.push((dev, interrupts_extended)),
+                Err(DeviceError::NotSupported) => {}
+                Err(err) => warn!("{MODULE}: failed to probe PCI function {name}: {err:?}"),
+            }
+        }
+        Ok(devs)
+    }
+
+    /// Create the driver for a function behind a PCI host bridge.
+    fn parse_pci_function(
+        &self,
+        func: &PciFunction,
+        name: &str,
+        interrupts_extended: &[u32],
+        index: usize,
+    ) -> DeviceResult<Device> {
+        #[cfg(feature = "virtio")]
+        if let Some(ty) = crate::virtio::pci_device_type(func) {
+            return self.parse_virtio_pci(func, ty);
+        }
+        match (func.vendor_id, func.device_id, func.bars[0]) {
+            // e1000 (8086:100e 8086:100f) and e1000e (8086:10d3)
+            (0x8086, 0x100e | 0x100f | 0x10d3, Some(PciBar::Memory { paddr, size, .. })) => {
+                let vaddr = self
+                    .query_or_map(paddr, size)
+                    .ok_or(DeviceError::NoResources)?;
+                // the driver only compares the IRQ number, the first cell of
+                // the specifier is taken as it, e.g. for PLIC
+                let irq_num = interrupts_extended.get(1).copied().unwrap_or(0);
+                Ok(Device::Net(Arc::new(crate::net::e1000::init(
+                    String::from(name),
+                    irq_num as usize,
+                    vaddr,
+                    size,
+                    index,
+                )?)))
+            }
+            _ => Err(DeviceError::NotSupported),
+        }
+    }
+
+    /// Create the driver for a VirtIO device over the PCI transport.
+    #[cfg(feature = "virtio")]
+    fn parse_virtio_pci(
+        &self,
+        func: &PciFunction,
+        ty: virtio_drivers::DeviceType,
+    ) -> DeviceResult<Device> {
+        use crate::virtio::*;
+        use virtio_drivers::DeviceType;
+
+        let transport = PciTransport::new(func, |paddr, size| self.query_or_map(paddr, size))?;
+        info!("{MODULE}: detected virtio-pci device: type={ty:?}");
+        let dev = match ty {
+            DeviceType::Block => Device::Block(Arc::new(VirtIoBlk::new(transport)?)),
+            DeviceType::GPU => Device::Display(Arc::new(VirtIoGpu::new(transport)?)),
+            DeviceType::Input => Device::Input(Arc::new(VirtIoInput::new(transport)?)),
+            DeviceType::EntropySource => Device::Rng(Arc::new(VirtIoRng::new(transport)?)),
+            DeviceType::MemoryBallooning => {
+                Device::Balloon(Arc::new(VirtIoBalloon::new(transport)?))
+            }
+            DeviceType::Socket => Device::Vsock(Arc::new(VirtIoVsock::new(transport)?)),
+            ty => {
+                // `virtio_drivers` only supports the MMIO transport
+                warn!("{MODULE}: virtio {ty:?} device over PCI is not supported");
+                return Err(DeviceError::NotSupported);
+            }
+        };
+        Ok(dev)
+    }
+
+    /// Parse nodes for the devices to power off or reset the machine.
+    fn parse_power(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+    ) -> DeviceResult<DevWithInterrupt> {
+        use crate::power::*;
+        let dev = Device::Power(match comp {
+            c if c.contains("sifive,test0") => {
+                let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
+                    self.query_or_map(paddr as usize, size as usize)
+                        .ok_or(DeviceError::NoResources)
+                })?;
+                Arc::new(unsafe { SifiveTest::new(base_vaddr) })
+            }
+            c if c.contains("syscon-poweroff") || c.contains("syscon-reboot") => {
+                let action = if c.contains("syscon-reboot") {
+                    SysconAction::Reboot
+                } else {
+                    SysconAction::Poweroff
+                };
+                // the registers belong to the system controller referred by `regmap`
+                let syscon = self
+                    .dt
+                    .find_by_phandle(node.prop_u32("regmap")?)
+                    .ok_or(DeviceError::InvalidParam)?;
+                let syscon_props = self
+                    .dt
+                    .inherit_props(syscon)
+                    .ok_or(DeviceError::InvalidParam)?;
+                let (paddr, size) = parse_reg(syscon, &syscon_props)?;
+                let offset = node.prop_u32("offset")?;
+                if offset as u64 + 4 > size {
+                    return Err(DeviceError::InvalidParam);
+                }
+                let (value, mask) = match (node.prop_u32("value"), node.prop_u32("mask")) {
+                    (Ok(value), mask) => (value, mask.unwrap_or(u32::MAX)),
+                    // the legacy binding writes `mask` as the value
+                    (Err(_), Ok(mask)) => (mask, u32::MAX),
+                    _ => return Err(DeviceError::InvalidParam),
+                };
+                let base_vaddr = self
+                    .query_or_map(paddr as usize, size as usize)
+                    .ok_or(DeviceError::NoResources)?;
+                Arc::new(unsafe {
+                    SysconPower::new(base_vaddr, offset as usize, value, mask, action)
+                })
+            }
+            #[cfg(target_arch = "aarch64")]
+            c if c.contains("arm,psci-0.2") || c.contains("arm,psci-1.0") => {
+                let method = match node.prop_str("method")? {
+                    "smc" => PsciMethod::Smc,
+                    "hvc" => PsciMethod::Hvc,
+                    _ => return Err(DeviceError::InvalidParam),
+                };
+                Arc::new(Psci::new(method))
+            }
+            _ => return Err(DeviceError::NotSupported),
+        });
+        Ok((dev, Vec::new()))
+    }
+
     /// Parse nodes for UART devices.
     fn parse_uart(
         &self,
@@ -255,25 +1683,76 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
     ) -> DeviceResult<DevWithInterrupt> {
         let interrupts_extended = parse_interrupts(node, props)?;
         let base_vaddr = parse_reg(node, props).and_then(|(paddr, size)| {
-            self.io_mapper
-                .query_or_map(paddr as usize, size as usize)
+            self.query_or_map(paddr as usize, size as usize)
                 .ok_or(DeviceError::NoResources)
         });
 
+        let clock_freq = self.dt.clock_frequency(node);
+        if clock_freq.is_none() {
+            warn!(
+                "{MODULE}: no clock-frequency in UART node {:?}, use the default clock",
+                node.name
+            );
+        }
+        let baud = node.prop_u32("current-speed").unwrap_or(DEFAULT_BAUD_RATE);
+
         use crate::uart::*;
         let dev = Device::Uart(match comp {
             c if c.contains("ns16550a") => {
-                Arc::new(unsafe { Uart16550Mmio::<u8>::new(base_vaddr?) })
+                let reg_shift = node.prop_u32("reg-shift").unwrap_or(0);
+                let reg_io_width = node.prop_u32("reg-io-width").unwrap_or(1);
+                // the registers must not overlap
+                if reg_shift < reg_io_width.trailing_zeros() {
+                    return Err(DeviceError::InvalidParam);
+                }
+                let base = base_vaddr?;
+                unsafe {
+                    match (reg_io_width, clock_freq) {
+                        (1, Some(clock)) => Arc::new(Uart16550Mmio::<u8>::with_reg_shift_clock(
+                            base, reg_shift, clock, baud,
+                        )),
+                        // keep the divisor set by the firmware
+                        (1, None) => Arc::new(Uart16550Mmio::<u8>::with_reg_shift(base, reg_shift)),
+                        (4, Some(clock)) => Arc::new(Uart16550Mmio::<u32>::with_reg_shift_clock(
+                            base, reg_shift, clock, baud,
+                        )),
+                        (4, None) => {
+                            Arc::new(Uart16550Mmio::<u32>::with_reg_shift(base, reg_shift))
+                        }
+                        _ => return Err(DeviceError::NotSupported),
+                    }
+                }
             }
+            c if c.contains("arm,pl011") => Arc::new(unsafe {
+                UartPl011::with_baud_rate(base_vaddr?, clock_freq.unwrap_or(PL011_CLOCK_FREQ), baud)
+            }),
             #[cfg(feature = "board-d1")]
-            c if c.contains("allwinner,sun20i-uart") => Arc::new(UartAllwinner::new(base_vaddr?)),
-            #[cfg(feature = "board-visionfive")]
+            c if c.contains("allwinner,sun20i-uart") => Arc::new(UartAllwinner::with_clock(
+                base_vaddr?,
+                clock_freq.unwrap_or(ALLWINNER_UART_CLOCK_FREQ),
+                baud,
+            )?),
             c if c.contains("snps,dw-apb-uart") => {
-                Arc::new(unsafe { Uart16550Mmio::<u32>::new(base_vaddr?) })
+                let reg_shift = node.prop_u32("reg-shift").unwrap_or(0);
+                let reg_io_width = node.prop_u32("reg-io-width").unwrap_or(1);
+                Arc::new(unsafe {
+                    match clock_freq {
+                        Some(clock) => {
+                            UartDw::with_clock(base_vaddr?, reg_shift, reg_io_width, clock, baud)?
+                        }
+                        // keep the divisor set by the firmware
+                        None => UartDw::new(base_vaddr?, reg_shift, reg_io_width)?,
+                    }
+                })
             }
-            #[cfg(feature = "board-fu740")]
-            c if c.contains("sifive,fu740-c000-uart") => {
-                Arc::new(unsafe { UartU740Mmio::<u32>::new(base_vaddr?) })
+            c if c.contains("sifive,uart0") || c.contains("sifive,fu740-c000-uart") => {
+                Arc::new(unsafe {
+                    match clock_freq {
+                        Some(clock) => UartSifive::with_clock(base_vaddr?, clock, baud),
+                        // keep the divisor set by the firmware
+                        None => UartSifive::new(base_vaddr?),
+                    }
+                })
             }
             _ => return Err(DeviceError::NotSupported),
         });
@@ -281,3 +1760,961 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
         Ok((dev, interrupts_extended))
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::prelude::IrqHandler;
+    use crate::scheme::Scheme;
+    use crate::utils::devicetree::test::FdtBuilder;
+    use crate::utils::test_utils::MockIoMapper;
+    use alloc::{boxed::Box, rc::Rc, vec};
+
+    // Built from the `.dts` sources in `testdata`, regenerate them with
+    // `dtc -I dts -O dtb -o <name>.dtb <name>.dts` after changing the sources.
+    const QEMU_RISCV_VIRT_DTB: &[u8] = include_bytes!("testdata/qemu-riscv-virt.dtb");
+    const MULTI_BUS_DTB: &[u8] = include_bytes!("testdata/multi-bus.dtb");
+    const D1_DTB: &[u8] = include_bytes!("testdata/d1.dtb");
+
+    /// An operation on [`MockIntc`].
+    #[derive(Debug, PartialEq, Eq)]
+    enum IrqOp {
+        Configure(usize, IrqTriggerMode, IrqPolarity),
+        Register(usize),
+        Unmask(usize),
+    }
+
+    std::thread_local! {
+        /// The operations on the [`MockIntc`]s created by the current test, with
+        /// the paths of the controllers.
+        static IRQ_OPS: RefCell<Vec<(String, IrqOp)>> = RefCell::new(Vec::new());
+    }
+
+    fn take_irq_ops() -> Vec<(String, IrqOp)> {
+        IRQ_OPS.with(|ops| ops.take())
+    }
+
+    /// The interrupt controller of the `zcore,mock-intc` nodes, which records
+    /// the operations to [`IRQ_OPS`].
+    struct MockIntc(String);
+
+    impl MockIntc {
+        fn new(path: &str) -> Self {
+            Self(path.into())
+        }
+
+        fn record(&self, op: IrqOp) -> DeviceResult {
+            IRQ_OPS.with(|ops| ops.borrow_mut().push((self.0.clone(), op)));
+            Ok(())
+        }
+    }
+
+    impl Scheme for MockIntc {
+        fn name(&self) -> &str {
+            "mock-intc"
+        }
+    }
+
+    impl IrqScheme for MockIntc {
+        fn is_valid_irq(&self, irq_num: usize) -> bool {
+            irq_num < 1024
+        }
+
+        fn mask(&self, _irq_num: usize) -> DeviceResult {
+            Ok(())
+        }
+
+        fn unmask(&self, irq_num: usize) -> DeviceResult {
+            self.record(IrqOp::Unmask(irq_num))
+        }
+
+        fn configure(&self, irq_num: usize, tm: IrqTriggerMode, pol: IrqPolarity) -> DeviceResult {
+            self.record(IrqOp::Configure(irq_num, tm, pol))
+        }
+
+        fn register_handler(&self, irq_num: usize, _handler: IrqHandler) -> DeviceResult {
+            self.record(IrqOp::Register(irq_num))
+        }
+
+        fn unregister(&self, _irq_num: usize) -> DeviceResult {
+            Ok(())
+        }
+    }
+
+    /// Load the in-memory device tree blob, with a [`MockIoMapper`] to check
+    /// the registers.
+    fn mock_builder(blob: &[u8]) -> (DevicetreeDriverBuilder<MockIoMapper>, MockIoMapper) {
+        mock_builder_with(blob, MockIoMapper::default())
+    }
+
+    fn mock_builder_with(
+        blob: &[u8],
+        mapper: MockIoMapper,
+    ) -> (DevicetreeDriverBuilder<MockIoMapper>, MockIoMapper) {
+        let builder = DevicetreeDriverBuilder::with_devicetree(
+            Devicetree::from_bytes(blob).unwrap(),
+            mapper.clone(),
+        )
+        .register_intc_probe("zcore,mock-intc", |_, props, _| {
+            Ok(Arc::new(MockIntc::new(&props.path)))
+        });
+        (builder, mapper)
+    }
+
+    /// Maps the physical addresses to the same virtual addresses, and records
+    /// the mapped and unmapped regions.
+    #[derive(Default)]
+    struct IdentityMapper(Rc<RefCell<(usize, Vec<(VirtAddr, usize)>)>>);
+
+    impl IoMapper for IdentityMapper {
+        fn query_or_map(&self, paddr: PhysAddr, _size: usize) -> Option<VirtAddr> {
+            self.0.borrow_mut().0 += 1;
+            Some(paddr)
+        }
+
+        fn unmap(&self, vaddr: VirtAddr, len: usize) -> DeviceResult {
+            self.0.borrow_mut().1.push((vaddr, len));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_from_phys() {
+        #[repr(C, align(4096))]
+        struct Pages([u8; 2 * PAGE_SIZE]);
+
+        let load = |data_len: usize| {
+            let blob = FdtBuilder::default()
+                .begin_node("")
+                .prop("data", &vec![0u8; data_len])
+                .end_node()
+                .build();
+            // the blob begins at a page boundary
+            let mut pages = Box::new(Pages([0; 2 * PAGE_SIZE]));
+            pages.0[..blob.len()].copy_from_slice(&blob);
+            let mapper = IdentityMapper::default();
+            let maps = mapper.0.clone();
+            let paddr = pages.0.as_ptr() as PhysAddr;
+            let builder = DevicetreeDriverBuilder::from_phys(paddr, mapper);
+            assert!(builder.is_ok());
+            let (mapped, unmapped) = &*maps.borrow();
+            assert_eq!(*mapped, unmapped.len());
+            unmapped.iter().map(|&(_, len)| len).collect::<Vec<_>>()
+        };
+        // the header is mapped to the end of the page
+        let unmapped = load(16);
+        assert_eq!(unmapped.len(), 1);
+        assert_eq!(unmapped[0], PAGE_SIZE);
+        // remapped since the blob is beyond the page
+        let unmapped = load(PAGE_SIZE);
+        assert_eq!(unmapped.len(), 2);
+        assert!(unmapped[1] > PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_dangling_interrupt_parent() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("rtc@101000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .prop_cells("interrupts-extended", &[0x99, 11])
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+
+        let builder =
+            DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                .unwrap();
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/rtc@101000", "/rtc@102000"]);
+        assert_eq!(probed.irq_errors.len(), 1);
+        assert_eq!(probed.irq_errors[0].0, "/rtc@101000");
+        assert!(matches!(probed.irq_errors[0].1, DeviceError::InvalidParam));
+    }
+
+    #[test]
+    fn test_duplicate_mmio() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("rtc@101000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .end_node()
+            .begin_node("rtc@101800")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1800, 0x1000])
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+
+        let builder =
+            DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                .unwrap();
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/rtc@101000", "/rtc@102000"]);
+    }
+
+    #[test]
+    fn test_probe_records() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("rtc@101000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .end_node()
+            .begin_node("rtc@101800")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1800, 0x1000])
+            .end_node()
+            .begin_node("rtc@103000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_str("status", "disabled")
+            .end_node()
+            .begin_node("foo@104000")
+            .prop("compatible", b"vendor,foo\0vendor,bar\0")
+            .end_node()
+            .end_node()
+            .build();
+
+        let builder =
+            DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                .unwrap()
+                .dump(true);
+        let probed = builder.build().unwrap();
+        let record = |path: &str| probed.records.iter().find(|r| r.path == path).unwrap();
+        let rtc = record("/rtc@101000");
+        assert!(matches!(rtc.status, ProbeStatus::Probed));
+        assert_eq!(rtc.mmio, Some(0x10_1000..0x10_2000));
+        assert!(matches!(
+            record("/rtc@101800").status,
+            ProbeStatus::Duplicated
+        ));
+        assert!(matches!(
+            record("/rtc@103000").status,
+            ProbeStatus::Disabled
+        ));
+        let foo = record("/foo@104000");
+        assert!(matches!(foo.status, ProbeStatus::NotSupported));
+        assert_eq!(foo.compatible, ["vendor,foo", "vendor,bar"]);
+        assert!(foo.mmio.is_none());
+    }
+
+    #[test]
+    fn test_disabled_bus() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("soc")
+            .prop_str("compatible", "simple-bus")
+            .prop_str("status", "disabled")
+            .prop("ranges", &[])
+            .begin_node("rtc@101000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .prop_str("status", "okay")
+            .end_node()
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+
+        let (builder, _) = mock_builder(&blob);
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/rtc@102000"]);
+        // the children of a disabled node are disabled too
+        assert!(matches!(
+            probe_status(&probed, "/soc/rtc@101000"),
+            ProbeStatus::Disabled
+        ));
+    }
+
+    #[test]
+    fn test_chosen() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("chosen")
+            .prop_str("stdout-path", "serial1:115200n8")
+            .prop_str("bootargs", "LOG=info")
+            .end_node()
+            .begin_node("aliases")
+            .prop_str("serial1", "/serial@10000100")
+            .end_node()
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100])
+            .end_node()
+            .begin_node("serial@10000100")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0100, 0x100])
+            .end_node()
+            .end_node()
+            .build();
+
+        let (builder, _) = mock_builder(&blob);
+        let (devices, chosen) = builder.build_with_chosen().unwrap();
+        assert_eq!(devices.len(), 2);
+        // not the first UART
+        let ptr = |dev: &Device| Arc::as_ptr(&dev.inner()) as *const ();
+        let stdout = chosen.stdout.unwrap();
+        assert!(matches!(stdout, Device::Uart(_)));
+        assert_eq!(ptr(&stdout), ptr(&devices[1]));
+        assert_eq!(chosen.bootargs.as_deref(), Some("LOG=info"));
+    }
+
+    #[test]
+    fn test_register_probe() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("rtc@101000")
+            .prop_str("compatible", "vendor,rtc")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .prop_cells("interrupts-extended", &[0x99, 11])
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+
+        let builder =
+            DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                .unwrap()
+                .register_probe("vendor,rtc", |node, props, mmap| {
+                    use crate::rtc::GoldfishRtc;
+                    let (paddr, size) = parse_reg(node, props)?;
+                    let base = mmap(paddr as usize, size as usize)?;
+                    let rtc = Arc::new(unsafe { GoldfishRtc::new(base) });
+                    Ok((Device::Rtc(rtc), Some(Vec::new())))
+                })
+                // take precedence over the built-in driver
+                .register_probe("google,goldfish-rtc", |_, _, _| {
+                    Err(DeviceError::NotSupported)
+                });
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/rtc@101000"]);
+        // the interrupts returned by the probe function are used
+        assert!(probed.irq_errors.is_empty());
+    }
+
+    #[test]
+    fn test_unmap_on_failure() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("foo@101000")
+            .prop_str("compatible", "vendor,foo")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+
+        let mapper = MockIoMapper::default();
+        let builder = DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, mapper.clone())
+            .unwrap()
+            .register_probe("vendor,foo", |node, props, mmap| {
+                let (paddr, size) = parse_reg(node, props)?;
+                mmap(paddr as usize, size as usize)?;
+                Err(DeviceError::IoError)
+            });
+        let probed = builder.build().unwrap();
+        let unmapped = mapper.unmapped();
+        assert_eq!(unmapped.len(), 1);
+        assert_eq!(unmapped[0].1, 0x1000);
+        // the mappings of the probed device are kept for removal
+        assert_eq!(probed.devices.len(), 1);
+        assert_eq!(probed.devices[0].mappings.len(), 1);
+        assert_ne!(probed.devices[0].mappings[0].0, unmapped[0].0);
+    }
+
+    #[test]
+    fn test_uart() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100])
+            .end_node()
+            .end_node()
+            .build();
+
+        let (builder, mapper) = mock_builder(&blob);
+        let probed = builder.build().unwrap();
+        assert_eq!(probed.devices.len(), 1);
+        assert!(matches!(probed.devices[0].device, Device::Uart(_)));
+        // LCR: 8 data bits, no parity and one stop bit
+        assert_eq!(mapper.read(0x1000_0000) >> 24, 0x03);
+        assert!(probed.devices[0].irqs.is_empty());
+        assert!(probed.irq_errors.is_empty());
+    }
+
+    #[test]
+    fn test_intc_and_uart() {
+        // as a PLIC, with the IRQ number only in the interrupt specifiers
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("soc")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop("ranges", &[])
+            .begin_node("plic@c000000")
+            .prop_str("compatible", "zcore,mock-intc")
+            .prop_cells("reg", &[0xc00_0000, 0x21_0000])
+            .prop("interrupt-controller", &[])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[2])
+            .end_node()
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100])
+            .prop_cells("interrupts", &[10])
+            .prop_cells("interrupt-parent", &[2])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let (builder, _) = mock_builder(&blob);
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/soc/plic@c000000", "/soc/serial@10000000"]);
+        assert!(matches!(probed.devices[0].device, Device::Irq(_)));
+        assert!(matches!(probed.devices[1].device, Device::Uart(_)));
+        assert!(probed.irq_errors.is_empty());
+        assert_eq!(probed.devices[1].irqs.len(), 1);
+        assert_eq!(probed.devices[1].irqs[0].1, 10);
+        // the trigger type is not configured without the second cell
+        assert_eq!(
+            take_irq_ops(),
+            [
+                (String::from("/soc/plic@c000000"), IrqOp::Register(10)),
+                (String::from("/soc/plic@c000000"), IrqOp::Unmask(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_node_order() {
+        // a cascaded controller and the UARTs on both controllers
+        let nodes: [fn(&mut FdtBuilder); 4] = [
+            |b| {
+                b.begin_node("intc@c000000")
+                    .prop_str("compatible", "zcore,mock-intc")
+                    .prop_cells("reg", &[0xc00_0000, 0x1000])
+                    .prop("interrupt-controller", &[])
+                    .prop_cells("#interrupt-cells", &[1])
+                    .prop_cells("phandle", &[1])
+                    .end_node();
+            },
+            |b| {
+                b.begin_node("intc@c001000")
+                    .prop_str("compatible", "zcore,mock-intc")
+                    .prop_cells("reg", &[0xc00_1000, 0x1000])
+                    .prop("interrupt-controller", &[])
+                    .prop_cells("#interrupt-cells", &[1])
+                    .prop_cells("phandle", &[2])
+                    .prop_cells("interrupts", &[5])
+                    .prop_cells("interrupt-parent", &[1])
+                    .end_node();
+            },
+            |b| {
+                b.begin_node("serial@10000000")
+                    .prop_str("compatible", "ns16550a")
+                    .prop_cells("reg", &[0x1000_0000, 0x100])
+                    .prop_cells("interrupts", &[10])
+                    .prop_cells("interrupt-parent", &[2])
+                    .end_node();
+            },
+            |b| {
+                b.begin_node("serial@10001000")
+                    .prop_str("compatible", "ns16550a")
+                    .prop_cells("reg", &[0x1000_1000, 0x100])
+                    .prop_cells("interrupts", &[11])
+                    .prop_cells("interrupt-parent", &[1])
+                    .end_node();
+            },
+        ];
+        let expected_irqs: [(String, Vec<usize>); 4] = [
+            (String::from("/intc@c000000"), vec![]),
+            (String::from("/intc@c001000"), vec![5]),
+            (String::from("/serial@10000000"), vec![10]),
+            (String::from("/serial@10001000"), vec![11]),
+        ];
+        let mut expected_ops: Vec<_> = [
+            ("/intc@c000000", IrqOp::Register(5)),
+            ("/intc@c000000", IrqOp::Unmask(5)),
+            ("/intc@c000000", IrqOp::Register(11)),
+            ("/intc@c000000", IrqOp::Unmask(11)),
+            ("/intc@c001000", IrqOp::Register(10)),
+            ("/intc@c001000", IrqOp::Unmask(10)),
+        ]
+        .iter()
+        .map(|op| std::format!("{op:?}"))
+        .collect();
+        expected_ops.sort();
+
+        // all permutations of the nodes
+        let mut orders = vec![Vec::new()];
+        for i in 0..nodes.len() {
+            orders = orders
+                .into_iter()
+                .flat_map(|order: Vec<usize>| {
+                    (0..=order.len()).map(move |pos| {
+                        let mut order = order.clone();
+                        order.insert(pos, i);
+                        order
+                    })
+                })
+                .collect();
+        }
+        assert_eq!(orders.len(), 24);
+
+        for order in orders {
+            let mut builder = FdtBuilder::default();
+            builder
+                .begin_node("")
+                .prop_cells("#address-cells", &[1])
+                .prop_cells("#size-cells", &[1]);
+            for &i in &order {
+                nodes[i](&mut builder);
+            }
+            let blob = builder.end_node().build();
+
+            let (builder, _) = mock_builder(&blob);
+            let probed = builder.build().unwrap();
+            assert!(probed.irq_errors.is_empty(), "order {order:?}");
+            let mut irqs: Vec<_> = probed
+                .devices
+                .iter()
+                .map(|d| {
+                    let irqs: Vec<_> = d.irqs.iter().map(|&(_, n)| n).collect();
+                    (d.path.clone(), irqs)
+                })
+                .collect();
+            irqs.sort();
+            assert_eq!(irqs, expected_irqs, "order {order:?}");
+            let mut ops: Vec<_> = take_irq_ops()
+                .iter()
+                .map(|(path, op)| std::format!("{:?}", (path.as_str(), op)))
+                .collect();
+            ops.sort();
+            assert_eq!(ops, expected_ops, "order {order:?}");
+        }
+    }
+
+    #[test]
+    fn test_intc_trigger_cells() {
+        // as the T-Head PLIC in the DTB of the Allwinner D1 SDK, without the
+        // CPU intc
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .begin_node("soc@3000000")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .prop("ranges", &[])
+            .begin_node("plic@10000000")
+            .prop_str("compatible", "zcore,mock-intc")
+            .prop_cells("#interrupt-cells", &[2])
+            .prop_cells("#address-cells", &[0])
+            .prop("interrupt-controller", &[])
+            .prop_cells("reg", &[0, 0x1000_0000, 0, 0x400_0000])
+            .prop_cells("interrupts-extended", &[1, 0xffff_ffff, 1, 9])
+            .prop_cells("riscv,ndev", &[200])
+            .prop_cells("phandle", &[2])
+            .end_node()
+            .begin_node("uart@2500000")
+            .prop_str("compatible", "snps,dw-apb-uart")
+            .prop_cells("reg", &[0, 0x250_0000, 0, 0x400])
+            .prop_cells("interrupts", &[18, 4])
+            .prop_cells("interrupt-parent", &[2])
+            .prop_cells("reg-shift", &[2])
+            .prop_cells("reg-io-width", &[4])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let (builder, _) = mock_builder(&blob);
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["/soc@3000000/plic@10000000", "/soc@3000000/uart@2500000"]
+        );
+        assert!(matches!(probed.devices[0].device, Device::Irq(_)));
+        assert_eq!(probed.devices[1].irqs.len(), 1);
+        assert_eq!(probed.devices[1].irqs[0].1, 18);
+        // only the interrupts of the PLIC to the missing CPU intc are not
+        // registered
+        assert_eq!(probed.irq_errors.len(), 1);
+        assert_eq!(probed.irq_errors[0].0, "/soc@3000000/plic@10000000");
+        // level triggered, active high in the second cell
+        let ops: Vec<_> = take_irq_ops().into_iter().map(|(_, op)| op).collect();
+        assert_eq!(
+            ops,
+            [
+                IrqOp::Configure(18, IrqTriggerMode::Level, IrqPolarity::ActiveHigh),
+                IrqOp::Register(18),
+                IrqOp::Unmask(18),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compatible_filters() {
+        type Builder = DevicetreeDriverBuilder<MockIoMapper>;
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("rtc@101000")
+            .prop("compatible", b"vendor,rtc\0google,goldfish-rtc\0")
+            .prop_cells("reg", &[0x10_1000, 0x1000])
+            .end_node()
+            .begin_node("rtc@102000")
+            .prop_str("compatible", "google,goldfish-rtc")
+            .prop_cells("reg", &[0x10_2000, 0x1000])
+            .end_node()
+            .end_node()
+            .build();
+        let probe = |filter: fn(Builder) -> Builder| -> Vec<String> {
+            let builder =
+                DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                    .unwrap();
+            let probed = filter(builder).build().unwrap();
+            probed.devices.into_iter().map(|d| d.path).collect()
+        };
+
+        // any string in `compatible` matches
+        let paths = probe(|b| b.deny_compatible("vendor,rtc"));
+        assert_eq!(paths, ["/rtc@102000"]);
+        let paths = probe(|b| b.allow_only(&["vendor,rtc"]));
+        assert_eq!(paths, ["/rtc@101000"]);
+        let paths =
+            probe(|b| b.filter_from_cmdline("console=ttyS0 drivers.deny=google,goldfish-rtc"));
+        assert!(paths.is_empty());
+        let paths = probe(|b| b.filter_from_cmdline("drivers.allow=google,goldfish-rtc"));
+        assert_eq!(paths, ["/rtc@101000", "/rtc@102000"]);
+    }
+    #[test]
+    fn test_gpio_keys() {
+        // the keys come before the GPIO controller they refer to
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("gpio-keys")
+            .prop_str("compatible", "gpio-keys")
+            .begin_node("power")
+            .prop_cells("gpios", &[1, 4, 14, GPIO_ACTIVE_LOW])
+            .prop_cells("linux,code", &[116])
+            .end_node()
+            .end_node()
+            .begin_node("pinctrl@2000000")
+            .prop_str("compatible", "allwinner,sun20i-d1-pinctrl")
+            .prop_cells("reg", &[0x200_0000, 0x800])
+            .prop("gpio-controller", &[])
+            .prop_cells("#gpio-cells", &[3])
+            .prop_cells("phandle", &[1])
+            .end_node()
+            .end_node()
+            .build();
+
+        let builder =
+            DevicetreeDriverBuilder::new(blob.as_ptr() as VirtAddr, MockIoMapper::default())
+                .unwrap();
+        let probed = builder.build().unwrap();
+        let paths: Vec<_> = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, ["/gpio-keys", "/pinctrl@2000000"]);
+        match &probed.devices[0].device {
+            Device::Input(keys) => {
+                use crate::prelude::CapabilityType;
+                assert!(keys.capability(CapabilityType::Key).contains(116));
+            }
+            dev => panic!("unexpected device {dev:?}"),
+        }
+        assert!(matches!(probed.devices[1].device, Device::Gpio(_)));
+    }
+
+    /// Returns the paths of the devices and the nodes whose interrupts failed
+    /// to register.
+    fn probed_paths(probed: &ProbedDevices) -> (Vec<&str>, Vec<&str>) {
+        let devices = probed.devices.iter().map(|d| d.path.as_str()).collect();
+        let irq_errors = probed
+            .irq_errors
+            .iter()
+            .map(|(path, err)| {
+                assert!(matches!(err, DeviceError::InvalidParam));
+                path.as_str()
+            })
+            .collect();
+        (devices, irq_errors)
+    }
+
+    fn probe_status<'a>(probed: &'a ProbedDevices, path: &str) -> &'a ProbeStatus {
+        &probed
+            .records
+            .iter()
+            .find(|r| r.path == path)
+            .unwrap()
+            .status
+    }
+
+    // The interrupt controllers of riscv are not available on the host, so
+    // the interrupts of the devices can not be registered.
+    #[test]
+    #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+    fn test_qemu_riscv_virt() {
+        // THRE and TEMT of LSR, which are polled before setting the divisor
+        let mapper = MockIoMapper::default().preset(0x1000_0005, 0x60);
+        let (builder, mapper) = mock_builder_with(QEMU_RISCV_VIRT_DTB, mapper);
+        let probed = builder.build().unwrap();
+        let (paths, irq_errors) = probed_paths(&probed);
+        assert_eq!(
+            paths,
+            [
+                "/poweroff",
+                "/reboot",
+                "/soc/rtc@101000",
+                "/soc/serial@10000000",
+                "/soc/test@100000",
+            ]
+        );
+        let devices: Vec<_> = probed.devices.iter().map(|d| &d.device).collect();
+        assert!(matches!(
+            devices[..],
+            [
+                Device::Power(_),
+                Device::Power(_),
+                Device::Rtc(_),
+                Device::Uart(_),
+                Device::Power(_),
+            ]
+        ));
+        assert_eq!(probed.console, Some(3));
+        assert_eq!(probed.bootargs.as_deref(), Some("LOG=warn"));
+        assert_eq!(probed.initrd, None);
+        assert_eq!(irq_errors, ["/soc/rtc@101000", "/soc/serial@10000000"]);
+        for path in [
+            "/cpus/cpu@0/interrupt-controller",
+            "/soc/plic@c000000",
+            "/soc/clint@2000000",
+            "/soc/virtio_mmio@10001000",
+        ] {
+            assert!(matches!(
+                probe_status(&probed, path),
+                ProbeStatus::NotSupported
+            ));
+        }
+        // the divisor of 115200 baud with the 3.6864MHz clock, and LCR
+        let regs = mapper.read(0x1000_0000);
+        assert_eq!(regs & 0xff, 2);
+        assert_eq!(regs >> 24, 0x03);
+    }
+
+    #[test]
+    fn test_multi_bus() {
+        let (builder, mapper) = mock_builder(MULTI_BUS_DTB);
+        let probed = builder.build().unwrap();
+        let (paths, irq_errors) = probed_paths(&probed);
+        assert_eq!(
+            paths,
+            [
+                "/interrupt-controller@8000000",
+                "/bus@100000000/serial@1000",
+                "/bus@100000000/bus@80000/rtc@2000",
+                "/bus@200000000/serial@200001000",
+            ]
+        );
+        let devices: Vec<_> = probed.devices.iter().map(|d| &d.device).collect();
+        assert!(matches!(
+            devices[..],
+            [
+                Device::Irq(_),
+                Device::Uart(_),
+                Device::Rtc(_),
+                Device::Uart(_),
+            ]
+        ));
+        assert!(matches!(
+            probe_status(&probed, "/bus@100000000/bus@80000/rtc@3000"),
+            ProbeStatus::Disabled
+        ));
+
+        // translated by the `ranges` of all buses
+        let mmio: Vec<_> = probed.devices.iter().map(|d| d.mmio.clone()).collect();
+        assert_eq!(
+            mmio[1..],
+            [
+                Some(0x1_0000_1000..0x1_0000_1100),
+                Some(0x1_0008_2000..0x1_0008_3000),
+                Some(0x2_0000_1000..0x2_0000_1400),
+            ]
+        );
+        // LCR of the DesignWare UART, 4 bytes apart
+        assert_eq!(mapper.read(0x2_0000_100c), 0x03);
+
+        use IrqOp::*;
+        use IrqPolarity::ActiveHigh;
+        use IrqTriggerMode::{Edge, Level};
+        let ops = take_irq_ops();
+        assert!(ops
+            .iter()
+            .all(|(path, _)| path == "/interrupt-controller@8000000"));
+        let ops: Vec<_> = ops.into_iter().map(|(_, op)| op).collect();
+        assert_eq!(
+            ops,
+            [
+                Configure(10, Level, ActiveHigh),
+                Register(10),
+                Unmask(10),
+                Configure(11, Edge, ActiveHigh),
+                Register(11),
+                Unmask(11),
+                Configure(13, Level, ActiveHigh),
+                Register(13),
+                Unmask(13),
+            ]
+        );
+        let irqs: Vec<Vec<usize>> = probed
+            .devices
+            .iter()
+            .map(|d| d.irqs.iter().map(|(_, irq_num)| *irq_num).collect())
+            .collect();
+        assert_eq!(irqs, [vec![], vec![10], vec![11], vec![13]]);
+        // the second specifier is truncated
+        assert_eq!(irq_errors, ["/bus@200000000/serial@200001000"]);
+    }
+
+    #[test]
+    #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+    fn test_d1() {
+        let (builder, mapper) = mock_builder(D1_DTB);
+        let probed = builder.build().unwrap();
+        let (paths, irq_errors) = probed_paths(&probed);
+        assert_eq!(
+            paths,
+            ["/gpio-keys", "/soc/pinctrl@2000000", "/soc/serial@2500000"]
+        );
+        let devices: Vec<_> = probed.devices.iter().map(|d| &d.device).collect();
+        assert!(matches!(
+            devices[..],
+            [Device::Input(_), Device::Gpio(_), Device::Uart(_)]
+        ));
+        // `stdout-path` refers to an alias
+        assert_eq!(probed.console, Some(2));
+        assert_eq!(irq_errors, ["/soc/pinctrl@2000000", "/soc/serial@2500000"]);
+        for path in ["/soc/interrupt-controller@10000000", "/soc/rtc@7090000"] {
+            assert!(matches!(
+                probe_status(&probed, path),
+                ProbeStatus::NotSupported
+            ));
+        }
+        assert_eq!(mapper.read(0x250_000c), 0x03);
+    }
+
+    #[test]
+    fn test_malformed_dtb() {
+        let truncated = &MULTI_BUS_DTB[..MULTI_BUS_DTB.len() - 4];
+        assert!(matches!(
+            Devicetree::from_bytes(truncated),
+            Err(DeviceError::InvalidDtb)
+        ));
+        // replace `FDT_BEGIN_NODE` of the root node with an invalid token
+        let mut blob = MULTI_BUS_DTB.to_vec();
+        let off_struct = u32::from_be_bytes([blob[8], blob[9], blob[10], blob[11]]) as usize;
+        blob[off_struct + 3] = 0x7;
+        assert!(matches!(
+            Devicetree::from_bytes(&blob),
+            Err(DeviceError::InvalidDtb)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_nodes() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("intc@8000000")
+            .prop_str("compatible", "zcore,mock-intc")
+            .prop("interrupt-controller", &[])
+            .prop_cells("#interrupt-cells", &[2])
+            .prop_cells("phandle", &[1])
+            .end_node()
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100, 0x1000_0100])
+            .end_node()
+            .end_node()
+            .build();
+        let (builder, _) = mock_builder(&blob);
+        let probed = builder.build().unwrap();
+        assert!(matches!(
+            probe_status(&probed, "/serial@10000000"),
+            ProbeStatus::Failed(DeviceError::InvalidParam)
+        ));
+
+        // another interrupt controller with the same phandle
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("intc@8000000")
+            .prop_str("compatible", "zcore,mock-intc")
+            .prop("interrupt-controller", &[])
+            .prop_cells("#interrupt-cells", &[2])
+            .prop_cells("phandle", &[1])
+            .end_node()
+            .begin_node("intc@9000000")
+            .prop_str("compatible", "zcore,mock-intc")
+            .prop("interrupt-controller", &[])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[1])
+            .end_node()
+            .end_node()
+            .build();
+        let (builder, _) = mock_builder(&blob);
+        assert!(matches!(builder.build(), Err(DeviceError::InvalidDtb)));
+    }
+}
@@ -3,9 +3,35 @@
 
 mod devicetree;
 
-pub use devicetree::DevicetreeDriverBuilder;
+pub use devicetree::{
+    ChosenInfo, DevicetreeDriverBuilder, IntcProbeFn, NamedDevice, ProbeFn, ProbeRecord,
+    ProbeStatus, ProbedDevices,
+};
 
-use crate::{PhysAddr, VirtAddr};
+use crate::bus::PAGE_SIZE;
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+/// Memory attributes of an MMIO mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioAttrs {
+    /// Device memory without gathering, reordering and early write
+    /// acknowledgement (device-nGnRnE), for device registers.
+    Device,
+    /// Normal memory without caching.
+    Uncached,
+    /// Normal memory without caching, but writes can be combined, e.g. for
+    /// framebuffers.
+    WriteCombining,
+    /// Normal cacheable memory, e.g. for the memory shared with devices by
+    /// cache coherent DMA.
+    Cached,
+}
+
+impl Default for MmioAttrs {
+    fn default() -> Self {
+        Self::Device
+    }
+}
 
 /// A trait implemented in kernel to translate device physical addresses to virtual
 /// addresses.
@@ -15,4 +41,77 @@ pub trait IoMapper {
     ///
     /// If an error accurs during translation or mapping, returns `None`.
     fn query_or_map(&self, paddr: PhysAddr, size: usize) -> Option<VirtAddr>;
+
+    /// Translate the page aligned physical address to virtual address. If not
+    /// mapped in the kernel page table, map the `size` bytes with `attrs`.
+    ///
+    /// If an error accurs during translation or mapping, returns `None`.
+    ///
+    /// The default implementation ignores `attrs`, and maps the pages as
+    /// device memory by [`IoMapper::query_or_map`], which is always safe but
+    /// may be slow for the frame buffers.
+    fn map_pages(&self, paddr: PhysAddr, size: usize, _attrs: MmioAttrs) -> Option<VirtAddr> {
+        self.query_or_map(paddr, size)
+    }
+
+    /// Map the MMIO region of `len` bytes at `paddr` with `attrs`, which is
+    /// extended to page boundaries. Returns the virtual address of `paddr`.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if the region is empty or out of
+    /// the address space.
+    fn map_mmio(&self, paddr: PhysAddr, len: usize, attrs: MmioAttrs) -> DeviceResult<VirtAddr> {
+        let end = paddr.checked_add(len).filter(|_| len != 0);
+        let end = end
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .ok_or(DeviceError::InvalidParam)?
+            & !(PAGE_SIZE - 1);
+        let base = paddr & !(PAGE_SIZE - 1);
+        let vaddr = self
+            .map_pages(base, end - base, attrs)
+            .ok_or(DeviceError::NoResources)?;
+        Ok(vaddr + (paddr - base))
+    }
+
+    /// Release the mapping of the region at `vaddr` returned by
+    /// [`IoMapper::query_or_map`], when the device fails to be created or is
+    /// removed. Does nothing by default, e.g. for the identity or linear
+    /// mappings which can not be released.
+    fn unmap(&self, _vaddr: VirtAddr, _len: usize) -> DeviceResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::test_utils::MockIoMapper;
+
+    #[test]
+    fn test_map_mmio() {
+        let mapper = MockIoMapper::default();
+        let vaddr0 = mapper.query_or_map(0x1000_0010, 0x20).unwrap();
+        let vaddr1 = mapper.map_mmio(0x1000_0ff0, 0x20, MmioAttrs::WriteCombining);
+        let regions = mapper.regions();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(vaddr0, regions[0].1 + 0x10);
+        assert_eq!(vaddr1.ok(), Some(regions[1].1 + 0xff0));
+        assert_eq!(
+            regions
+                .iter()
+                .map(|&(paddr, _, size, attrs)| (paddr, size, attrs))
+                .collect::<alloc::vec::Vec<_>>(),
+            [
+                (0x1000_0000, 0x1000, MmioAttrs::Device),
+                (0x1000_0000, 0x2000, MmioAttrs::WriteCombining),
+            ]
+        );
+        assert!(matches!(
+            mapper.map_mmio(0x1000_0000, 0, MmioAttrs::Device),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            mapper.map_mmio(usize::MAX - 0x10, 0x20, MmioAttrs::Device),
+            Err(DeviceError::InvalidParam)
+        ));
+    }
 }
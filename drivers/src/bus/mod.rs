@@ -1,5 +1,6 @@
 #[cfg(any(target_arch = "x86_64", target_arch = "riscv64"))]
 pub mod pci;
+pub mod pci_ecam;
 
 pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
     unsafe { drivers_phys_to_virt(paddr) }
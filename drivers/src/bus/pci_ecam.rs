@@ -0,0 +1,418 @@
+//! Enumerate PCI functions behind a generic ECAM host bridge, e.g. the
+//! `pci-host-ecam-generic` node of QEMU virt machines, and assign their BARs
+//! from the windows in the `ranges` property.
+//!
+//! Only the functions on the buses of the host bridge are probed, PCI-to-PCI
+//! bridges are listed but not configured.
+
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use crate::io::{Io, Mmio};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_COMMAND: usize = 0x04;
+const PCI_CLASS_REVISION: usize = 0x08;
+/// The header type is the third byte of this word.
+const PCI_HEADER_TYPE_WORD: usize = 0x0c;
+const PCI_BAR0: usize = 0x10;
+/// The interrupt pin is the second byte of this word.
+const PCI_INTERRUPT_WORD: usize = 0x3c;
+
+const PCI_COMMAND_IO: u32 = 1 << 0;
+const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+const PCI_COMMAND_MASTER: u32 = 1 << 2;
+
+const PCI_HEADER_TYPE_NORMAL: u8 = 0;
+const PCI_HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+const PCI_BAR_IO: u32 = 1 << 0;
+const PCI_BAR_MEM_TYPE_64: u32 = 2 << 1;
+const PCI_BAR_MEM_PREFETCH: u32 = 1 << 3;
+
+/// The first I/O port to assign, the lower ones may be decoded by legacy ISA
+/// devices.
+const FIRST_IO_PORT: u64 = 0x1000;
+
+/// Address spaces of the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciSpace {
+    Io,
+    Mem32,
+    Mem64,
+}
+
+/// A window of the PCI bus address space to the CPU physical address space,
+/// an entry of the `ranges` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciRange {
+    pub space: PciSpace,
+    pub prefetchable: bool,
+    pub pci_addr: u64,
+    pub cpu_addr: u64,
+    pub size: u64,
+}
+
+impl PciRange {
+    /// Parse the `ranges` property of a PCI host bridge, each entry has a
+    /// 3-cell PCI address, a CPU address of `parent_address_cells` and a size
+    /// of `size_cells`. The configuration space entries are ignored.
+    pub fn parse(
+        cells: &[u32],
+        parent_address_cells: usize,
+        size_cells: usize,
+    ) -> DeviceResult<Vec<Self>> {
+        let read = |cells: &[u32]| cells.iter().fold(0u64, |acc, &c| (acc << 32) | c as u64);
+        let entry_len = 3 + parent_address_cells + size_cells;
+        if parent_address_cells > 2 || size_cells > 2 || cells.len() % entry_len != 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mut ranges = Vec::new();
+        for entry in cells.chunks(entry_len) {
+            let phys_hi = entry[0];
+            let space = match (phys_hi >> 24) & 0x3 {
+                1 => PciSpace::Io,
+                2 => PciSpace::Mem32,
+                3 => PciSpace::Mem64,
+                _ => continue,
+            };
+            ranges.push(Self {
+                space,
+                prefetchable: phys_hi & (1 << 30) != 0,
+                pci_addr: read(&entry[1..3]),
+                cpu_addr: read(&entry[3..3 + parent_address_cells]),
+                size: read(&entry[3 + parent_address_cells..]),
+            });
+        }
+        Ok(ranges)
+    }
+
+    fn contains(&self, pci_addr: u64, size: u64) -> bool {
+        pci_addr >= self.pci_addr && pci_addr + size <= self.pci_addr + self.size
+    }
+}
+
+/// A base address register, translated to the CPU physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciBar {
+    Memory {
+        paddr: PhysAddr,
+        size: usize,
+        prefetchable: bool,
+    },
+    /// I/O ports are accessed through memory on hosts other than x86.
+    Io { paddr: PhysAddr, size: usize },
+}
+
+/// A function found on the PCI bus.
+#[derive(Debug, Clone)]
+pub struct PciFunction {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// The assigned BARs. The upper half of a 64-bit BAR is `None`.
+    pub bars: [Option<PciBar>; 6],
+    /// 1 to 4 for INTA# to INTD#, or 0 if it does not use an interrupt.
+    pub interrupt_pin: u8,
+    /// The virtual address of the configuration space of the function.
+    pub config_vaddr: VirtAddr,
+}
+
+impl PciFunction {
+    /// Returns the unit address of the function in the `interrupt-map` of the
+    /// host bridge, i.e. the PCI address with only the bus, device and
+    /// function numbers.
+    pub fn unit_addr(&self) -> [u32; 3] {
+        let bdf =
+            (self.bus as u32) << 16 | (self.device as u32) << 11 | (self.function as u32) << 8;
+        [bdf, 0, 0]
+    }
+}
+
+/// A generic PCI host bridge with the ECAM configuration space.
+pub struct EcamHost {
+    base: VirtAddr,
+    buses: RangeInclusive<u8>,
+    ranges: Vec<PciRange>,
+    /// The next free PCI address in each of `ranges`.
+    next: Vec<u64>,
+}
+
+impl EcamHost {
+    /// Construct a host bridge with the configuration space of `buses` mapped
+    /// at `base`, and the address windows `ranges` to assign BARs from.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address, and
+    /// it must map the configuration space of all `buses`.
+    pub unsafe fn new(base: VirtAddr, buses: RangeInclusive<u8>, ranges: Vec<PciRange>) -> Self {
+        let next = ranges
+            .iter()
+            .map(|r| match r.space {
+                PciSpace::Io => r.pci_addr.max(FIRST_IO_PORT),
+                _ => r.pci_addr,
+            })
+            .collect();
+        Self {
+            base,
+            buses,
+            ranges,
+            next,
+        }
+    }
+
+    fn config_vaddr(&self, bus: u8, device: u8, function: u8) -> VirtAddr {
+        let bus = (bus - *self.buses.start()) as usize;
+        self.base + (bus << 20 | (device as usize) << 15 | (function as usize) << 12)
+    }
+
+    fn reg(&self, bus: u8, device: u8, function: u8, offset: usize) -> &'static mut Mmio<u32> {
+        unsafe { Mmio::<u32>::from_base(self.config_vaddr(bus, device, function) + offset) }
+    }
+
+    /// Walk all buses, devices and functions, assign the BARs of the found
+    /// functions, enable them and returns them.
+    pub fn enumerate(&mut self) -> Vec<PciFunction> {
+        let mut functions = Vec::new();
+        for bus in self.buses.clone() {
+            for device in 0..32 {
+                for function in 0..8 {
+                    let id = self.reg(bus, device, function, PCI_VENDOR_ID).read();
+                    if id & 0xffff == 0xffff {
+                        if function == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    let header_type =
+                        (self.reg(bus, device, function, PCI_HEADER_TYPE_WORD).read() >> 16) as u8;
+                    functions.push(self.probe_function(bus, device, function, id, header_type));
+                    if function == 0 && header_type & PCI_HEADER_TYPE_MULTI_FUNCTION == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        functions
+    }
+
+    fn probe_function(
+        &mut self,
+        bus: u8,
+        device: u8,
+        function: u8,
+        id: u32,
+        header_type: u8,
+    ) -> PciFunction {
+        let class = self.reg(bus, device, function, PCI_CLASS_REVISION).read();
+        let interrupt = self.reg(bus, device, function, PCI_INTERRUPT_WORD).read();
+        let mut func = PciFunction {
+            bus,
+            device,
+            function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            bars: [None; 6],
+            interrupt_pin: (interrupt >> 8) as u8,
+            config_vaddr: self.config_vaddr(bus, device, function),
+        };
+        if header_type & !PCI_HEADER_TYPE_MULTI_FUNCTION == PCI_HEADER_TYPE_NORMAL {
+            self.assign_bars(&mut func);
+        } else {
+            debug!("pci: skip bridge {:02x}:{:02x}.{}", bus, device, function);
+        }
+        func
+    }
+
+    /// Size and assign all BARs of `func`, then enable the decoding.
+    fn assign_bars(&mut self, func: &mut PciFunction) {
+        let (bus, device, function) = (func.bus, func.device, func.function);
+        let command = self.reg(bus, device, function, PCI_COMMAND);
+        // disable the decoding while changing BARs, and do not clear the status
+        let mut cmd = command.read() & 0xffff & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY);
+        command.write(cmd);
+
+        let mut i = 0;
+        while i < 6 {
+            let bar = self.reg(bus, device, function, PCI_BAR0 + i * 4);
+            let orig = bar.read();
+            bar.write(u32::MAX);
+            let mask_lo = bar.read();
+            bar.write(orig);
+
+            let is_io = orig & PCI_BAR_IO != 0;
+            let is_64 = !is_io && orig & (3 << 1) == PCI_BAR_MEM_TYPE_64;
+            let prefetchable = !is_io && orig & PCI_BAR_MEM_PREFETCH != 0;
+            let mask = if is_64 && i < 5 {
+                let bar_hi = self.reg(bus, device, function, PCI_BAR0 + (i + 1) * 4);
+                let orig_hi = bar_hi.read();
+                bar_hi.write(u32::MAX);
+                let mask_hi = bar_hi.read();
+                bar_hi.write(orig_hi);
+                (mask_hi as u64) << 32 | (mask_lo & !0xf) as u64
+            } else if is_io {
+                // the upper 16 bits may be hardwired to 0 for 16-bit I/O
+                let mask = mask_lo & !0x3;
+                let mask = if mask != 0 && mask >> 16 == 0 {
+                    mask | 0xffff_0000
+                } else {
+                    mask
+                };
+                0xffff_ffff_0000_0000 | mask as u64
+            } else {
+                0xffff_ffff_0000_0000 | (mask_lo & !0xf) as u64
+            };
+            let index = i;
+            i += if is_64 { 2 } else { 1 };
+            if mask as u32 == 0 && (!is_64 || mask >> 32 == 0) {
+                // not implemented
+                continue;
+            }
+            let size = (!mask).wrapping_add(1);
+
+            let space = match (is_io, is_64) {
+                (true, _) => PciSpace::Io,
+                (false, false) => PciSpace::Mem32,
+                (false, true) => PciSpace::Mem64,
+            };
+            let pci_addr = match self.allocate(space, prefetchable, size) {
+                Some(addr) => addr,
+                None => {
+                    warn!(
+                        "pci: no space for BAR{} of {:02x}:{:02x}.{}, size {:#x}",
+                        index, bus, device, function, size
+                    );
+                    continue;
+                }
+            };
+            let flags = orig & if is_io { 0x3 } else { 0xf };
+            self.reg(bus, device, function, PCI_BAR0 + index * 4)
+                .write(pci_addr as u32 | flags);
+            if is_64 {
+                self.reg(bus, device, function, PCI_BAR0 + (index + 1) * 4)
+                    .write((pci_addr >> 32) as u32);
+            }
+
+            let paddr = match self.translate(space, pci_addr, size) {
+                Some(paddr) => paddr as PhysAddr,
+                None => continue,
+            };
+            func.bars[index] = Some(if is_io {
+                cmd |= PCI_COMMAND_IO;
+                PciBar::Io {
+                    paddr,
+                    size: size as usize,
+                }
+            } else {
+                cmd |= PCI_COMMAND_MEMORY;
+                PciBar::Memory {
+                    paddr,
+                    size: size as usize,
+                    prefetchable,
+                }
+            });
+        }
+        self.reg(bus, device, function, PCI_COMMAND)
+            .write(cmd | PCI_COMMAND_MASTER);
+    }
+
+    /// Allocate `size` bytes aligned to `size` from the windows for `space`.
+    /// 64-bit memory BARs may also be placed in 32-bit windows, and the
+    /// windows with the same prefetchability are preferred.
+    fn allocate(&mut self, space: PciSpace, prefetchable: bool, size: u64) -> Option<u64> {
+        let candidates: &[PciSpace] = match space {
+            PciSpace::Io => &[PciSpace::Io],
+            PciSpace::Mem32 => &[PciSpace::Mem32],
+            PciSpace::Mem64 => &[PciSpace::Mem64, PciSpace::Mem32],
+        };
+        for &want_prefetch in &[prefetchable, !prefetchable] {
+            // non-prefetchable BARs must not be placed in prefetchable windows
+            if want_prefetch && !prefetchable {
+                continue;
+            }
+            for &want_space in candidates {
+                for (i, r) in self.ranges.iter().enumerate() {
+                    if r.space != want_space || r.prefetchable != want_prefetch {
+                        continue;
+                    }
+                    let addr = (self.next[i] + size - 1) & !(size - 1);
+                    if r.contains(addr, size) {
+                        self.next[i] = addr + size;
+                        return Some(addr);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Translate the PCI address of a BAR to the CPU physical address.
+    fn translate(&self, space: PciSpace, pci_addr: u64, size: u64) -> Option<u64> {
+        let is_io = space == PciSpace::Io;
+        self.ranges
+            .iter()
+            .find(|r| (r.space == PciSpace::Io) == is_io && r.contains(pci_addr, size))
+            .map(|r| r.cpu_addr + (pci_addr - r.pci_addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The `ranges` of the PCI host bridge of QEMU riscv virt machine.
+    #[rustfmt::skip]
+    const QEMU_RANGES: [u32; 21] = [
+        0x0100_0000, 0, 0, 0, 0x0300_0000, 0, 0x1_0000, // I/O
+        0x0200_0000, 0, 0x4000_0000, 0, 0x4000_0000, 0, 0x4000_0000, // 32-bit memory
+        0x0300_0000, 0x4, 0, 0x4, 0, 0x4, 0, // 64-bit memory
+    ];
+
+    #[test]
+    fn test_parse_ranges() {
+        let ranges = PciRange::parse(&QEMU_RANGES, 2, 2).unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].space, PciSpace::Io);
+        assert_eq!(ranges[0].cpu_addr, 0x300_0000);
+        assert_eq!(ranges[2].space, PciSpace::Mem64);
+        assert_eq!(ranges[2].pci_addr, 0x4_0000_0000);
+        assert_eq!(ranges[2].size, 0x4_0000_0000);
+        assert!(PciRange::parse(&QEMU_RANGES[..20], 2, 2).is_err());
+    }
+
+    #[test]
+    fn test_allocate() {
+        let ranges = PciRange::parse(&QEMU_RANGES, 2, 2).unwrap();
+        let mut host = unsafe { EcamHost::new(0, 0..=0, ranges) };
+        // aligned to the size, after the legacy I/O ports
+        assert_eq!(host.allocate(PciSpace::Io, false, 0x20), Some(0x1000));
+        assert_eq!(
+            host.allocate(PciSpace::Mem32, false, 0x1000),
+            Some(0x4000_0000)
+        );
+        assert_eq!(
+            host.allocate(PciSpace::Mem32, false, 0x4000),
+            Some(0x4000_4000)
+        );
+        assert_eq!(
+            host.allocate(PciSpace::Mem64, true, 0x4000),
+            Some(0x4_0000_0000)
+        );
+        assert_eq!(host.translate(PciSpace::Io, 0x1000, 0x20), Some(0x300_1000));
+        assert_eq!(
+            host.translate(PciSpace::Mem32, 0x4000_4000, 0x4000),
+            Some(0x4000_4000)
+        );
+        assert_eq!(host.allocate(PciSpace::Mem32, false, 0x8000_0000), None);
+    }
+}
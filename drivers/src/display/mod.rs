@@ -1,5 +1,7 @@
-//! Only UEFI Display currently.
+//! Frame buffers set up by the firmware or bootloader.
 
+mod simple_fb;
 mod uefi;
 
+pub use simple_fb::SimpleFramebuffer;
 pub use uefi::UefiDisplay;
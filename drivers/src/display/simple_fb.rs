@@ -0,0 +1,57 @@
+//! Frame buffer set up by the bootloader, e.g. the `simple-framebuffer` node
+//! in the device tree.
+
+use crate::prelude::{ColorFormat, DisplayInfo, FrameBuffer};
+use crate::scheme::{DisplayScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+pub struct SimpleFramebuffer {
+    info: DisplayInfo,
+}
+
+impl SimpleFramebuffer {
+    /// Construct a `SimpleFramebuffer` of `width` x `height` pixels in
+    /// `format`, whose rows are `stride` bytes apart, at `fb_base_vaddr` of
+    /// `fb_size` bytes.
+    pub fn new(
+        fb_base_vaddr: usize,
+        fb_size: usize,
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: ColorFormat,
+    ) -> DeviceResult<Self> {
+        let info = DisplayInfo {
+            width,
+            height,
+            stride,
+            format,
+            fb_base_vaddr,
+            fb_size,
+        };
+        if stride < format.row_bytes(width) || (stride as usize) * (height as usize) > fb_size {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok(Self { info })
+    }
+}
+
+impl Scheme for SimpleFramebuffer {
+    fn name(&self) -> &str {
+        "simple-framebuffer"
+    }
+}
+
+impl DisplayScheme for SimpleFramebuffer {
+    #[inline]
+    fn info(&self) -> DisplayInfo {
+        self.info
+    }
+
+    #[inline]
+    fn fb(&self) -> FrameBuffer {
+        unsafe {
+            FrameBuffer::from_raw_parts_mut(self.info.fb_base_vaddr as *mut u8, self.info.fb_size)
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! The GPIO (PIO) controller of Allwinner D1, the `allwinner,sun20i-d1-pinctrl`
+//! node.
+//!
+//! Pins are numbered as `bank * 32 + index`, e.g. PE14 is 4 * 32 + 14, the same
+//! as the first two cells of the GPIO specifiers.
+
+use alloc::collections::BTreeMap;
+
+use lock::Mutex;
+
+use crate::io::{Io, Mmio};
+use crate::prelude::{GpioDirection, GpioEdge, GpioPull, IrqHandler};
+use crate::scheme::{GpioScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+/// Number of pins in each bank, from PA to PG. PA does not exist on D1.
+const BANK_PINS: [usize; 7] = [0, 13, 8, 23, 18, 7, 19];
+const PINS_PER_BANK: usize = 32;
+
+/// Offset between the registers of the banks.
+const BANK_STRIDE: usize = 0x30;
+/// Function select, 4 bits for each pin.
+const REG_CFG: usize = 0x00;
+const REG_DAT: usize = 0x10;
+/// Pull-up/down select, 2 bits for each pin.
+const REG_PULL: usize = 0x24;
+
+/// The external interrupt registers of bank `n` are at `EINT_BASE + n *
+/// EINT_STRIDE`.
+const EINT_BASE: usize = 0x200;
+const EINT_STRIDE: usize = 0x20;
+/// Trigger mode, 4 bits for each pin.
+const REG_EINT_CFG: usize = 0x00;
+const REG_EINT_CTL: usize = 0x10;
+/// Write 1 to clear.
+const REG_EINT_STATUS: usize = 0x14;
+
+const FUNC_INPUT: u32 = 0;
+const FUNC_OUTPUT: u32 = 1;
+const FUNC_EINT: u32 = 0xe;
+
+const EINT_POSITIVE_EDGE: u32 = 0;
+const EINT_NEGATIVE_EDGE: u32 = 1;
+const EINT_DOUBLE_EDGE: u32 = 4;
+
+struct Registers {
+    base: &'static mut Mmio<u32>,
+}
+
+impl Registers {
+    fn reg(&mut self, offset: usize) -> &mut Mmio<u32> {
+        self.base.add(offset / 4)
+    }
+
+    /// Write `value` to the `bits`-bit field of `pin` in the array of
+    /// registers from `offset`.
+    fn write_field(&mut self, offset: usize, bits: usize, pin: usize, value: u32) {
+        let per_reg = 32 / bits;
+        let reg = self.reg(offset + pin / per_reg * 4);
+        let shift = pin % per_reg * bits;
+        let mask = ((1 << bits) - 1) << shift;
+        reg.write((reg.read() & !mask) | ((value << shift) & mask));
+    }
+
+    fn set_function(&mut self, bank: usize, index: usize, func: u32) {
+        self.write_field(bank * BANK_STRIDE + REG_CFG, 4, index, func);
+    }
+
+    fn eint_reg(&mut self, bank: usize, offset: usize) -> &mut Mmio<u32> {
+        self.reg(EINT_BASE + bank * EINT_STRIDE + offset)
+    }
+}
+
+/// Driver of the GPIO controller of Allwinner D1.
+pub struct GpioAllwinner {
+    regs: Mutex<Registers>,
+    /// Pin -> interrupt handler.
+    handlers: Mutex<BTreeMap<usize, IrqHandler>>,
+}
+
+impl GpioAllwinner {
+    /// Construct a driver with the registers at `base`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize) -> Self {
+        Self {
+            regs: Mutex::new(Registers {
+                base: Mmio::<u32>::from_base(base),
+            }),
+            handlers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the bank and the index in the bank of `pin`.
+    fn locate(pin: usize) -> DeviceResult<(usize, usize)> {
+        let (bank, index) = (pin / PINS_PER_BANK, pin % PINS_PER_BANK);
+        match BANK_PINS.get(bank) {
+            Some(&n) if index < n => Ok((bank, index)),
+            _ => Err(DeviceError::InvalidParam),
+        }
+    }
+}
+
+impl Scheme for GpioAllwinner {
+    fn name(&self) -> &str {
+        "gpio-allwinner"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // each bank has its own interrupt, just check all of them
+        let mut pending = [0u32; BANK_PINS.len()];
+        {
+            let mut regs = self.regs.lock();
+            for (bank, status) in pending.iter_mut().enumerate().skip(1) {
+                let enabled = regs.eint_reg(bank, REG_EINT_CTL).read();
+                *status = regs.eint_reg(bank, REG_EINT_STATUS).read() & enabled;
+                if *status != 0 {
+                    regs.eint_reg(bank, REG_EINT_STATUS).write(*status);
+                }
+            }
+        }
+        let handlers = self.handlers.lock();
+        for (bank, &status) in pending.iter().enumerate() {
+            for index in (0..PINS_PER_BANK).filter(|i| status & (1 << i) != 0) {
+                match handlers.get(&(bank * PINS_PER_BANK + index)) {
+                    Some(handler) => handler(),
+                    None => warn!(
+                        "gpio-allwinner: no handler for P{}{}",
+                        (b'A' + bank as u8) as char,
+                        index
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl GpioScheme for GpioAllwinner {
+    fn num_pins(&self) -> usize {
+        BANK_PINS.len() * PINS_PER_BANK
+    }
+
+    fn set_direction(&self, pin: usize, dir: GpioDirection) -> DeviceResult {
+        let (bank, index) = Self::locate(pin)?;
+        let func = match dir {
+            GpioDirection::Input => FUNC_INPUT,
+            GpioDirection::Output => FUNC_OUTPUT,
+        };
+        self.regs.lock().set_function(bank, index, func);
+        Ok(())
+    }
+
+    fn read(&self, pin: usize) -> DeviceResult<bool> {
+        let (bank, index) = Self::locate(pin)?;
+        let data = self.regs.lock().reg(bank * BANK_STRIDE + REG_DAT).read();
+        Ok(data & (1 << index) != 0)
+    }
+
+    fn write(&self, pin: usize, level: bool) -> DeviceResult {
+        let (bank, index) = Self::locate(pin)?;
+        self.regs
+            .lock()
+            .write_field(bank * BANK_STRIDE + REG_DAT, 1, index, level as u32);
+        Ok(())
+    }
+
+    fn set_pull(&self, pin: usize, pull: GpioPull) -> DeviceResult {
+        let (bank, index) = Self::locate(pin)?;
+        let value = match pull {
+            GpioPull::None => 0,
+            GpioPull::Up => 1,
+            GpioPull::Down => 2,
+        };
+        self.regs
+            .lock()
+            .write_field(bank * BANK_STRIDE + REG_PULL, 2, index, value);
+        Ok(())
+    }
+
+    fn request_irq(&self, pin: usize, edge: GpioEdge, handler: IrqHandler) -> DeviceResult {
+        let (bank, index) = Self::locate(pin)?;
+        let mut handlers = self.handlers.lock();
+        if handlers.contains_key(&pin) {
+            return Err(DeviceError::AlreadyExists);
+        }
+        handlers.insert(pin, handler);
+        drop(handlers);
+
+        let mode = match edge {
+            GpioEdge::Rising => EINT_POSITIVE_EDGE,
+            GpioEdge::Falling => EINT_NEGATIVE_EDGE,
+            GpioEdge::Both => EINT_DOUBLE_EDGE,
+        };
+        let mut regs = self.regs.lock();
+        regs.set_function(bank, index, FUNC_EINT);
+        regs.write_field(
+            EINT_BASE + bank * EINT_STRIDE + REG_EINT_CFG,
+            4,
+            index,
+            mode,
+        );
+        regs.eint_reg(bank, REG_EINT_STATUS).write(1 << index);
+        regs.write_field(EINT_BASE + bank * EINT_STRIDE + REG_EINT_CTL, 1, index, 1);
+        Ok(())
+    }
+
+    fn free_irq(&self, pin: usize) -> DeviceResult {
+        let (bank, index) = Self::locate(pin)?;
+        if self.handlers.lock().remove(&pin).is_none() {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mut regs = self.regs.lock();
+        regs.write_field(EINT_BASE + bank * EINT_STRIDE + REG_EINT_CTL, 1, index, 0);
+        regs.set_function(bank, index, FUNC_INPUT);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// PE14, the 15th pin of bank E.
+    const PIN: usize = 4 * PINS_PER_BANK + 14;
+
+    fn mock_registers() -> (usize, *mut [u32; 0x100]) {
+        let regs = Box::into_raw(Box::new([0u32; 0x100]));
+        (regs as usize, regs)
+    }
+
+    fn word(regs: *mut [u32; 0x100], offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((regs as *const u32).add(offset / 4)) }
+    }
+
+    #[test]
+    fn test_pin_config() {
+        let (base, regs) = mock_registers();
+        let gpio = unsafe { GpioAllwinner::new(base) };
+        let bank = 4 * BANK_STRIDE;
+
+        gpio.set_direction(PIN, GpioDirection::Output).unwrap();
+        assert_eq!(word(regs, bank + REG_CFG + 4), FUNC_OUTPUT << 24);
+        gpio.write(PIN, true).unwrap();
+        assert_eq!(word(regs, bank + REG_DAT), 1 << 14);
+        assert!(gpio.read(PIN).unwrap());
+        gpio.set_pull(PIN, GpioPull::Down).unwrap();
+        assert_eq!(word(regs, bank + REG_PULL), 2 << 28);
+
+        // PA does not exist, and PF has only 7 pins
+        assert!(gpio.read(3).is_err());
+        assert!(gpio.read(5 * PINS_PER_BANK + 7).is_err());
+    }
+
+    #[test]
+    fn test_irq() {
+        let (base, regs) = mock_registers();
+        let gpio = unsafe { GpioAllwinner::new(base) };
+        let eint = EINT_BASE + 4 * EINT_STRIDE;
+        let count = Arc::new(AtomicUsize::new(0));
+        let cloned = count.clone();
+        let handler = Box::new(move || {
+            cloned.fetch_add(1, Ordering::SeqCst);
+        });
+
+        gpio.request_irq(PIN, GpioEdge::Both, handler).unwrap();
+        assert_eq!(word(regs, 4 * BANK_STRIDE + REG_CFG + 4), FUNC_EINT << 24);
+        assert_eq!(word(regs, eint + REG_EINT_CFG + 4), EINT_DOUBLE_EDGE << 24);
+        assert_eq!(word(regs, eint + REG_EINT_CTL), 1 << 14);
+        assert!(matches!(
+            gpio.request_irq(PIN, GpioEdge::Rising, Box::new(|| {})),
+            Err(DeviceError::AlreadyExists)
+        ));
+
+        unsafe {
+            core::ptr::write_volatile(
+                (regs as *mut u32).add((eint + REG_EINT_STATUS) / 4),
+                1 << 14,
+            )
+        };
+        gpio.handle_irq(0);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        gpio.free_irq(PIN).unwrap();
+        assert_eq!(word(regs, eint + REG_EINT_CTL), 0);
+        gpio.handle_irq(0);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
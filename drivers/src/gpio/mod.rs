@@ -0,0 +1,5 @@
+//! GPIO controller drivers.
+
+mod gpio_allwinner;
+
+pub use gpio_allwinner::GpioAllwinner;
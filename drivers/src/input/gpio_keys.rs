@@ -0,0 +1,140 @@
+//! Keys and buttons connected to GPIO pins, the `gpio-keys` node.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use crate::prelude::{
+    CapabilityType, GpioDirection, GpioEdge, InputCapability, InputEvent, InputEventType,
+};
+use crate::scheme::{impl_event_scheme, GpioScheme, InputScheme, Scheme};
+use crate::utils::EventListener;
+use crate::DeviceResult;
+
+/// A key on a GPIO pin.
+pub struct GpioKey {
+    /// The GPIO controller of the pin.
+    pub gpio: Arc<dyn GpioScheme>,
+    pub pin: usize,
+    /// The key is pressed on the low level.
+    pub active_low: bool,
+    /// The code of key events, e.g. [`KEY_POWER`](super::input_event_codes::key::KEY_POWER).
+    pub code: u16,
+}
+
+/// Driver of the keys on GPIO pins, reporting key events on both edges.
+pub struct GpioKeys {
+    keys: Vec<GpioKey>,
+    listener: EventListener<InputEvent>,
+}
+
+impl_event_scheme!(GpioKeys, InputEvent);
+
+impl GpioKeys {
+    /// Construct the driver, and request the interrupts of `keys` from their
+    /// GPIO controllers.
+    pub fn new(keys: Vec<GpioKey>) -> DeviceResult<Arc<Self>> {
+        let ret = Arc::new(Self {
+            keys,
+            listener: EventListener::new(),
+        });
+        for (i, key) in ret.keys.iter().enumerate() {
+            key.gpio.set_direction(key.pin, GpioDirection::Input)?;
+            let cloned = ret.clone();
+            key.gpio.request_irq(
+                key.pin,
+                GpioEdge::Both,
+                Box::new(move || cloned.handle_key(i)),
+            )?;
+        }
+        Ok(ret)
+    }
+
+    /// Report the state of the `i`-th key.
+    fn handle_key(&self, i: usize) {
+        let key = &self.keys[i];
+        let level = match key.gpio.read(key.pin) {
+            Ok(level) => level,
+            Err(err) => {
+                warn!("gpio-keys: failed to read the pin {}: {:?}", key.pin, err);
+                return;
+            }
+        };
+        let pressed = level != key.active_low;
+        self.listener.trigger(InputEvent {
+            event_type: InputEventType::Key,
+            code: key.code,
+            value: pressed as i32,
+        });
+        self.listener.trigger(InputEvent {
+            event_type: InputEventType::Syn,
+            code: super::input_event_codes::syn::SYN_REPORT,
+            value: 0,
+        });
+    }
+}
+
+impl Scheme for GpioKeys {
+    fn name(&self) -> &str {
+        "gpio-keys"
+    }
+}
+
+impl InputScheme for GpioKeys {
+    fn capability(&self, cap_type: CapabilityType) -> InputCapability {
+        use super::input_event_codes::ev::*;
+        let mut cap = InputCapability::empty();
+        match cap_type {
+            CapabilityType::Event => cap.set_all(&[EV_SYN, EV_KEY]),
+            CapabilityType::Key => {
+                for key in &self.keys {
+                    cap.set(key.code);
+                }
+            }
+            _ => {}
+        }
+        cap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpio::GpioAllwinner;
+    use crate::input::input_event_codes::key::KEY_POWER;
+    use crate::scheme::EventScheme;
+    use core::sync::atomic::{AtomicI32, Ordering};
+
+    #[test]
+    fn test_key_events() {
+        // PB0, active low
+        let regs = Box::into_raw(Box::new([0u32; 0x100])) as *mut u32;
+        let gpio = Arc::new(unsafe { GpioAllwinner::new(regs as usize) });
+        let keys = GpioKeys::new(alloc::vec![GpioKey {
+            gpio: gpio.clone(),
+            pin: 32,
+            active_low: true,
+            code: KEY_POWER,
+        }])
+        .unwrap();
+        assert!(keys.capability(CapabilityType::Key).contains(KEY_POWER));
+
+        let value = Arc::new(AtomicI32::new(-1));
+        let cloned = value.clone();
+        keys.subscribe(
+            Box::new(move |e| {
+                if matches!(e.event_type, InputEventType::Key) {
+                    cloned.store(e.value, Ordering::SeqCst);
+                }
+            }),
+            false,
+        );
+
+        // the edge interrupt of bank B with the pin at the low level
+        unsafe { core::ptr::write_volatile(regs.add((0x220 + 0x14) / 4), 1) };
+        gpio.handle_irq(0);
+        assert_eq!(value.load(Ordering::SeqCst), 1);
+        // released
+        unsafe { core::ptr::write_volatile(regs.add((0x30 + 0x10) / 4), 1) };
+        gpio.handle_irq(0);
+        assert_eq!(value.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,7 +1,10 @@
-//! Only Mouse currently.
+//! Input devices built on other devices, e.g. a mouse on an input device,
+//! or keys on GPIO pins.
 
+mod gpio_keys;
 mod mouse;
 
 pub mod input_event_codes;
 
+pub use gpio_keys::{GpioKey, GpioKeys};
 pub use mouse::{Mouse, MouseFlags, MouseState};
@@ -1,5 +1,30 @@
 use super::Io;
+use crate::{DeviceError, DeviceResult};
+use core::mem::{align_of, size_of};
 use core::ops::{BitAnd, BitOr, Not};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// 保证 MMIO 读在之后的访存之前完成。
+/// Order the MMIO read before the following memory accesses.
+#[inline(always)]
+fn read_barrier() {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    unsafe {
+        core::arch::asm!("fence i,r")
+    };
+    compiler_fence(Ordering::Acquire);
+}
+
+// 保证之前的访存在 MMIO 写之前完成。
+/// Order the previous memory accesses before the MMIO write.
+#[inline(always)]
+fn write_barrier() {
+    compiler_fence(Ordering::Release);
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    unsafe {
+        core::arch::asm!("fence w,o")
+    };
+}
 
 // 主存映射 I/O。
 /// Memory-mapped I/O.
@@ -34,20 +59,200 @@ where
     type Value = T;
 
     fn read(&self) -> T {
-        #[allow(clippy::let_and_return)]
-        unsafe {
-            let val = core::ptr::read_volatile(&self.0 as *const _);
-            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-            core::arch::asm!("fence i,r");
-            val
-        }
+        let val = unsafe { core::ptr::read_volatile(&self.0 as *const _) };
+        read_barrier();
+        val
     }
 
     fn write(&mut self, value: T) {
-        unsafe {
-            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-            core::arch::asm!("fence w,o");
-            core::ptr::write_volatile(&mut self.0 as *mut _, value)
-        };
+        write_barrier();
+        unsafe { core::ptr::write_volatile(&mut self.0 as *mut _, value) };
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+// 可以作为一个寄存器访问的整数类型。
+/// Integer types that can be accessed as a memory-mapped register, i.e. `u8`,
+/// `u16`, `u32` and `u64`.
+pub trait MmioWidth: Copy + private::Sealed {}
+
+macro_rules! impl_mmio_width {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+            impl MmioWidth for $t {}
+        )*
+    };
+}
+
+impl_mmio_width!(u8, u16, u32, u64);
+
+// 一段主存映射的寄存器，按相对基址的偏移访问。
+/// A window of memory-mapped registers, accessed by the byte offset from its
+/// base address.
+///
+/// Each access is checked to be inside the window and naturally aligned.
+/// Drivers should [`check`](Self::check) the registers they access once the
+/// region is created, since its size and base may come from the device tree,
+/// then the accesses never panic.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    base: usize,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` must be the virtual address of
+    /// `size` bytes of registers, which are mapped as long as the region is
+    /// used.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if the region is empty or wraps
+    /// around the address space.
+    pub unsafe fn new(base: usize, size: usize) -> DeviceResult<Self> {
+        match base.checked_add(size) {
+            Some(_) if size > 0 => Ok(Self { base, size }),
+            _ => Err(DeviceError::InvalidParam),
+        }
+    }
+
+    /// Returns the base virtual address.
+    pub const fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the size in bytes.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Checks the `T`-wide register at `offset` is inside the region and
+    /// naturally aligned, or returns [`DeviceError::InvalidParam`].
+    pub fn check<T>(&self, offset: usize) -> DeviceResult {
+        let in_region = offset
+            .checked_add(size_of::<T>())
+            .map_or(false, |end| end <= self.size);
+        if in_region && (self.base + offset) % align_of::<T>() == 0 {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    /// Returns the address of the `T`-wide register at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is out of the region or misaligned.
+    fn addr_of<T>(&self, offset: usize) -> usize {
+        if self.check::<T>(offset).is_err() {
+            panic!(
+                "invalid MMIO access at {:#x} of the region {:#x}..{:#x}",
+                offset,
+                self.base,
+                self.base + self.size
+            );
+        }
+        self.base + offset
+    }
+
+    /// Reads the `T`-wide register at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is out of the region or misaligned, which the
+    /// driver should have [`check`](Self::check)ed.
+    pub fn read_at<T: MmioWidth>(&self, offset: usize) -> T {
+        let val = unsafe { core::ptr::read_volatile(self.addr_of::<T>(offset) as *const T) };
+        read_barrier();
+        val
+    }
+
+    /// Writes `value` to the `T`-wide register at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is out of the region or misaligned, which the
+    /// driver should have [`check`](Self::check)ed.
+    pub fn write_at<T: MmioWidth>(&self, offset: usize, value: T) {
+        let addr = self.addr_of::<T>(offset);
+        write_barrier();
+        unsafe { core::ptr::write_volatile(addr as *mut T, value) };
+    }
+
+    /// Returns the register block `R` at `offset`, e.g. the `RegisterBlock`
+    /// generated by `svd2rust`, whose fields are accessed with volatile
+    /// operations.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if the register block is out of
+    /// the region or misaligned.
+    pub fn block_at<R>(&self, offset: usize) -> DeviceResult<&'static R> {
+        self.check::<R>(offset)?;
+        Ok(unsafe { &*((self.base + offset) as *const R) })
+    }
+
+    /// Same as [`block_at`](Self::block_at), but returns a mutable reference,
+    /// for the register blocks accessed through `&mut self`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must ensure that there is no
+    /// other reference to the register block.
+    pub unsafe fn block_mut_at<R>(&self, offset: usize) -> DeviceResult<&'static mut R> {
+        self.check::<R>(offset)?;
+        Ok(&mut *((self.base + offset) as *mut R))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region() {
+        let mut regs = [0u64; 2];
+        let region = unsafe { MmioRegion::new(regs.as_mut_ptr() as usize, 16) }.unwrap();
+        region.write_at::<u32>(0, 0x1234_5678);
+        region.write_at::<u16>(4, 0xabcd);
+        region.write_at::<u8>(6, 0xef);
+        region.write_at::<u64>(8, u64::MAX);
+        assert_eq!(region.read_at::<u8>(0), 0x78);
+        assert_eq!(region.read_at::<u16>(2), 0x1234);
+        assert_eq!(region.read_at::<u64>(8), u64::MAX);
+        assert_eq!(regs[0], 0x00ef_abcd_1234_5678);
+        assert_eq!(region.block_at::<[u32; 4]>(0).unwrap()[1], 0x00ef_abcd);
+    }
+
+    #[test]
+    fn test_check() {
+        let mut regs = [0u32; 2];
+        let region = unsafe { MmioRegion::new(regs.as_mut_ptr() as usize, 8) }.unwrap();
+        assert!(region.check::<u32>(4).is_ok());
+        assert!(region.check::<u32>(6).is_err());
+        assert!(region.check::<u32>(2).is_err());
+        assert!(region.check::<u64>(usize::MAX).is_err());
+        assert!(region.block_at::<[u32; 3]>(0).is_err());
+        assert!(unsafe { MmioRegion::new(usize::MAX, 2) }.is_err());
+        assert!(unsafe { MmioRegion::new(regs.as_mut_ptr() as usize, 0) }.is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_out_of_region() {
+        let mut regs = [0u32; 2];
+        let region = unsafe { MmioRegion::new(regs.as_mut_ptr() as usize, 8) }.unwrap();
+        region.read_at::<u32>(6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_misaligned() {
+        let mut regs = [0u32; 2];
+        let region = unsafe { MmioRegion::new(regs.as_mut_ptr() as usize, 8) }.unwrap();
+        region.read_at::<u32>(2);
     }
 }
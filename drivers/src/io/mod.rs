@@ -11,7 +11,7 @@ mod mmio;
 #[cfg(target_arch = "x86_64")]
 mod pmio;
 
-pub use mmio::Mmio;
+pub use mmio::{Mmio, MmioRegion, MmioWidth};
 #[cfg(target_arch = "x86_64")]
 pub use pmio::Pmio;
 
@@ -77,3 +77,47 @@ impl<I: Io> WriteOnly<I> {
         self.0.write(value);
     }
 }
+
+// 外设地址空间的一个可读写单元。
+/// A readable and writable unit in device address space.
+#[repr(transparent)]
+pub struct ReadWrite<I>(I);
+
+impl<I> ReadWrite<I> {
+    // 构造外设地址空间的一个可读写单元。
+    /// Constructs a readable and writable unit in device address space.
+    pub const fn new(inner: I) -> Self {
+        Self(inner)
+    }
+}
+
+impl<I: Io> Io for ReadWrite<I> {
+    type Value = I::Value;
+
+    #[inline(always)]
+    fn read(&self) -> Self::Value {
+        self.0.read()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: Self::Value) {
+        self.0.write(value)
+    }
+}
+
+// 通过可变引用访问外设地址空间的一个单元，用于不相邻的寄存器。
+/// Access a unit in device address space by a mutable reference, for the
+/// registers not adjacent to each other.
+impl<I: Io + ?Sized> Io for &mut I {
+    type Value = I::Value;
+
+    #[inline(always)]
+    fn read(&self) -> Self::Value {
+        (**self).read()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, value: Self::Value) {
+        (**self).write(value)
+    }
+}
@@ -1,9 +1,22 @@
-use crate::prelude::IrqHandler;
+//! ARM Generic Interrupt Controller version 2 (GICv2), e.g. GIC-400.
+//!
+//! Reference: <https://developer.arm.com/documentation/ihi0048/latest>
+
+use alloc::vec::Vec;
+
+use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
 use crate::scheme::{IrqScheme, Scheme};
-use crate::utils::IrqManager;
-use crate::DeviceResult;
+use crate::utils::{IrqManager, IrqStats};
+use crate::{DeviceError, DeviceResult};
 use lock::Mutex;
 
+/// Interrupt IDs 0..16 are SGIs, which can not be registered.
+const PPI_BASE: usize = 16;
+/// Interrupt IDs 32..1020 are SPIs.
+const SPI_BASE: usize = 32;
+/// Interrupt IDs 1020..1024 are special.
+const MAX_IRQ: usize = 1020;
+
 pub static GICC_SIZE: usize = 0x1000;
 pub static GICD_SIZE: usize = 0x1000;
 static GICD_CTLR: u32 = 0x000;
@@ -21,7 +34,8 @@ static GICC_PMR: u32 = 0x0004;
 pub struct IntController {
     gicc: GicCpuIf,
     gicd: GicDistIf,
-    manager: Mutex<IrqManager<50>>,
+    manager: Mutex<IrqManager<MAX_IRQ>>,
+    stats: IrqStats<MAX_IRQ>,
 }
 
 struct GicDistIf {
@@ -43,7 +57,8 @@ impl IntController {
                 ncpus: 0,
                 nirqs: 0,
             },
-            manager: Mutex::new(IrqManager::new(0..50)),
+            manager: Mutex::new(IrqManager::new(PPI_BASE..MAX_IRQ)),
+            stats: IrqStats::new(),
         }
     }
 
@@ -85,16 +100,35 @@ impl IntController {
             }
 
             // Enable CPU0's GIC interface
-            self.gicc.write(GICC_CTLR, 1);
-
-            // Set CPU0's Interrupt Priority Mask
-            self.gicc.write(GICC_PMR, 0xff);
+            self.init_cpu_if();
 
             // Enable IRQ distribution
             self.gicd.write(GICD_CTLR, 0x1);
         }
     }
 
+    /// Enable the CPU interface of the current CPU.
+    fn init_cpu_if(&self) {
+        unsafe {
+            // Set the Interrupt Priority Mask
+            self.gicc.write(GICC_PMR, 0xff);
+            self.gicc.write(GICC_CTLR, 1);
+        }
+    }
+
+    /// Set the interrupt to be edge-triggered or level-sensitive in ICFGR.
+    fn set_edge_triggered(&self, irq: u32, edge: bool) {
+        // ICFGR is shared by 16 interrupts
+        let _guard = self.manager.lock();
+        unsafe {
+            let offset = GICD_ICFGR + (4 * (irq / 16));
+            let bit = 1 << ((irq % 16) * 2 + 1);
+            let val = self.gicd.read(offset);
+            self.gicd
+                .write(offset, if edge { val | bit } else { val & !bit });
+        }
+    }
+
     pub fn irq_enable(&self, irq: u32) {
         unsafe {
             let offset = GICD_ISENABLER + (4 * (irq / 32));
@@ -130,9 +164,15 @@ impl Scheme for IntController {
         "ARM Generic Interrupt Controller"
     }
 
+    /// Handle the interrupt acknowledged by [`IntController::pending_irq`].
     fn handle_irq(&self, irq_num: usize) {
-        if irq_num != usize::MAX {
-            self.manager.lock().handle(irq_num).ok();
+        if irq_num == usize::MAX {
+            return;
+        }
+        self.stats.inc(irq_num);
+        if self.manager.lock().handle(irq_num).is_err() {
+            warn!("no registered handler for IRQ {}!", irq_num);
+            self.irq_disable(irq_num as u32);
         }
         self.irq_eoi(irq_num as u32);
     }
@@ -140,32 +180,63 @@ impl Scheme for IntController {
 
 impl IrqScheme for IntController {
     fn is_valid_irq(&self, irq_num: usize) -> bool {
-        irq_num != usize::MAX
+        (PPI_BASE..(self.gicd.nirqs as usize).min(MAX_IRQ)).contains(&irq_num)
     }
 
     fn mask(&self, irq_num: usize) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
         self.irq_disable(irq_num as u32);
         Ok(())
     }
 
     fn unmask(&self, irq_num: usize) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
         self.irq_enable(irq_num as u32);
         Ok(())
     }
 
+    /// Only the rising edge or the high level can be sensed. The configuration
+    /// of PPIs may be read-only, the write is ignored then.
+    fn configure(&self, irq_num: usize, tm: IrqTriggerMode, pol: IrqPolarity) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
+        if pol == IrqPolarity::ActiveLow {
+            return Err(DeviceError::NotSupported);
+        }
+        self.set_edge_triggered(irq_num as u32, tm == IrqTriggerMode::Edge);
+        Ok(())
+    }
+
     fn register_handler(&self, irq_num: usize, handler: IrqHandler) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
         self.manager
             .lock()
             .register_handler(irq_num, handler)
-            .map_err(|irq_num| {
-                trace!("Unknown irq_num: {:?}", irq_num);
-            })
-            .ok();
-        Ok(())
+            .map(|_| ())
     }
 
-    fn unregister(&self, _irq_num: usize) -> DeviceResult {
-        todo!()
+    fn unregister(&self, irq_num: usize) -> DeviceResult {
+        self.manager.lock().unregister_handler(irq_num)
+    }
+
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        let irqs: Vec<_> = self.manager.lock().registered_irqs().collect();
+        self.stats.collect(irqs.into_iter())
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    fn init_hart(&self) {
+        self.init_cpu_if();
     }
 }
 
@@ -198,3 +269,16 @@ pub fn init(gicc_base: usize, gicd_base: usize) -> IntController {
 pub fn get_irq_num(gicc_base: usize, gicd_base: usize) -> usize {
     IntController::new(gicc_base, gicd_base).pending_irq()
 }
+
+/// Translate the interrupt specifier in the device tree to the interrupt ID.
+///
+/// The first cell is 0 for SPIs and 1 for PPIs, the second cell is the
+/// interrupt number relative to the first SPI or PPI. Returns `None` if the
+/// number is out of the range of SPIs or PPIs.
+pub fn spec_to_irq(spec: &[u32]) -> Option<usize> {
+    match spec {
+        [0, num, ..] if (*num as usize) < MAX_IRQ - SPI_BASE => Some(*num as usize + SPI_BASE),
+        [1, num, ..] if (*num as usize) < SPI_BASE - PPI_BASE => Some(*num as usize + PPI_BASE),
+        _ => None,
+    }
+}
@@ -0,0 +1,335 @@
+//! ARM Generic Interrupt Controller version 3 (GICv3).
+//!
+//! Reference: <https://developer.arm.com/documentation/ihi0069/latest>
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ops::Range;
+
+use crate::io::{Io, Mmio};
+use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
+use crate::scheme::{IrqScheme, Scheme};
+use crate::utils::{IrqManager, IrqStats};
+use crate::{DeviceError, DeviceResult};
+use lock::Mutex;
+
+/// Interrupt IDs 0..16 are SGIs, which can not be registered.
+const PPI_BASE: usize = 16;
+/// Interrupt IDs 32..1020 are SPIs.
+const SPI_BASE: usize = 32;
+/// Interrupt IDs 1020..1024 are special.
+const MAX_IRQ: usize = 1020;
+
+const GICD_CTLR: usize = 0x0000 / 4;
+const GICD_TYPER: usize = 0x0004 / 4;
+const GICD_IGROUPR: usize = 0x0080 / 4;
+const GICD_ISENABLER: usize = 0x0100 / 4;
+const GICD_ICENABLER: usize = 0x0180 / 4;
+const GICD_IPRIORITYR: usize = 0x0400 / 4;
+const GICD_ICFGR: usize = 0x0c00 / 4;
+const GICD_IROUTER: usize = 0x6000 / 4;
+
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ENABLE_G1: u32 = 1;
+
+/// Each redistributor has the RD_base and SGI_base frames of 64 KiB.
+const GICR_STRIDE: usize = 0x2_0000;
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_TYPER: usize = 0x0008 / 4;
+const GICR_WAKER: usize = 0x0014 / 4;
+const GICR_IGROUPR0: usize = 0x0080 / 4;
+const GICR_ISENABLER0: usize = 0x0100 / 4;
+const GICR_ICENABLER0: usize = 0x0180 / 4;
+const GICR_IPRIORITYR: usize = 0x0400 / 4;
+const GICR_ICFGR: usize = 0x0c00 / 4;
+
+const GICR_TYPER_LAST: u32 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// The priority of all interrupts, in the middle of the range.
+const DEFAULT_PRIORITY: u32 = 0xa0;
+
+struct GicV3Unlocked {
+    gicd: &'static mut Mmio<u32>,
+    /// The SGI_base frame of the redistributor of the current CPU.
+    gicr_sgi: &'static mut Mmio<u32>,
+    manager: IrqManager<MAX_IRQ>,
+}
+
+/// Driver of GICv3, with the distributor and redistributors in MMIO, and the
+/// CPU interface in system registers.
+pub struct GicV3 {
+    inner: Mutex<GicV3Unlocked>,
+    irq_range: Range<usize>,
+    stats: IrqStats<MAX_IRQ>,
+}
+
+/// Returns the affinity of the current CPU in the format of `GICR_TYPER[63:32]`
+/// and `GICD_IROUTER`, i.e. `Aff3.Aff2.Aff1.Aff0`.
+fn cpu_affinity() -> u32 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    ((mpidr >> 8) & 0xff00_0000 | mpidr & 0xff_ffff) as u32
+}
+
+/// Find the redistributor of the current CPU by the affinity, and wake it up.
+/// Returns its RD_base.
+fn find_redistributor(gicr_base: usize, gicr_size: usize) -> Option<usize> {
+    let affinity = cpu_affinity();
+    for rd_base in (gicr_base..gicr_base + gicr_size).step_by(GICR_STRIDE) {
+        let gicr = unsafe { Mmio::<u32>::from_base(rd_base) };
+        let typer_low = gicr.add(GICR_TYPER).read();
+        if gicr.add(GICR_TYPER + 1).read() == affinity {
+            let waker = gicr.add(GICR_WAKER);
+            waker.write(waker.read() & !GICR_WAKER_PROCESSOR_SLEEP);
+            while waker.read() & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                core::hint::spin_loop();
+            }
+            return Some(rd_base);
+        }
+        if typer_low & GICR_TYPER_LAST != 0 {
+            break;
+        }
+    }
+    None
+}
+
+impl GicV3Unlocked {
+    /// Number of interrupt IDs supported by the distributor.
+    fn irq_count(&self) -> usize {
+        let typer = self.gicd.add(GICD_TYPER).read();
+        (((typer & 0x1f) as usize + 1) * 32).min(MAX_IRQ)
+    }
+
+    fn wait_for_rwp(&self) {
+        while self.gicd.add(GICD_CTLR).read() & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn init(&mut self, irq_count: usize) {
+        self.gicd.add(GICD_CTLR).write(0);
+        self.wait_for_rwp();
+        for irq in (SPI_BASE..irq_count).step_by(32) {
+            // disable all SPIs, in the non-secure group 1
+            self.gicd.add(GICD_ICENABLER + irq / 32).write(u32::MAX);
+            self.gicd.add(GICD_IGROUPR + irq / 32).write(u32::MAX);
+        }
+        for irq in (SPI_BASE..irq_count).step_by(16) {
+            // level-sensitive
+            self.gicd.add(GICD_ICFGR + irq / 16).write(0);
+        }
+        for irq in (SPI_BASE..irq_count).step_by(4) {
+            self.gicd
+                .add(GICD_IPRIORITYR + irq / 4)
+                .write(DEFAULT_PRIORITY * 0x0101_0101);
+        }
+        self.wait_for_rwp();
+        self.gicd
+            .add(GICD_CTLR)
+            .write(GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1);
+
+        // route all SPIs to the current CPU
+        let affinity = cpu_affinity() as u64;
+        let router = (affinity & 0xff00_0000) << 8 | affinity & 0xff_ffff;
+        for irq in SPI_BASE..irq_count {
+            self.gicd.add(GICD_IROUTER + irq * 2).write(router as u32);
+            self.gicd
+                .add(GICD_IROUTER + irq * 2 + 1)
+                .write((router >> 32) as u32);
+        }
+        self.init_hart();
+    }
+
+    /// Initialize the SGIs and PPIs in the redistributor, and the CPU
+    /// interface of the current CPU.
+    fn init_hart(&mut self) {
+        self.gicr_sgi.add(GICR_ICENABLER0).write(u32::MAX);
+        self.gicr_sgi.add(GICR_IGROUPR0).write(u32::MAX);
+        for irq in (0..SPI_BASE).step_by(4) {
+            self.gicr_sgi
+                .add(GICR_IPRIORITYR + irq / 4)
+                .write(DEFAULT_PRIORITY * 0x0101_0101);
+        }
+        unsafe {
+            // enable the system register interface
+            let mut sre: u64;
+            asm!("mrs {}, icc_sre_el1", out(reg) sre);
+            sre |= 1;
+            asm!("msr icc_sre_el1, {}", "isb", in(reg) sre);
+            // accept all priorities
+            asm!("msr icc_pmr_el1, {}", in(reg) 0xffu64);
+            asm!("msr icc_bpr1_el1, {}", in(reg) 0u64);
+            asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1u64);
+        }
+    }
+
+    /// Set the interrupt to be edge-triggered or level-sensitive in ICFGR of
+    /// the redistributor (for PPIs) or the distributor (for SPIs).
+    fn set_edge_triggered(&mut self, irq_num: usize, edge: bool) {
+        let cfg = if irq_num < SPI_BASE {
+            self.gicr_sgi.add(GICR_ICFGR + irq_num / 16)
+        } else {
+            self.gicd.add(GICD_ICFGR + irq_num / 16)
+        };
+        let bit = 1 << ((irq_num % 16) * 2 + 1);
+        cfg.write(if edge {
+            cfg.read() | bit
+        } else {
+            cfg.read() & !bit
+        });
+    }
+
+    fn toggle(&mut self, irq_num: usize, enable: bool) {
+        let mask = 1 << (irq_num % 32);
+        if irq_num < SPI_BASE {
+            let reg = if enable {
+                GICR_ISENABLER0
+            } else {
+                GICR_ICENABLER0
+            };
+            self.gicr_sgi.add(reg).write(mask);
+        } else {
+            let reg = if enable {
+                GICD_ISENABLER
+            } else {
+                GICD_ICENABLER
+            };
+            self.gicd.add(reg + irq_num / 32).write(mask);
+        }
+    }
+
+    /// Acknowledge the highest priority pending interrupt of group 1.
+    fn ack(&mut self) -> Option<usize> {
+        let iar: u64;
+        unsafe { asm!("mrs {}, icc_iar1_el1", out(reg) iar) };
+        let irq_num = (iar & 0xff_ffff) as usize;
+        if irq_num >= MAX_IRQ {
+            None
+        } else {
+            Some(irq_num)
+        }
+    }
+
+    fn eoi(&mut self, irq_num: usize) {
+        unsafe { asm!("msr icc_eoir1_el1, {}", "isb", in(reg) irq_num as u64) };
+    }
+}
+
+impl GicV3 {
+    /// Construct a `GicV3` with the distributor at `gicd_base` and the
+    /// redistributors in `gicr_base..gicr_base + gicr_size`, and enable the
+    /// distributor.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the redistributor of the
+    /// current CPU is not found.
+    pub fn new(gicd_base: usize, gicr_base: usize, gicr_size: usize) -> DeviceResult<Self> {
+        let rd_base = find_redistributor(gicr_base, gicr_size).ok_or_else(|| {
+            warn!(
+                "gic-v3: no redistributor for CPU affinity {:#x}",
+                cpu_affinity()
+            );
+            DeviceError::NotSupported
+        })?;
+        let mut inner = GicV3Unlocked {
+            gicd: unsafe { Mmio::<u32>::from_base(gicd_base) },
+            gicr_sgi: unsafe { Mmio::<u32>::from_base(rd_base + GICR_SGI_BASE) },
+            manager: IrqManager::new(PPI_BASE..MAX_IRQ),
+        };
+        let irq_count = inner.irq_count();
+        inner.init(irq_count);
+        Ok(Self {
+            inner: Mutex::new(inner),
+            irq_range: PPI_BASE..irq_count,
+            stats: IrqStats::new(),
+        })
+    }
+}
+
+impl Scheme for GicV3 {
+    fn name(&self) -> &str {
+        "arm-gic-v3"
+    }
+
+    fn handle_irq(&self, _unused: usize) {
+        let mut inner = self.inner.lock();
+        while let Some(irq_num) = inner.ack() {
+            self.stats.inc(irq_num);
+            if inner.manager.handle(irq_num).is_err() {
+                warn!("no registered handler for IRQ {}!", irq_num);
+                inner.toggle(irq_num, false);
+            }
+            trace!("arm gic-v3 handle irq: {}", irq_num);
+            inner.eoi(irq_num);
+        }
+    }
+}
+
+impl IrqScheme for GicV3 {
+    fn is_valid_irq(&self, irq_num: usize) -> bool {
+        self.irq_range.contains(&irq_num)
+    }
+
+    fn mask(&self, irq_num: usize) -> DeviceResult {
+        if self.is_valid_irq(irq_num) {
+            self.inner.lock().toggle(irq_num, false);
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    fn unmask(&self, irq_num: usize) -> DeviceResult {
+        if self.is_valid_irq(irq_num) {
+            self.inner.lock().toggle(irq_num, true);
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    /// Only the rising edge or the high level can be sensed.
+    fn configure(&self, irq_num: usize, tm: IrqTriggerMode, pol: IrqPolarity) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
+        if pol == IrqPolarity::ActiveLow {
+            return Err(DeviceError::NotSupported);
+        }
+        self.inner
+            .lock()
+            .set_edge_triggered(irq_num, tm == IrqTriggerMode::Edge);
+        Ok(())
+    }
+
+    fn register_handler(&self, irq_num: usize, handler: IrqHandler) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.inner
+            .lock()
+            .manager
+            .register_handler(irq_num, handler)
+            .map(|_| ())
+    }
+
+    fn unregister(&self, irq_num: usize) -> DeviceResult {
+        self.inner.lock().manager.unregister_handler(irq_num)
+    }
+
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        let irqs: Vec<_> = self.inner.lock().manager.registered_irqs().collect();
+        self.stats.collect(irqs.into_iter())
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    fn init_hart(&self) {
+        self.inner.lock().init_hart();
+    }
+}
@@ -2,12 +2,16 @@
 
 cfg_if::cfg_if! {
     if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
+        mod riscv_aplic;
+        mod riscv_clint;
         mod riscv_intc;
         mod riscv_plic;
 
         /// Implementation of risc-v interrupt controller.
         #[doc(cfg(any(target_arch = "riscv32", target_arch = "riscv64")))]
         pub mod riscv {
+            pub use super::riscv_aplic::Aplic;
+            pub use super::riscv_clint::Clint;
             pub use super::riscv_intc::{Intc, ScauseIntCode};
             pub use super::riscv_plic::Plic;
         }
@@ -16,9 +20,16 @@ cfg_if::cfg_if! {
         /// Implementation of x86 Advanced Programmable Interrupt Controller.
         #[doc(cfg(any(target_arch = "x86", target_arch = "x86_64")))]
         pub mod x86 {
-            pub use super::x86_apic::Apic;
+            pub use super::x86_apic::{Apic, IoApic};
         }
     } else if #[cfg(target_arch = "aarch64")] {
         pub mod gic_400;
+        mod gic_v3;
+
+        /// Implementation of ARM Generic Interrupt Controller.
+        #[doc(cfg(target_arch = "aarch64"))]
+        pub mod arm {
+            pub use super::gic_v3::GicV3;
+        }
     }
 }
@@ -0,0 +1,269 @@
+//! RISC-V Advanced Platform-Level Interrupt Controller (APLIC), in the direct
+//! delivery mode.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ops::Range;
+
+use crate::io::{Io, Mmio};
+use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
+use crate::scheme::{IrqScheme, Scheme};
+use crate::utils::{IrqManager, IrqStats};
+use crate::{DeviceError, DeviceResult};
+use lock::Mutex;
+
+/// The APLIC supports at most 1023 interrupt sources.
+const IRQ_RANGE: Range<usize> = 1..1024;
+
+/// The priority of registered interrupt sources, 1 is the highest.
+const DEFAULT_PRIORITY: u32 = 1;
+/// Interrupts with priority below the threshold are delivered, 0 means no
+/// threshold.
+const DEFAULT_THRESHOLD: u32 = 0;
+
+/// The APLIC supports at most 16384 harts in a domain.
+const MAX_HARTS: usize = 16384;
+
+const APLIC_DOMAINCFG: usize = 0x0000 / 4;
+const APLIC_SOURCECFG_BASE: usize = 0x0004 / 4;
+const APLIC_SETIENUM: usize = 0x1edc / 4;
+const APLIC_CLRIENUM: usize = 0x1fdc / 4;
+const APLIC_TARGET_BASE: usize = 0x3004 / 4;
+const APLIC_IDC_BASE: usize = 0x4000 / 4;
+
+/// Offsets in the interrupt delivery control (IDC) structure of each hart.
+const IDC_IDELIVERY: usize = 0x00 / 4;
+const IDC_IFORCE: usize = 0x04 / 4;
+const IDC_ITHRESHOLD: usize = 0x08 / 4;
+const IDC_CLAIMI: usize = 0x1c / 4;
+const IDC_SIZE: usize = 0x20 / 4;
+
+/// Enable the interrupts of the domain, in direct delivery mode (`DM` = 0)
+/// and little-endian (`BE` = 0).
+const DOMAINCFG_IE: u32 = 1 << 8;
+
+const SOURCECFG_SM_INACTIVE: u32 = 0;
+const SOURCECFG_SM_EDGE1: u32 = 4;
+const SOURCECFG_SM_EDGE0: u32 = 5;
+const SOURCECFG_SM_LEVEL1: u32 = 6;
+const SOURCECFG_SM_LEVEL0: u32 = 7;
+
+const TARGET_HART_SHIFT: u32 = 18;
+
+struct AplicUnlocked {
+    regs: &'static mut Mmio<u32>,
+    manager: IrqManager<1024>,
+}
+
+/// The Advanced Platform-Level Interrupt Controller of the RISC-V Advanced
+/// Interrupt Architecture (AIA), in direct delivery mode.
+///
+/// Only the interrupt domain of the S-mode is driven, the M-mode domain is
+/// owned by the firmware.
+pub struct Aplic {
+    inner: Mutex<AplicUnlocked>,
+    /// Interrupt sources that actually exist.
+    irq_range: Range<usize>,
+    /// Number of harts with an IDC structure in the domain.
+    harts: usize,
+    /// Out of the lock, to be read without contention in the interrupt path.
+    stats: IrqStats<1024>,
+}
+
+impl AplicUnlocked {
+    fn sourcecfg(&mut self, irq_num: usize) -> &mut Mmio<u32> {
+        debug_assert!(IRQ_RANGE.contains(&irq_num));
+        self.regs.add(APLIC_SOURCECFG_BASE + irq_num - 1)
+    }
+
+    /// Set the source mode of `irq_num`, returns `false` if the source is not
+    /// implemented or not delegated to this domain, i.e. `sourcecfg` is
+    /// read-only zero.
+    fn set_source_mode(&mut self, irq_num: usize, mode: u32) -> bool {
+        let sourcecfg = self.sourcecfg(irq_num);
+        sourcecfg.write(mode);
+        sourcecfg.read() == mode
+    }
+
+    /// Toggle the interrupt enable bit of `irq_num`.
+    fn toggle(&mut self, irq_num: usize, enable: bool) {
+        debug_assert!(IRQ_RANGE.contains(&irq_num));
+        let reg = if enable {
+            APLIC_SETIENUM
+        } else {
+            APLIC_CLRIENUM
+        };
+        self.regs.add(reg).write(irq_num as _);
+    }
+
+    /// Deliver `irq_num` to the hart with `priority`.
+    fn set_target(&mut self, irq_num: usize, hart: usize, priority: u32) {
+        debug_assert!(IRQ_RANGE.contains(&irq_num));
+        self.regs
+            .add(APLIC_TARGET_BASE + irq_num - 1)
+            .write((hart as u32) << TARGET_HART_SHIFT | priority);
+    }
+
+    fn idc(&mut self, hart: usize) -> &mut Mmio<u32> {
+        self.regs.add(APLIC_IDC_BASE + IDC_SIZE * hart)
+    }
+
+    /// Claim the highest priority pending interrupt of the hart, which also
+    /// clears its pending bit.
+    fn claim(&mut self, hart: usize) -> Option<usize> {
+        let irq_num = (self.idc(hart).add(IDC_CLAIMI).read() >> 16) as usize & 0x3ff;
+        if irq_num == 0 {
+            None
+        } else {
+            Some(irq_num)
+        }
+    }
+
+    /// Enable the interrupt delivery to the hart.
+    fn init_idc(&mut self, hart: usize) {
+        let idc = self.idc(hart);
+        idc.add(IDC_IFORCE).write(0);
+        idc.add(IDC_ITHRESHOLD).write(DEFAULT_THRESHOLD);
+        idc.add(IDC_IDELIVERY).write(1);
+    }
+}
+
+impl Aplic {
+    /// Construct an `Aplic` with `num_sources` interrupt sources, numbered
+    /// from 1 to `num_sources`, as the `riscv,num-sources` property in the
+    /// device tree, and `harts` IDC structures, as the pairs in the
+    /// `interrupts-extended` property.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize, num_sources: usize, harts: usize) -> Self {
+        let irq_range = IRQ_RANGE.start..(num_sources + 1).min(IRQ_RANGE.end);
+        let mut inner = AplicUnlocked {
+            regs: Mmio::<u32>::from_base(base),
+            manager: IrqManager::new(irq_range.clone()),
+        };
+        // all sources are inactive and disabled until registered
+        for irq_num in irq_range.clone() {
+            inner.toggle(irq_num, false);
+            inner.sourcecfg(irq_num).write(SOURCECFG_SM_INACTIVE);
+        }
+        inner.regs.add(APLIC_DOMAINCFG).write(DOMAINCFG_IE);
+        let aplic = Self {
+            inner: Mutex::new(inner),
+            irq_range,
+            harts: harts.clamp(1, MAX_HARTS),
+            stats: IrqStats::new(),
+        };
+        aplic.init_hart();
+        aplic
+    }
+
+    /// Returns the IDC structure of the current hart.
+    fn current_hart(&self) -> usize {
+        (cpu_id() as usize).min(self.harts - 1)
+    }
+}
+
+impl Scheme for Aplic {
+    fn name(&self) -> &str {
+        "riscv-aplic"
+    }
+
+    fn handle_irq(&self, _unused: usize) {
+        let hart = self.current_hart();
+        let mut inner = self.inner.lock();
+        while let Some(irq_num) = inner.claim(hart) {
+            self.stats.inc(irq_num);
+            if inner.manager.handle(irq_num).is_err() {
+                warn!("no registered handler for IRQ {}!", irq_num);
+                inner.toggle(irq_num, false);
+            }
+            trace!("riscv aplic handle irq: {}", irq_num);
+        }
+    }
+}
+
+impl IrqScheme for Aplic {
+    fn is_valid_irq(&self, irq_num: usize) -> bool {
+        self.irq_range.contains(&irq_num)
+    }
+
+    fn configure(&self, irq_num: usize, tm: IrqTriggerMode, pol: IrqPolarity) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mode = match (tm, pol) {
+            (IrqTriggerMode::Edge, IrqPolarity::ActiveHigh) => SOURCECFG_SM_EDGE1,
+            (IrqTriggerMode::Edge, IrqPolarity::ActiveLow) => SOURCECFG_SM_EDGE0,
+            (IrqTriggerMode::Level, IrqPolarity::ActiveHigh) => SOURCECFG_SM_LEVEL1,
+            (IrqTriggerMode::Level, IrqPolarity::ActiveLow) => SOURCECFG_SM_LEVEL0,
+        };
+        if self.inner.lock().set_source_mode(irq_num, mode) {
+            Ok(())
+        } else {
+            Err(DeviceError::NotSupported)
+        }
+    }
+
+    fn mask(&self, irq_num: usize) -> DeviceResult {
+        if self.is_valid_irq(irq_num) {
+            self.inner.lock().toggle(irq_num, false);
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    fn unmask(&self, irq_num: usize) -> DeviceResult {
+        if self.is_valid_irq(irq_num) {
+            self.inner.lock().toggle(irq_num, true);
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    /// Sources not configured yet are level-triggered and active-high, as the
+    /// most devices are.
+    fn register_handler(&self, irq_num: usize, handler: IrqHandler) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) {
+            return Err(DeviceError::InvalidParam);
+        }
+        let hart = self.current_hart();
+        let mut inner = self.inner.lock();
+        if inner.sourcecfg(irq_num).read() == SOURCECFG_SM_INACTIVE
+            && !inner.set_source_mode(irq_num, SOURCECFG_SM_LEVEL1)
+        {
+            return Err(DeviceError::NotSupported);
+        }
+        inner.manager.register_handler(irq_num, handler).map(|_| {
+            inner.set_target(irq_num, hart, DEFAULT_PRIORITY);
+        })
+    }
+
+    fn unregister(&self, irq_num: usize) -> DeviceResult {
+        self.inner.lock().manager.unregister_handler(irq_num)
+    }
+
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        let irqs: Vec<_> = self.inner.lock().manager.registered_irqs().collect();
+        self.stats.collect(irqs.into_iter())
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    fn init_hart(&self) {
+        self.inner.lock().init_idc(self.current_hart());
+    }
+}
+
+fn cpu_id() -> u8 {
+    let mut cpu_id;
+    unsafe {
+        asm!("mv {0}, tp", out(reg) cpu_id);
+    }
+    cpu_id
+}
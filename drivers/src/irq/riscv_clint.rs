@@ -0,0 +1,90 @@
+//! RISC-V Core Local Interruptor (CLINT), for the machine-mode timer and
+//! software interrupts.
+//!
+//! Reference: <https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc>
+
+use crate::io::{Io, Mmio};
+use crate::scheme::{Scheme, TimerScheme};
+use crate::{DeviceError, DeviceResult};
+
+/// The MSIP register of hart `i` is at `CLINT_MSIP + 4 * i`.
+const CLINT_MSIP: usize = 0x0000 / 4;
+/// The MTIMECMP register of hart `i` is at `CLINT_MTIMECMP + 8 * i`.
+const CLINT_MTIMECMP: usize = 0x4000 / 4;
+const CLINT_MTIME: usize = 0xbff8 / 4;
+
+/// The MTIMECMP registers take 0x4000..0xbff8, so at most 4095 harts.
+const MAX_HARTS: usize = 4095;
+
+/// Driver of the CLINT.
+///
+/// The registers are usually only accessible in machine mode, so the SBI is
+/// needed to use it from supervisor mode.
+pub struct Clint {
+    regs: &'static mut Mmio<u32>,
+    num_harts: usize,
+}
+
+impl Clint {
+    /// Construct a `Clint` whose registers start at `base`, for the harts with
+    /// IDs less than `num_harts`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize, num_harts: usize) -> Self {
+        Self {
+            regs: Mmio::<u32>::from_base(base),
+            num_harts: num_harts.min(MAX_HARTS),
+        }
+    }
+
+    fn check_hart(&self, hart_id: usize) -> DeviceResult {
+        if hart_id < self.num_harts {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+}
+
+impl Scheme for Clint {
+    fn name(&self) -> &str {
+        "riscv-clint"
+    }
+}
+
+impl TimerScheme for Clint {
+    fn read_time(&self) -> u64 {
+        // read the high half again in case the low half overflows
+        loop {
+            let high = self.regs.add(CLINT_MTIME + 1).read();
+            let low = self.regs.add(CLINT_MTIME).read();
+            if self.regs.add(CLINT_MTIME + 1).read() == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    fn set_timer(&self, hart_id: usize, deadline: u64) -> DeviceResult {
+        self.check_hart(hart_id)?;
+        let mtimecmp = CLINT_MTIMECMP + hart_id * 2;
+        // no spurious interrupt while the two halves are written
+        self.regs.add(mtimecmp).write(u32::MAX);
+        self.regs.add(mtimecmp + 1).write((deadline >> 32) as u32);
+        self.regs.add(mtimecmp).write(deadline as u32);
+        Ok(())
+    }
+
+    fn send_ipi(&self, hart_id: usize) -> DeviceResult {
+        self.check_hart(hart_id)?;
+        self.regs.add(CLINT_MSIP + hart_id).write(1);
+        Ok(())
+    }
+
+    fn clear_ipi(&self, hart_id: usize) -> DeviceResult {
+        self.check_hart(hart_id)?;
+        self.regs.add(CLINT_MSIP + hart_id).write(0);
+        Ok(())
+    }
+}
@@ -1,31 +1,51 @@
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::ops::Range;
 
 use crate::io::{Io, Mmio};
-use crate::prelude::IrqHandler;
+use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
 use crate::scheme::{IrqScheme, Scheme};
-use crate::{utils::IrqManager, DeviceError, DeviceResult};
+use crate::utils::{IrqManager, IrqStats};
+use crate::{DeviceError, DeviceResult};
 use cfg_if::cfg_if;
 use lock::Mutex;
 
+/// The PLIC supports at most 1023 interrupt sources.
 const IRQ_RANGE: Range<usize> = 1..1024;
 
-const PLIC_PRIORITY_BASE: usize = 0x0;
+/// The priority of registered interrupt sources, 0 means never interrupt.
+const DEFAULT_PRIORITY: u32 = 1;
+/// Interrupts with priority above the threshold are delivered.
+const DEFAULT_THRESHOLD: u32 = 0;
+
+/// The PLIC supports at most 15872 contexts.
+const MAX_CONTEXTS: usize = 15872;
+
 cfg_if! {
     if #[cfg(feature = "board-fu740")] {
-        const PLIC_ENABLE_BASE: usize = 0x2080 - 0x80;
-        const PLIC_CONTEXT_BASE: usize = 0x20_1000 - 0x1000;
+        /// Hart 0 has the M-mode context only, the S-mode context of hart `i`
+        /// is `2 * i`.
+        const S_MODE_CONTEXT_OFFSET: usize = 0;
     } else {
-        const PLIC_ENABLE_BASE: usize = 0x2080;
-        const PLIC_CONTEXT_BASE: usize = 0x20_1000;
+        /// Each hart has the M-mode and S-mode contexts, the S-mode context of
+        /// hart `i` is `2 * i + 1`.
+        const S_MODE_CONTEXT_OFFSET: usize = 1;
     }
 }
+
+const PLIC_PRIORITY_BASE: usize = 0x0;
+const PLIC_ENABLE_BASE: usize = 0x2000;
+const PLIC_CONTEXT_BASE: usize = 0x20_0000;
 const PLIC_CONTEXT_THRESHOLD: usize = 0x0;
 const PLIC_CONTEXT_CLAIM: usize = 0x4 / core::mem::size_of::<u32>();
 
-const PLIC_ENABLE_HART_OFFSET: usize = 0x100 / core::mem::size_of::<u32>();
-const PLIC_PRIORITY_HART_OFFSET: usize = 0x2000 / core::mem::size_of::<u32>();
-const PLIC_CONTEXT_CLAIM_HART_OFFSET: usize = 0x2000 / core::mem::size_of::<u32>();
+const PLIC_ENABLE_CONTEXT_OFFSET: usize = 0x80 / core::mem::size_of::<u32>();
+const PLIC_CONTEXT_OFFSET: usize = 0x1000 / core::mem::size_of::<u32>();
+
+/// The control register of T-Head C9xx PLIC, e.g. on Allwinner D1.
+const PLIC_THEAD_CTRL: usize = 0x1F_FFFC;
+/// Allow S-mode to access the registers other than the contexts.
+const PLIC_THEAD_CTRL_S_PER: u32 = 1;
 
 struct PlicUnlocked {
     priority_base: &'static mut Mmio<u32>,
@@ -36,16 +56,23 @@ struct PlicUnlocked {
 
 pub struct Plic {
     inner: Mutex<PlicUnlocked>,
+    /// Interrupt sources that actually exist.
+    irq_range: Range<usize>,
+    /// Number of contexts, i.e. the interrupt targets.
+    contexts: usize,
+    /// The maximum priority supported.
+    max_priority: u32,
+    /// Out of the lock, to be read without contention in the interrupt path.
+    stats: IrqStats<1024>,
 }
 
 impl PlicUnlocked {
-    /// Toggle irq enable on the current hart.
-    fn toggle(&mut self, irq_num: usize, enable: bool) {
+    /// Toggle irq enable on the context.
+    fn toggle(&mut self, context: usize, irq_num: usize, enable: bool) {
         debug_assert!(IRQ_RANGE.contains(&irq_num));
-        let hart_id = cpu_id() as usize;
         let mmio = self
             .enable_base
-            .add(PLIC_ENABLE_HART_OFFSET * hart_id + irq_num / 32);
+            .add(PLIC_ENABLE_CONTEXT_OFFSET * context + irq_num / 32);
 
         let mask = 1 << (irq_num % 32);
         if enable {
@@ -55,12 +82,11 @@ impl PlicUnlocked {
         }
     }
 
-    /// Ask the PLIC what type of interrupt is occurred on the current hart.
-    fn pending_irq(&mut self) -> Option<usize> {
-        let hart_id = cpu_id() as usize;
+    /// Ask the PLIC what type of interrupt is occurred on the context.
+    fn claim(&mut self, context: usize) -> Option<usize> {
         let irq_num = self
             .context_base
-            .add(PLIC_CONTEXT_CLAIM_HART_OFFSET * hart_id + PLIC_CONTEXT_CLAIM)
+            .add(PLIC_CONTEXT_OFFSET * context + PLIC_CONTEXT_CLAIM)
             .read() as usize;
         if irq_num == 0 {
             None
@@ -69,46 +95,144 @@ impl PlicUnlocked {
         }
     }
 
-    /// Tell the PLIC we've served this IRQ.
-    fn eoi(&mut self, irq_num: usize) {
+    /// Tell the PLIC we've served this IRQ on the context.
+    fn complete(&mut self, context: usize, irq_num: usize) {
         debug_assert!(IRQ_RANGE.contains(&irq_num));
-        let hart_id = cpu_id() as usize;
         self.context_base
-            .add(PLIC_CONTEXT_CLAIM + PLIC_CONTEXT_CLAIM_HART_OFFSET * hart_id)
+            .add(PLIC_CONTEXT_OFFSET * context + PLIC_CONTEXT_CLAIM)
             .write(irq_num as _);
     }
 
     /// Set the priority for the irq_num.
-    fn set_priority(&mut self, irq_num: usize, priority: u8) {
+    fn set_priority(&mut self, irq_num: usize, priority: u32) {
         debug_assert!(IRQ_RANGE.contains(&irq_num));
-        self.priority_base.add(irq_num).write(priority as _);
+        self.priority_base.add(irq_num).write(priority);
     }
 
-    /// Set current hart's priority threshold to 0.
-    fn set_threshold(&mut self, threshold: u8) {
-        let hart_id = cpu_id() as usize;
-        self.context_base
-            .add(PLIC_PRIORITY_HART_OFFSET * hart_id + PLIC_CONTEXT_THRESHOLD)
-            .write(threshold as _);
+    /// Probe the maximum priority, by writing all ones to the priority
+    /// register of `irq_num`, whose unimplemented bits are hardwired to 0.
+    fn max_priority(&mut self, irq_num: usize) -> u32 {
+        self.set_priority(irq_num, u32::MAX);
+        let max = self.priority_base.add(irq_num).read();
+        self.set_priority(irq_num, 0);
+        max
     }
 
-    fn init_hart(&mut self) {
-        self.set_threshold(1);
+    /// Set the priority threshold of the context.
+    fn set_threshold(&mut self, context: usize, threshold: u32) {
+        self.context_base
+            .add(PLIC_CONTEXT_OFFSET * context + PLIC_CONTEXT_THRESHOLD)
+            .write(threshold);
     }
 }
 
 impl Plic {
+    /// Construct a `Plic` with the maximum number of interrupt sources.
     pub fn new(base: usize) -> Self {
+        Self::with_ndev(base, IRQ_RANGE.end - 1)
+    }
+
+    /// Construct a `Plic` with `ndev` interrupt sources, numbered from 1 to
+    /// `ndev`, as the `riscv,ndev` property in the device tree.
+    pub fn with_ndev(base: usize, ndev: usize) -> Self {
+        Self::with_contexts(base, ndev, MAX_CONTEXTS)
+    }
+
+    /// Construct a `Plic` with `ndev` interrupt sources and `contexts`
+    /// contexts, as the pairs in the `interrupts-extended` property in the
+    /// device tree.
+    ///
+    /// If there is only one context, it is used by all harts.
+    pub fn with_contexts(base: usize, ndev: usize, contexts: usize) -> Self {
+        let irq_range = IRQ_RANGE.start..(ndev + 1).min(IRQ_RANGE.end);
         let mut inner = PlicUnlocked {
             priority_base: unsafe { Mmio::<u32>::from_base(base + PLIC_PRIORITY_BASE) },
             enable_base: unsafe { Mmio::<u32>::from_base(base + PLIC_ENABLE_BASE) },
             context_base: unsafe { Mmio::<u32>::from_base(base + PLIC_CONTEXT_BASE) },
-            manager: IrqManager::new(IRQ_RANGE),
+            manager: IrqManager::new(irq_range.clone()),
         };
-        inner.init_hart();
-        Self {
+        let max_priority = inner.max_priority(irq_range.start);
+        let plic = Self {
             inner: Mutex::new(inner),
+            irq_range,
+            contexts: contexts.min(MAX_CONTEXTS),
+            max_priority,
+            stats: IrqStats::new(),
+        };
+        plic.init_hart();
+        plic
+    }
+
+    /// Same as [`Plic::with_contexts`], for the T-Head C9xx PLIC which only
+    /// allows S-mode to access the priority and enable registers after the
+    /// S-mode permission bit of its control register is set.
+    pub fn thead_with_contexts(base: usize, ndev: usize, contexts: usize) -> Self {
+        let ctrl = unsafe { Mmio::<u32>::from_base(base + PLIC_THEAD_CTRL) };
+        ctrl.write(ctrl.read() | PLIC_THEAD_CTRL_S_PER);
+        Self::with_contexts(base, ndev, contexts)
+    }
+
+    /// Returns the S-mode context of the hart.
+    fn hart_context(&self, hart_id: usize) -> usize {
+        if self.contexts == 1 {
+            0
+        } else {
+            hart_id * 2 + S_MODE_CONTEXT_OFFSET
+        }
+    }
+
+    /// Returns the S-mode context of the current hart.
+    fn current_context(&self) -> usize {
+        self.hart_context(cpu_id() as usize)
+    }
+
+    /// Enable the interrupt source `irq_num` on the `context`, so that the
+    /// interrupts can be delivered to other harts.
+    pub fn enable_for_context(&self, irq_num: usize, context: usize) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) || context >= self.contexts {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.inner.lock().toggle(context, irq_num, true);
+        Ok(())
+    }
+
+    /// Claim the highest priority pending interrupt on the `context`.
+    pub fn claim(&self, context: usize) -> Option<usize> {
+        if context < self.contexts {
+            self.inner.lock().claim(context)
+        } else {
+            None
+        }
+    }
+
+    /// Signal the completion of the interrupt `irq_num` claimed on the
+    /// `context`.
+    pub fn complete(&self, context: usize, irq_num: usize) {
+        if context < self.contexts && self.is_valid_irq(irq_num) {
+            self.inner.lock().complete(context, irq_num);
+        }
+    }
+
+    /// Set the priority of the interrupt source `irq_num`. Priority 0 means
+    /// never interrupt, and sources with higher priority are claimed first.
+    pub fn set_priority(&self, irq_num: usize, priority: u32) -> DeviceResult {
+        if !self.is_valid_irq(irq_num) || priority > self.max_priority {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.inner.lock().set_priority(irq_num, priority);
+        Ok(())
+    }
+
+    /// Set the priority threshold of the hart, only the interrupts with
+    /// priority above the threshold are delivered to it.
+    pub fn set_threshold(&self, hart_id: usize, threshold: u32) -> DeviceResult {
+        if threshold > self.max_priority {
+            return Err(DeviceError::InvalidParam);
         }
+        self.inner
+            .lock()
+            .set_threshold(self.hart_context(hart_id), threshold);
+        Ok(())
     }
 }
 
@@ -118,26 +242,39 @@ impl Scheme for Plic {
     }
 
     fn handle_irq(&self, _unused: usize) {
+        let context = self.current_context();
         let mut inner = self.inner.lock();
-        while let Some(irq_num) = inner.pending_irq() {
+        while let Some(irq_num) = inner.claim(context) {
+            self.stats.inc(irq_num);
             if inner.manager.handle(irq_num).is_err() {
                 warn!("no registered handler for IRQ {}!", irq_num);
                 inner.set_priority(irq_num, 0);
             }
             trace!("riscv plic handle irq: {}", irq_num);
-            inner.eoi(irq_num);
+            inner.complete(context, irq_num);
         }
     }
 }
 
 impl IrqScheme for Plic {
     fn is_valid_irq(&self, irq_num: usize) -> bool {
-        IRQ_RANGE.contains(&irq_num)
+        self.irq_range.contains(&irq_num)
+    }
+
+    /// The trigger mode is handled by the interrupt gateways, nothing to do.
+    fn configure(&self, irq_num: usize, _tm: IrqTriggerMode, _pol: IrqPolarity) -> DeviceResult {
+        if self.is_valid_irq(irq_num) {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
     }
 
     fn mask(&self, irq_num: usize) -> DeviceResult {
         if self.is_valid_irq(irq_num) {
-            self.inner.lock().toggle(irq_num, false);
+            self.inner
+                .lock()
+                .toggle(self.current_context(), irq_num, false);
             Ok(())
         } else {
             Err(DeviceError::InvalidParam)
@@ -146,7 +283,9 @@ impl IrqScheme for Plic {
 
     fn unmask(&self, irq_num: usize) -> DeviceResult {
         if self.is_valid_irq(irq_num) {
-            self.inner.lock().toggle(irq_num, true);
+            self.inner
+                .lock()
+                .toggle(self.current_context(), irq_num, true);
             Ok(())
         } else {
             Err(DeviceError::InvalidParam)
@@ -156,7 +295,7 @@ impl IrqScheme for Plic {
     fn register_handler(&self, irq_num: usize, handler: IrqHandler) -> DeviceResult {
         let mut inner = self.inner.lock();
         inner.manager.register_handler(irq_num, handler).map(|_| {
-            inner.set_priority(irq_num, 7);
+            inner.set_priority(irq_num, DEFAULT_PRIORITY);
         })
     }
 
@@ -164,8 +303,19 @@ impl IrqScheme for Plic {
         self.inner.lock().manager.unregister_handler(irq_num)
     }
 
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        let irqs: Vec<_> = self.inner.lock().manager.registered_irqs().collect();
+        self.stats.collect(irqs.into_iter())
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
     fn init_hart(&self) {
-        self.inner.lock().init_hart();
+        self.inner
+            .lock()
+            .set_threshold(self.current_context(), DEFAULT_THRESHOLD);
     }
 }
 
@@ -4,7 +4,7 @@ use core::{fmt, ptr::NonNull};
 use acpi::platform::interrupt::InterruptModel;
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use lock::Mutex;
-use x2apic::ioapic::{IoApic as IoApicInner, IrqFlags, IrqMode};
+use x2apic::ioapic::{IoApic as IoApicInner, IrqFlags, IrqMode, RedirectionTableEntry};
 
 use super::{IrqPolarity, IrqTriggerMode, Phys2VirtFn};
 
@@ -59,13 +59,41 @@ pub struct IoApicList {
     io_apics: Vec<IoApic>,
 }
 
+/// Compose a redirection table entry which delivers the interrupt to the
+/// `vector` of the local APIC `dest` in physical destination mode. The entry
+/// is masked.
+fn redirection_entry(
+    vector: u8,
+    dest: u8,
+    tm: IrqTriggerMode,
+    pol: IrqPolarity,
+) -> RedirectionTableEntry {
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_vector(vector);
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_dest(dest);
+
+    let mut flags = IrqFlags::MASKED; // destination mode: physical
+    if matches!(tm, IrqTriggerMode::Level) {
+        flags |= IrqFlags::LEVEL_TRIGGERED;
+    }
+    if matches!(pol, IrqPolarity::ActiveLow) {
+        flags |= IrqFlags::LOW_ACTIVE;
+    }
+    entry.set_flags(flags);
+    entry
+}
+
 impl IoApic {
-    /// Create a new [`IoApic`] from fields parsed from the ACPI table, and
-    /// initialize it by disabling all interrupts.
-    pub fn new(id: u8, base_vaddr: usize, gsi_start: u32) -> Self {
+    /// Create a new [`IoApic`] whose registers are mapped at `base_vaddr`, and
+    /// whose first redirection entry is for the GSI `gsi_start`, and initialize
+    /// it by disabling all interrupts.
+    ///
+    /// It can be used without ACPI, if the I/O APIC is known to the platform.
+    pub fn new(base_vaddr: usize, gsi_start: u32) -> Self {
         let mut inner = unsafe { IoApicInner::new(base_vaddr as u64) };
         let max_entry = unsafe { inner.max_table_entry() };
-        unsafe { assert_eq!(id, inner.id()) };
+        let id = unsafe { inner.id() };
 
         unsafe {
             inner.init(super::X86_INT_BASE as u8);
@@ -123,24 +151,21 @@ impl IoApic {
 
     /// Set the interrupt triggle mode, polarity and other fields of the `gsi`
     /// in redirection table.
+    ///
+    /// Only [`IrqTriggerMode::Level`] sets the trigger mode bit, as in the
+    /// I/O APIC datasheet. The bit used to be set for [`IrqTriggerMode::Edge`]
+    /// instead, so interrupts configured as edge-triggered, e.g. the legacy
+    /// ISA IRQs, were level-sensitive before.
     pub fn configure(&self, gsi: u32, tm: IrqTriggerMode, pol: IrqPolarity, dest: u8, vector: u8) {
         let idx = (gsi - self.gsi_start) as u8;
-        let mut inner = self.inner.lock();
-        let mut entry = unsafe { inner.table_entry(idx) };
-        entry.set_vector(vector);
-        entry.set_mode(IrqMode::Fixed);
-        entry.set_dest(dest);
-
-        let mut flags = IrqFlags::MASKED; // destination mode: physical
-        if matches!(tm, IrqTriggerMode::Edge) {
-            flags |= IrqFlags::LEVEL_TRIGGERED;
-        }
-        if matches!(pol, IrqPolarity::ActiveLow) {
-            flags |= IrqFlags::LOW_ACTIVE;
-        }
-        entry.set_flags(flags);
+        let entry = redirection_entry(vector, dest, tm, pol);
+        unsafe { self.inner.lock().set_table_entry(idx, entry) };
+    }
+}
 
-        unsafe { inner.set_table_entry(idx, entry) };
+impl From<Vec<IoApic>> for IoApicList {
+    fn from(io_apics: Vec<IoApic>) -> Self {
+        Self { io_apics }
     }
 }
 
@@ -155,11 +180,12 @@ impl IoApicList {
                 apic.io_apics
                     .iter()
                     .map(|i| {
-                        IoApic::new(
-                            i.id,
+                        let io_apic = IoApic::new(
                             phys_to_virt(i.address as usize),
                             i.global_system_interrupt_base,
-                        )
+                        );
+                        assert_eq!(io_apic.id, i.id);
+                        io_apic
                     })
                     .collect()
             } else {
@@ -202,3 +228,25 @@ impl fmt::Debug for IoApic {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redirection_entry() {
+        // the trigger mode bit is clear for edge-triggered interrupts
+        let entry = redirection_entry(0x24, 1, IrqTriggerMode::Edge, IrqPolarity::ActiveHigh);
+        assert_eq!(entry.vector(), 0x24);
+        assert_eq!(entry.dest(), 1);
+        assert_eq!(entry.flags(), IrqFlags::MASKED);
+
+        let entry = redirection_entry(0x30, 0, IrqTriggerMode::Level, IrqPolarity::ActiveLow);
+        assert_eq!(entry.vector(), 0x30);
+        assert_eq!(entry.dest(), 0);
+        assert_eq!(
+            entry.flags(),
+            IrqFlags::MASKED | IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE
+        );
+    }
+}
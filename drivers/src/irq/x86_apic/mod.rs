@@ -3,14 +3,18 @@ mod ioapic;
 mod lapic;
 
 use self::consts::{X86_INT_BASE, X86_INT_LOCAL_APIC_BASE};
-use self::ioapic::{IoApic, IoApicList};
+use self::ioapic::IoApicList;
 use self::lapic::LocalApic;
 use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
 use crate::scheme::{IrqScheme, Scheme};
-use crate::{utils::IrqManager, DeviceError, DeviceResult, PhysAddr, VirtAddr};
+use crate::utils::{IrqManager, IrqStats};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+use alloc::vec::Vec;
 use core::ops::Range;
 use lock::Mutex;
 
+pub use self::ioapic::IoApic;
+
 const IOAPIC_IRQ_RANGE: Range<usize> = X86_INT_BASE..X86_INT_LOCAL_APIC_BASE;
 const LAPIC_IRQ_RANGE: Range<usize> = 0..16;
 
@@ -21,15 +25,28 @@ pub struct Apic {
     ioapic_list: IoApicList,
     manager_ioapic: Mutex<IrqManager<256>>,
     manager_lapic: Mutex<IrqManager<16>>,
+    /// Counters indexed by the interrupt vector.
+    stats: IrqStats<256>,
 }
 
 impl Apic {
-    /// Construct a new `Apic`.
+    /// Construct a new `Apic`, with the I/O APICs probed from the ACPI table.
     pub fn new(acpi_rsdp: usize, phys_to_virt: Phys2VirtFn) -> Self {
+        Self::with_list(IoApicList::new(acpi_rsdp, phys_to_virt))
+    }
+
+    /// Construct a new `Apic` with the given I/O APICs, for the platforms
+    /// without ACPI.
+    pub fn with_io_apics(io_apics: Vec<IoApic>) -> Self {
+        Self::with_list(IoApicList::from(io_apics))
+    }
+
+    fn with_list(ioapic_list: IoApicList) -> Self {
         Self {
-            ioapic_list: IoApicList::new(acpi_rsdp, phys_to_virt),
+            ioapic_list,
             manager_ioapic: Mutex::new(IrqManager::new(IOAPIC_IRQ_RANGE)),
             manager_lapic: Mutex::new(IrqManager::new(LAPIC_IRQ_RANGE)),
+            stats: IrqStats::new(),
         }
     }
 
@@ -79,7 +96,7 @@ impl Scheme for Apic {
     }
 
     fn handle_irq(&self, vector: usize) {
-        Self::local_apic().eoi();
+        self.stats.inc(vector);
         let res = if vector >= X86_INT_LOCAL_APIC_BASE {
             let handler = self.manager_lapic.lock();
             handler.handle(vector - X86_INT_LOCAL_APIC_BASE)
@@ -89,6 +106,9 @@ impl Scheme for Apic {
         if res.is_err() {
             warn!("no registered handler for interrupt vector {}!", vector);
         }
+        // the EOI goes to the local APIC, which forwards it to the I/O APIC
+        // for level-triggered interrupts
+        Self::local_apic().eoi();
     }
 }
 
@@ -169,6 +189,22 @@ impl IrqScheme for Apic {
         }
     }
 
+    /// The IRQ numbers are interrupt vectors.
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        let mut vectors: Vec<_> = self.manager_ioapic.lock().registered_irqs().collect();
+        vectors.extend(
+            self.manager_lapic
+                .lock()
+                .registered_irqs()
+                .map(|i| i + X86_INT_LOCAL_APIC_BASE),
+        );
+        self.stats.collect(vectors.into_iter())
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
     fn apic_timer_enable(&self) {
         // SAFETY: this will called only once for every core
         Apic::local_apic().enable_timer();
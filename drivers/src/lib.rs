@@ -20,14 +20,19 @@ pub mod mock;
 #[doc(cfg(feature = "virtio"))]
 pub mod virtio;
 
+pub mod block;
 pub mod builder;
 pub mod bus;
 pub mod display;
+pub mod gpio;
 pub mod input;
 pub mod io;
 pub mod irq;
+pub mod manager;
 pub mod net;
+pub mod power;
 pub mod prelude;
+pub mod rtc;
 pub mod scheme;
 pub mod uart;
 pub mod utils;
@@ -51,6 +56,10 @@ pub enum DeviceError {
     NoResources,
     /// The device driver is not implemented, supported, or enabled.
     NotSupported,
+    /// The device tree blob is malformed.
+    InvalidDtb,
+    /// The operation would block, try again later.
+    Again,
 }
 
 /// A type alias for the result of a device operation.
@@ -59,30 +68,51 @@ pub type DeviceResult<T = ()> = core::result::Result<T, DeviceError>;
 /// Static shell of shared dynamic device [`Scheme`](crate::scheme::Scheme) types.
 #[derive(Clone)]
 pub enum Device {
+    /// Memory balloon device
+    Balloon(Arc<dyn scheme::BalloonScheme>),
     /// Block device
     Block(Arc<dyn scheme::BlockScheme>),
     /// Display device
     Display(Arc<dyn scheme::DisplayScheme>),
+    /// GPIO controller
+    Gpio(Arc<dyn scheme::GpioScheme>),
     /// Input device
     Input(Arc<dyn scheme::InputScheme>),
     /// Interrupt request and handle
     Irq(Arc<dyn scheme::IrqScheme>),
     /// Network device
     Net(Arc<dyn scheme::NetScheme>),
+    /// Power off and reset
+    Power(Arc<dyn scheme::PowerScheme>),
+    /// Hardware random number generator
+    Rng(Arc<dyn scheme::RngScheme>),
+    /// Real-time clock
+    Rtc(Arc<dyn scheme::RtcScheme>),
+    /// Timer with per-CPU deadlines
+    Timer(Arc<dyn scheme::TimerScheme>),
     /// Uart port
     Uart(Arc<dyn scheme::UartScheme>),
+    /// Socket between the guest and the host
+    Vsock(Arc<dyn scheme::VsockScheme>),
 }
 
 impl Device {
     /// Get a general [`Scheme`](scheme::Scheme) from the device.
     pub fn inner(&self) -> Arc<dyn scheme::Scheme> {
         match self {
+            Self::Balloon(d) => d.clone().upcast(),
             Self::Block(d) => d.clone().upcast(),
             Self::Display(d) => d.clone().upcast(),
+            Self::Gpio(d) => d.clone().upcast(),
             Self::Input(d) => d.clone().upcast(),
             Self::Irq(d) => d.clone().upcast(),
             Self::Net(d) => d.clone().upcast(),
+            Self::Power(d) => d.clone().upcast(),
+            Self::Rng(d) => d.clone().upcast(),
+            Self::Rtc(d) => d.clone().upcast(),
+            Self::Timer(d) => d.clone().upcast(),
             Self::Uart(d) => d.clone().upcast(),
+            Self::Vsock(d) => d.clone().upcast(),
         }
     }
 }
@@ -90,12 +120,19 @@ impl Device {
 impl fmt::Debug for Device {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Self::Balloon(d) => write!(f, "BalloonDevice({:?})", d.name()),
             Self::Block(d) => write!(f, "BlockDevice({:?})", d.name()),
             Self::Display(d) => write!(f, "DisplayDevice({:?})", d.name()),
+            Self::Gpio(d) => write!(f, "GpioDevice({:?})", d.name()),
             Self::Input(d) => write!(f, "InputDevice({:?})", d.name()),
             Self::Irq(d) => write!(f, "IrqDevice({:?})", d.name()),
             Self::Net(d) => write!(f, "NetDevice({:?})", d.name()),
+            Self::Power(d) => write!(f, "PowerDevice({:?})", d.name()),
+            Self::Rng(d) => write!(f, "RngDevice({:?})", d.name()),
+            Self::Rtc(d) => write!(f, "RtcDevice({:?})", d.name()),
+            Self::Timer(d) => write!(f, "TimerDevice({:?})", d.name()),
             Self::Uart(d) => write!(f, "UartDevice({:?})", d.name()),
+            Self::Vsock(d) => write!(f, "VsockDevice({:?})", d.name()),
         }
     }
 }
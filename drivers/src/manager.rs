@@ -0,0 +1,390 @@
+//! Index the probed devices by kind and by name.
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use lock::{Mutex, RwLock, RwLockReadGuard};
+
+use crate::builder::{IoMapper, NamedDevice, ProbedDevices};
+use crate::scheme::{
+    BalloonScheme, BlockScheme, DisplayScheme, GpioScheme, InputScheme, IrqScheme, NetScheme,
+    PowerScheme, RngScheme, RtcScheme, Scheme, TimerScheme, UartScheme, VsockScheme,
+};
+use crate::{Device, DeviceError, DeviceResult, VirtAddr};
+
+/// A wrapper of a device array with the same [`Scheme`].
+pub struct DeviceList<T: Scheme + ?Sized>(RwLock<Vec<Arc<T>>>);
+
+impl<T: Scheme + ?Sized> DeviceList<T> {
+    fn add(&self, dev: Arc<T>) {
+        self.0.write().push(dev);
+    }
+
+    /// Remove the device which is the same driver instance as `ptr`.
+    fn remove(&self, ptr: *const ()) {
+        self.0
+            .write()
+            .retain(|d| Arc::as_ptr(d) as *const () != ptr);
+    }
+
+    /// Convert self into a vector.
+    pub fn as_vec(&self) -> RwLockReadGuard<'_, Vec<Arc<T>>> {
+        self.0.read()
+    }
+
+    /// Returns the device at given position, or `None` if out of bounds.
+    pub fn try_get(&self, idx: usize) -> Option<Arc<T>> {
+        self.0.read().get(idx).cloned()
+    }
+
+    /// Returns the device with the given name, or `None` if not found.
+    pub fn find(&self, name: &str) -> Option<Arc<T>> {
+        self.0.read().iter().find(|d| d.name() == name).cloned()
+    }
+
+    /// Returns the first device of this device array, or `None` if it is empty.
+    pub fn first(&self) -> Option<Arc<T>> {
+        self.try_get(0)
+    }
+
+    /// Returns the first device of this device array.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the array is empty.
+    pub fn first_unwrap(&self) -> Arc<T> {
+        self.first()
+            .unwrap_or_else(|| panic!("device not initialized: {}", core::any::type_name::<T>()))
+    }
+
+    /// Returns the number of devices.
+    pub fn len(&self) -> usize {
+        self.0.read().len()
+    }
+
+    /// Returns `true` if there is no device.
+    pub fn is_empty(&self) -> bool {
+        self.0.read().is_empty()
+    }
+}
+
+impl<T: Scheme + ?Sized> Default for DeviceList<T> {
+    fn default() -> Self {
+        Self(RwLock::new(Vec::new()))
+    }
+}
+
+/// The mapped regions and the interrupts a probed device holds, which are
+/// released when the device is removed.
+struct Resources {
+    device: Device,
+    mappings: Vec<(VirtAddr, usize)>,
+    irqs: Vec<(Arc<dyn IrqScheme>, usize)>,
+}
+
+/// Identifies the driver instance regardless of the trait it is used as.
+fn device_ptr(dev: &Device) -> *const () {
+    Arc::as_ptr(&dev.inner()) as *const ()
+}
+
+/// All devices of the system, grouped by their kinds in the order they were
+/// added, and optionally named, e.g. `ttyS0` for the first UART.
+///
+/// All methods take `&self`, so the manager can be shared as a global.
+#[derive(Default)]
+pub struct DeviceManager {
+    balloons: DeviceList<dyn BalloonScheme>,
+    blocks: DeviceList<dyn BlockScheme>,
+    displays: DeviceList<dyn DisplayScheme>,
+    gpios: DeviceList<dyn GpioScheme>,
+    inputs: DeviceList<dyn InputScheme>,
+    irqs: DeviceList<dyn IrqScheme>,
+    nets: DeviceList<dyn NetScheme>,
+    powers: DeviceList<dyn PowerScheme>,
+    rngs: DeviceList<dyn RngScheme>,
+    rtcs: DeviceList<dyn RtcScheme>,
+    timers: DeviceList<dyn TimerScheme>,
+    uarts: DeviceList<dyn UartScheme>,
+    vsocks: DeviceList<dyn VsockScheme>,
+    names: RwLock<BTreeMap<String, Device>>,
+    resources: Mutex<Vec<Resources>>,
+}
+
+impl DeviceManager {
+    /// Construct an empty manager.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a device to the list of its kind.
+    pub fn add(&self, dev: Device) {
+        match dev {
+            Device::Balloon(d) => self.balloons.add(d),
+            Device::Block(d) => self.blocks.add(d),
+            Device::Display(d) => self.displays.add(d),
+            Device::Gpio(d) => self.gpios.add(d),
+            Device::Input(d) => self.inputs.add(d),
+            Device::Irq(d) => self.irqs.add(d),
+            Device::Net(d) => self.nets.add(d),
+            Device::Power(d) => self.powers.add(d),
+            Device::Rng(d) => self.rngs.add(d),
+            Device::Rtc(d) => self.rtcs.add(d),
+            Device::Timer(d) => self.timers.add(d),
+            Device::Uart(d) => self.uarts.add(d),
+            Device::Vsock(d) => self.vsocks.add(d),
+        }
+    }
+
+    /// Add a device probed from the device tree, which is also named by its
+    /// aliases or node names. If a name is already used, the device added
+    /// first keeps it. Its MMIO regions and interrupts are released by
+    /// [`remove`](Self::remove).
+    pub fn add_named(&self, named: NamedDevice) {
+        {
+            let mut names = self.names.write();
+            for name in named.names {
+                names.entry(name).or_insert_with(|| named.device.clone());
+            }
+        }
+        self.resources.lock().push(Resources {
+            device: named.device.clone(),
+            mappings: named.mappings,
+            irqs: named.irqs,
+        });
+        self.add(named.device);
+    }
+
+    /// Give the device another name, replacing the device of the same name.
+    /// The device is not added to the list of its kind.
+    pub fn alias(&self, name: &str, dev: Device) {
+        self.names.write().insert(String::from(name), dev);
+    }
+
+    /// Remove the device with the name, and all other names of it.
+    ///
+    /// The device is shut down first, then its interrupts are masked and
+    /// unregistered, and its mapped regions are unmapped by the `io_mapper`. If
+    /// the device fails to shut down, it is kept and the error is returned.
+    pub fn remove(&self, name: &str, io_mapper: &dyn IoMapper) -> DeviceResult<Device> {
+        let dev = self.get(name).ok_or(DeviceError::InvalidParam)?;
+        dev.inner().shutdown()?;
+
+        let ptr = device_ptr(&dev);
+        let res = {
+            let mut resources = self.resources.lock();
+            resources
+                .iter()
+                .position(|r| device_ptr(&r.device) == ptr)
+                .map(|idx| resources.remove(idx))
+        };
+        if let Some(res) = res {
+            for (irq, irq_num) in res.irqs {
+                if let Err(err) = irq.mask(irq_num).and_then(|_| irq.unregister(irq_num)) {
+                    warn!(
+                        "DeviceManager: failed to release interrupt {} of {:?}: {:?}",
+                        irq_num, name, err
+                    );
+                }
+            }
+            for (vaddr, len) in res.mappings {
+                if let Err(err) = io_mapper.unmap(vaddr, len) {
+                    warn!(
+                        "DeviceManager: failed to unmap {:#x} of {:?}: {:?}",
+                        vaddr, name, err
+                    );
+                }
+            }
+        }
+        self.names.write().retain(|_, d| device_ptr(d) != ptr);
+        match &dev {
+            Device::Balloon(_) => self.balloons.remove(ptr),
+            Device::Block(_) => self.blocks.remove(ptr),
+            Device::Display(_) => self.displays.remove(ptr),
+            Device::Gpio(_) => self.gpios.remove(ptr),
+            Device::Input(_) => self.inputs.remove(ptr),
+            Device::Irq(_) => self.irqs.remove(ptr),
+            Device::Net(_) => self.nets.remove(ptr),
+            Device::Power(_) => self.powers.remove(ptr),
+            Device::Rng(_) => self.rngs.remove(ptr),
+            Device::Rtc(_) => self.rtcs.remove(ptr),
+            Device::Timer(_) => self.timers.remove(ptr),
+            Device::Uart(_) => self.uarts.remove(ptr),
+            Device::Vsock(_) => self.vsocks.remove(ptr),
+        }
+        Ok(dev)
+    }
+
+    /// Returns the device with the name, or `None` if there is no such one.
+    pub fn get(&self, name: &str) -> Option<Device> {
+        self.names.read().get(name).cloned()
+    }
+
+    /// Returns all names and the devices they refer to.
+    pub fn names(&self) -> Vec<(String, Device)> {
+        self.names
+            .read()
+            .iter()
+            .map(|(name, dev)| (name.clone(), dev.clone()))
+            .collect()
+    }
+
+    /// Returns all devices which implement the [`BalloonScheme`].
+    pub fn balloons(&self) -> &DeviceList<dyn BalloonScheme> {
+        &self.balloons
+    }
+
+    /// Returns all devices which implement the [`BlockScheme`].
+    pub fn blocks(&self) -> &DeviceList<dyn BlockScheme> {
+        &self.blocks
+    }
+
+    /// Returns all devices which implement the [`DisplayScheme`].
+    pub fn displays(&self) -> &DeviceList<dyn DisplayScheme> {
+        &self.displays
+    }
+
+    /// Returns all devices which implement the [`GpioScheme`].
+    pub fn gpios(&self) -> &DeviceList<dyn GpioScheme> {
+        &self.gpios
+    }
+
+    /// Returns all devices which implement the [`InputScheme`].
+    pub fn inputs(&self) -> &DeviceList<dyn InputScheme> {
+        &self.inputs
+    }
+
+    /// Returns all devices which implement the [`IrqScheme`].
+    pub fn irqs(&self) -> &DeviceList<dyn IrqScheme> {
+        &self.irqs
+    }
+
+    /// Returns all devices which implement the [`NetScheme`].
+    pub fn nets(&self) -> &DeviceList<dyn NetScheme> {
+        &self.nets
+    }
+
+    /// Returns all devices which implement the [`PowerScheme`].
+    pub fn powers(&self) -> &DeviceList<dyn PowerScheme> {
+        &self.powers
+    }
+
+    /// Returns all devices which implement the [`RngScheme`].
+    pub fn rngs(&self) -> &DeviceList<dyn RngScheme> {
+        &self.rngs
+    }
+
+    /// Returns all devices which implement the [`RtcScheme`].
+    pub fn rtcs(&self) -> &DeviceList<dyn RtcScheme> {
+        &self.rtcs
+    }
+
+    /// Returns all devices which implement the [`TimerScheme`].
+    pub fn timers(&self) -> &DeviceList<dyn TimerScheme> {
+        &self.timers
+    }
+
+    /// Returns all devices which implement the [`UartScheme`].
+    pub fn uarts(&self) -> &DeviceList<dyn UartScheme> {
+        &self.uarts
+    }
+
+    /// Returns all devices which implement the [`VsockScheme`].
+    pub fn vsocks(&self) -> &DeviceList<dyn VsockScheme> {
+        &self.vsocks
+    }
+}
+
+impl From<Vec<Device>> for DeviceManager {
+    fn from(devices: Vec<Device>) -> Self {
+        let manager = Self::new();
+        for dev in devices {
+            manager.add(dev);
+        }
+        manager
+    }
+}
+
+/// The devices are added by [`DeviceManager::add_named`].
+impl From<ProbedDevices> for DeviceManager {
+    fn from(probed: ProbedDevices) -> Self {
+        let manager = Self::new();
+        for named in probed.devices {
+            manager.add_named(named);
+        }
+        manager
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builder::NamedDevice;
+    use crate::rtc::GoldfishRtc;
+    use crate::utils::test_utils::MockIoMapper;
+    use alloc::{boxed::Box, format, vec};
+
+    fn mock_rtc() -> Device {
+        let regs = Box::leak(Box::new([0u32; 0x20 / 4]));
+        Device::Rtc(Arc::new(unsafe {
+            GoldfishRtc::new(regs.as_ptr() as usize)
+        }))
+    }
+
+    #[test]
+    fn test_index_and_alias() {
+        let rtc = mock_rtc();
+        let manager = DeviceManager::from(vec![rtc.clone(), mock_rtc()]);
+        assert_eq!(manager.rtcs().len(), 2);
+        assert!(manager.uarts().is_empty());
+
+        manager.alias("rtc0", rtc);
+        match manager.get("rtc0") {
+            Some(Device::Rtc(d)) => assert!(core::ptr::eq(
+                Arc::as_ptr(&d) as *const (),
+                Arc::as_ptr(&manager.rtcs().first_unwrap()) as *const ()
+            )),
+            dev => panic!("unexpected device {:?}", dev),
+        }
+        assert!(manager.get("rtc1").is_none());
+        assert_eq!(manager.names().len(), 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let named = |name: &str, mappings| NamedDevice {
+            names: vec![String::from(name)],
+            path: format!("/{}", name),
+            compatible: String::from("google,goldfish-rtc"),
+            mmio: None,
+            mappings,
+            irqs: Vec::new(),
+            device: mock_rtc(),
+        };
+        let manager = DeviceManager::from(ProbedDevices {
+            devices: vec![
+                named("rtc0", vec![(0x8000_1000, 0x1000)]),
+                named("rtc1", Vec::new()),
+            ],
+            console: None,
+            irq_errors: Vec::new(),
+            records: Vec::new(),
+            bootargs: None,
+            initrd: None,
+        });
+        let rtc0 = manager.get("rtc0").unwrap();
+        manager.alias("clock", rtc0);
+
+        let mapper = MockIoMapper::default();
+        assert!(matches!(
+            manager.remove("rtc0", &mapper),
+            Ok(Device::Rtc(_))
+        ));
+        assert_eq!(mapper.unmapped(), vec![(0x8000_1000, 0x1000)]);
+        assert_eq!(manager.rtcs().len(), 1);
+        assert!(manager.get("clock").is_none());
+        assert!(manager.get("rtc1").is_some());
+        assert!(matches!(
+            manager.remove("rtc0", &mapper),
+            Err(DeviceError::InvalidParam)
+        ));
+    }
+}
@@ -15,6 +15,7 @@ impl MockDisplay {
         let info = DisplayInfo {
             width,
             height,
+            stride: format.row_bytes(width),
             format,
             fb_base_vaddr: fb.as_ptr() as usize,
             fb_size,
@@ -37,6 +38,7 @@ impl MockDisplay {
         let info = DisplayInfo {
             width,
             height,
+            stride: format.row_bytes(width),
             format,
             fb_base_vaddr: fb.as_ptr() as usize,
             fb_size,
@@ -60,7 +60,7 @@ impl SdlWindow {
             .unwrap();
 
         texture
-            .update(None, &self.display.fb(), info.pitch() as usize)
+            .update(None, &self.display.fb(), info.stride as usize)
             .unwrap();
         self.canvas.copy(&texture, None, None).unwrap();
         self.canvas.present();
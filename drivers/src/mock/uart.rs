@@ -3,7 +3,7 @@ use std::collections::VecDeque;
 use async_std::{io, io::prelude::*, task};
 use lock::Mutex;
 
-use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
+use crate::scheme::{impl_event_scheme, uart::UartEvent, Scheme, UartScheme};
 use crate::utils::EventListener;
 use crate::DeviceResult;
 
@@ -14,10 +14,10 @@ lazy_static::lazy_static! {
 }
 
 pub struct MockUart {
-    listener: EventListener,
+    listener: EventListener<UartEvent>,
 }
 
-impl_event_scheme!(MockUart);
+impl_event_scheme!(MockUart, UartEvent);
 
 impl MockUart {
     pub fn new() -> Self {
@@ -60,7 +60,7 @@ impl Scheme for MockUart {
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.listener.trigger(());
+        self.listener.trigger(UartEvent::Received);
     }
 }
 
@@ -14,7 +14,8 @@ use smoltcp::Result;
 
 use super::{timer_now_as_micros, ProviderImpl};
 use crate::net::get_sockets;
-use crate::scheme::{NetScheme, Scheme};
+use crate::scheme::{impl_event_scheme, NetScheme, Scheme};
+use crate::utils::EventListener;
 use crate::{DeviceError, DeviceResult};
 use isomorphic_drivers::net::ethernet::intel::e1000::E1000;
 use isomorphic_drivers::net::ethernet::structs::EthernetAddress as DriverEthernetAddress;
@@ -29,8 +30,11 @@ pub struct E1000Interface {
     driver: E1000Driver,
     name: String,
     irq: usize,
+    listener: Arc<EventListener>,
 }
 
+impl_event_scheme!(E1000Interface);
+
 impl Scheme for E1000Interface {
     fn name(&self) -> &str {
         "e1000"
@@ -45,6 +49,7 @@ impl Scheme for E1000Interface {
         let data = self.driver.0.lock().handle_interrupt();
 
         if data {
+            self.listener.trigger(());
             let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
             let sockets = get_sockets();
             let mut sockets = sockets.lock();
@@ -92,22 +97,27 @@ impl NetScheme for E1000Interface {
         }
     }
 
+    /// A frame larger than `buf` is dropped.
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
         if let Some(vec_recv) = self.driver.0.lock().receive() {
-            buf.copy_from_slice(&vec_recv);
-            Ok(vec_recv.len())
+            let len = vec_recv.len();
+            if len > buf.len() {
+                return Err(DeviceError::InvalidParam);
+            }
+            buf[..len].copy_from_slice(&vec_recv);
+            Ok(len)
         } else {
-            Err(DeviceError::NotReady)
+            Err(DeviceError::Again)
         }
     }
 
-    fn send(&self, data: &[u8]) -> DeviceResult<usize> {
-        if self.driver.0.lock().can_send() {
-            let mut driver = self.driver.0.lock();
-            driver.send(data);
-            Ok(data.len())
+    fn send(&self, frame: &[u8]) -> DeviceResult {
+        let mut driver = self.driver.0.lock();
+        if driver.can_send() {
+            driver.send(frame);
+            Ok(())
         } else {
-            Err(DeviceError::NotReady)
+            Err(DeviceError::Again)
         }
     }
 }
@@ -208,6 +218,7 @@ pub fn init(
         driver: net_driver,
         name,
         irq,
+        listener: Arc::new(EventListener::new()),
     };
 
     Ok(e1000_iface)
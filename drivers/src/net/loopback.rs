@@ -7,7 +7,8 @@ use alloc::sync::Arc;
 use alloc::string::String;
 use lock::Mutex;
 
-use crate::scheme::{NetScheme, Scheme};
+use crate::scheme::{impl_event_scheme, NetScheme, Scheme};
+use crate::utils::EventListener;
 use crate::{DeviceError, DeviceResult};
 
 use alloc::vec::Vec;
@@ -18,6 +19,19 @@ use smoltcp::wire::IpCidr;
 pub struct LoopbackInterface {
     pub iface: Arc<Mutex<Interface<'static, Loopback>>>,
     pub name: String,
+    listener: Arc<EventListener>,
+}
+
+impl_event_scheme!(LoopbackInterface);
+
+impl LoopbackInterface {
+    pub fn new(iface: Interface<'static, Loopback>, name: String) -> Self {
+        Self {
+            iface: Arc::new(Mutex::new(iface)),
+            name,
+            listener: Arc::new(EventListener::new()),
+        }
+    }
 }
 
 impl Scheme for LoopbackInterface {
@@ -32,7 +46,7 @@ impl NetScheme for LoopbackInterface {
     fn recv(&self, _buf: &mut [u8]) -> DeviceResult<usize> {
         unimplemented!()
     }
-    fn send(&self, _buf: &[u8]) -> DeviceResult<usize> {
+    fn send(&self, _frame: &[u8]) -> DeviceResult {
         unimplemented!()
     }
     fn poll(&self) -> DeviceResult {
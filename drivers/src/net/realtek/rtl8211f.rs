@@ -21,7 +21,7 @@ pub const PINCTRL_GPIO_BASE: u32 = 0x02000000;
 const EMAC_BGR_REG: u32 = 0x097C; // CCU
 const EMAC_25M_CLK_REG: u32 = 0x0970;
 
-const EMAC_EPHY_CLK_REG0: u32 = 0x30; // SYS_CFG
+pub const EMAC_EPHY_CLK_REG0: u32 = 0x30; // SYS_CFG
 
 // mac addr 3a:c5:31:d5:de:88
 const MAC_ADDR: &str = "3a:c5:31:d5:de:88";
@@ -210,7 +210,7 @@ impl<P> RTL8211F<P>
 where
     P: Provider,
 {
-    pub fn new(mac_addr: &[u8; 6]) -> Self {
+    pub fn new(mac_addr: &[u8; 6], sys_cfg_base: u32) -> Self {
         assert_eq!(size_of::<DmaDesc>(), 16);
 
         let mut mac: [u8; 6] = [0; 6];
@@ -329,7 +329,7 @@ where
         RTL8211F {
             base: GMAC_BASE,
             base_ccu: CCU_BASE,
-            base_phy: SYS_CFG_BASE,
+            base_phy: sys_cfg_base,
 
             pinctrl: PINCTRL_GPIO_BASE,
 
@@ -646,6 +646,47 @@ where
         // 开始接收数据吧
     }
 
+    /// 通过 MDIO 读取 PHY 的链路状态，返回 (是否连通, 速率 Mbps, 是否全双工)
+    pub fn link_status(&mut self) -> (bool, u32, bool) {
+        let phyaddr = 0;
+        // BMSR 的链路状态位在断开后一直为 0，直到被读取一次，因此读两次
+        self.mdio_read(phyaddr, MII_BMSR);
+        let bmsr = self.mdio_read(phyaddr, MII_BMSR);
+        if (bmsr & BMSR_LSTATUS) == 0 {
+            return (false, 0, false);
+        }
+
+        if self.autoneg != AUTONEG_ENABLE {
+            let bmcr = self.mdio_read(phyaddr, MII_BMCR);
+            let speed = if (bmcr & BMCR_SPEED1000) != 0 {
+                1000
+            } else if (bmcr & BMCR_SPEED100) != 0 {
+                100
+            } else {
+                10
+            };
+            return (true, speed, (bmcr & BMCR_FULLDPLX) != 0);
+        }
+
+        // 与 adjust_link 相同，取双方能力的交集，按速率从高到低匹配
+        let lpagb = self.mdio_read(phyaddr, MII_STAT1000);
+        let advgb = self.mdio_read(phyaddr, MII_CTRL1000);
+        let lpa = self.mdio_read(phyaddr, MII_LPA) & self.mdio_read(phyaddr, MII_ADVERTISE);
+        let (speed, full_duplex) =
+            if (lpagb & LPA_1000FULL) != 0 && (advgb & ADVERTISE_1000FULL) != 0 {
+                (1000, true)
+            } else if (lpagb & LPA_1000HALF) != 0 && (advgb & ADVERTISE_1000HALF) != 0 {
+                (1000, false)
+            } else if (lpa & LPA_100FULL) != 0 {
+                (100, true)
+            } else if (lpa & LPA_100HALF) != 0 {
+                (100, false)
+            } else {
+                (10, (lpa & LPA_10FULL) != 0)
+            };
+        (true, speed, full_duplex)
+    }
+
     pub fn can_recv(&mut self) -> bool {
         let desc = &self.recv_ring[self.rx_dirty];
         invalidate_dcache(
@@ -819,8 +860,65 @@ where
         true
     }
 
+    /// 接收一个网络帧，直接在 DMA 缓冲区上调用 `f`，不经过中间缓冲区
+    ///
+    /// 跳过出错的帧，没有收到帧时返回 `None`。
+    pub fn recv_with<R, F>(&mut self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut ret = None;
+        let mut f = Some(f);
+        while ret.is_none() {
+            let entry = self.rx_dirty;
+            let desc = &self.recv_ring[entry];
+            invalidate_dcache(
+                virt_to_phys(desc as *const DmaDesc as usize) as u64,
+                size_of::<DmaDesc>() as u64,
+            );
+            if desc_get_own(desc) != 0 {
+                break;
+            }
+            self.rx_dirty = (entry + 1) % DMA_DESC_RX;
+
+            // Frame length bit[16:29], 包括 FCS
+            let status = desc.desc0;
+            let frame_len = ((status >> 16) & 0x3fff) as usize;
+            //discard frame when last_desc, err_sum, len_err, mii_err
+            if ((status >> 8) & 0x1) == 0 || (status & 0x9008) != 0 || frame_len < 4 {
+                debug!("Get error packet");
+                continue;
+            }
+
+            let buffer = self.recv_buffers[entry];
+            invalidate_dcache(virt_to_phys(buffer) as u64, frame_len as u64);
+            let skb = unsafe { slice::from_raw_parts_mut(buffer as *mut u8, frame_len - 4) };
+            ret = f.take().map(|f| f(skb));
+            // 写回 `f` 修改过的内容，以免之后覆盖 DMA 写入的数据
+            flush_cache(virt_to_phys(buffer) as u64, frame_len as u64);
+        }
+        self.rx_refill();
+        ret
+    }
+
     pub fn geth_send(&mut self, send_buff: &[u8]) -> Result<i32, &str> {
+        self.send_with(send_buff.len(), |target| {
+            target.copy_from_slice(send_buff);
+            Ok(0)
+        })?
+    }
+
+    /// 在 DMA 发送缓冲区中直接用 `f` 填写 `frame_len` 字节的网络帧并发送，
+    /// 不经过中间缓冲区。`f` 返回错误时不发送。
+    pub fn send_with<R, E, F>(&mut self, frame_len: usize, f: F) -> Result<Result<R, E>, &str>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R, E>,
+    {
         // Tx Ring full 判断一下？
+        if frame_len > MAX_BUF_SZ as usize {
+            error!("The packet: {} to be send is TOO LARGE !", frame_len);
+            return Err("frame too large");
+        }
 
         let mut entry = self.tx_dirty;
         //let mut first = &mut self.send_ring[entry];
@@ -831,16 +929,14 @@ where
         let csum_insert = 0; // 是否CHECKSUM_PARTIAL
 
         // linux驱动中的skb_headlen是什么?
-        let mut len = send_buff.len() as u32;
+        let mut len = frame_len as u32;
 
         // send buffer长度需要注意下, 应该2k左右
-        let target = unsafe {
-            slice::from_raw_parts_mut(self.send_buffers[entry] as *mut u8, send_buff.len())
-        };
-        target.copy_from_slice(send_buff);
-
-        if len > MAX_BUF_SZ {
-            error!("The packet: {} to be send is TOO LARGE !", len);
+        let target =
+            unsafe { slice::from_raw_parts_mut(self.send_buffers[entry] as *mut u8, frame_len) };
+        let ret = f(target);
+        if ret.is_err() {
+            return Ok(ret);
         }
 
         info!("========== TX PKT DATA: >>>>>>>>>>");
@@ -885,7 +981,7 @@ where
         );
         flush_cache(
             virt_to_phys(self.send_buffers[desc_count] as usize) as u64,
-            send_buff.len() as u64,
+            frame_len as u64,
         );
 
         info!(
@@ -911,7 +1007,7 @@ where
         // 环形缓冲区的内存unmap之类的
         self.tx_complete();
 
-        Ok(0)
+        Ok(ret)
     }
 
     pub fn rx_refill(&mut self) {
@@ -2,6 +2,7 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lock::Mutex;
 
 use smoltcp::iface::*;
@@ -15,9 +16,14 @@ use super::realtek::rtl8211f::{self, RTL8211F};
 use super::{timer_now_as_micros, ProviderImpl, PAGE_SIZE};
 
 use crate::net::get_sockets;
-use crate::scheme::{NetScheme, Scheme};
+use crate::scheme::{impl_event_scheme, LinkStatus, NetScheme, Scheme};
+use crate::utils::EventListener;
 use crate::{DeviceError, DeviceResult};
 
+/// Minimum interval between two PHY link checks in [`NetScheme::poll`], each
+/// of which takes several busy-waiting MDIO reads.
+const LINK_CHECK_INTERVAL_US: u64 = 1_000_000;
+
 #[derive(Clone)]
 pub struct RTLxDriver(Arc<Mutex<RTL8211F<ProviderImpl>>>);
 
@@ -27,6 +33,30 @@ pub struct RTLxInterface {
     pub driver: RTLxDriver,
     pub name: String,
     pub irq: usize,
+    listener: Arc<EventListener>,
+    link_up: Arc<AtomicBool>,
+    link_checked_us: Arc<AtomicU64>,
+}
+
+impl_event_scheme!(RTLxInterface);
+
+impl RTLxInterface {
+    /// Read the link state from the PHY, and trigger the event if the link
+    /// goes up or down.
+    fn update_link(&self) -> LinkStatus {
+        let (up, speed_mbps, full_duplex) = self.driver.0.lock().link_status();
+        self.link_checked_us
+            .store(timer_now_as_micros(), Ordering::Relaxed);
+        if self.link_up.swap(up, Ordering::Relaxed) != up {
+            info!("rtl8211f link is {}", if up { "up" } else { "down" });
+            self.listener.trigger(());
+        }
+        LinkStatus {
+            up,
+            speed_mbps,
+            full_duplex,
+        }
+    }
 }
 
 impl Scheme for RTLxInterface {
@@ -58,6 +88,7 @@ impl Scheme for RTLxInterface {
                 }
             }
             self.driver.0.lock().int_enable();
+            self.listener.trigger(());
             //return true;
         }
     }
@@ -76,8 +107,17 @@ impl NetScheme for RTLxInterface {
         Vec::from(self.iface.lock().ip_addrs())
     }
 
+    fn link_status(&self) -> LinkStatus {
+        self.update_link()
+    }
+
     fn poll(&self) -> DeviceResult {
-        let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+        let now = timer_now_as_micros();
+        let last = self.link_checked_us.load(Ordering::Relaxed);
+        if now.wrapping_sub(last) >= LINK_CHECK_INTERVAL_US {
+            self.update_link();
+        }
+        let timestamp = Instant::from_micros(now as i64);
         let sockets = get_sockets();
         let mut sockets = sockets.lock();
         match self.iface.lock().poll(&mut sockets, timestamp) {
@@ -92,23 +132,35 @@ impl NetScheme for RTLxInterface {
         }
     }
 
+    /// Copy the frame from the RX DMA buffer into `buf` directly. The frame
+    /// is dropped if `buf` is too small.
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
-        if self.driver.0.lock().can_recv() {
-            let (vec_recv, rxcount) = self.driver.0.lock().geth_recv(1);
-            buf.copy_from_slice(&vec_recv);
-            Ok(rxcount as usize)
-        } else {
-            Err(DeviceError::NotReady)
-        }
+        self.driver
+            .0
+            .lock()
+            .recv_with(|frame| {
+                let len = frame.len();
+                if len > buf.len() {
+                    return Err(DeviceError::InvalidParam);
+                }
+                buf[..len].copy_from_slice(frame);
+                Ok(len)
+            })
+            .ok_or(DeviceError::Again)?
     }
 
-    fn send(&self, data: &[u8]) -> DeviceResult<usize> {
-        if self.driver.0.lock().can_send() {
-            self.driver.0.lock().geth_send(data).unwrap();
-            Ok(data.len())
-        } else {
-            Err(DeviceError::NotReady)
+    /// Copy `frame` into the TX DMA buffer directly.
+    fn send(&self, frame: &[u8]) -> DeviceResult {
+        let mut driver = self.driver.0.lock();
+        if !driver.can_send() {
+            return Err(DeviceError::Again);
         }
+        driver
+            .send_with(frame.len(), |target| {
+                target.copy_from_slice(frame);
+                Ok(())
+            })
+            .map_err(|_| DeviceError::InvalidParam)?
     }
 }
 
@@ -128,13 +180,9 @@ impl<'a> Device<'a> for RTLxDriver {
     }
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        if self.0.lock().can_recv() {
-            //这里每次只接收一个网络包
-            let (vec_recv, _rxcount) = self.0.lock().geth_recv(1);
-            Some((RTLxRxToken(vec_recv), RTLxTxToken(self.clone())))
-        } else {
-            None
-        }
+        // 处理收到的帧时可能发送应答，会再次获取驱动的锁，因此复制出来
+        let vec_recv = self.0.lock().recv_with(|frame| frame.to_vec())?;
+        Some((RTLxRxToken(vec_recv), RTLxTxToken(self.clone())))
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
@@ -160,23 +208,28 @@ impl phy::TxToken for RTLxTxToken {
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut buffer = [0u8; 1536];
-        let result = f(&mut buffer[..len]);
-        if result.is_ok() {
-            (self.0).0.lock().geth_send(&buffer[..len]).unwrap();
-        }
-        result
+        // 直接填写 DMA 发送缓冲区
+        let mut driver = (self.0).0.lock();
+        driver
+            .send_with(len, f)
+            .unwrap_or(Err(smoltcp::Error::Truncated))
     }
 }
 
+/// `ephy_clk_reg` is the physical address of the EPHY clock register in the
+/// system configuration block. If it is `None`, the address on D1 is used.
 pub fn rtlx_init<F: Fn(usize, usize) -> Option<usize>>(
     irq: usize,
+    ephy_clk_reg: Option<usize>,
     mapper: F,
 ) -> DeviceResult<RTLxInterface> {
+    let sys_cfg_base = ephy_clk_reg.map_or(rtl8211f::SYS_CFG_BASE, |paddr| {
+        paddr as u32 - rtl8211f::EMAC_EPHY_CLK_REG0
+    });
     mapper(rtl8211f::PINCTRL_GPIO_BASE as usize, PAGE_SIZE * 2);
-    mapper(rtl8211f::SYS_CFG_BASE as usize, PAGE_SIZE * 2);
+    mapper(sys_cfg_base as usize, PAGE_SIZE * 2);
 
-    let mut rtl8211f = RTL8211F::<ProviderImpl>::new(&[0u8; 6]);
+    let mut rtl8211f = RTL8211F::<ProviderImpl>::new(&[0u8; 6], sys_cfg_base);
     let mac = rtl8211f.get_umac();
     //启动前请为D1插上网线
     warn!("Please plug in the Ethernet cable");
@@ -208,6 +261,10 @@ pub fn rtlx_init<F: Fn(usize, usize) -> Option<usize>>(
         driver: net_driver,
         name: String::from("rtl8211f"),
         irq,
+        listener: Arc::new(EventListener::new()),
+        // adjust_link 等待直到链路连通
+        link_up: Arc::new(AtomicBool::new(true)),
+        link_checked_us: Arc::new(AtomicU64::new(timer_now_as_micros())),
     };
 
     Ok(rtl8211f_iface)
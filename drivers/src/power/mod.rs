@@ -0,0 +1,11 @@
+//! Drivers to power off or reset the machine.
+
+#[cfg(target_arch = "aarch64")]
+mod psci;
+mod sifive_test;
+mod syscon;
+
+#[cfg(target_arch = "aarch64")]
+pub use psci::{Psci, PsciMethod};
+pub use sifive_test::SifiveTest;
+pub use syscon::{SysconAction, SysconPower};
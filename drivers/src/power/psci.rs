@@ -0,0 +1,64 @@
+//! The Power State Coordination Interface of ARM, called by SMC or HVC.
+//!
+//! Reference: Arm Power State Coordination Interface, DEN0022.
+
+use crate::scheme::{PowerScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// The conduit to call PSCI functions, as the `method` property of the `/psci`
+/// node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciMethod {
+    /// Call the secure monitor (EL3).
+    Smc,
+    /// Call the hypervisor (EL2).
+    Hvc,
+}
+
+/// Power off and reset the machine by PSCI 0.2 or later.
+pub struct Psci {
+    method: PsciMethod,
+}
+
+impl Psci {
+    pub fn new(method: PsciMethod) -> Self {
+        Self { method }
+    }
+
+    /// Call the function `func` without arguments, and returns the result in
+    /// `x0`.
+    fn call(&self, func: u32) -> i32 {
+        let mut ret = func as usize;
+        unsafe {
+            match self.method {
+                PsciMethod::Smc => core::arch::asm!("smc #0", inout("x0") ret, clobber_abi("C")),
+                PsciMethod::Hvc => core::arch::asm!("hvc #0", inout("x0") ret, clobber_abi("C")),
+            }
+        }
+        ret as i32
+    }
+}
+
+impl Scheme for Psci {
+    fn name(&self) -> &str {
+        "psci"
+    }
+}
+
+impl PowerScheme for Psci {
+    fn shutdown(&self) -> DeviceResult {
+        warn!("psci: SYSTEM_OFF returned {}", self.call(PSCI_SYSTEM_OFF));
+        Err(DeviceError::IoError)
+    }
+
+    fn reboot(&self) -> DeviceResult {
+        warn!(
+            "psci: SYSTEM_RESET returned {}",
+            self.call(PSCI_SYSTEM_RESET)
+        );
+        Err(DeviceError::IoError)
+    }
+}
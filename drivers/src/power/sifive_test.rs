@@ -0,0 +1,51 @@
+//! The SiFive test device, i.e. the "test finisher" of QEMU, which exits QEMU
+//! or resets the machine when written.
+
+use lock::Mutex;
+
+use crate::io::{Io, Mmio};
+use crate::scheme::{PowerScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+/// Exit QEMU with the status 0.
+const FINISHER_PASS: u32 = 0x5555;
+/// Reset the machine.
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Driver of the SiFive test device (`sifive,test0`).
+pub struct SifiveTest {
+    reg: Mutex<&'static mut Mmio<u32>>,
+}
+
+impl SifiveTest {
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize) -> Self {
+        Self {
+            reg: Mutex::new(Mmio::<u32>::from_base(base)),
+        }
+    }
+
+    fn finish(&self, status: u32) -> DeviceResult {
+        self.reg.lock().write(status);
+        // not a real machine, or the write is ignored
+        Err(DeviceError::IoError)
+    }
+}
+
+impl Scheme for SifiveTest {
+    fn name(&self) -> &str {
+        "sifive-test"
+    }
+}
+
+impl PowerScheme for SifiveTest {
+    fn shutdown(&self) -> DeviceResult {
+        self.finish(FINISHER_PASS)
+    }
+
+    fn reboot(&self) -> DeviceResult {
+        self.finish(FINISHER_RESET)
+    }
+}
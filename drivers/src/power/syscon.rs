@@ -0,0 +1,82 @@
+//! Power off or reset the machine by writing a register of a system
+//! controller, as the `syscon-poweroff` and `syscon-reboot` nodes describe.
+
+use lock::Mutex;
+
+use crate::io::{Io, Mmio};
+use crate::scheme::{PowerScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+/// What writing the register does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysconAction {
+    /// Power off the machine (`syscon-poweroff`).
+    Poweroff,
+    /// Reset the machine (`syscon-reboot`).
+    Reboot,
+}
+
+/// Driver of the `syscon-poweroff` and `syscon-reboot` nodes, which write
+/// `value` to the bits `mask` of a register.
+pub struct SysconPower {
+    reg: Mutex<&'static mut Mmio<u32>>,
+    value: u32,
+    mask: u32,
+    action: SysconAction,
+}
+
+impl SysconPower {
+    /// Construct with the register at `base + offset` of the system
+    /// controller.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(
+        base: usize,
+        offset: usize,
+        value: u32,
+        mask: u32,
+        action: SysconAction,
+    ) -> Self {
+        Self {
+            reg: Mutex::new(Mmio::<u32>::from_base(base + offset)),
+            value,
+            mask,
+            action,
+        }
+    }
+
+    fn trigger(&self) -> DeviceResult {
+        let mut reg = self.reg.lock();
+        let old = if self.mask == u32::MAX { 0 } else { reg.read() };
+        reg.write((old & !self.mask) | (self.value & self.mask));
+        // the machine should be gone by now
+        Err(DeviceError::IoError)
+    }
+}
+
+impl Scheme for SysconPower {
+    fn name(&self) -> &str {
+        match self.action {
+            SysconAction::Poweroff => "syscon-poweroff",
+            SysconAction::Reboot => "syscon-reboot",
+        }
+    }
+}
+
+impl PowerScheme for SysconPower {
+    fn shutdown(&self) -> DeviceResult {
+        match self.action {
+            SysconAction::Poweroff => self.trigger(),
+            SysconAction::Reboot => Err(DeviceError::NotSupported),
+        }
+    }
+
+    fn reboot(&self) -> DeviceResult {
+        match self.action {
+            SysconAction::Reboot => self.trigger(),
+            SysconAction::Poweroff => Err(DeviceError::NotSupported),
+        }
+    }
+}
@@ -1,11 +1,19 @@
 //! Re-export most commonly used driver types.
 
-pub use crate::scheme::display::{ColorFormat, DisplayInfo, FrameBuffer, Rectangle, RgbColor};
-pub use crate::scheme::input::{CapabilityType, InputCapability, InputEvent, InputEventType};
+pub use crate::scheme::display::{
+    ColorFormat, DisplayInfo, FrameBuffer, Rectangle, RgbColor, CURSOR_SIZE,
+};
+pub use crate::scheme::gpio::{GpioDirection, GpioEdge, GpioPull};
+pub use crate::scheme::input::{
+    CapabilityType, InputCapability, InputEvent, InputEventType, KeyLeds, TypedInputEvent,
+};
 pub use crate::scheme::irq::{IrqHandler, IrqPolarity, IrqTriggerMode};
+pub use crate::scheme::uart::{
+    FlowControl, LineConfig, LineErrorCounts, LineErrors, Parity, StopBits, UartConfig, UartEvent,
+};
 pub use crate::{Device, DeviceError, DeviceResult};
 
 /// Re-export types from [`input`](crate::input).
 pub mod input {
-    pub use crate::input::{Mouse, MouseFlags, MouseState};
+    pub use crate::input::{GpioKey, GpioKeys, Mouse, MouseFlags, MouseState};
 }
@@ -0,0 +1,110 @@
+//! Goldfish RTC, emulated by QEMU.
+//!
+//! Reference: <https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT>
+use lock::Mutex;
+
+use crate::io::{Io, Mmio, ReadOnly, WriteOnly};
+use crate::scheme::{impl_event_scheme, RtcScheme, Scheme};
+use crate::utils::EventListener;
+use crate::DeviceResult;
+
+#[repr(C)]
+struct GoldfishRtcInner {
+    /// Low 32 bits of the time, reading it latches the high 32 bits.
+    time_low: Mmio<u32>,
+    /// High 32 bits of the time.
+    time_high: Mmio<u32>,
+    /// Low 32 bits of the alarm, writing it arms the alarm.
+    alarm_low: Mmio<u32>,
+    /// High 32 bits of the alarm.
+    alarm_high: Mmio<u32>,
+    /// Interrupt enable
+    irq_enabled: Mmio<u32>,
+    /// Disarm the alarm
+    clear_alarm: WriteOnly<Mmio<u32>>,
+    /// Whether the alarm is armed
+    alarm_status: ReadOnly<Mmio<u32>>,
+    /// Clear the interrupt
+    clear_interrupt: WriteOnly<Mmio<u32>>,
+}
+
+impl GoldfishRtcInner {
+    fn read_time(&self) -> u64 {
+        // TIME_LOW must be read first
+        let low = self.time_low.read();
+        let high = self.time_high.read();
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_time(&mut self, ns: u64) {
+        // TIME_HIGH must be written first
+        self.time_high.write((ns >> 32) as u32);
+        self.time_low.write(ns as u32);
+    }
+
+    fn set_alarm(&mut self, ns: u64) {
+        self.alarm_high.write((ns >> 32) as u32);
+        self.alarm_low.write(ns as u32);
+    }
+}
+
+/// Driver for the Goldfish RTC.
+pub struct GoldfishRtc {
+    inner: Mutex<&'static mut GoldfishRtcInner>,
+    listener: EventListener,
+}
+
+impl_event_scheme!(GoldfishRtc);
+
+impl GoldfishRtc {
+    /// Construct a `GoldfishRtc` whose registers start at `base`, with the
+    /// alarm interrupt enabled.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize) -> Self {
+        let rtc: &mut GoldfishRtcInner = Mmio::<u32>::from_base_as(base);
+        rtc.clear_alarm.write(1);
+        rtc.clear_interrupt.write(1);
+        rtc.irq_enabled.write(1);
+        Self {
+            inner: Mutex::new(rtc),
+            listener: EventListener::new(),
+        }
+    }
+}
+
+impl Scheme for GoldfishRtc {
+    fn name(&self) -> &str {
+        "goldfish-rtc"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        {
+            let mut inner = self.inner.lock();
+            inner.clear_interrupt.write(1);
+            trace!(
+                "goldfish-rtc: alarm fired, armed {}",
+                inner.alarm_status.read()
+            );
+        }
+        self.listener.trigger(());
+    }
+}
+
+impl RtcScheme for GoldfishRtc {
+    fn read_time(&self) -> u64 {
+        self.inner.lock().read_time()
+    }
+
+    fn set_time(&self, ns: u64) -> DeviceResult {
+        self.inner.lock().set_time(ns);
+        Ok(())
+    }
+
+    fn set_alarm(&self, ns: u64) -> DeviceResult {
+        self.inner.lock().set_alarm(ns);
+        Ok(())
+    }
+}
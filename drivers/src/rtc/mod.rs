@@ -0,0 +1,7 @@
+//! Real-time clock drivers.
+
+mod goldfish;
+mod pl031;
+
+pub use goldfish::GoldfishRtc;
+pub use pl031::Pl031;
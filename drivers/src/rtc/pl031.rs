@@ -0,0 +1,195 @@
+//! ARM PrimeCell Real Time Clock (PL031).
+//!
+//! Reference: <https://developer.arm.com/documentation/ddi0224/latest>
+use lock::Mutex;
+
+use crate::io::{Io, Mmio, ReadOnly, WriteOnly};
+use crate::scheme::{impl_event_scheme, RtcScheme, Scheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// The peripheral and PrimeCell ID registers are at the end of the 4 KiB page.
+const ID_REGS_END: usize = 0x1000;
+const PERIPH_ID0: usize = 0xfe0 / 4;
+const PERIPH_ID1: usize = 0xfe4 / 4;
+/// The part number in `PeriphID0` and `PeriphID1[3:0]`.
+const PART_NUMBER: u32 = 0x031;
+
+/// The only bit of RTCCR, RTCIMSC, RTCRIS, RTCMIS and RTCICR.
+const ENABLE: u32 = 1;
+
+#[repr(C)]
+struct Pl031Inner {
+    /// Data register, the current time in seconds
+    data: ReadOnly<Mmio<u32>>,
+    /// Match register
+    matched: Mmio<u32>,
+    /// Load register
+    load: Mmio<u32>,
+    /// Control register
+    ctrl: Mmio<u32>,
+    /// Interrupt mask set/clear register
+    int_mask: Mmio<u32>,
+    /// Raw interrupt status register
+    raw_int_sts: ReadOnly<Mmio<u32>>,
+    /// Masked interrupt status register
+    masked_int_sts: ReadOnly<Mmio<u32>>,
+    /// Interrupt clear register
+    int_clear: WriteOnly<Mmio<u32>>,
+}
+
+/// Driver for ARM PL031 RTC.
+pub struct Pl031 {
+    inner: Mutex<&'static mut Pl031Inner>,
+    listener: EventListener,
+}
+
+impl_event_scheme!(Pl031);
+
+impl Pl031 {
+    /// Construct a `Pl031` whose `size` bytes of registers start at `base`,
+    /// and start the RTC if it is stopped.
+    ///
+    /// The part number is checked if the ID registers are within `size`,
+    /// otherwise returns [`DeviceError::NotSupported`] on mismatch.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize, size: usize) -> DeviceResult<Self> {
+        if size >= ID_REGS_END {
+            let regs = Mmio::<u32>::from_base(base);
+            let part =
+                (regs.add(PERIPH_ID1).read() & 0xf) << 8 | regs.add(PERIPH_ID0).read() & 0xff;
+            if part != PART_NUMBER {
+                warn!("pl031: unexpected part number {:#x}", part);
+                return Err(DeviceError::NotSupported);
+            }
+        } else {
+            warn!("pl031: ID registers not mapped, size {:#x}", size);
+        }
+        let rtc: &mut Pl031Inner = Mmio::<u32>::from_base_as(base);
+        rtc.int_mask.write(0);
+        rtc.int_clear.write(ENABLE);
+        if rtc.ctrl.read() & ENABLE == 0 {
+            rtc.ctrl.write(ENABLE);
+        }
+        Ok(Self {
+            inner: Mutex::new(rtc),
+            listener: EventListener::new(),
+        })
+    }
+}
+
+impl Scheme for Pl031 {
+    fn name(&self) -> &str {
+        "rtc-pl031"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        {
+            let mut inner = self.inner.lock();
+            if inner.masked_int_sts.read() & ENABLE == 0 {
+                return;
+            }
+            // the alarm is one-shot
+            inner.int_mask.write(0);
+            inner.int_clear.write(ENABLE);
+        }
+        self.listener.trigger(());
+    }
+}
+
+impl RtcScheme for Pl031 {
+    fn read_time(&self) -> u64 {
+        self.inner.lock().data.read() as u64 * NSEC_PER_SEC
+    }
+
+    fn set_time(&self, ns: u64) -> DeviceResult {
+        let secs = ns / NSEC_PER_SEC;
+        if secs > u32::MAX as u64 {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.inner.lock().load.write(secs as u32);
+        Ok(())
+    }
+
+    /// The alarm has a resolution of 1 second, it fires no earlier than `ns`.
+    fn set_alarm(&self, ns: u64) -> DeviceResult {
+        let secs = (ns + NSEC_PER_SEC - 1) / NSEC_PER_SEC;
+        if secs > u32::MAX as u64 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mut inner = self.inner.lock();
+        inner.matched.write(secs as u32);
+        inner.int_clear.write(ENABLE);
+        inner.int_mask.write(ENABLE);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheme::EventScheme;
+    use crate::utils::test_utils::MockRegisters;
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The 4 KiB register page of a PL031 in the memory.
+    fn mock_registers() -> MockRegisters<u32> {
+        let regs = MockRegisters::new(ID_REGS_END / 4);
+        regs.write(PERIPH_ID0 * 4, 0x31);
+        regs.write(PERIPH_ID1 * 4, 0x10);
+        regs
+    }
+
+    #[test]
+    fn test_probe() {
+        let regs = mock_registers();
+        assert!(unsafe { Pl031::new(regs.base(), 0x1000) }.is_ok());
+        assert_eq!(regs.read(0xc), ENABLE);
+
+        // the ID registers are not checked if not mapped
+        regs.write(0xfe0, 0x41);
+        assert!(unsafe { Pl031::new(regs.base(), 0x1000) }.is_err());
+        assert!(unsafe { Pl031::new(regs.base(), 0x100) }.is_ok());
+    }
+
+    #[test]
+    fn test_time_and_alarm() {
+        let regs = mock_registers();
+        let rtc = unsafe { Pl031::new(regs.base(), 0x1000) }.unwrap();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let cloned = fired.clone();
+        rtc.subscribe(
+            Box::new(move |_| {
+                cloned.fetch_add(1, Ordering::Relaxed);
+            }),
+            false,
+        );
+
+        regs.write(0x0, 1_600_000_000);
+        assert_eq!(rtc.read_time(), 1_600_000_000 * NSEC_PER_SEC);
+        rtc.set_time(1_700_000_000 * NSEC_PER_SEC + 1).unwrap();
+        assert_eq!(regs.read(0x8), 1_700_000_000);
+
+        // rounded up to the next second
+        rtc.set_alarm(1_700_000_005 * NSEC_PER_SEC + 1).unwrap();
+        assert_eq!(regs.read(0x4), 1_700_000_006);
+        assert_eq!(regs.read(0x10), ENABLE);
+
+        // not fired yet
+        rtc.handle_irq(0);
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+
+        regs.write(0x18, ENABLE);
+        regs.write(0x1c, 0);
+        rtc.handle_irq(0);
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+        assert_eq!(regs.read(0x10), 0);
+        assert_eq!(regs.read(0x1c), ENABLE);
+    }
+}
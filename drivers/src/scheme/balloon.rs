@@ -0,0 +1,21 @@
+use super::{event::EventScheme, Scheme};
+use crate::{DeviceResult, PhysAddr};
+
+/// Memory balloons, by which the host reclaims the memory of the guest.
+///
+/// The event is triggered when the host changes the target size.
+pub trait BalloonScheme: Scheme + EventScheme<Event = ()> {
+    /// Returns the number of pages the host wants in the balloon.
+    fn target_pages(&self) -> usize;
+
+    /// Returns the number of pages currently in the balloon.
+    fn actual_pages(&self) -> usize;
+
+    /// Give the pages at `pages` to the host. They must not be accessed until
+    /// deflated.
+    fn inflate(&self, pages: &[PhysAddr]) -> DeviceResult;
+
+    /// Take the pages at `pages` back from the host. They can be used once it
+    /// returns.
+    fn deflate(&self, pages: &[PhysAddr]) -> DeviceResult;
+}
@@ -1,8 +1,53 @@
-use super::Scheme;
-use crate::DeviceResult;
+use alloc::vec::Vec;
 
-pub trait BlockScheme: Scheme {
+use super::{event::EventScheme, Scheme};
+use crate::utils::DmaBuf;
+use crate::{DeviceError, DeviceResult};
+
+/// Identifies a submitted request until it completes.
+pub type RequestId = usize;
+
+/// Block devices.
+///
+/// The event is triggered when submitted requests complete.
+pub trait BlockScheme: Scheme + EventScheme<Event = ()> {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult;
     fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult;
-    fn flush(&self) -> DeviceResult;
+
+    /// Submit a request to read `buf.len()` bytes from `sector` into `buf`,
+    /// without waiting for it to complete.
+    fn submit_read(&self, _sector: u64, _buf: DmaBuf) -> DeviceResult<RequestId> {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Submit a request to write `buf` to `sector`, without waiting for it to
+    /// complete.
+    fn submit_write(&self, _sector: u64, _buf: DmaBuf) -> DeviceResult<RequestId> {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Returns the submitted requests completed since the last call, with
+    /// their results.
+    fn poll_completions(&self) -> Vec<(RequestId, DeviceResult)> {
+        Vec::new()
+    }
+
+    /// Make all completed writes durable.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the device can not guarantee
+    /// it.
+    fn flush(&self) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Whether the device rejects writes.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the capacity of the device in 512-byte sectors, or 0 if it is
+    /// unknown.
+    fn capacity(&self) -> u64 {
+        0
+    }
 }
@@ -1,5 +1,8 @@
 use super::Scheme;
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
+
+/// Width and height of the images of hardware cursors.
+pub const CURSOR_SIZE: u32 = 64;
 
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,7 +17,7 @@ pub enum ColorFormat {
     ARGB8888,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rectangle {
     pub x: u32,
     pub y: u32,
@@ -32,6 +35,8 @@ pub struct DisplayInfo {
     pub width: u32,
     /// visible height
     pub height: u32,
+    /// number of bytes between each row, may be larger than the visible width
+    pub stride: u32,
     /// color encoding format of RGBA
     pub format: ColorFormat,
     /// frame buffer base virtual address
@@ -67,7 +72,34 @@ impl RgbColor {
     }
 }
 
+impl Rectangle {
+    /// Clamp the rectangle to a screen of `width` x `height`.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if the result has zero area.
+    pub fn clamp(&self, width: u32, height: u32) -> DeviceResult<Self> {
+        let left = self.x.min(width);
+        let right = left.saturating_add(self.width).min(width);
+        let top = self.y.min(height);
+        let bottom = top.saturating_add(self.height).min(height);
+        if left == right || top == bottom {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok(Self {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+}
+
 impl ColorFormat {
+    /// Number of bytes of a row of `width` pixels without padding.
+    #[inline]
+    pub const fn row_bytes(self, width: u32) -> u32 {
+        width * self.bytes() as u32
+    }
+
     /// Number of bits per pixel.
     #[inline]
     pub const fn depth(self) -> u8 {
@@ -152,17 +184,18 @@ impl<'a> core::ops::DerefMut for FrameBuffer<'a> {
     }
 }
 
-impl DisplayInfo {
-    /// Number of bytes between each row of the frame buffer.
-    #[inline]
-    pub const fn pitch(self) -> u32 {
-        self.width * self.format.bytes() as u32
-    }
-}
-
 pub trait DisplayScheme: Scheme {
+    /// Returns the resolution, stride, pixel format and the frame buffer.
     fn info(&self) -> DisplayInfo;
 
+    /// Change the resolution to `width` x `height`, the frame buffer may be
+    /// reallocated, so the [`info`](Self::info) must be queried again.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the resolution is fixed.
+    fn set_resolution(&self, _width: u32, _height: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
     /// Returns the framebuffer.
     fn fb(&self) -> FrameBuffer;
 
@@ -170,9 +203,11 @@ pub trait DisplayScheme: Scheme {
     #[inline]
     fn draw_pixel(&self, x: u32, y: u32, color: RgbColor) {
         let info = self.info();
-        let offset = (x + y * info.width) as usize * info.format.bytes() as usize;
-        if offset < info.fb_size {
-            unsafe { self.fb().write_color(offset, color, info.format) };
+        if x < info.width && y < info.height {
+            let offset = (y * info.stride + x * info.format.bytes() as u32) as usize;
+            if offset + info.format.bytes() as usize <= info.fb_size {
+                unsafe { self.fb().write_color(offset, color, info.format) };
+            }
         }
     }
 
@@ -204,6 +239,23 @@ pub trait DisplayScheme: Scheme {
         )
     }
 
+    /// Set the image of the hardware cursor, which is [`CURSOR_SIZE`] x
+    /// [`CURSOR_SIZE`] pixels in [`ColorFormat::ARGB8888`]. The pixel at
+    /// (`hot_x`, `hot_y`) of the image points at the cursor position.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if there is no hardware cursor.
+    fn set_cursor_image(&self, _img: &[u8], _hot_x: u32, _hot_y: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Move the hardware cursor to (`x`, `y`) on screen, without redrawing
+    /// the framebuffer.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if there is no hardware cursor.
+    fn move_cursor(&self, _x: u32, _y: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
     /// Whether need to flush the frambuffer to screen.
     #[inline]
     fn need_flush(&self) -> bool {
@@ -215,4 +267,55 @@ pub trait DisplayScheme: Scheme {
     fn flush(&self) -> DeviceResult {
         Ok(())
     }
+
+    /// Flush the rectangle of `width` x `height` at (`x`, `y`) to screen,
+    /// clamped to the screen. Flushes the whole framebuffer by default.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if the rectangle has zero area.
+    fn flush_rect(&self, x: u32, y: u32, width: u32, height: u32) -> DeviceResult {
+        let info = self.info();
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+        .clamp(info.width, info.height)?;
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rect_clamp() {
+        let rect = |x, y, width, height| Rectangle {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(
+            rect(10, 20, 30, 40).clamp(800, 600).unwrap(),
+            rect(10, 20, 30, 40)
+        );
+        assert_eq!(
+            rect(780, 590, 100, 100).clamp(800, 600).unwrap(),
+            rect(780, 590, 20, 10)
+        );
+        assert_eq!(
+            rect(0, 0, u32::MAX, u32::MAX).clamp(800, 600).unwrap(),
+            rect(0, 0, 800, 600)
+        );
+        assert!(matches!(
+            rect(10, 20, 0, 40).clamp(800, 600),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            rect(800, 20, 10, 10).clamp(800, 600),
+            Err(DeviceError::InvalidParam)
+        ));
+    }
 }
@@ -18,6 +18,13 @@ macro_rules! impl_event_scheme {
     ($struct:ident<'_> $(, $event_ty:ty)?) => {
         impl_event_scheme!(@impl_base $struct<'_> $(, $event_ty)?);
     };
+    ($struct:ident < $($types:ident),* >, $event_ty:ty where $($preds:tt)+) => {
+        impl < $($types),* > $crate::scheme::EventScheme for $struct < $($types),* >
+            where $($preds)+
+        {
+            impl_event_scheme!(@impl_body, $event_ty);
+        }
+    };
     ($struct:ident < $($types:ident),* > $(where $($preds:tt)+)? $(, $event_ty:ty)?) => {
         impl_event_scheme!(@impl_base $struct < $($types),* > $(where $($preds)+)? $(, $event_ty)?);
     };
@@ -0,0 +1,53 @@
+use super::{irq::IrqHandler, Scheme};
+use crate::DeviceResult;
+
+/// Direction of a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioDirection {
+    Input,
+    Output,
+}
+
+/// The internal pull resistor of a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioPull {
+    None,
+    Up,
+    Down,
+}
+
+/// The edges of the level to trigger the interrupt of a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// GPIO controllers, whose pins are numbered from 0 in the way of the
+/// controller.
+pub trait GpioScheme: Scheme {
+    /// Returns the number of pins, some of them may not exist.
+    fn num_pins(&self) -> usize;
+
+    /// Set `pin` as a GPIO input or output.
+    fn set_direction(&self, pin: usize, dir: GpioDirection) -> DeviceResult;
+
+    /// Returns the level of `pin`, `true` for high.
+    fn read(&self, pin: usize) -> DeviceResult<bool>;
+
+    /// Drive `pin` to the level, `true` for high.
+    fn write(&self, pin: usize, level: bool) -> DeviceResult;
+
+    /// Configure the pull resistor of `pin`.
+    fn set_pull(&self, pin: usize, pull: GpioPull) -> DeviceResult;
+
+    /// Set `pin` as an interrupt input, and call `handler` on the `edge`.
+    ///
+    /// Returns [`DeviceError::AlreadyExists`](crate::DeviceError::AlreadyExists)
+    /// if the interrupt of `pin` is requested.
+    fn request_irq(&self, pin: usize, edge: GpioEdge, handler: IrqHandler) -> DeviceResult;
+
+    /// Disable the interrupt of `pin`, and remove its handler.
+    fn free_irq(&self, pin: usize) -> DeviceResult;
+}
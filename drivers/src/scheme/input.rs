@@ -1,7 +1,10 @@
+use alloc::string::String;
 use core::fmt;
 
+use bitflags::bitflags;
+
 use super::{event::EventScheme, Scheme};
-use crate::input::input_event_codes::ev::*;
+use crate::input::input_event_codes::{ev::*, led::*};
 
 numeric_enum_macro::numeric_enum! {
     #[repr(u16)]
@@ -47,6 +50,29 @@ pub struct InputEvent {
     pub value: i32,
 }
 
+/// An input event decoded from the raw [`InputEvent`]s of a frame, which ends
+/// with `SYN_REPORT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypedInputEvent {
+    /// A key of keyboards is pressed, released or repeated.
+    Key { code: u16, pressed: bool },
+    /// The pointer moved by `(dx, dy)`, e.g. by a mouse.
+    RelMotion { dx: i32, dy: i32 },
+    /// The pointer moved to `(x, y)`, e.g. by a tablet.
+    AbsMotion { x: i32, y: i32 },
+    /// A button of mice, joysticks or gamepads is pressed or released.
+    Button { code: u16, pressed: bool },
+}
+
+bitflags! {
+    /// LEDs of keyboards.
+    pub struct KeyLeds: u32 {
+        const NUM = 1 << LED_NUML;
+        const CAPS = 1 << LED_CAPSL;
+        const SCROLL = 1 << LED_SCROLLL;
+    }
+}
+
 #[repr(u16)]
 #[derive(Clone, Copy, Debug)]
 pub enum CapabilityType {
@@ -128,4 +154,18 @@ impl fmt::Debug for InputCapability {
 pub trait InputScheme: Scheme + EventScheme<Event = InputEvent> {
     /// Returns the capability bitmap of the specific kind of event.
     fn capability(&self, cap_type: CapabilityType) -> InputCapability;
+
+    /// Take the oldest decoded event, or returns `None` if there is no one.
+    ///
+    /// The default implementation always returns `None`, the raw events are
+    /// only delivered to the subscribers.
+    fn poll_event(&self) -> Option<TypedInputEvent> {
+        None
+    }
+
+    /// Returns the name of the device reported by itself. The default
+    /// implementation returns the [`Scheme::name`].
+    fn device_name(&self) -> String {
+        String::from(self.name())
+    }
 }
@@ -1,20 +1,24 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::ops::Range;
 
 use super::Scheme;
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
 
 /// A type alias for
 pub type IrqHandler = Box<dyn Fn() + Send + Sync>;
 
-#[derive(Debug)]
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IrqTriggerMode {
     Edge,
     Level,
 }
 
-#[derive(Debug)]
+/// Polarity of an interrupt line, i.e. the rising edge or the high level is
+/// active for [`IrqPolarity::ActiveHigh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IrqPolarity {
     ActiveHigh,
     ActiveLow,
@@ -32,8 +36,11 @@ pub trait IrqScheme: Scheme {
 
     /// Configure the specified interrupt vector. If it is invoked, it must be
     /// invoked prior to interrupt registration.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the controller can not sense
+    /// the interrupt in this way.
     fn configure(&self, _irq_num: usize, _tm: IrqTriggerMode, _pol: IrqPolarity) -> DeviceResult {
-        unimplemented!()
+        Err(DeviceError::NotSupported)
     }
 
     /// Add an interrupt handler to an IRQ.
@@ -71,6 +78,15 @@ pub trait IrqScheme: Scheme {
         unimplemented!()
     }
 
+    /// Returns how many times each registered IRQ has fired, as `(irq_num,
+    /// count)` pairs.
+    fn irq_stats(&self) -> Vec<(usize, u64)> {
+        Vec::new()
+    }
+
+    /// Reset the counters of [`IrqScheme::irq_stats`].
+    fn reset_stats(&self) {}
+
     /// Init irq for current cpu.
     /// Some IRQ hardware requires per-CPU initialization.
     fn init_hart(&self) {
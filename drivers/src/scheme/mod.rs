@@ -5,12 +5,19 @@
 //!
 //! The [`Scheme`] trait is suitable for any architecture.
 
+pub(super) mod balloon;
 pub(super) mod block;
 pub(super) mod display;
+pub(super) mod gpio;
 pub(super) mod input;
 pub(super) mod irq;
 pub(super) mod net;
+pub(super) mod power;
+pub(super) mod rng;
+pub(super) mod rtc;
+pub(super) mod timer;
 pub(super) mod uart;
+pub(super) mod vsock;
 
 #[macro_use]
 pub(super) mod event;
@@ -18,13 +25,22 @@ pub(super) use impl_event_scheme;
 
 use alloc::sync::Arc;
 
-pub use block::BlockScheme;
+use crate::DeviceResult;
+
+pub use balloon::BalloonScheme;
+pub use block::{BlockScheme, RequestId};
 pub use display::DisplayScheme;
 pub use event::EventScheme;
+pub use gpio::GpioScheme;
 pub use input::InputScheme;
 pub use irq::IrqScheme;
-pub use net::NetScheme;
+pub use net::{LinkStatus, NetScheme};
+pub use power::PowerScheme;
+pub use rng::RngScheme;
+pub use rtc::RtcScheme;
+pub use timer::TimerScheme;
 pub use uart::UartScheme;
+pub use vsock::{VsockConnId, VsockScheme, VSOCK_HOST_CID};
 
 /// Common of all device drivers.
 ///
@@ -35,6 +51,13 @@ pub trait Scheme: SchemeUpcast + Send + Sync {
 
     /// Handles an interrupt.
     fn handle_irq(&self, _irq_num: usize) {}
+
+    /// Quiesces the device before it is removed, e.g. stops its queues, so
+    /// that it no longer accesses memory or raises interrupts. Its IRQs and
+    /// MMIO regions are released by the owner after that.
+    fn shutdown(&self) -> DeviceResult {
+        Ok(())
+    }
 }
 
 /// Used to convert a concrete type pointer to a general [`Scheme`] pointer.
@@ -1,13 +1,55 @@
-use super::Scheme;
+use super::{event::EventScheme, Scheme};
 use crate::DeviceResult;
 use alloc::string::String;
 use alloc::vec::Vec;
 use smoltcp::wire::{EthernetAddress, IpCidr};
 
-pub trait NetScheme: Scheme {
+/// Link state of a network interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkStatus {
+    /// Whether the link is up.
+    pub up: bool,
+    /// Link speed in Mbps, or 0 if it is unknown.
+    pub speed_mbps: u32,
+    /// Whether the link is full duplex.
+    pub full_duplex: bool,
+}
+
+/// Network interfaces which send and receive Ethernet frames.
+///
+/// The event is triggered when frames are received or the link state is
+/// changed.
+pub trait NetScheme: Scheme + EventScheme<Event = ()> {
+    /// Receive a frame into `buf`, and return the length of the frame.
+    ///
+    /// Returns [`DeviceError::Again`](crate::DeviceError::Again) if no frame
+    /// is available, or [`DeviceError::InvalidParam`](crate::DeviceError::InvalidParam)
+    /// if the frame is larger than `buf`. The frame is never truncated.
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize>;
-    fn send(&self, buf: &[u8]) -> DeviceResult<usize>;
+
+    /// Send the whole `frame`.
+    ///
+    /// Returns [`DeviceError::Again`](crate::DeviceError::Again) if the TX
+    /// ring is full.
+    fn send(&self, frame: &[u8]) -> DeviceResult;
+
     fn get_mac(&self) -> EthernetAddress;
+
+    /// Returns the MAC address of the interface.
+    fn mac_address(&self) -> [u8; 6] {
+        self.get_mac().0
+    }
+
+    /// Returns the link state. The default implementation reports the link
+    /// is always up, with the unknown speed.
+    fn link_status(&self) -> LinkStatus {
+        LinkStatus {
+            up: true,
+            speed_mbps: 0,
+            full_duplex: true,
+        }
+    }
+
     fn get_ifname(&self) -> String;
     fn get_ip_address(&self) -> Vec<IpCidr>;
     fn poll(&self) -> DeviceResult;
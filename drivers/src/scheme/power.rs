@@ -0,0 +1,15 @@
+use super::Scheme;
+use crate::{DeviceError, DeviceResult};
+
+/// Devices to power off or reset the whole machine.
+pub trait PowerScheme: Scheme {
+    /// Power off the machine. Returns only if failed.
+    fn shutdown(&self) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Reset the machine. Returns only if failed.
+    fn reboot(&self) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+}
@@ -0,0 +1,9 @@
+use super::Scheme;
+use crate::DeviceResult;
+
+/// Sources of hardware randomness.
+pub trait RngScheme: Scheme {
+    /// Fill `buf` with random bytes, and returns the number of bytes filled,
+    /// which may be less than the length of `buf`.
+    fn fill(&self, buf: &mut [u8]) -> DeviceResult<usize>;
+}
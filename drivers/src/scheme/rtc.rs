@@ -0,0 +1,20 @@
+use super::{event::EventScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+/// Real-time clocks, which keep the wall-clock time.
+///
+/// The event is triggered when the alarm fires.
+pub trait RtcScheme: Scheme + EventScheme<Event = ()> {
+    /// Returns the current time, in nanoseconds since the UNIX epoch.
+    fn read_time(&self) -> u64;
+
+    /// Set the current time, in nanoseconds since the UNIX epoch.
+    fn set_time(&self, _ns: u64) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Fire the alarm at the time `ns`, in nanoseconds since the UNIX epoch.
+    fn set_alarm(&self, _ns: u64) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+}
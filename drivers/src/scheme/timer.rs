@@ -0,0 +1,23 @@
+use super::Scheme;
+use crate::{DeviceError, DeviceResult};
+
+/// Timers with a per-CPU deadline, which may also send inter-processor
+/// interrupts (IPIs).
+pub trait TimerScheme: Scheme {
+    /// Returns the current time, in ticks of the timebase.
+    fn read_time(&self) -> u64;
+
+    /// Fire the timer interrupt of the CPU `cpu_id` once the time reaches
+    /// `deadline`, in ticks of the timebase.
+    fn set_timer(&self, cpu_id: usize, deadline: u64) -> DeviceResult;
+
+    /// Send a software interrupt to the CPU `cpu_id`.
+    fn send_ipi(&self, _cpu_id: usize) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Clear the pending software interrupt of the CPU `cpu_id`.
+    fn clear_ipi(&self, _cpu_id: usize) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+}
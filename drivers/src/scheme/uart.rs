@@ -1,13 +1,301 @@
 use super::{event::EventScheme, Scheme};
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
+use bitflags::bitflags;
 
-pub trait UartScheme: Scheme + EventScheme<Event = ()> {
+/// Flow control mode of the serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// No flow control.
+    None,
+    /// Hardware flow control by the RTS/CTS lines.
+    RtsCts,
+}
+
+/// Parity mode of the serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    /// No parity bit.
+    None,
+    /// The parity bit makes the number of 1s odd.
+    Odd,
+    /// The parity bit makes the number of 1s even.
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+/// Number of stop bits of the serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    /// 1 stop bit.
+    One,
+    /// 2 stop bits (1.5 stop bits if the word length is 5 bits).
+    Two,
+}
+
+/// Character frame format of the serial line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    /// Number of data bits, from 5 to 8.
+    pub data_bits: u8,
+    /// Parity mode.
+    pub parity: Parity,
+    /// Number of stop bits.
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    /// 8 data bits, no parity, 1 stop bit (8N1).
+    fn default() -> Self {
+        Self {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+/// Configuration of the serial line, as the termios settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    /// Baud rate.
+    pub baud: u32,
+    /// Number of data bits, from 5 to 8.
+    pub data_bits: u8,
+    /// Parity mode.
+    pub parity: Parity,
+    /// Number of stop bits.
+    pub stop_bits: StopBits,
+    /// Flow control mode.
+    pub flow_control: FlowControl,
+}
+
+impl UartConfig {
+    /// Construct with the baud rate and the character frame format, without
+    /// flow control.
+    pub fn new(baud: u32, line: LineConfig) -> Self {
+        Self {
+            baud,
+            data_bits: line.data_bits,
+            parity: line.parity,
+            stop_bits: line.stop_bits,
+            flow_control: FlowControl::None,
+        }
+    }
+
+    /// Set the flow control mode.
+    pub fn with_flow_control(mut self, mode: FlowControl) -> Self {
+        self.flow_control = mode;
+        self
+    }
+
+    /// Returns the character frame format.
+    pub fn line(&self) -> LineConfig {
+        LineConfig {
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+        }
+    }
+}
+
+/// Events of the serial line, as the payload of [`EventScheme`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartEvent {
+    /// Data received.
+    Received,
+    /// A break condition is detected on the line.
+    Break,
+    /// A parity, framing or overrun error occurred.
+    LineError,
+    /// A transmission requested in the background (e.g. by DMA) is finished.
+    Sent,
+    /// Received bytes are dropped since the software receive buffer is full.
+    Overflow,
+    /// The TX FIFO is empty and can accept more bytes, raised only if the TX
+    /// interrupt is enabled by [`UartScheme::set_tx_irq`].
+    TxReady,
+}
+
+bitflags! {
+    /// Errors detected on the serial line, with the same bits as the LSR of
+    /// 16550.
+    pub struct LineErrors: u8 {
+        /// A received byte is lost since the RX FIFO is full.
+        const OVERRUN = 1 << 1;
+        /// A received byte has a wrong parity bit.
+        const PARITY = 1 << 2;
+        /// A received byte has no valid stop bit.
+        const FRAMING = 1 << 3;
+        /// A break condition is detected.
+        const BREAK = 1 << 4;
+    }
+}
+
+/// Numbers of the line errors, as returned by [`UartScheme::take_errors`].
+///
+/// Each error is counted once each time the UART is polled and reports it,
+/// so the counts are the numbers of polls in which the errors are seen, not
+/// the numbers of the erroneous bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineErrorCounts {
+    /// Polls with overrun errors of the RX FIFO.
+    pub overrun: u64,
+    /// Polls with parity errors.
+    pub parity: u64,
+    /// Polls with framing errors.
+    pub framing: u64,
+    /// Polls with break conditions.
+    pub brk: u64,
+}
+
+impl LineErrorCounts {
+    /// Count the errors reported by one poll.
+    pub fn add(&mut self, errors: LineErrors) {
+        let count = |flag| errors.contains(flag) as u64;
+        self.overrun += count(LineErrors::OVERRUN);
+        self.parity += count(LineErrors::PARITY);
+        self.framing += count(LineErrors::FRAMING);
+        self.brk += count(LineErrors::BREAK);
+    }
+}
+
+pub trait UartScheme: Scheme + EventScheme<Event = UartEvent> {
     fn try_recv(&self) -> DeviceResult<Option<u8>>;
     fn send(&self, ch: u8) -> DeviceResult;
+
+    /// Send a byte if the transmitter can accept it now, without waiting.
+    /// Returns `false` if the TX FIFO is full.
+    ///
+    /// The default implementation falls back to the blocking [`send`](Self::send).
+    fn try_send(&self, ch: u8) -> DeviceResult<bool> {
+        self.send(ch).map(|_| true)
+    }
+
     fn write_str(&self, s: &str) -> DeviceResult {
         for c in s.bytes() {
             self.send(c)?;
         }
         Ok(())
     }
+
+    /// Wait until all bytes accepted by [`write_str`](Self::write_str) and
+    /// others are pushed into the hardware, by polling. Useful if the
+    /// interrupts are disabled, e.g. on panic.
+    ///
+    /// The default implementation does nothing, as the bytes are sent
+    /// synchronously.
+    fn flush(&self) -> DeviceResult {
+        Ok(())
+    }
+
+    /// Write as many bytes of `buf` as the TX FIFO can currently hold, and
+    /// return the number of bytes written, without blocking.
+    ///
+    /// The default implementation sends all bytes one by one.
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        for &c in buf {
+            self.send(c)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Send the bytes of `buf`, and return the number of bytes sent, which may
+    /// be less than `buf.len()` if no more bytes can be accepted for now, so
+    /// the callers should send the rest again.
+    ///
+    /// The default implementation sends all bytes one by one.
+    fn send_slice(&self, buf: &[u8]) -> DeviceResult<usize> {
+        for &c in buf {
+            self.send(c)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Receive the available bytes into `buf` without blocking, and return the
+    /// number of bytes received. Zero means there is no data.
+    ///
+    /// The default implementation receives bytes one by one.
+    fn recv_slice(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            match self.try_recv()? {
+                Some(c) => *b = c,
+                None => return Ok(i),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Enable or disable the interrupt raised when the TX FIFO is empty,
+    /// which is reported as [`UartEvent::TxReady`].
+    fn set_tx_irq(&self, _enabled: bool) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Set the character frame format of the serial line.
+    fn configure_line(&self, _cfg: LineConfig) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Hold the line in the break condition for at least `duration_us`
+    /// microseconds.
+    fn send_break(&self, _duration_us: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Returns the line errors detected since the last call, and clear them.
+    ///
+    /// This does not consume the received data.
+    fn line_errors(&self) -> LineErrors {
+        LineErrors::empty()
+    }
+
+    /// Returns the numbers of the line errors seen since the last call, and
+    /// reset them.
+    ///
+    /// The default implementation returns all zeros, as the errors are not
+    /// counted.
+    fn take_errors(&self) -> LineErrorCounts {
+        LineErrorCounts::default()
+    }
+
+    /// Set the baud rate of the serial line.
+    fn set_baud_rate(&self, _baud: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Returns the current baud rate, or `None` if it is unknown.
+    fn baud_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Set the baud rate, the character frame format and the flow control at
+    /// once. The bytes being sent are drained before the change.
+    fn set_config(&self, _config: UartConfig) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Returns the current baud rate, character frame format and flow control.
+    fn config(&self) -> DeviceResult<UartConfig> {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Assert or de-assert RTS to tell the remote side whether we are ready to
+    /// receive, if the hardware flow control is enabled.
+    fn set_rts(&self, _asserted: bool) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Enable or disable the hardware flow control.
+    fn set_flow_control(&self, _mode: FlowControl) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Returns whether the remote side is ready to receive. It is always
+    /// `true` if the hardware flow control is disabled.
+    fn cts_active(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,39 @@
+use super::{event::EventScheme, Scheme};
+use crate::DeviceResult;
+
+/// The context ID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// Identifies a connection by its local port.
+pub type VsockConnId = u32;
+
+/// Stream sockets between the guest and the host, addressed by context IDs
+/// (CIDs) and ports.
+///
+/// The event is triggered when a connection receives data or changes its
+/// state.
+pub trait VsockScheme: Scheme + EventScheme<Event = ()> {
+    /// Returns the context ID of this guest.
+    fn guest_cid(&self) -> u64;
+
+    /// Connect to `port` of the peer `cid`, and block until the peer accepts
+    /// or refuses it, or the request times out.
+    fn connect(&self, cid: u64, port: u32) -> DeviceResult<VsockConnId>;
+
+    /// Send as much of `buf` as the peer can currently take, and returns the
+    /// number of bytes sent.
+    ///
+    /// Returns [`DeviceError::NotReady`](crate::DeviceError::NotReady) if the
+    /// receive buffer of the peer is full.
+    fn send(&self, conn: VsockConnId, buf: &[u8]) -> DeviceResult<usize>;
+
+    /// Receive the data to `buf` without blocking, and returns the number of
+    /// bytes received, or 0 if the peer will not send any more.
+    ///
+    /// Returns [`DeviceError::NotReady`](crate::DeviceError::NotReady) if
+    /// there is no data yet.
+    fn recv(&self, conn: VsockConnId, buf: &mut [u8]) -> DeviceResult<usize>;
+
+    /// Shut down the connection in both directions.
+    fn close(&self, conn: VsockConnId) -> DeviceResult;
+}
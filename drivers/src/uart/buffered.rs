@@ -1,34 +1,208 @@
 use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 
 use lock::Mutex;
 
+use crate::scheme::uart::{
+    FlowControl, LineConfig, LineErrorCounts, LineErrors, UartConfig, UartEvent,
+};
 use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
 use crate::utils::EventListener;
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
 
-const BUF_CAPACITY: usize = 4096;
+use super::wait_for;
+
+const DEFAULT_BUF_CAPACITY: usize = 256;
+
+/// Statistics of the RX buffer of a [`BufferedUart`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferedUartStats {
+    /// Number of bytes received from the UART, including the dropped ones.
+    pub received: u64,
+    /// Number of received bytes dropped since the RX buffer is full.
+    pub dropped: u64,
+    /// The maximum number of bytes ever held in the RX buffer.
+    pub high_water: usize,
+}
 
 pub struct BufferedUart {
     inner: Arc<dyn UartScheme>,
     buf: Mutex<VecDeque<u8>>,
-    listener: EventListener,
+    capacity: usize,
+    /// Bytes waiting for the TX FIFO, used only if the TX interrupt is
+    /// supported by `inner`.
+    tx_buf: Mutex<VecDeque<u8>>,
+    tx_capacity: usize,
+    tx_irq: bool,
+    listener: EventListener<UartEvent>,
     name: String,
+    rts_asserted: AtomicBool,
+    received_count: AtomicU64,
+    overrun_count: AtomicU64,
+    high_water: AtomicUsize,
+    /// Line errors taken from `inner`, until taken by `line_errors()`.
+    line_errors: AtomicU8,
+    error_counts: Mutex<LineErrorCounts>,
 }
 
-impl_event_scheme!(BufferedUart);
+impl_event_scheme!(BufferedUart, UartEvent);
 
 impl BufferedUart {
     pub fn new(uart: Arc<dyn UartScheme>) -> Arc<Self> {
+        Self::with_capacity(uart, DEFAULT_BUF_CAPACITY, DEFAULT_BUF_CAPACITY)
+    }
+
+    /// Construct a `BufferedUart` whose RX buffer holds at most `rx_cap` bytes,
+    /// and TX buffer holds at most `tx_cap` bytes.
+    ///
+    /// If the TX interrupt of `uart` is supported, bytes to send are queued
+    /// in the TX buffer and pushed into the TX FIFO in the interrupt handler.
+    /// Otherwise they are sent by `uart` directly.
+    pub fn with_capacity(uart: Arc<dyn UartScheme>, rx_cap: usize, tx_cap: usize) -> Arc<Self> {
+        let tx_irq = uart.set_tx_irq(false).is_ok();
         let ret = Arc::new(Self {
             inner: uart.clone(),
             name: alloc::format!("{}-buffered", uart.name()),
-            buf: Mutex::new(VecDeque::with_capacity(BUF_CAPACITY)),
+            buf: Mutex::new(VecDeque::with_capacity(rx_cap)),
+            capacity: rx_cap,
+            tx_buf: Mutex::new(VecDeque::with_capacity(if tx_irq { tx_cap } else { 0 })),
+            tx_capacity: tx_cap,
+            tx_irq,
             listener: EventListener::new(),
+            rts_asserted: AtomicBool::new(true),
+            received_count: AtomicU64::new(0),
+            overrun_count: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+            line_errors: AtomicU8::new(0),
+            error_counts: Mutex::new(LineErrorCounts::default()),
         });
         let cloned = ret.clone();
-        uart.subscribe(Box::new(move |_| cloned.handle_irq(0)), false);
+        uart.subscribe(
+            Box::new(move |event| match event {
+                UartEvent::Received => cloned.handle_irq(0),
+                UartEvent::TxReady => {
+                    cloned.flush_tx();
+                }
+                _ => {
+                    cloned.listener.trigger(*event);
+                    // The erroneous byte may be in the RX FIFO as well
+                    cloned.handle_irq(0);
+                }
+            }),
+            false,
+        );
         ret
     }
+
+    /// Returns the number of received bytes dropped since the RX buffer is
+    /// full.
+    pub fn overrun_count(&self) -> u64 {
+        self.overrun_count.load(Ordering::Relaxed)
+    }
+
+    /// Reset the overrun counter.
+    pub fn clear_overrun(&self) {
+        self.overrun_count.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics of the RX buffer.
+    pub fn stats(&self) -> BufferedUartStats {
+        BufferedUartStats {
+            received: self.received_count.load(Ordering::Relaxed),
+            dropped: self.overrun_count.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Take the line errors from the wrapped UART, and count them.
+    fn record_errors(&self) {
+        let errors = self.inner.line_errors();
+        if !errors.is_empty() {
+            self.line_errors.fetch_or(errors.bits(), Ordering::Relaxed);
+            self.error_counts.lock().add(errors);
+        }
+    }
+
+    /// Write as many bytes of `buf` as possible without blocking, and return
+    /// the number of bytes written, which is 0 if the TX buffer is full.
+    ///
+    /// The bytes are queued in the TX buffer and sent in the background if the
+    /// TX interrupt is supported, otherwise they are pushed into the TX FIFO of
+    /// the wrapped UART directly.
+    pub fn try_write(&self, buf: &[u8]) -> usize {
+        self.enqueue(buf).unwrap_or(0)
+    }
+
+    /// Write as many bytes of `buf` as possible without blocking, either into
+    /// the TX buffer or the TX FIFO of the wrapped UART.
+    ///
+    /// Returns [`DeviceError::Again`] if the TX buffer is full.
+    fn enqueue(&self, buf: &[u8]) -> DeviceResult<usize> {
+        if !self.tx_irq {
+            return self.inner.write_bytes(buf);
+        }
+        match self.queue_tx(buf) {
+            0 if !buf.is_empty() => Err(DeviceError::Again),
+            n => Ok(n),
+        }
+    }
+
+    /// Push as many bytes from the TX buffer into the TX FIFO as it can hold,
+    /// and disable the TX interrupt once the TX buffer is empty.
+    ///
+    /// Nothing is pushed while the remote side is not ready to receive, the
+    /// TX interrupt is raised again once it is ready. Returns `true` if bytes
+    /// are left in the TX buffer for this reason.
+    fn flush_tx(&self) -> bool {
+        let mut tx_buf = self.tx_buf.lock();
+        let mut blocked = false;
+        while !tx_buf.is_empty() {
+            if !self.inner.cts_active() {
+                blocked = true;
+                break;
+            }
+            match self.inner.write_bytes(tx_buf.as_slices().0) {
+                Ok(n) if n > 0 => {
+                    tx_buf.drain(..n);
+                }
+                _ => break,
+            }
+        }
+        self.inner.set_tx_irq(!tx_buf.is_empty()).ok();
+        blocked
+    }
+
+    /// Queue as many bytes of `buf` as the TX buffer can hold, then try to
+    /// send them. Returns the number of bytes queued.
+    fn queue_tx(&self, buf: &[u8]) -> usize {
+        let n = {
+            let mut tx_buf = self.tx_buf.lock();
+            let n = buf.len().min(self.tx_capacity - tx_buf.len());
+            tx_buf.extend(&buf[..n]);
+            n
+        };
+        if n > 0 {
+            self.flush_tx();
+        }
+        n
+    }
+
+    /// Assert RTS again if the RX buffer is drained down to the low watermark.
+    fn update_rts(&self, len: usize) {
+        if len <= self.low_watermark() && !self.rts_asserted.swap(true, Ordering::Relaxed) {
+            self.inner.set_rts(true).ok();
+        }
+    }
+
+    /// De-assert RTS when the buffer is filled up to this level.
+    fn high_watermark(&self) -> usize {
+        self.capacity * 3 / 4
+    }
+
+    /// Assert RTS again when the buffer is drained down to this level.
+    fn low_watermark(&self) -> usize {
+        self.capacity / 4
+    }
 }
 
 impl Scheme for BufferedUart {
@@ -37,27 +211,387 @@ impl Scheme for BufferedUart {
     }
 
     fn handle_irq(&self, _unused: usize) {
+        let mut overflow = false;
         while let Some(c) = self.inner.try_recv().unwrap_or(None) {
+            self.received_count.fetch_add(1, Ordering::Relaxed);
             let mut buf = self.buf.lock();
-            if buf.len() < BUF_CAPACITY {
+            if buf.len() < self.capacity {
                 let c = if c == b'\r' { b'\n' } else { c };
                 buf.push_back(c);
+                self.high_water.fetch_max(buf.len(), Ordering::Relaxed);
+            } else {
+                self.overrun_count.fetch_add(1, Ordering::Relaxed);
+                overflow = true;
             }
         }
-        if self.buf.lock().len() > 0 {
-            self.listener.trigger(());
+        self.record_errors();
+        let len = self.buf.lock().len();
+        if len >= self.high_watermark() && self.rts_asserted.swap(false, Ordering::Relaxed) {
+            self.inner.set_rts(false).ok();
+        }
+        if overflow {
+            self.listener.trigger(UartEvent::Overflow);
+        }
+        if len > 0 {
+            self.listener.trigger(UartEvent::Received);
         }
     }
 }
 
 impl UartScheme for BufferedUart {
     fn try_recv(&self) -> DeviceResult<Option<u8>> {
-        Ok(self.buf.lock().pop_front())
+        let mut buf = self.buf.lock();
+        let c = buf.pop_front();
+        self.update_rts(buf.len());
+        Ok(c)
+    }
+    fn recv_slice(&self, dst: &mut [u8]) -> DeviceResult<usize> {
+        let mut buf = self.buf.lock();
+        let n = dst.len().min(buf.len());
+        let (front, back) = buf.as_slices();
+        let m = n.min(front.len());
+        dst[..m].copy_from_slice(&front[..m]);
+        dst[m..n].copy_from_slice(&back[..n - m]);
+        buf.drain(..n);
+        self.update_rts(buf.len());
+        Ok(n)
     }
+    /// Returns [`DeviceError::Again`] if the TX buffer is full.
     fn send(&self, ch: u8) -> DeviceResult {
-        self.inner.send(ch)
+        if !self.tx_irq {
+            return self.inner.send(ch);
+        }
+        match self.queue_tx(&[ch]) {
+            0 => Err(DeviceError::Again),
+            _ => Ok(()),
+        }
     }
+    /// Returns `false` if the TX buffer is full.
+    fn try_send(&self, ch: u8) -> DeviceResult<bool> {
+        if !self.tx_irq {
+            return self.inner.try_send(ch);
+        }
+        Ok(self.queue_tx(&[ch]) > 0)
+    }
+    /// Blocks until all bytes are queued, as the console can not retry.
+    ///
+    /// Returns [`DeviceError::Again`] if the remote side is not ready to
+    /// receive, or [`DeviceError::NotReady`] if the TX buffer stops draining,
+    /// and the rest of `s` is not queued.
     fn write_str(&self, s: &str) -> DeviceResult {
-        self.inner.write_str(s)
+        if !self.tx_irq {
+            return self.inner.write_str(s);
+        }
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let mut n = 0;
+            let mut blocked = false;
+            wait_for(|| {
+                n = self.queue_tx(bytes);
+                if n == 0 {
+                    // The interrupt may be disabled, drain the TX buffer by polling
+                    blocked = self.flush_tx();
+                }
+                n > 0 || blocked
+            })?;
+            if blocked {
+                return Err(DeviceError::Again);
+            }
+            bytes = &bytes[n..];
+        }
+        Ok(())
+    }
+    /// Returns [`DeviceError::Again`] if the TX buffer is full.
+    fn send_slice(&self, buf: &[u8]) -> DeviceResult<usize> {
+        self.enqueue(buf)
+    }
+    /// Returns [`DeviceError::Again`] if the TX buffer is full.
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        self.enqueue(buf)
+    }
+    /// Wait until all bytes in the TX buffer are pushed into the TX FIFO.
+    ///
+    /// Returns [`DeviceError::Again`] if the remote side is not ready to
+    /// receive, or [`DeviceError::NotReady`] if the TX buffer stops draining.
+    fn flush(&self) -> DeviceResult {
+        let mut blocked = false;
+        wait_for(|| {
+            blocked = self.flush_tx();
+            blocked || self.tx_buf.lock().is_empty()
+        })?;
+        if blocked {
+            return Err(DeviceError::Again);
+        }
+        self.inner.flush()
+    }
+    fn configure_line(&self, cfg: LineConfig) -> DeviceResult {
+        self.flush()?;
+        self.inner.configure_line(cfg)
+    }
+    fn line_errors(&self) -> LineErrors {
+        self.record_errors();
+        LineErrors::from_bits_truncate(self.line_errors.swap(0, Ordering::Relaxed))
+    }
+    /// The wrapped UART is polled for the errors on each interrupt.
+    fn take_errors(&self) -> LineErrorCounts {
+        self.record_errors();
+        core::mem::take(&mut *self.error_counts.lock())
+    }
+    fn send_break(&self, duration_us: u32) -> DeviceResult {
+        self.flush()?;
+        self.inner.send_break(duration_us)
+    }
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        self.flush()?;
+        self.inner.set_baud_rate(baud)
+    }
+    fn baud_rate(&self) -> Option<u32> {
+        self.inner.baud_rate()
+    }
+    fn set_config(&self, config: UartConfig) -> DeviceResult {
+        self.flush()?;
+        self.inner.set_config(config)
+    }
+    fn config(&self) -> DeviceResult<UartConfig> {
+        self.inner.config()
+    }
+    fn set_rts(&self, asserted: bool) -> DeviceResult {
+        self.inner.set_rts(asserted)
+    }
+    fn set_flow_control(&self, mode: FlowControl) -> DeviceResult {
+        self.flush()?;
+        self.inner.set_flow_control(mode)
+    }
+    fn cts_active(&self) -> bool {
+        self.inner.cts_active()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheme::EventScheme;
+    use alloc::vec::Vec;
+
+    const FIFO_DEPTH: usize = 4;
+
+    /// A UART whose TX FIFO is moved onto the line only by `drain`, and
+    /// bytes are received only by `receive`.
+    struct FakeUart {
+        listener: EventListener<UartEvent>,
+        rx: Mutex<VecDeque<u8>>,
+        fifo: Mutex<Vec<u8>>,
+        sent: Mutex<Vec<u8>>,
+        tx_irq: AtomicBool,
+        cts: AtomicBool,
+        errors: AtomicU8,
+    }
+
+    impl_event_scheme!(FakeUart, UartEvent);
+
+    impl FakeUart {
+        fn new() -> Self {
+            Self {
+                listener: EventListener::new(),
+                rx: Mutex::new(VecDeque::new()),
+                fifo: Mutex::new(Vec::new()),
+                sent: Mutex::new(Vec::new()),
+                tx_irq: AtomicBool::new(true),
+                cts: AtomicBool::new(true),
+                errors: AtomicU8::new(0),
+            }
+        }
+
+        /// Receive `bytes`, then raise the RX interrupt.
+        fn receive(&self, bytes: &[u8]) {
+            self.rx.lock().extend(bytes);
+            self.listener.trigger(UartEvent::Received);
+        }
+
+        /// Send all bytes in the TX FIFO, then raise the TX interrupt.
+        fn drain(&self) {
+            let bytes: Vec<u8> = self.fifo.lock().drain(..).collect();
+            self.sent.lock().extend(bytes);
+            if self.tx_irq.load(Ordering::Relaxed) {
+                self.listener.trigger(UartEvent::TxReady);
+            }
+        }
+    }
+
+    impl Scheme for FakeUart {
+        fn name(&self) -> &str {
+            "fake-uart"
+        }
+    }
+
+    impl UartScheme for FakeUart {
+        fn try_recv(&self) -> DeviceResult<Option<u8>> {
+            Ok(self.rx.lock().pop_front())
+        }
+
+        fn send(&self, ch: u8) -> DeviceResult {
+            self.sent.lock().push(ch);
+            Ok(())
+        }
+
+        fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+            let mut fifo = self.fifo.lock();
+            let n = buf.len().min(FIFO_DEPTH - fifo.len());
+            fifo.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn set_tx_irq(&self, enabled: bool) -> DeviceResult {
+            self.tx_irq.store(enabled, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn cts_active(&self) -> bool {
+            self.cts.load(Ordering::Relaxed)
+        }
+
+        fn line_errors(&self) -> LineErrors {
+            LineErrors::from_bits_truncate(self.errors.swap(0, Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn test_tx_ring() {
+        let uart = Arc::new(FakeUart::new());
+        let buffered = BufferedUart::with_capacity(uart.clone(), 8, 8);
+        assert!(!uart.tx_irq.load(Ordering::Relaxed));
+
+        // the FIFO is filled at once, the rest waits for the interrupt
+        assert_eq!(buffered.write_bytes(b"0123456789").unwrap(), 8);
+        assert_eq!(*uart.fifo.lock(), b"0123");
+        assert!(uart.tx_irq.load(Ordering::Relaxed));
+        buffered.send(b'x').unwrap();
+        buffered.write_str("abc").unwrap();
+        assert!(matches!(buffered.send(b'y'), Err(DeviceError::Again)));
+        assert!(matches!(
+            buffered.write_bytes(b"y"),
+            Err(DeviceError::Again)
+        ));
+        assert_eq!(buffered.try_write(b"y"), 0);
+
+        uart.drain();
+        assert_eq!(*uart.fifo.lock(), b"4567");
+        assert!(uart.tx_irq.load(Ordering::Relaxed));
+        uart.drain();
+        assert_eq!(*uart.fifo.lock(), b"xabc");
+        assert!(!uart.tx_irq.load(Ordering::Relaxed));
+        uart.drain();
+        assert_eq!(*uart.sent.lock(), b"01234567xabc");
+    }
+
+    #[test]
+    fn test_tx_stuck() {
+        let uart = Arc::new(FakeUart::new());
+        let buffered = BufferedUart::with_capacity(uart.clone(), 8, 8);
+
+        // the TX FIFO never drains
+        assert_eq!(buffered.write_bytes(b"01234567").unwrap(), 8);
+        assert!(matches!(buffered.flush(), Err(DeviceError::NotReady)));
+        assert!(matches!(
+            buffered.write_str("x"),
+            Err(DeviceError::NotReady)
+        ));
+
+        uart.drain();
+        buffered.flush().unwrap();
+        assert_eq!(*uart.fifo.lock(), b"4567");
+    }
+
+    #[test]
+    fn test_tx_cts() {
+        let uart = Arc::new(FakeUart::new());
+        let buffered = BufferedUart::with_capacity(uart.clone(), 8, 8);
+
+        // nothing is pushed into the TX FIFO until CTS is active
+        uart.cts.store(false, Ordering::Relaxed);
+        assert_eq!(buffered.write_bytes(b"012345").unwrap(), 6);
+        assert!(uart.fifo.lock().is_empty());
+        assert!(uart.tx_irq.load(Ordering::Relaxed));
+        uart.drain();
+        assert!(uart.fifo.lock().is_empty());
+        assert!(matches!(buffered.flush(), Err(DeviceError::Again)));
+        buffered.write_str("67").unwrap();
+        assert!(matches!(buffered.write_str("8"), Err(DeviceError::Again)));
+
+        uart.cts.store(true, Ordering::Relaxed);
+        uart.drain();
+        assert_eq!(*uart.fifo.lock(), b"0123");
+        uart.drain();
+        assert_eq!(*uart.fifo.lock(), b"4567");
+        assert!(!uart.tx_irq.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_rx_stats() {
+        let uart = Arc::new(FakeUart::new());
+        let buffered = BufferedUart::with_capacity(uart.clone(), 4, 8);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cloned = events.clone();
+        buffered.subscribe(Box::new(move |event| cloned.lock().push(*event)), false);
+
+        uart.receive(b"ab\rcdef");
+        assert_eq!(
+            buffered.stats(),
+            BufferedUartStats {
+                received: 7,
+                dropped: 3,
+                high_water: 4,
+            }
+        );
+        assert_eq!(
+            core::mem::take(&mut *events.lock()),
+            [UartEvent::Overflow, UartEvent::Received]
+        );
+
+        assert_eq!(buffered.try_recv().unwrap(), Some(b'a'));
+        assert_eq!(buffered.try_recv().unwrap(), Some(b'b'));
+        uart.receive(b"g");
+        assert_eq!(
+            buffered.stats(),
+            BufferedUartStats {
+                received: 8,
+                dropped: 3,
+                high_water: 4,
+            }
+        );
+        assert_eq!(*events.lock(), [UartEvent::Received]);
+        let mut rest = [0; 8];
+        assert_eq!(buffered.recv_slice(&mut rest).unwrap(), 3);
+        assert_eq!(&rest[..3], b"\ncg");
+        assert_eq!(buffered.recv_slice(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_line_errors() {
+        let uart = Arc::new(FakeUart::new());
+        let buffered = BufferedUart::new(uart.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cloned = events.clone();
+        buffered.subscribe(Box::new(move |event| cloned.lock().push(*event)), false);
+
+        let errors = LineErrors::PARITY | LineErrors::BREAK;
+        uart.errors.store(errors.bits(), Ordering::Relaxed);
+        uart.listener.trigger(UartEvent::Break);
+        uart.errors
+            .store(LineErrors::PARITY.bits(), Ordering::Relaxed);
+        uart.receive(b"a");
+        assert_eq!(*events.lock(), [UartEvent::Break, UartEvent::Received]);
+
+        assert_eq!(buffered.line_errors(), errors);
+        assert_eq!(buffered.line_errors(), LineErrors::empty());
+        assert_eq!(
+            buffered.take_errors(),
+            LineErrorCounts {
+                parity: 2,
+                brk: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(buffered.take_errors(), LineErrorCounts::default());
     }
 }
@@ -4,19 +4,102 @@ mod buffered;
 mod uart_16550;
 #[cfg(feature = "board-d1")]
 mod uart_allwinner;
-#[cfg(target_arch = "aarch64")]
+mod uart_dw;
 mod uart_pl011;
-#[cfg(feature = "board-fu740")]
-mod uart_u740;
+mod uart_sifive;
 
-pub use buffered::BufferedUart;
+pub use crate::scheme::uart::LineErrorCounts;
+pub use buffered::{BufferedUart, BufferedUartStats};
 pub use uart_16550::Uart16550Mmio;
+pub use uart_dw::UartDw;
+pub use uart_pl011::{Pl011Mmio, UartPl011};
+pub use uart_sifive::UartSifive;
 
 #[cfg(target_arch = "x86_64")]
 pub use uart_16550::Uart16550Pmio;
 #[cfg(feature = "board-d1")]
 pub use uart_allwinner::UartAllwinner;
-#[cfg(target_arch = "aarch64")]
-pub use uart_pl011::Pl011Uart;
-#[cfg(feature = "board-fu740")]
-pub use uart_u740::UartU740Mmio;
+
+use crate::scheme::uart::{LineConfig, Parity, StopBits};
+use crate::{DeviceError, DeviceResult};
+
+/// How many times to poll the status before giving up waiting for the
+/// transmitter, far longer than draining the FIFO at 300 baud.
+const DRAIN_TIMEOUT: usize = 10_000_000;
+
+/// Spin until `done` returns true, e.g. the transmitter becomes empty.
+///
+/// Returns [`DeviceError::NotReady`] if it does not happen in time, e.g. the
+/// transmitter is stopped by the flow control.
+fn wait_for(mut done: impl FnMut() -> bool) -> DeviceResult {
+    for _ in 0..DRAIN_TIMEOUT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(DeviceError::NotReady)
+}
+
+/// Compute the divisor latch value of a 16x oversampling UART, rounded to the
+/// nearest integer.
+fn baud_divisor(clock_freq: u32, baud: u32) -> DeviceResult<u16> {
+    if baud == 0 {
+        return Err(DeviceError::InvalidParam);
+    }
+    let divisor = (clock_freq as u64 + 8 * baud as u64) / (16 * baud as u64);
+    match divisor {
+        1..=0xffff => Ok(divisor as u16),
+        _ => Err(DeviceError::InvalidParam),
+    }
+}
+
+/// Compose bit 0 to 5 of the 16550-compatible line control register from
+/// `cfg`.
+fn line_ctrl_bits(cfg: LineConfig) -> DeviceResult<u8> {
+    if !(5..=8).contains(&cfg.data_bits) {
+        return Err(DeviceError::InvalidParam);
+    }
+    // word length select
+    let mut bits = cfg.data_bits - 5;
+    if cfg.stop_bits == StopBits::Two {
+        bits |= 1 << 2;
+    }
+    // parity enable, even parity select and stick parity
+    bits |= match cfg.parity {
+        Parity::None => 0,
+        Parity::Odd => 1 << 3,
+        Parity::Even => 1 << 3 | 1 << 4,
+        Parity::Mark => 1 << 3 | 1 << 5,
+        Parity::Space => 1 << 3 | 1 << 4 | 1 << 5,
+    };
+    Ok(bits)
+}
+
+/// Decode the frame format from bit 0 to 5 of the 16550-compatible line
+/// control register, the reverse of [`line_ctrl_bits`].
+fn line_config(bits: u8) -> LineConfig {
+    let parity = match (bits >> 3) & 0x7 {
+        0b001 => Parity::Odd,
+        0b011 => Parity::Even,
+        0b101 => Parity::Mark,
+        0b111 => Parity::Space,
+        _ => Parity::None,
+    };
+    LineConfig {
+        data_bits: (bits & 0x3) + 5,
+        parity,
+        stop_bits: if bits & (1 << 2) != 0 {
+            StopBits::Two
+        } else {
+            StopBits::One
+        },
+    }
+}
+
+/// Returns the number of characters to send during a break of `duration_us`
+/// microseconds, assuming 10 bits per character.
+fn break_chars(duration_us: u32, baud: u32) -> u64 {
+    let bits = duration_us as u64 * baud as u64;
+    ((bits + 10_000_000 - 1) / 10_000_000).max(1)
+}
@@ -1,13 +1,26 @@
 use core::convert::TryInto;
 use core::ops::{BitAnd, BitOr, Not};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 
 use bitflags::bitflags;
 use lock::Mutex;
 
 use crate::io::{Io, Mmio, ReadOnly};
+use crate::scheme::uart::{FlowControl, LineConfig, LineErrors, UartConfig, UartEvent};
 use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
 use crate::utils::EventListener;
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
+
+use super::{baud_divisor, break_chars, line_config, line_ctrl_bits, wait_for};
+
+/// Input clock of the classic 16550, from a 1.8432 MHz crystal.
+const DEFAULT_CLOCK_FREQ: u32 = 1_843_200;
+/// Depth of the TX FIFO of 16550A.
+const FIFO_DEPTH: usize = 16;
+/// The byte sent and expected to be received in the loopback self-test.
+const LOOPBACK_PATTERN: u8 = 0xAE;
+/// Maximum number of polls of LSR to wait for the looped back byte.
+const LOOPBACK_TIMEOUT: usize = 10000;
 
 bitflags! {
     /// Interrupt enable flags
@@ -24,13 +37,43 @@ bitflags! {
     /// Line status flags
     struct LineStsFlags: u8 {
         const INPUT_FULL = 1;
-        // 1 to 4 unknown
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR = 1 << 2;
+        const FRAMING_ERROR = 1 << 3;
+        const BREAK_INTERRUPT = 1 << 4;
         const OUTPUT_EMPTY = 1 << 5;
-        // 6 and 7 unknown
+        const TRANSMITTER_EMPTY = 1 << 6;
+        // 7 unknown
+    }
+}
+
+bitflags! {
+    /// Modem control flags
+    struct ModemCtrlFlags: u8 {
+        const DATA_TERMINAL_READY = 1;
+        const REQUEST_TO_SEND = 1 << 1;
+        const AUXILIARY_OUTPUT_1 = 1 << 2;
+        const AUXILIARY_OUTPUT_2 = 1 << 3;
+        const LOOPBACK = 1 << 4;
+        const AUTO_FLOW_CONTROL = 1 << 5;
+        // 6 and 7 are unused
+    }
+}
+
+bitflags! {
+    /// Modem status flags
+    struct ModemStsFlags: u8 {
+        const DELTA_CLEAR_TO_SEND = 1;
+        const DELTA_DATA_SET_READY = 1 << 1;
+        const TRAILING_EDGE_RING_INDICATOR = 1 << 2;
+        const DELTA_DATA_CARRIER_DETECT = 1 << 3;
+        const CLEAR_TO_SEND = 1 << 4;
+        const DATA_SET_READY = 1 << 5;
+        const RING_INDICATOR = 1 << 6;
+        const DATA_CARRIER_DETECT = 1 << 7;
     }
 }
 
-#[repr(C)]
 struct Uart16550Inner<T: Io> {
     /// Data register, read to receive, write to send
     data: T,
@@ -46,6 +89,10 @@ struct Uart16550Inner<T: Io> {
     line_sts: ReadOnly<T>,
     /// Modem status
     modem_sts: ReadOnly<T>,
+    /// Scratch
+    scratch: T,
+    /// RTS/CTS flow control is done by the driver, as AFE is not implemented.
+    manual_flow: bool,
 }
 
 impl<T: Io> Uart16550Inner<T>
@@ -60,12 +107,29 @@ where
         // set interrupt watermark at 14 bytes
         self.fifo_ctrl.write(0xC7.into());
 
+        // 8 data bits, no parity, 1 stop bit
+        self.line_ctrl.write(0x03.into());
+
         // Mark data terminal ready, signal request to send
         // and enable auxilliary output #2 (used as interrupt line for CPU)
         self.modem_ctrl.write(0x0B.into());
 
-        // Enable interrupts
-        self.int_en.write(0x01.into());
+        // Enable interrupts of received data and line status
+        self.int_en
+            .write((IntEnFlags::RECEIVED | IntEnFlags::ERRORED).bits().into());
+    }
+
+    /// Returns the depth of the TX FIFO. Bits 6 and 7 of IIR (read from the
+    /// FIFO control register) are set if the FIFO is enabled.
+    fn fifo_depth(&self) -> usize {
+        let iir: u8 = (self.fifo_ctrl.read() & 0xFF.into())
+            .try_into()
+            .unwrap_or(0);
+        if iir & 0xC0 == 0xC0 {
+            FIFO_DEPTH
+        } else {
+            1
+        }
     }
 
     fn line_sts(&self) -> LineStsFlags {
@@ -74,22 +138,369 @@ where
         )
     }
 
-    fn try_recv(&mut self) -> DeviceResult<Option<u8>> {
-        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
-            Ok(Some(
-                (self.data.read() & 0xFF.into()).try_into().unwrap_or(0),
-            ))
+    fn modem_ctrl(&self) -> ModemCtrlFlags {
+        ModemCtrlFlags::from_bits_truncate(
+            (self.modem_ctrl.read() & 0xFF.into())
+                .try_into()
+                .unwrap_or(0),
+        )
+    }
+
+    fn set_modem_ctrl(&mut self, flags: ModemCtrlFlags) {
+        self.modem_ctrl.write(flags.bits().into());
+    }
+
+    fn modem_sts(&self) -> ModemStsFlags {
+        ModemStsFlags::from_bits_truncate(
+            (self.modem_sts.read() & 0xFF.into())
+                .try_into()
+                .unwrap_or(0),
+        )
+    }
+
+    /// Check whether the scratch register holds the written values, which is
+    /// missing on 8250 and absent chips.
+    fn scratch_test(&mut self) -> bool {
+        let saved = self.scratch.read();
+        let passed = [0x5a, 0xa5].iter().all(|&pattern| {
+            self.scratch.write(pattern.into());
+            (self.scratch.read() & 0xFF.into()).try_into().unwrap_or(0) == pattern
+        });
+        self.scratch.write(saved);
+        passed
+    }
+
+    /// Check whether the AFE bit of MCR sticks, which is only implemented on
+    /// chips with automatic flow control.
+    fn has_auto_flow_control(&mut self) -> bool {
+        if !self.scratch_test() {
+            return false;
+        }
+        let saved = self.modem_ctrl();
+        self.set_modem_ctrl(saved | ModemCtrlFlags::AUTO_FLOW_CONTROL);
+        let supported = self
+            .modem_ctrl()
+            .contains(ModemCtrlFlags::AUTO_FLOW_CONTROL);
+        self.set_modem_ctrl(saved);
+        supported
+    }
+
+    /// Use the automatic flow control if AFE is implemented, otherwise RTS is
+    /// toggled by the callers of `set_rts`, and bytes are not pushed into the
+    /// TX FIFO while CTS is inactive.
+    fn set_flow_control(&mut self, mode: FlowControl) {
+        let mut flags = self.modem_ctrl();
+        match mode {
+            FlowControl::None => {
+                flags.remove(ModemCtrlFlags::AUTO_FLOW_CONTROL);
+                if self.manual_flow {
+                    self.manual_flow = false;
+                    // Wait for THRE instead of the CTS change
+                    let wanted = self.int_en().contains(IntEnFlags::STATUS_CHANGE);
+                    self.update_int_en(IntEnFlags::STATUS_CHANGE, false);
+                    self.update_int_en(IntEnFlags::SENT, wanted);
+                }
+            }
+            FlowControl::RtsCts => {
+                if self.has_auto_flow_control() {
+                    flags.insert(ModemCtrlFlags::AUTO_FLOW_CONTROL);
+                } else {
+                    self.manual_flow = true;
+                }
+                flags.insert(ModemCtrlFlags::REQUEST_TO_SEND);
+            }
+        }
+        self.set_modem_ctrl(flags);
+    }
+
+    fn flow_control(&self) -> FlowControl {
+        if self.manual_flow
+            || self
+                .modem_ctrl()
+                .contains(ModemCtrlFlags::AUTO_FLOW_CONTROL)
+        {
+            FlowControl::RtsCts
         } else {
-            Ok(None)
+            FlowControl::None
         }
     }
 
+    /// With automatic flow control, RTS is only asserted if the MCR bit is set
+    /// and the RX FIFO is not full.
+    fn set_rts(&mut self, asserted: bool) {
+        let mut flags = self.modem_ctrl();
+        flags.set(ModemCtrlFlags::REQUEST_TO_SEND, asserted);
+        self.set_modem_ctrl(flags);
+    }
+
+    fn cts_active(&self) -> bool {
+        self.modem_sts().contains(ModemStsFlags::CLEAR_TO_SEND)
+    }
+
+    /// Whether the flow control done by the driver forbids sending now.
+    /// Reading MSR also clears the modem status interrupt.
+    fn cts_blocked(&self) -> bool {
+        self.manual_flow && !self.cts_active()
+    }
+
+    /// Whether THR is empty and bytes are allowed to be sent.
+    fn can_send(&self) -> bool {
+        !self.cts_blocked() && self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY)
+    }
+
+    /// Wait until THR is empty and bytes are allowed to be sent.
+    ///
+    /// Returns [`DeviceError::Again`] if the remote side is still not ready to
+    /// receive in time, or [`DeviceError::NotReady`] if THR does not empty.
+    fn wait_send(&self) -> DeviceResult {
+        wait_for(|| self.can_send()).map_err(|err| {
+            if self.cts_blocked() {
+                DeviceError::Again
+            } else {
+                err
+            }
+        })
+    }
+
+    /// Receive a byte if any, with the line errors cleared by reading LSR.
+    fn try_recv(&mut self) -> (Option<u8>, LineErrors) {
+        let sts = self.line_sts();
+        let ch = if sts.contains(LineStsFlags::INPUT_FULL) {
+            Some((self.data.read() & 0xFF.into()).try_into().unwrap_or(0))
+        } else {
+            None
+        };
+        (ch, LineErrors::from_bits_truncate(sts.bits()))
+    }
+
     fn send(&mut self, ch: u8) -> DeviceResult {
-        while !self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY) {}
+        self.wait_send()?;
         self.data.write(ch.into());
         Ok(())
     }
 
+    fn try_send(&mut self, ch: u8) -> bool {
+        if !self.can_send() {
+            return false;
+        }
+        self.data.write(ch.into());
+        true
+    }
+
+    /// Send all bytes of `buf`, pushing up to `fifo_depth` bytes each time
+    /// THRE is set.
+    fn send_slice(&mut self, buf: &[u8], fifo_depth: usize) -> DeviceResult {
+        for chunk in buf.chunks(fifo_depth.max(1)) {
+            self.wait_send()?;
+            for &c in chunk {
+                self.data.write(c.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive bytes into `buf` until the RX FIFO is empty, and return the
+    /// number of bytes received with the line errors seen.
+    fn recv_slice(&mut self, buf: &mut [u8]) -> (usize, LineErrors) {
+        let mut errors = LineErrors::empty();
+        for (i, b) in buf.iter_mut().enumerate() {
+            let (ch, errs) = self.try_recv();
+            errors |= errs;
+            match ch {
+                Some(c) => *b = c,
+                None => return (i, errors),
+            }
+        }
+        (buf.len(), errors)
+    }
+
+    /// Replace the frame format bits of LCR, keeping the break control and
+    /// DLAB bits.
+    fn set_line_ctrl(&mut self, bits: u8) {
+        let line_ctrl = self.line_ctrl.read() & 0xC0.into();
+        self.line_ctrl.write(line_ctrl | bits.into());
+    }
+
+    /// Returns the frame format bits of LCR.
+    fn frame_format(&mut self) -> u8 {
+        (self.line_ctrl.read() & 0x3F.into())
+            .try_into()
+            .unwrap_or(0)
+    }
+
+    /// THRE is set only when the TX FIFO is empty, then up to `fifo_depth`
+    /// bytes can be pushed at once.
+    fn write_bytes(&mut self, buf: &[u8], fifo_depth: usize) -> usize {
+        if !self.can_send() {
+            return 0;
+        }
+        let n = buf.len().min(fifo_depth);
+        for &c in &buf[..n] {
+            self.data.write(c.into());
+        }
+        n
+    }
+
+    /// Send a byte in the loopback mode and check whether it can be received,
+    /// and the modem outputs are looped back to the modem inputs, like the
+    /// probe of 16550 in Linux. IER and MCR are restored after test.
+    ///
+    /// Returns [`DeviceError::NotReady`] without sending if there are received
+    /// bytes in the RX FIFO, which are left to the reader.
+    fn self_test(&mut self) -> DeviceResult<bool> {
+        // Do not cut off the bytes being sent
+        wait_for(|| self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY))?;
+        let int_en = self.int_en.read();
+        let modem_ctrl = self.modem_ctrl.read();
+
+        self.int_en.write(0x00.into());
+        // No more bytes are received from the line in the loopback mode
+        self.set_modem_ctrl(
+            ModemCtrlFlags::LOOPBACK
+                | ModemCtrlFlags::REQUEST_TO_SEND
+                | ModemCtrlFlags::AUXILIARY_OUTPUT_2,
+        );
+        let result = if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+            Err(DeviceError::NotReady)
+        } else {
+            // RTS is looped back to CTS, and OUT2 to DCD
+            let inputs = ModemStsFlags::CLEAR_TO_SEND
+                | ModemStsFlags::DATA_SET_READY
+                | ModemStsFlags::RING_INDICATOR
+                | ModemStsFlags::DATA_CARRIER_DETECT;
+            let mut passed = false;
+            if self.modem_sts() & inputs
+                == ModemStsFlags::CLEAR_TO_SEND | ModemStsFlags::DATA_CARRIER_DETECT
+            {
+                self.data.write(LOOPBACK_PATTERN.into());
+                for _ in 0..LOOPBACK_TIMEOUT {
+                    if let (Some(ch), _) = self.try_recv() {
+                        passed = ch == LOOPBACK_PATTERN;
+                        break;
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+            Ok(passed)
+        };
+
+        self.modem_ctrl.write(modem_ctrl);
+        self.int_en.write(int_en);
+        result
+    }
+
+    /// Read LSR to find out the cause of the interrupt. Reading LSR also
+    /// clears the error bits, so they are returned as well.
+    fn line_event(&self) -> (UartEvent, LineErrors) {
+        let sts = self.line_sts();
+        let event = if sts.contains(LineStsFlags::BREAK_INTERRUPT) {
+            UartEvent::Break
+        } else if sts.intersects(
+            LineStsFlags::OVERRUN_ERROR | LineStsFlags::PARITY_ERROR | LineStsFlags::FRAMING_ERROR,
+        ) {
+            UartEvent::LineError
+        } else {
+            UartEvent::Received
+        };
+        (event, LineErrors::from_bits_truncate(sts.bits()))
+    }
+
+    fn int_en(&self) -> IntEnFlags {
+        IntEnFlags::from_bits_truncate((self.int_en.read() & 0xFF.into()).try_into().unwrap_or(0))
+    }
+
+    /// Set or clear `flags` in IER, keeping the other bits.
+    fn update_int_en(&mut self, flags: IntEnFlags, enabled: bool) {
+        let int_en = self.int_en.read();
+        let flags = T::Value::from(flags.bits());
+        if enabled {
+            self.int_en.write(int_en | flags);
+        } else {
+            self.int_en.write(int_en & !flags);
+        }
+    }
+
+    /// Whether the TX interrupt is enabled and bytes can be sent.
+    ///
+    /// With the flow control done by the driver, the THR empty interrupt is
+    /// masked while CTS is inactive, otherwise it would be raised again and
+    /// again as THR is not written. The modem status interrupt raised by the
+    /// CTS change is waited for instead.
+    fn tx_ready(&mut self) -> bool {
+        let int_en = self.int_en();
+        if !self.manual_flow {
+            return int_en.contains(IntEnFlags::SENT)
+                && self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY);
+        }
+        if !int_en.contains(IntEnFlags::STATUS_CHANGE) {
+            return false;
+        }
+        if self.cts_blocked() {
+            self.update_int_en(IntEnFlags::SENT, false);
+            return false;
+        }
+        self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY)
+    }
+
+    /// Enable or disable the THR empty interrupt, and the modem status
+    /// interrupt as well if the flow control is done by the driver.
+    fn set_tx_irq(&mut self, enabled: bool) {
+        if self.manual_flow {
+            self.update_int_en(IntEnFlags::STATUS_CHANGE, enabled);
+        }
+        let blocked = self.cts_blocked();
+        self.update_int_en(IntEnFlags::SENT, enabled && !blocked);
+    }
+
+    /// Read the line errors from LSR without receiving data.
+    fn line_errors(&self) -> LineErrors {
+        LineErrors::from_bits_truncate(self.line_sts().bits())
+    }
+
+    /// Set the break control bit of LCR while sending `chars` characters,
+    /// which are not on the line but take the time to transmit. The break is
+    /// cleared even if the transmitter gets stuck.
+    fn send_break(&mut self, chars: u64) -> DeviceResult {
+        wait_for(|| self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY))?;
+        let line_ctrl = self.line_ctrl.read();
+        self.line_ctrl.write(line_ctrl | 0x40.into());
+        let mut result = Ok(());
+        for _ in 0..chars {
+            result = wait_for(|| self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
+            if result.is_err() {
+                break;
+            }
+            self.data.write(0.into());
+        }
+        let result = result
+            .and_then(|_| wait_for(|| self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY)));
+        self.line_ctrl.write(line_ctrl & !T::Value::from(0x40));
+        result
+    }
+
+    /// Read the divisor latch.
+    fn divisor(&mut self) -> u16 {
+        let line_ctrl = self.line_ctrl.read();
+        self.line_ctrl.write(line_ctrl | 0x80.into());
+        let low: u8 = (self.data.read() & 0xFF.into()).try_into().unwrap_or(0);
+        let high: u8 = (self.int_en.read() & 0xFF.into()).try_into().unwrap_or(0);
+        self.line_ctrl.write(line_ctrl);
+        (high as u16) << 8 | low as u16
+    }
+
+    /// Program the divisor latch. Wait for the transmitter to drain first, so
+    /// that queued bytes are not sent at the new rate.
+    fn set_divisor(&mut self, divisor: u16) -> DeviceResult {
+        wait_for(|| self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY))?;
+        let line_ctrl = self.line_ctrl.read();
+        // Set DLAB to access DLL and DLM
+        self.line_ctrl.write(line_ctrl | 0x80.into());
+        self.data.write((divisor as u8).into());
+        self.int_en.write(((divisor >> 8) as u8).into());
+        self.line_ctrl.write(line_ctrl & !T::Value::from(0x80));
+        Ok(())
+    }
+
     fn write_str(&mut self, s: &str) -> DeviceResult {
         for b in s.bytes() {
             match b {
@@ -106,60 +517,221 @@ where
     }
 }
 
-/// MMIO driver for UART 16550
-pub struct Uart16550Mmio<V: 'static>
+/// Returns the registers at `base`, which are `1 << reg_shift` bytes apart.
+///
+/// # Safety
+///
+/// This function is unsafe because `base` may be an arbitrary address.
+unsafe fn mmio_registers<V>(base: usize, reg_shift: u32) -> Uart16550Inner<&'static mut Mmio<V>>
 where
     V: Copy + BitAnd<Output = V> + BitOr<Output = V> + Not<Output = V>,
 {
-    inner: Mutex<&'static mut Uart16550Inner<Mmio<V>>>,
-    listener: EventListener,
+    let reg = |index: usize| Mmio::<V>::from_base(base + (index << reg_shift));
+    Uart16550Inner {
+        data: reg(0),
+        int_en: reg(1),
+        fifo_ctrl: reg(2),
+        line_ctrl: reg(3),
+        modem_ctrl: reg(4),
+        line_sts: ReadOnly::new(reg(5)),
+        modem_sts: ReadOnly::new(reg(6)),
+        scratch: reg(7),
+        manual_flow: false,
+    }
 }
 
-impl_event_scheme!(Uart16550Mmio<V>
+/// Driver for UART 16550 over the registers accessed by `T`, shared by
+/// [`Uart16550Mmio`] and `Uart16550Pmio`.
+pub struct Uart16550<T: Io> {
+    inner: Mutex<Uart16550Inner<T>>,
+    name: &'static str,
+    listener: EventListener<UartEvent>,
+    clock_freq: u32,
+    baud_rate: AtomicU32,
+    fifo_depth: usize,
+    /// Line errors seen when reading LSR, until taken by `line_errors()`.
+    line_errors: AtomicU8,
+}
+
+/// MMIO driver for UART 16550
+pub type Uart16550Mmio<V> = Uart16550<&'static mut Mmio<V>>;
+
+impl_event_scheme!(Uart16550<T>, UartEvent
 where
-    V: Copy
-        + BitAnd<Output = V>
-        + BitOr<Output = V>
-        + Not<Output = V>
-        + From<u8>
-        + TryInto<u8>
-        + Send
+    T: Io + Send,
+    T::Value: From<u8> + TryInto<u8>
 );
 
-impl<V> Scheme for Uart16550Mmio<V>
+impl<T> Scheme for Uart16550<T>
 where
-    V: Copy + BitAnd<Output = V> + BitOr<Output = V> + Not<Output = V> + Send,
+    T: Io + Send,
+    T::Value: From<u8> + TryInto<u8>,
 {
     fn name(&self) -> &str {
-        "uart16550-mmio"
+        self.name
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.listener.trigger(());
+        let (event, errors, tx_ready) = {
+            let mut inner = self.inner.lock();
+            let (event, errors) = inner.line_event();
+            (event, errors, inner.tx_ready())
+        };
+        self.line_errors.fetch_or(errors.bits(), Ordering::Relaxed);
+        self.listener.trigger(event);
+        if tx_ready {
+            self.listener.trigger(UartEvent::TxReady);
+        }
     }
 }
 
-impl<V> UartScheme for Uart16550Mmio<V>
+impl<T> UartScheme for Uart16550<T>
 where
-    V: Copy
-        + BitAnd<Output = V>
-        + BitOr<Output = V>
-        + Not<Output = V>
-        + From<u8>
-        + TryInto<u8>
-        + Send,
+    T: Io + Send,
+    T::Value: From<u8> + TryInto<u8>,
 {
     fn try_recv(&self) -> DeviceResult<Option<u8>> {
-        self.inner.lock().try_recv()
+        let (ch, errors) = self.inner.lock().try_recv();
+        self.line_errors.fetch_or(errors.bits(), Ordering::Relaxed);
+        Ok(ch)
     }
 
     fn send(&self, ch: u8) -> DeviceResult {
         self.inner.lock().send(ch)
     }
 
+    fn try_send(&self, ch: u8) -> DeviceResult<bool> {
+        Ok(self.inner.lock().try_send(ch))
+    }
+
     fn write_str(&self, s: &str) -> DeviceResult {
         self.inner.lock().write_str(s)
     }
+
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        Ok(self.inner.lock().write_bytes(buf, self.fifo_depth))
+    }
+
+    fn send_slice(&self, buf: &[u8]) -> DeviceResult<usize> {
+        self.inner.lock().send_slice(buf, self.fifo_depth)?;
+        Ok(buf.len())
+    }
+
+    fn recv_slice(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let (n, errors) = self.inner.lock().recv_slice(buf);
+        self.line_errors.fetch_or(errors.bits(), Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn set_tx_irq(&self, enabled: bool) -> DeviceResult {
+        self.inner.lock().set_tx_irq(enabled);
+        Ok(())
+    }
+
+    fn configure_line(&self, cfg: LineConfig) -> DeviceResult {
+        let bits = line_ctrl_bits(cfg)?;
+        self.inner.lock().set_line_ctrl(bits);
+        Ok(())
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        let errors = self.inner.lock().line_errors();
+        let latched = self.line_errors.swap(0, Ordering::Relaxed);
+        errors | LineErrors::from_bits_truncate(latched)
+    }
+
+    fn send_break(&self, duration_us: u32) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        let baud = self.clock_freq / (16 * inner.divisor().max(1) as u32);
+        inner.send_break(break_chars(duration_us, baud))
+    }
+
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        let divisor = baud_divisor(self.clock_freq, baud)?;
+        self.inner.lock().set_divisor(divisor)?;
+        self.baud_rate.store(baud, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate.load(Ordering::Relaxed) {
+            0 => None,
+            baud => Some(baud),
+        }
+    }
+
+    fn set_config(&self, config: UartConfig) -> DeviceResult {
+        let divisor = baud_divisor(self.clock_freq, config.baud)?;
+        let bits = line_ctrl_bits(config.line())?;
+        let mut inner = self.inner.lock();
+        inner.set_divisor(divisor)?;
+        inner.set_line_ctrl(bits);
+        inner.set_flow_control(config.flow_control);
+        self.baud_rate.store(config.baud, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn config(&self) -> DeviceResult<UartConfig> {
+        let mut inner = self.inner.lock();
+        // the divisor may be set by the firmware
+        let baud = self
+            .baud_rate()
+            .unwrap_or_else(|| self.clock_freq / (16 * inner.divisor().max(1) as u32));
+        let config = UartConfig::new(baud, line_config(inner.frame_format()));
+        Ok(config.with_flow_control(inner.flow_control()))
+    }
+
+    fn set_rts(&self, asserted: bool) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        if inner.flow_control() == FlowControl::RtsCts {
+            inner.set_rts(asserted);
+        }
+        Ok(())
+    }
+
+    /// RTS/CTS flow control is done by the driver if the automatic flow
+    /// control is not implemented.
+    fn set_flow_control(&self, mode: FlowControl) -> DeviceResult {
+        self.inner.lock().set_flow_control(mode);
+        Ok(())
+    }
+
+    /// CTS is handled by the hardware with AFE, only the flow control done by
+    /// the driver holds the callers back.
+    fn cts_active(&self) -> bool {
+        !self.inner.lock().cts_blocked()
+    }
+}
+
+impl<T> Uart16550<T>
+where
+    T: Io + Send,
+    T::Value: From<u8> + TryInto<u8>,
+{
+    /// Initialize the UART with the registers `uart`, whose input clock is
+    /// taken as the one of the classic 16550.
+    fn from_registers(mut uart: Uart16550Inner<T>, name: &'static str) -> Self {
+        uart.init();
+        let fifo_depth = uart.fifo_depth();
+        Self {
+            inner: Mutex::new(uart),
+            name,
+            listener: EventListener::new(),
+            clock_freq: DEFAULT_CLOCK_FREQ,
+            baud_rate: AtomicU32::new(0),
+            fifo_depth,
+            line_errors: AtomicU8::new(0),
+        }
+    }
+
+    /// Check whether the UART is alive by sending a byte in the loopback mode,
+    /// without a connected terminal.
+    ///
+    /// Returns [`DeviceError::NotReady`] if there are received bytes not read
+    /// yet, which are not discarded by the test.
+    pub fn self_test(&self) -> DeviceResult<bool> {
+        self.inner.lock().self_test()
+    }
 }
 
 impl<V> Uart16550Mmio<V>
@@ -172,31 +744,86 @@ where
         + TryInto<u8>
         + Send,
 {
-    unsafe fn new_common(base: usize) -> Self {
-        let uart: &mut Uart16550Inner<Mmio<V>> = Mmio::<V>::from_base_as(base);
-        uart.init();
-        Self {
-            inner: Mutex::new(uart),
-            listener: EventListener::new(),
+    unsafe fn new_common(base: usize, reg_shift: u32) -> Self {
+        Self::from_registers(mmio_registers::<V>(base, reg_shift), "uart16550-mmio")
+    }
+
+    unsafe fn with_clock_common(base: usize, reg_shift: u32, clock_freq: u32, baud: u32) -> Self {
+        let mut uart = Self::new_common(base, reg_shift);
+        uart.clock_freq = clock_freq;
+        if let Err(err) = uart.set_baud_rate(baud) {
+            warn!(
+                "uart16550: failed to set baud rate {} with clock {}Hz: {:?}",
+                baud, clock_freq, err
+            );
+            uart.clock_freq = DEFAULT_CLOCK_FREQ;
         }
+        uart
+    }
+
+    /// Construct with the registers `1 << reg_shift` bytes apart and accessed
+    /// in the width of `V`, as the `reg-shift` and `reg-io-width` properties
+    /// in the device tree.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_reg_shift(base: usize, reg_shift: u32) -> Self {
+        Self::new_common(base, reg_shift)
+    }
+
+    /// Same as [`Self::with_reg_shift`], and set the baud rate to `baud`
+    /// according to the UART input clock `clock_freq`. If failed, the divisor
+    /// is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_reg_shift_clock(
+        base: usize,
+        reg_shift: u32,
+        clock_freq: u32,
+        baud: u32,
+    ) -> Self {
+        Self::with_clock_common(base, reg_shift, clock_freq, baud)
     }
 }
 
 impl Uart16550Mmio<u8> {
     /// # Safety
     ///
-    /// This function is unsafe because `base_addr` may be an arbitrary address.
+    /// This function is unsafe because `base` may be an arbitrary address.
     pub unsafe fn new(base: usize) -> Self {
-        Self::new_common(base)
+        Self::new_common(base, 0)
+    }
+
+    /// Construct with the UART input clock `clock_freq`, and set the baud rate
+    /// to `baud`. If failed, the divisor is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_clock(base: usize, clock_freq: u32, baud: u32) -> Self {
+        Self::with_clock_common(base, 0, clock_freq, baud)
     }
 }
 
 impl Uart16550Mmio<u32> {
     /// # Safety
     ///
-    /// This function is unsafe because `base_addr` may be an arbitrary address.
+    /// This function is unsafe because `base` may be an arbitrary address.
     pub unsafe fn new(base: usize) -> Self {
-        Self::new_common(base)
+        Self::new_common(base, 2)
+    }
+
+    /// Construct with the UART input clock `clock_freq`, and set the baud rate
+    /// to `baud`. If failed, the divisor is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_clock(base: usize, clock_freq: u32, baud: u32) -> Self {
+        Self::with_clock_common(base, 2, clock_freq, baud)
     }
 }
 
@@ -204,59 +831,419 @@ impl Uart16550Mmio<u32> {
 mod pmio {
     use super::*;
     use crate::io::Pmio;
+    use alloc::vec::Vec;
 
-    /// Pmio driver for UART 16550
-    pub struct Uart16550Pmio {
-        inner: Mutex<Uart16550Inner<Pmio<u8>>>,
-        listener: EventListener,
+    /// I/O port bases of the legacy COM1 to COM4, with their conventional ISA
+    /// IRQ lines.
+    const STANDARD_PORTS: [(u16, u8); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+
+    fn registers(base: u16) -> Uart16550Inner<Pmio<u8>> {
+        Uart16550Inner::<Pmio<u8>> {
+            data: Pmio::new(base),
+            int_en: Pmio::new(base + 1),
+            fifo_ctrl: Pmio::new(base + 2),
+            line_ctrl: Pmio::new(base + 3),
+            modem_ctrl: Pmio::new(base + 4),
+            line_sts: ReadOnly::new(Pmio::new(base + 5)),
+            modem_sts: ReadOnly::new(Pmio::new(base + 6)),
+            scratch: Pmio::new(base + 7),
+            manual_flow: false,
+        }
     }
 
-    impl_event_scheme!(Uart16550Pmio);
+    /// Pmio driver for UART 16550
+    pub type Uart16550Pmio = Uart16550<Pmio<u8>>;
 
-    impl Scheme for Uart16550Pmio {
-        fn name(&self) -> &str {
-            "uart16550-Pmio"
+    impl Uart16550Pmio {
+        /// Construct a `Uart16550Pmio` whose address starts at `base`.
+        pub fn new(base: u16) -> Self {
+            Self::from_registers(registers(base), "uart16550-Pmio")
         }
 
-        fn handle_irq(&self, _irq_num: usize) {
-            self.listener.trigger(());
+        /// Find the legacy COM1 to COM4 ports that respond to the scratch
+        /// register test, and returns their I/O port bases with IRQ lines.
+        ///
+        /// The ports are not initialized, and the scratch registers are
+        /// restored.
+        pub fn probe_standard_ports() -> Vec<(u16, u8)> {
+            STANDARD_PORTS
+                .iter()
+                .copied()
+                .filter(|&(base, _)| registers(base).scratch_test())
+                .collect()
         }
     }
+}
 
-    impl UartScheme for Uart16550Pmio {
-        fn try_recv(&self) -> DeviceResult<Option<u8>> {
-            self.inner.lock().try_recv()
-        }
+#[cfg(target_arch = "x86_64")]
+pub use pmio::Uart16550Pmio;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::test_utils::MockRegisters;
+    use alloc::{boxed::Box, collections::VecDeque, rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    /// Registers of a 16550 in the memory, whose received byte is always the
+    /// last sent one.
+    fn mock_registers() -> MockRegisters<u8> {
+        let regs = MockRegisters::new(8);
+        // THR empty, transmitter empty, data ready
+        regs.write(
+            5,
+            (LineStsFlags::OUTPUT_EMPTY
+                | LineStsFlags::TRANSMITTER_EMPTY
+                | LineStsFlags::INPUT_FULL)
+                .bits(),
+        );
+        regs
+    }
+
+    /// A 16550 emulated behind [`Io`], which loops the sent bytes and the
+    /// modem outputs back in the loopback mode, as the hardware does.
+    #[derive(Default)]
+    struct Emulated16550 {
+        regs: [u8; 8],
+        rx_fifo: VecDeque<u8>,
+        /// Bytes sent to the line.
+        sent: Vec<u8>,
+    }
 
-        fn send(&self, ch: u8) -> DeviceResult {
-            self.inner.lock().send(ch)
+    struct EmulatedReg(Rc<RefCell<Emulated16550>>, usize);
+
+    impl Io for EmulatedReg {
+        type Value = u8;
+
+        fn read(&self) -> u8 {
+            let mut dev = self.0.borrow_mut();
+            let mcr = ModemCtrlFlags::from_bits_truncate(dev.regs[4]);
+            match self.1 {
+                0 => dev.rx_fifo.pop_front().unwrap_or(0),
+                5 => {
+                    let mut lsr = LineStsFlags::OUTPUT_EMPTY | LineStsFlags::TRANSMITTER_EMPTY;
+                    lsr.set(LineStsFlags::INPUT_FULL, !dev.rx_fifo.is_empty());
+                    lsr.bits()
+                }
+                6 if mcr.contains(ModemCtrlFlags::LOOPBACK) => {
+                    let mut msr = ModemStsFlags::empty();
+                    msr.set(
+                        ModemStsFlags::DATA_SET_READY,
+                        mcr.contains(ModemCtrlFlags::DATA_TERMINAL_READY),
+                    );
+                    msr.set(
+                        ModemStsFlags::CLEAR_TO_SEND,
+                        mcr.contains(ModemCtrlFlags::REQUEST_TO_SEND),
+                    );
+                    msr.set(
+                        ModemStsFlags::RING_INDICATOR,
+                        mcr.contains(ModemCtrlFlags::AUXILIARY_OUTPUT_1),
+                    );
+                    msr.set(
+                        ModemStsFlags::DATA_CARRIER_DETECT,
+                        mcr.contains(ModemCtrlFlags::AUXILIARY_OUTPUT_2),
+                    );
+                    msr.bits()
+                }
+                reg => dev.regs[reg],
+            }
         }
 
-        fn write_str(&self, s: &str) -> DeviceResult {
-            self.inner.lock().write_str(s)
+        fn write(&mut self, value: u8) {
+            let mut dev = self.0.borrow_mut();
+            let mcr = ModemCtrlFlags::from_bits_truncate(dev.regs[4]);
+            match self.1 {
+                0 if mcr.contains(ModemCtrlFlags::LOOPBACK) => dev.rx_fifo.push_back(value),
+                0 => dev.sent.push(value),
+                reg => dev.regs[reg] = value,
+            }
         }
     }
 
-    impl Uart16550Pmio {
-        /// Construct a `Uart16550Pmio` whose address starts at `base`.
-        pub fn new(base: u16) -> Self {
-            let mut uart = Uart16550Inner::<Pmio<u8>> {
-                data: Pmio::new(base),
-                int_en: Pmio::new(base + 1),
-                fifo_ctrl: Pmio::new(base + 2),
-                line_ctrl: Pmio::new(base + 3),
-                modem_ctrl: Pmio::new(base + 4),
-                line_sts: ReadOnly::new(Pmio::new(base + 5)),
-                modem_sts: ReadOnly::new(Pmio::new(base + 6)),
-            };
-            uart.init();
-            Self {
-                inner: Mutex::new(uart),
-                listener: EventListener::new(),
-            }
+    fn emulated_uart() -> (Uart16550Inner<EmulatedReg>, Rc<RefCell<Emulated16550>>) {
+        let dev = Rc::new(RefCell::new(Emulated16550::default()));
+        let reg = |index: usize| EmulatedReg(dev.clone(), index);
+        let mut uart = Uart16550Inner {
+            data: reg(0),
+            int_en: reg(1),
+            fifo_ctrl: reg(2),
+            line_ctrl: reg(3),
+            modem_ctrl: reg(4),
+            line_sts: ReadOnly::new(reg(5)),
+            modem_sts: ReadOnly::new(reg(6)),
+            scratch: reg(7),
+            manual_flow: false,
+        };
+        uart.init();
+        (uart, dev)
+    }
+
+    #[test]
+    fn test_self_test() {
+        let (mut uart, dev) = emulated_uart();
+        let (int_en, modem_ctrl) = (dev.borrow().regs[1], dev.borrow().regs[4]);
+        assert!(uart.self_test().unwrap());
+        // the byte is looped back rather than sent to the line
+        assert!(dev.borrow().sent.is_empty());
+        assert!(dev.borrow().rx_fifo.is_empty());
+        assert_eq!(dev.borrow().regs[1], int_en);
+        assert_eq!(dev.borrow().regs[4], modem_ctrl);
+
+        // the received bytes are not consumed
+        dev.borrow_mut().rx_fifo.push_back(b'x');
+        assert!(matches!(uart.self_test(), Err(DeviceError::NotReady)));
+        assert_eq!(uart.try_recv().0, Some(b'x'));
+        assert_eq!(dev.borrow().regs[4], modem_ctrl);
+
+        // plain memory is not taken as a 16550
+        let regs = MockRegisters::<u8>::new(8);
+        regs.write(
+            5,
+            (LineStsFlags::OUTPUT_EMPTY | LineStsFlags::TRANSMITTER_EMPTY).bits(),
+        );
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        assert!(!uart.self_test().unwrap());
+    }
+
+    #[test]
+    fn test_line_errors() {
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        let idle = (LineStsFlags::OUTPUT_EMPTY | LineStsFlags::TRANSMITTER_EMPTY).bits();
+
+        // errors seen by `try_recv` are latched
+        regs.write(0, 0x41);
+        regs.write(
+            5,
+            idle | (LineStsFlags::INPUT_FULL
+                | LineStsFlags::PARITY_ERROR
+                | LineStsFlags::FRAMING_ERROR)
+                .bits(),
+        );
+        assert_eq!(uart.try_recv().unwrap(), Some(0x41));
+        regs.write(5, idle);
+        assert_eq!(uart.line_errors(), LineErrors::PARITY | LineErrors::FRAMING);
+        assert_eq!(uart.line_errors(), LineErrors::empty());
+
+        // reading errors does not consume the received data
+        regs.write(0, 0x55);
+        regs.write(
+            5,
+            idle | (LineStsFlags::INPUT_FULL
+                | LineStsFlags::OVERRUN_ERROR
+                | LineStsFlags::BREAK_INTERRUPT)
+                .bits(),
+        );
+        assert_eq!(uart.line_errors(), LineErrors::OVERRUN | LineErrors::BREAK);
+        assert_eq!(uart.try_recv().unwrap(), Some(0x55));
+    }
+
+    #[test]
+    fn test_reg_shift() {
+        // 32-bit registers 4 bytes apart, all bits set to catch byte accesses
+        let regs = MockRegisters::<u32>::new(8);
+        for reg in 0..8 {
+            regs.write(reg << 2, u32::MAX);
         }
+        let uart = unsafe { Uart16550Mmio::<u32>::with_reg_shift(regs.base(), 2) };
+        assert_eq!(
+            regs.read(1 << 2),
+            (IntEnFlags::RECEIVED | IntEnFlags::ERRORED).bits() as u32
+        );
+        assert_eq!(regs.read(3 << 2), 0x03);
+        assert_eq!(regs.read(4 << 2), 0x0B);
+        uart.send(b'a').unwrap();
+        assert_eq!(regs.read(0), b'a' as u32);
+
+        // byte registers 4 bytes apart
+        let regs = MockRegisters::<u8>::new(32);
+        regs.write(5 << 2, LineStsFlags::OUTPUT_EMPTY.bits());
+        let uart = unsafe { Uart16550Mmio::<u8>::with_reg_shift(regs.base(), 2) };
+        assert_eq!(regs.read(3 << 2), 0x03);
+        assert_eq!(regs.read(3), 0);
+        uart.send(b'b').unwrap();
+        assert_eq!(regs.read(0), b'b');
     }
-}
 
-#[cfg(target_arch = "x86_64")]
-pub use pmio::Uart16550Pmio;
+    #[test]
+    fn test_try_send() {
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        assert!(uart.try_send(b'a').unwrap());
+        assert_eq!(regs.read(0), b'a');
+
+        // THR is not empty
+        regs.write(5, 0);
+        assert!(!uart.try_send(b'b').unwrap());
+        assert_eq!(regs.read(0), b'a');
+    }
+
+    #[test]
+    fn test_slice() {
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        assert_eq!(uart.send_slice(b"hello").unwrap(), 5);
+        assert_eq!(regs.read(0), b'o');
+
+        // the data is always ready in the mock
+        let mut buf = [0; 4];
+        assert_eq!(uart.recv_slice(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"oooo");
+        regs.write(5, LineStsFlags::OUTPUT_EMPTY.bits());
+        assert_eq!(uart.recv_slice(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tx_irq() {
+        use crate::scheme::EventScheme;
+        use alloc::sync::Arc;
+        use core::sync::atomic::AtomicUsize;
+
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        let tx_ready = Arc::new(AtomicUsize::new(0));
+        let cloned = tx_ready.clone();
+        uart.subscribe(
+            Box::new(move |event| {
+                if *event == UartEvent::TxReady {
+                    cloned.fetch_add(1, Ordering::Relaxed);
+                }
+            }),
+            false,
+        );
+
+        uart.handle_irq(0);
+        assert_eq!(tx_ready.load(Ordering::Relaxed), 0);
+        uart.set_tx_irq(true).unwrap();
+        assert_eq!(
+            regs.read(1) & IntEnFlags::SENT.bits(),
+            IntEnFlags::SENT.bits()
+        );
+        uart.handle_irq(0);
+        assert_eq!(tx_ready.load(Ordering::Relaxed), 1);
+        uart.set_tx_irq(false).unwrap();
+        assert_eq!(regs.read(1) & IntEnFlags::SENT.bits(), 0);
+    }
+
+    #[test]
+    fn test_config() {
+        use crate::scheme::uart::{Parity, StopBits};
+
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::with_clock(regs.base(), 1_843_200, 115200) };
+        let config = uart.config().unwrap();
+        assert_eq!(config, UartConfig::new(115200, LineConfig::default()));
+
+        let config = UartConfig {
+            baud: 9600,
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            flow_control: FlowControl::None,
+        };
+        uart.set_config(config).unwrap();
+        assert_eq!(regs.read(3), 0x1E);
+        assert_eq!(uart.config().unwrap(), config);
+        // DLL, the DLAB bit is cleared after that
+        assert_eq!(regs.read(0), 12);
+
+        // nothing is changed if any of them is invalid
+        let invalid = UartConfig {
+            data_bits: 9,
+            ..config
+        };
+        assert!(uart.set_config(invalid).is_err());
+        let invalid = UartConfig { baud: 0, ..config };
+        assert!(uart.set_config(invalid).is_err());
+        assert_eq!(uart.config().unwrap(), config);
+    }
+
+    #[test]
+    fn test_auto_flow_control() {
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::with_clock(regs.base(), 1_843_200, 115200) };
+        let config = UartConfig::new(115200, LineConfig::default());
+
+        // AFE sticks in the mock, so it is detected
+        uart.set_config(config.with_flow_control(FlowControl::RtsCts))
+            .unwrap();
+        let afe = ModemCtrlFlags::AUTO_FLOW_CONTROL.bits();
+        assert_eq!(regs.read(4) & afe, afe);
+
+        // CTS is inactive in the mock, but the hardware holds the bytes back
+        assert!(uart.cts_active());
+        assert_eq!(uart.write_bytes(b"ab").unwrap(), 2);
+        assert_eq!(regs.read(0), b'b');
+        let sent = IntEnFlags::SENT.bits();
+        let status_change = IntEnFlags::STATUS_CHANGE.bits();
+        uart.set_tx_irq(true).unwrap();
+        assert_eq!(regs.read(1) & (sent | status_change), sent);
+
+        uart.set_config(config).unwrap();
+        assert_eq!(regs.read(4) & afe, 0);
+        assert!(uart.cts_active());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        use crate::scheme::EventScheme;
+        use alloc::sync::Arc;
+        use core::sync::atomic::AtomicUsize;
+
+        let regs = mock_registers();
+        let uart = unsafe { Uart16550Mmio::<u8>::new(regs.base()) };
+        let rts = ModemCtrlFlags::REQUEST_TO_SEND.bits();
+        let afe = ModemCtrlFlags::AUTO_FLOW_CONTROL.bits();
+
+        // RTS is left alone without flow control
+        uart.set_rts(false).unwrap();
+        assert_eq!(regs.read(4) & rts, rts);
+        assert!(uart.cts_active());
+
+        // AFE sticks in the mock
+        uart.set_flow_control(FlowControl::RtsCts).unwrap();
+        assert_eq!(regs.read(4) & afe, afe);
+        assert_eq!(uart.config().unwrap().flow_control, FlowControl::RtsCts);
+        uart.set_rts(false).unwrap();
+        assert_eq!(regs.read(4) & rts, 0);
+        uart.set_flow_control(FlowControl::None).unwrap();
+        assert_eq!(regs.read(4) & afe, 0);
+        assert_eq!(uart.config().unwrap().flow_control, FlowControl::None);
+
+        // the scratch register and AFE always stick in the mock, so the
+        // fallback for the chips without AFE is chosen by hand
+        uart.inner.lock().manual_flow = true;
+        assert!(!uart.cts_active());
+        assert_eq!(uart.write_bytes(b"ab").unwrap(), 0);
+        assert!(!uart.try_send(b'a').unwrap());
+        assert!(matches!(uart.send(b'a'), Err(DeviceError::Again)));
+
+        let tx_ready = Arc::new(AtomicUsize::new(0));
+        let cloned = tx_ready.clone();
+        uart.subscribe(
+            Box::new(move |event| {
+                if *event == UartEvent::TxReady {
+                    cloned.fetch_add(1, Ordering::Relaxed);
+                }
+            }),
+            false,
+        );
+        let sent = IntEnFlags::SENT.bits();
+        let status_change = IntEnFlags::STATUS_CHANGE.bits();
+        uart.set_tx_irq(true).unwrap();
+        assert_eq!(regs.read(1) & (sent | status_change), status_change);
+        uart.handle_irq(0);
+        assert_eq!(tx_ready.load(Ordering::Relaxed), 0);
+
+        // the modem status interrupt on CTS change
+        regs.write(6, ModemStsFlags::CLEAR_TO_SEND.bits());
+        uart.handle_irq(0);
+        assert_eq!(tx_ready.load(Ordering::Relaxed), 1);
+        assert_eq!(uart.write_bytes(b"ab").unwrap(), 2);
+        uart.set_tx_irq(true).unwrap();
+        assert_eq!(regs.read(1) & (sent | status_change), sent | status_change);
+
+        uart.set_flow_control(FlowControl::None).unwrap();
+        assert_eq!(regs.read(1) & (sent | status_change), sent);
+    }
+}
@@ -1,26 +1,103 @@
 ﻿use crate::{
-    scheme::{impl_event_scheme, Scheme, UartScheme},
-    utils::EventListener,
-    DeviceResult, VirtAddr,
+    builder::IoMapper,
+    bus::{phys_to_virt, PAGE_SIZE},
+    io::{Io, Mmio, MmioRegion},
+    scheme::{
+        impl_event_scheme,
+        uart::{FlowControl, LineConfig, LineErrors, UartConfig, UartEvent},
+        Scheme, UartScheme,
+    },
+    utils::{DmaBuf, EventListener},
+    DeviceError, DeviceResult, PhysAddr, VirtAddr,
 };
 use d1_pac::uart;
 use lock::Mutex;
 
+use super::{baud_divisor, break_chars, line_config, line_ctrl_bits, wait_for};
+
+/// UART 模块的输入时钟，即 APB1 时钟
+const CLOCK_FREQ: u32 = 24_000_000;
+/// 初始化时配置的波特率
+const DEFAULT_BAUD_RATE: u32 = 115200;
+
+/// UART0 的物理地址，UARTn 依次相隔 0x400
+const UART0_PADDR: PhysAddr = 0x0250_0000;
+const UART_STRIDE: usize = 0x400;
+const UART_NUM: usize = 6;
+
+/// 由串口的物理地址得到其编号，地址不是 UART0 到 UART5 之一时返回 `None`
+fn uart_index(paddr: PhysAddr) -> Option<usize> {
+    let offset = paddr.checked_sub(UART0_PADDR)?;
+    let index = offset / UART_STRIDE;
+    (offset % UART_STRIDE == 0 && index < UART_NUM).then(|| index)
+}
+
 pub struct UartAllwinner {
     inner: Mutex<Inner>,
-    listener: EventListener,
+    listener: EventListener<UartEvent>,
 }
 
-impl_event_scheme!(UartAllwinner);
+impl_event_scheme!(UartAllwinner, UartEvent);
 
 impl UartAllwinner {
-    pub fn new(base: VirtAddr) -> Self {
-        let inner = Inner(base);
-        inner.init();
-        Self {
+    pub fn new(base: VirtAddr) -> DeviceResult<Self> {
+        Self::with_clock(base, CLOCK_FREQ, DEFAULT_BAUD_RATE)
+    }
+
+    /// 以输入时钟 `clock_freq` 创建串口，并设置波特率为 `baud`
+    ///
+    /// 无法设置时退回到默认的时钟和波特率。寄存器未对齐时返回
+    /// [`DeviceError::InvalidParam`]，发送一直不能完成时返回
+    /// [`DeviceError::NotReady`]。
+    pub fn with_clock(base: VirtAddr, clock_freq: u32, baud: u32) -> DeviceResult<Self> {
+        let regs = unsafe { MmioRegion::new(base, core::mem::size_of::<uart::RegisterBlock>())? };
+        let mut inner = Inner {
+            regs: regs.block_at(0)?,
+            clock_freq,
+            baud_rate: baud,
+            dma: None,
+            errors: LineErrors::empty(),
+        };
+        inner.init()?;
+        Ok(Self {
             inner: Mutex::new(inner),
             listener: EventListener::new(),
+        })
+    }
+
+    /// 创建使用 DMA 发送的串口，`uart` 是串口的物理地址，`dma_channel` 是占用的 DMA 通道
+    ///
+    /// DMA 请求号由 `uart` 对应的串口编号决定，`uart` 须为 UART0 到 UART5 之一。
+    ///
+    /// 设置 DMA 失败时退回到轮询发送。DMAC 的中断也要交给 [`Scheme::handle_irq`] 处理。
+    pub fn new_with_dma<M: IoMapper>(
+        io_mapper: &M,
+        uart: PhysAddr,
+        dma_channel: usize,
+    ) -> DeviceResult<Self> {
+        let base = io_mapper
+            .query_or_map(uart, UART_STRIDE)
+            .ok_or(DeviceError::NoResources)?;
+        let ret = Self::new(base)?;
+        match Dma::new(io_mapper, uart, dma_channel) {
+            Ok(dma) => {
+                let mut inner = ret.inner.lock();
+                // DMA 模式 1
+                #[rustfmt::skip]
+                inner.block().fcr().write(|w| w
+                    .xfifor().set_bit()
+                    .rfifor().set_bit()
+                    .fifoe() .set_bit()
+                    .dmam()  .set_bit()
+                );
+                inner.dma = Some(dma);
+            }
+            Err(err) => warn!(
+                "uart-allwinner: failed to setup DMA channel {}: {:?}, fallback to PIO",
+                dma_channel, err
+            ),
         }
+        Ok(ret)
     }
 }
 
@@ -30,9 +107,23 @@ impl Scheme for UartAllwinner {
         "uart-allwinner"
     }
 
-    #[inline]
     fn handle_irq(&self, _irq_num: usize) {
-        self.listener.trigger(());
+        // 先释放锁再通知，回调中可能会访问串口
+        let (sent, event, tx_ready) = {
+            let mut inner = self.inner.lock();
+            // DMA 发送完成
+            let sent = inner.dma.as_ref().map_or(false, |dma| dma.ack_interrupt());
+            (sent, inner.line_event(), inner.tx_ready())
+        };
+        if sent {
+            self.listener.trigger(UartEvent::Sent);
+        }
+        if let Some(event) = event {
+            self.listener.trigger(event);
+        }
+        if tx_ready {
+            self.listener.trigger(UartEvent::TxReady);
+        }
     }
 }
 
@@ -47,36 +138,103 @@ impl UartScheme for UartAllwinner {
         self.inner.lock().send(ch)
     }
 
+    #[inline]
+    fn try_send(&self, ch: u8) -> DeviceResult<bool> {
+        Ok(self.inner.lock().try_send(ch))
+    }
+
     #[inline]
     fn write_str(&self, s: &str) -> DeviceResult {
         self.inner.lock().write_str(s)
     }
+
+    #[inline]
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        Ok(self.inner.lock().write_bytes(buf))
+    }
+
+    fn set_tx_irq(&self, enabled: bool) -> DeviceResult {
+        self.inner.lock().set_tx_irq(enabled)
+    }
+
+    fn configure_line(&self, cfg: LineConfig) -> DeviceResult {
+        self.inner.lock().configure_line(cfg)
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        self.inner.lock().line_errors()
+    }
+
+    fn send_break(&self, duration_us: u32) -> DeviceResult {
+        let inner = self.inner.lock();
+        inner.send_break(break_chars(duration_us, inner.baud_rate))
+    }
+
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        self.inner.lock().set_baud_rate(baud)
+    }
+
+    #[inline]
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.inner.lock().baud_rate)
+    }
+
+    fn set_config(&self, config: UartConfig) -> DeviceResult {
+        self.inner.lock().set_config(config)
+    }
+
+    fn config(&self) -> DeviceResult<UartConfig> {
+        Ok(self.inner.lock().config())
+    }
 }
 
-struct Inner(VirtAddr);
+/// 在 DLAB 置位时写入分频系数
+fn write_divisor(block: &uart::RegisterBlock, divisor: u16) {
+    block.lcr.modify(|_, w| w.dlab().set_bit());
+    block.dll().write(|w| w.dll().variant(divisor as u8));
+    block.dlh().write(|w| w.dlh().variant((divisor >> 8) as u8));
+    block.lcr.modify(|_, w| w.dlab().clear_bit());
+}
+
+/// 写入 LCR 的低 6 位
+fn write_line_ctrl(block: &uart::RegisterBlock, bits: u8) {
+    block
+        .lcr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !0x3f | bits as u32) });
+}
+
+struct Inner {
+    regs: &'static uart::RegisterBlock,
+    clock_freq: u32,
+    baud_rate: u32,
+    dma: Option<Dma>,
+    /// 读 LSR 时发现的线路错误，由 `line_errors` 取走
+    errors: LineErrors,
+}
 
 impl Inner {
     /// 初始化串口控制器
     /// BAUD 115200
     /// FIFO ON
-    fn init(&self) {
+    ///
+    /// 发送一直不能完成而无法设置线路时返回错误
+    fn init(&mut self) -> DeviceResult {
         let block = self.block();
         // disable interrupts
         block.ier().reset();
         // enable fifo
         block.fcr().write(|w| w.fifoe().set_bit());
         {
-            block.halt.write(|w| w.halt_tx().set_bit());
-            block.lcr.write(|w| w.dlab().set_bit());
-            // 13 for 115200
-            block.dll().write(|w| w.dll().variant(13));
-            block.dlh().write(|w| w.dlh().variant(0));
             // no break | parity disabled | 1 stop bit | 8 data bits
-            block.lcr.write(|w| w.dls().eight());
-            #[rustfmt::skip]
-            block.halt.write(|w| w
-                .change_update().set_bit()
-                .chcfg_at_busy().set_bit());
+            self.configure_line(LineConfig::default())?;
+            if let Err(err) = self.set_baud_rate(self.baud_rate) {
+                warn!(
+                    "uart-allwinner: failed to set baud rate {} with clock {}Hz: {:?}",
+                    self.baud_rate, self.clock_freq, err
+                );
+                self.clock_freq = CLOCK_FREQ;
+                self.set_baud_rate(DEFAULT_BAUD_RATE)?;
+            }
         }
         // reset fifo
         #[rustfmt::skip]
@@ -89,12 +247,130 @@ impl Inner {
         block.mcr.reset();
         // enable interrupts
         block.ier().write(|w| w.erbfi().set_bit());
+        Ok(())
+    }
+
+    /// 修改 LCR 及分频系数
+    ///
+    /// 等待发送完成后，暂停发送，执行 `f` 修改配置，然后更新配置。
+    /// 发送一直不能完成时不修改配置。
+    fn update_config(&self, f: impl FnOnce(&uart::RegisterBlock)) -> DeviceResult {
+        let block = self.block();
+        // 等待已经进入 FIFO 的数据发送完
+        wait_for(|| block.lsr.read().temt().bit_is_set())?;
+        block.halt.write(|w| w.halt_tx().set_bit());
+        f(block);
+        #[rustfmt::skip]
+        block.halt.write(|w| w
+            .change_update().set_bit()
+            .chcfg_at_busy().set_bit());
+        Ok(())
+    }
+
+    /// 设置波特率
+    ///
+    /// 在 DLAB 置位时写入分频系数。
+    fn set_baud_rate(&mut self, baud: u32) -> DeviceResult {
+        let divisor = baud_divisor(self.clock_freq, baud)?;
+        self.update_config(|block| write_divisor(block, divisor))?;
+        self.baud_rate = baud;
+        Ok(())
+    }
+
+    /// 设置数据位、校验位和停止位
+    ///
+    /// LCR 的低 6 位与 16550 兼容。
+    fn configure_line(&mut self, cfg: LineConfig) -> DeviceResult {
+        let bits = line_ctrl_bits(cfg)?;
+        self.update_config(|block| write_line_ctrl(block, bits))
+    }
+
+    /// 同时设置波特率和帧格式
+    ///
+    /// 先检查全部参数，只暂停一次发送。不支持 RTS/CTS 流控。
+    fn set_config(&mut self, config: UartConfig) -> DeviceResult {
+        if config.flow_control != FlowControl::None {
+            return Err(DeviceError::NotSupported);
+        }
+        let divisor = baud_divisor(self.clock_freq, config.baud)?;
+        let bits = line_ctrl_bits(config.line())?;
+        self.update_config(|block| {
+            write_divisor(block, divisor);
+            write_line_ctrl(block, bits);
+        })?;
+        self.baud_rate = config.baud;
+        Ok(())
+    }
+
+    /// 读取当前的波特率和帧格式
+    fn config(&self) -> UartConfig {
+        let bits = self.block().lcr.read().bits() as u8 & 0x3f;
+        UartConfig::new(self.baud_rate, line_config(bits))
+    }
+
+    /// 读 LSR 判断中断原因，读 LSR 同时清除错误位，因此记录下来
+    fn line_event(&mut self) -> Option<UartEvent> {
+        let lsr = self.block().lsr.read();
+        self.errors |= LineErrors::from_bits_truncate(lsr.bits() as u8);
+        if lsr.bi().bit_is_set() {
+            Some(UartEvent::Break)
+        } else if lsr.oe().bit_is_set() || lsr.pe().bit_is_set() || lsr.fe().bit_is_set() {
+            Some(UartEvent::LineError)
+        } else if lsr.dr().bit_is_set() {
+            Some(UartEvent::Received)
+        } else {
+            None
+        }
+    }
+
+    /// 发送缓冲区空中断已开启，且 THR 为空
+    fn tx_ready(&self) -> bool {
+        let block = self.block();
+        block.ier().read().etbei().bit_is_set() && block.lsr.read().thre().bit_is_set()
+    }
+
+    /// 开启或关闭发送缓冲区空中断
+    ///
+    /// 使用 DMA 发送时不支持，`write_bytes` 会绕过 DMA 打乱顺序。
+    fn set_tx_irq(&self, enabled: bool) -> DeviceResult {
+        if self.dma.is_some() {
+            return Err(DeviceError::NotSupported);
+        }
+        self.block().ier().modify(|_, w| w.etbei().bit(enabled));
+        Ok(())
+    }
+
+    /// 发送 break：置位 LCR 的 BC 位，同时发送 `chars` 个字符计时
+    ///
+    /// 发送一直不能完成时也会清除 BC 位。
+    fn send_break(&self, chars: u64) -> DeviceResult {
+        let block = self.block();
+        if let Some(dma) = &self.dma {
+            dma.wait_idle();
+        }
+        wait_for(|| block.lsr.read().temt().bit_is_set())?;
+        block.lcr.modify(|_, w| w.bc().set_bit());
+        let result = (0..chars)
+            .try_for_each(|_| self.send(0))
+            .and_then(|_| wait_for(|| block.lsr.read().temt().bit_is_set()));
+        block.lcr.modify(|_, w| w.bc().clear_bit());
+        result
+    }
+
+    /// 取走记录的线路错误，LSR 的低位与 16550 兼容
+    fn line_errors(&mut self) -> LineErrors {
+        let lsr = self.block().lsr.read();
+        let errors = self.errors | LineErrors::from_bits_truncate(lsr.bits() as u8);
+        self.errors = LineErrors::empty();
+        errors
     }
 
     /// 接收
-    fn try_recv(&self) -> DeviceResult<Option<u8>> {
+    fn try_recv(&mut self) -> DeviceResult<Option<u8>> {
         let block = self.block();
-        if block.lsr.read().dr().bit_is_set() {
+        let lsr = block.lsr.read();
+        self.errors |= LineErrors::from_bits_truncate(lsr.bits() as u8);
+        if lsr.dr().bit_is_set() {
             Ok(Some(block.rbr().read().bits() as _))
         } else {
             Ok(None)
@@ -103,6 +379,10 @@ impl Inner {
 
     /// 发送
     fn send(&self, ch: u8) -> DeviceResult {
+        // 等待 DMA 发送完，保证顺序
+        if let Some(dma) = &self.dma {
+            dma.wait_idle();
+        }
         let block = self.block();
         // 等待 FIFO 空位
         while block.usr.read().tfnf().is_full() {
@@ -112,7 +392,36 @@ impl Inner {
         Ok(())
     }
 
+    /// 不阻塞地发送一个字节，DMA 未发送完或 THR 非空时返回 `false`
+    fn try_send(&self, ch: u8) -> bool {
+        if self.dma.as_ref().map_or(false, |dma| dma.is_busy()) {
+            return false;
+        }
+        let block = self.block();
+        if !block.lsr.read().thre().bit_is_set() {
+            return false;
+        }
+        block.thr().write(|w| w.thr().variant(ch));
+        true
+    }
+
+    /// 不阻塞地发送，FIFO 满时停止，返回已发送的字节数
+    fn write_bytes(&self, buf: &[u8]) -> usize {
+        let block = self.block();
+        for (i, &c) in buf.iter().enumerate() {
+            if block.usr.read().tfnf().is_full() {
+                return i;
+            }
+            block.thr().write(|w| w.thr().variant(c));
+        }
+        buf.len()
+    }
+
     fn write_str(&mut self, s: &str) -> DeviceResult {
+        if let Some(dma) = &mut self.dma {
+            dma.write_str(s);
+            return Ok(());
+        }
         for b in s.bytes() {
             match b {
                 b'\n' => {
@@ -126,7 +435,174 @@ impl Inner {
     }
 
     #[inline]
-    fn block(&self) -> &uart::RegisterBlock {
-        unsafe { &*(self.0 as *const _) }
+    fn block(&self) -> &'static uart::RegisterBlock {
+        self.regs
+    }
+}
+
+/// DMAC 的物理地址
+const DMAC_PADDR: PhysAddr = 0x0300_2000;
+const DMAC_SIZE: usize = 0x1000;
+const DMAC_CHANNEL_NUM: usize = 16;
+
+/// 中断使能和中断状态寄存器，每个寄存器 8 个通道，每个通道 4 位
+const DMAC_IRQ_EN: usize = 0x00;
+const DMAC_IRQ_PEND: usize = 0x10;
+/// 通道忙状态，每个通道 1 位
+const DMAC_STA: usize = 0x30;
+/// 通道 n 的寄存器位于 0x100 + n * 0x40
+const DMAC_CHANNEL_BASE: usize = 0x100;
+const DMAC_CHANNEL_STRIDE: usize = 0x40;
+const DMAC_EN: usize = 0x00;
+const DMAC_DESC_ADDR: usize = 0x08;
+
+/// 描述符链处理完成中断
+const DMA_QUEUE_IRQ: u32 = 1 << 2;
+/// DRAM 的 DRQ 编号
+const DRQ_DRAM: u32 = 1;
+/// UART0 TX 的 DRQ 编号，UARTn 依次加一
+const DRQ_UART0_TX: u32 = 14;
+/// 目的地址为 IO 模式（地址不递增）
+const DMA_DEST_IO_MODE: u32 = 1 << 24;
+/// 描述符链的结束标志
+const DMA_LINK_END: u32 = 0xffff_f800;
+
+/// DMA 描述符
+#[repr(C)]
+struct DmaDesc {
+    config: u32,
+    src: u32,
+    dst: u32,
+    byte_count: u32,
+    param: u32,
+    link: u32,
+}
+
+/// 一个 DMA 通道，使用一页内存存放描述符和发送缓冲区
+struct Dma {
+    dmac: VirtAddr,
+    channel: usize,
+    /// THR 的物理地址
+    thr: PhysAddr,
+    drq: u32,
+    page: DmaBuf,
+}
+
+impl Dma {
+    /// 缓冲区在页内的偏移，之前是描述符
+    const BUF_OFFSET: usize = 0x40;
+    const BUF_SIZE: usize = PAGE_SIZE - Self::BUF_OFFSET;
+
+    fn new<M: IoMapper>(io_mapper: &M, uart: PhysAddr, channel: usize) -> DeviceResult<Self> {
+        let index = uart_index(uart).ok_or(DeviceError::InvalidParam)?;
+        if channel >= DMAC_CHANNEL_NUM {
+            return Err(DeviceError::InvalidParam);
+        }
+        let dmac = io_mapper
+            .query_or_map(DMAC_PADDR, DMAC_SIZE)
+            .ok_or(DeviceError::NoResources)?;
+        let dma = Self {
+            dmac,
+            channel,
+            thr: uart,
+            drq: DRQ_UART0_TX + index as u32,
+            page: DmaBuf::new(PAGE_SIZE)?,
+        };
+        // 使能描述符链完成中断
+        let (irq_en, shift) = dma.irq_reg(DMAC_IRQ_EN);
+        irq_en.write(irq_en.read() | DMA_QUEUE_IRQ << shift);
+        Ok(dma)
+    }
+
+    fn reg(&self, offset: usize) -> &'static mut Mmio<u32> {
+        unsafe { Mmio::from_base(self.dmac + offset) }
+    }
+
+    fn channel_reg(&self, offset: usize) -> &'static mut Mmio<u32> {
+        self.reg(DMAC_CHANNEL_BASE + self.channel * DMAC_CHANNEL_STRIDE + offset)
+    }
+
+    /// 中断使能或中断状态寄存器，以及本通道的位偏移
+    fn irq_reg(&self, base: usize) -> (&'static mut Mmio<u32>, usize) {
+        (self.reg(base + self.channel / 8 * 4), self.channel % 8 * 4)
+    }
+
+    fn is_busy(&self) -> bool {
+        self.reg(DMAC_STA).read() & (1 << self.channel) != 0
+    }
+
+    fn wait_idle(&self) {
+        while self.is_busy() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// 清除本通道的中断，返回是否有中断
+    fn ack_interrupt(&self) -> bool {
+        let (irq_pend, shift) = self.irq_reg(DMAC_IRQ_PEND);
+        let pending = irq_pend.read() & (0xf << shift);
+        if pending != 0 {
+            irq_pend.write(pending);
+        }
+        pending != 0
+    }
+
+    fn desc(&self) -> &'static mut DmaDesc {
+        unsafe { &mut *(phys_to_virt(self.page.paddr()) as *mut DmaDesc) }
+    }
+
+    fn buf(&self) -> &'static mut [u8] {
+        let vaddr = phys_to_virt(self.page.paddr() + Self::BUF_OFFSET);
+        unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, Self::BUF_SIZE) }
+    }
+
+    /// 把字符串分段复制到缓冲区并提交，最后一段提交后立即返回
+    fn write_str(&mut self, s: &str) {
+        let mut bytes = s.bytes().peekable();
+        while bytes.peek().is_some() {
+            // 上一次发送完成后才能覆盖缓冲区
+            self.wait_idle();
+            let buf = self.buf();
+            let mut len = 0;
+            while len + 2 <= buf.len() {
+                match bytes.next() {
+                    Some(b'\n') => {
+                        buf[len] = b'\r';
+                        buf[len + 1] = b'\n';
+                        len += 2;
+                    }
+                    Some(b) => {
+                        buf[len] = b;
+                        len += 1;
+                    }
+                    None => break,
+                }
+            }
+            self.submit(len);
+        }
+    }
+
+    /// 提交缓冲区中前 `len` 字节的发送
+    fn submit(&mut self, len: usize) {
+        *self.desc() = DmaDesc {
+            // 8 位宽，突发长度为 1
+            config: DRQ_DRAM | self.drq << 16 | DMA_DEST_IO_MODE,
+            src: (self.page.paddr() + Self::BUF_OFFSET) as u32,
+            dst: self.thr as u32,
+            byte_count: len as u32,
+            param: 0,
+            link: DMA_LINK_END,
+        };
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.channel_reg(DMAC_DESC_ADDR)
+            .write(self.page.paddr() as u32);
+        self.channel_reg(DMAC_EN).write(1);
+    }
+}
+
+impl Drop for Dma {
+    /// 先停止通道，再释放描述符和缓冲区所在的页
+    fn drop(&mut self) {
+        self.channel_reg(DMAC_EN).write(0);
     }
 }
@@ -0,0 +1,464 @@
+//! Synopsys DesignWare APB UART, a 16550-compatible UART used in many SoCs,
+//! e.g. RK3399, JH7110 and Allwinner D1.
+//!
+//! Reference: DesignWare DW_apb_uart Databook, and `8250_dw.c` in Linux.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use lock::Mutex;
+
+use crate::io::MmioRegion;
+use crate::scheme::uart::{LineConfig, LineErrors, UartEvent};
+use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult, VirtAddr};
+
+use super::{baud_divisor, break_chars, line_ctrl_bits, wait_for};
+
+/// Depth of the FIFOs if enabled, the minimal configuration of the IP.
+const FIFO_DEPTH: usize = 16;
+/// Maximum number of tries to write LCR while the UART is busy.
+const LCR_WRITE_RETRIES: usize = 1000;
+
+/// Register indexes, the offset is `index << reg_shift`.
+const REG_DATA: usize = 0; // RBR, THR and DLL
+const REG_IER: usize = 1; // IER and DLH
+const REG_IIR: usize = 2; // IIR and FCR
+const REG_LCR: usize = 3;
+const REG_MCR: usize = 4;
+const REG_LSR: usize = 5;
+/// UART status register, specific to the DesignWare UART.
+const REG_USR: usize = 31;
+/// Number of registers in the register window.
+const REG_COUNT: usize = 32;
+
+const IER_RDA: u8 = 1;
+const IER_RLS: u8 = 1 << 2;
+
+/// Interrupt ID in IIR.
+const IIR_ID_MASK: u8 = 0x0f;
+const IIR_NO_INTERRUPT: u8 = 0x01;
+/// LCR was written while the UART is busy, cleared by reading USR.
+const IIR_BUSY_DETECT: u8 = 0x07;
+/// FIFOs are enabled.
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_FORMAT_MASK: u8 = 0x3f;
+const LCR_BREAK: u8 = 1 << 6;
+const LCR_DLAB: u8 = 1 << 7;
+
+/// DTR, RTS and OUT2.
+const MCR_DEFAULT: u8 = 0x0b;
+
+const LSR_DATA_READY: u8 = 1;
+const LSR_ERRORS: u8 = 0x0e;
+const LSR_BREAK: u8 = 1 << 4;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+struct UartDwInner {
+    regs: MmioRegion,
+    reg_shift: u32,
+    reg_io_width: u32,
+    fifo_depth: usize,
+    /// Line errors seen when reading LSR, until taken by `line_errors()`.
+    errors: LineErrors,
+}
+
+impl UartDwInner {
+    fn read(&self, reg: usize) -> u8 {
+        let offset = reg << self.reg_shift;
+        match self.reg_io_width {
+            1 => self.regs.read_at::<u8>(offset),
+            2 => self.regs.read_at::<u16>(offset) as u8,
+            _ => self.regs.read_at::<u32>(offset) as u8,
+        }
+    }
+
+    fn write(&mut self, reg: usize, value: u8) {
+        let offset = reg << self.reg_shift;
+        match self.reg_io_width {
+            1 => self.regs.write_at(offset, value),
+            2 => self.regs.write_at(offset, value as u16),
+            _ => self.regs.write_at(offset, value as u32),
+        }
+    }
+
+    fn init(&mut self) {
+        // Disable interrupts
+        self.write(REG_IER, 0);
+        // Enable and clear the FIFOs
+        self.write(REG_IIR, FCR_FIFO_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+        self.fifo_depth = if self.read(REG_IIR) & IIR_FIFO_ENABLED == IIR_FIFO_ENABLED {
+            FIFO_DEPTH
+        } else {
+            1
+        };
+        // 8 data bits, no parity, 1 stop bit
+        self.set_line_ctrl(line_ctrl_bits(LineConfig::default()).unwrap());
+        self.write(REG_MCR, MCR_DEFAULT);
+        // Enable interrupts of received data and line status
+        self.write(REG_IER, IER_RDA | IER_RLS);
+    }
+
+    /// Writes to LCR are ignored while the UART is busy, which also raises the
+    /// busy detect interrupt. Clear the FIFOs and retry until it sticks, like
+    /// `dw8250_check_lcr` in Linux.
+    fn write_lcr(&mut self, value: u8) {
+        for _ in 0..LCR_WRITE_RETRIES {
+            self.write(REG_LCR, value);
+            if self.read(REG_LCR) == value {
+                return;
+            }
+            self.write(REG_IIR, FCR_FIFO_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+            self.read(REG_DATA);
+            self.read(REG_USR);
+        }
+        warn!(
+            "uart-dw: failed to write LCR {:#x}, the UART is busy",
+            value
+        );
+    }
+
+    fn line_sts(&mut self) -> u8 {
+        let sts = self.read(REG_LSR);
+        self.errors |= LineErrors::from_bits_truncate(sts);
+        sts
+    }
+
+    fn wait_transmitter_empty(&mut self) -> DeviceResult {
+        wait_for(|| self.line_sts() & LSR_TRANSMITTER_EMPTY != 0)
+    }
+
+    /// Replace the frame format bits of LCR, keeping the break control and
+    /// DLAB bits.
+    fn set_line_ctrl(&mut self, bits: u8) {
+        let line_ctrl = self.read(REG_LCR) & !LCR_FORMAT_MASK;
+        self.write_lcr(line_ctrl | bits);
+    }
+
+    /// Read the divisor latch.
+    fn divisor(&mut self) -> u16 {
+        let line_ctrl = self.read(REG_LCR);
+        self.write_lcr(line_ctrl | LCR_DLAB);
+        let divisor = (self.read(REG_IER) as u16) << 8 | self.read(REG_DATA) as u16;
+        self.write_lcr(line_ctrl);
+        divisor
+    }
+
+    /// Program the divisor latch. Wait for the transmitter to drain first, so
+    /// that queued bytes are not sent at the new rate.
+    fn set_divisor(&mut self, divisor: u16) -> DeviceResult {
+        self.wait_transmitter_empty()?;
+        let line_ctrl = self.read(REG_LCR);
+        self.write_lcr(line_ctrl | LCR_DLAB);
+        self.write(REG_DATA, divisor as u8);
+        self.write(REG_IER, (divisor >> 8) as u8);
+        self.write_lcr(line_ctrl & !LCR_DLAB);
+        Ok(())
+    }
+
+    /// Returns the event of the interrupt, or `None` if there is no interrupt
+    /// or it is a busy detect one, which is acknowledged by reading USR.
+    fn interrupt_event(&mut self) -> Option<UartEvent> {
+        match self.read(REG_IIR) & IIR_ID_MASK {
+            IIR_NO_INTERRUPT => None,
+            IIR_BUSY_DETECT => {
+                self.read(REG_USR);
+                None
+            }
+            _ => {
+                let sts = self.line_sts();
+                if sts & LSR_BREAK != 0 {
+                    Some(UartEvent::Break)
+                } else if sts & LSR_ERRORS != 0 {
+                    Some(UartEvent::LineError)
+                } else {
+                    Some(UartEvent::Received)
+                }
+            }
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<u8> {
+        if self.line_sts() & LSR_DATA_READY != 0 {
+            Some(self.read(REG_DATA))
+        } else {
+            None
+        }
+    }
+
+    fn send(&mut self, ch: u8) {
+        while self.line_sts() & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(REG_DATA, ch);
+    }
+
+    /// THRE is set only when the TX FIFO is empty, then up to `fifo_depth`
+    /// bytes can be pushed at once.
+    fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        if self.line_sts() & LSR_THR_EMPTY == 0 {
+            return 0;
+        }
+        let n = buf.len().min(self.fifo_depth);
+        for &c in &buf[..n] {
+            self.write(REG_DATA, c);
+        }
+        n
+    }
+
+    /// Set the break control bit of LCR while sending `chars` characters,
+    /// which are not on the line but take the time to transmit. The break is
+    /// cleared even if the transmitter gets stuck.
+    fn send_break(&mut self, chars: u64) -> DeviceResult {
+        self.wait_transmitter_empty()?;
+        let line_ctrl = self.read(REG_LCR);
+        self.write_lcr(line_ctrl | LCR_BREAK);
+        let mut result = Ok(());
+        for _ in 0..chars {
+            result = wait_for(|| self.line_sts() & LSR_THR_EMPTY != 0);
+            if result.is_err() {
+                break;
+            }
+            self.write(REG_DATA, 0);
+        }
+        let result = result.and_then(|_| self.wait_transmitter_empty());
+        self.write_lcr(line_ctrl & !LCR_BREAK);
+        result
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(b);
+        }
+    }
+}
+
+/// MMIO driver for the DesignWare APB UART.
+pub struct UartDw {
+    inner: Mutex<UartDwInner>,
+    listener: EventListener<UartEvent>,
+    /// The input clock, or 0 if unknown.
+    clock_freq: u32,
+    baud_rate: AtomicU32,
+}
+
+impl_event_scheme!(UartDw, UartEvent);
+
+impl UartDw {
+    /// Construct a `UartDw` whose register `n` is at `base + (n << reg_shift)`
+    /// and accessed in `reg_io_width` bytes, as the `reg-shift` and
+    /// `reg-io-width` properties in the device tree. The baud rate divisor set
+    /// by the firmware is kept.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if `reg_io_width` is not 1, 2 or
+    /// 4, or the registers are misaligned in this layout.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: VirtAddr, reg_shift: u32, reg_io_width: u32) -> DeviceResult<Self> {
+        if !matches!(reg_io_width, 1 | 2 | 4) || reg_shift > 4 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let regs = MmioRegion::new(base, REG_COUNT << reg_shift)?;
+        for reg in 0..REG_COUNT {
+            match reg_io_width {
+                1 => regs.check::<u8>(reg << reg_shift)?,
+                2 => regs.check::<u16>(reg << reg_shift)?,
+                _ => regs.check::<u32>(reg << reg_shift)?,
+            }
+        }
+        let mut inner = UartDwInner {
+            regs,
+            reg_shift,
+            reg_io_width,
+            fifo_depth: 1,
+            errors: LineErrors::empty(),
+        };
+        inner.init();
+        Ok(Self {
+            inner: Mutex::new(inner),
+            listener: EventListener::new(),
+            clock_freq: 0,
+            baud_rate: AtomicU32::new(0),
+        })
+    }
+
+    /// Same as [`UartDw::new`], and set the baud rate to `baud` according to
+    /// the input clock `clock_freq`. If failed, the divisor is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_clock(
+        base: VirtAddr,
+        reg_shift: u32,
+        reg_io_width: u32,
+        clock_freq: u32,
+        baud: u32,
+    ) -> DeviceResult<Self> {
+        let mut uart = Self::new(base, reg_shift, reg_io_width)?;
+        uart.clock_freq = clock_freq;
+        if let Err(err) = uart.set_baud_rate(baud) {
+            warn!(
+                "uart-dw: failed to set baud rate {} with clock {}Hz: {:?}",
+                baud, clock_freq, err
+            );
+        }
+        Ok(uart)
+    }
+}
+
+impl Scheme for UartDw {
+    fn name(&self) -> &str {
+        "uart-dw"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let event = self.inner.lock().interrupt_event();
+        if let Some(event) = event {
+            self.listener.trigger(event);
+        }
+    }
+}
+
+impl UartScheme for UartDw {
+    fn try_recv(&self) -> DeviceResult<Option<u8>> {
+        Ok(self.inner.lock().try_recv())
+    }
+
+    fn send(&self, ch: u8) -> DeviceResult {
+        self.inner.lock().send(ch);
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> DeviceResult {
+        self.inner.lock().write_str(s);
+        Ok(())
+    }
+
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        Ok(self.inner.lock().write_bytes(buf))
+    }
+
+    fn configure_line(&self, cfg: LineConfig) -> DeviceResult {
+        let bits = line_ctrl_bits(cfg)?;
+        self.inner.lock().set_line_ctrl(bits);
+        Ok(())
+    }
+
+    fn line_errors(&self) -> LineErrors {
+        let mut inner = self.inner.lock();
+        inner.line_sts();
+        core::mem::replace(&mut inner.errors, LineErrors::empty())
+    }
+
+    fn send_break(&self, duration_us: u32) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        let baud = match self.baud_rate.load(Ordering::Relaxed) {
+            0 if self.clock_freq != 0 => self.clock_freq / (16 * inner.divisor().max(1) as u32),
+            0 => return Err(DeviceError::NotSupported),
+            baud => baud,
+        };
+        inner.send_break(break_chars(duration_us, baud))
+    }
+
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        if self.clock_freq == 0 {
+            return Err(DeviceError::NotSupported);
+        }
+        let divisor = baud_divisor(self.clock_freq, baud)?;
+        self.inner.lock().set_divisor(divisor)?;
+        self.baud_rate.store(baud, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate.load(Ordering::Relaxed) {
+            0 => None,
+            baud => Some(baud),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheme::EventScheme;
+    use crate::utils::test_utils::MockRegisters;
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::AtomicUsize;
+
+    /// 32-bit registers with `reg-shift` 2 in the memory.
+    fn mock_registers() -> MockRegisters<u32> {
+        let regs = MockRegisters::new(REG_COUNT);
+        regs.write(REG_LSR << 2, (LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY) as u32);
+        regs
+    }
+
+    #[test]
+    fn test_reg_layout() {
+        let regs = mock_registers();
+        let uart = unsafe { UartDw::with_clock(regs.base(), 2, 4, 24_000_000, 115200) }.unwrap();
+        assert_eq!(uart.baud_rate(), Some(115200));
+        // divisor 13 in DLL and DLH, and DLAB is cleared
+        assert_eq!(regs.read(REG_DATA << 2), 13);
+        assert_eq!(regs.read(REG_IER << 2), 0);
+        assert_eq!(regs.read(REG_LCR << 2), 0x03);
+        assert!(unsafe { UartDw::new(regs.base(), 2, 3) }.is_err());
+        // 32-bit registers 1 byte apart
+        assert!(unsafe { UartDw::new(regs.base(), 0, 4) }.is_err());
+    }
+
+    #[test]
+    fn test_busy_detect() {
+        let regs = mock_registers();
+        let uart = unsafe { UartDw::new(regs.base(), 2, 4) }.unwrap();
+        let events = Arc::new(AtomicUsize::new(0));
+        let counter = events.clone();
+        uart.subscribe(
+            Box::new(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }),
+            false,
+        );
+
+        regs.write(REG_IIR << 2, IIR_BUSY_DETECT as u32);
+        uart.handle_irq(0);
+        assert_eq!(events.load(Ordering::Relaxed), 0);
+
+        // received data available
+        regs.write(REG_IIR << 2, 0x04);
+        regs.write(REG_LSR << 2, (LSR_THR_EMPTY | LSR_DATA_READY) as u32);
+        regs.write(REG_DATA << 2, 0x41);
+        uart.handle_irq(0);
+        assert_eq!(events.load(Ordering::Relaxed), 1);
+        assert_eq!(uart.try_recv().unwrap(), Some(0x41));
+    }
+
+    #[test]
+    fn test_stuck_transmitter() {
+        let regs = mock_registers();
+        let uart = unsafe { UartDw::with_clock(regs.base(), 2, 4, 24_000_000, 115200) }.unwrap();
+        regs.write(REG_LSR << 2, LSR_THR_EMPTY as u32);
+        assert!(matches!(
+            uart.set_baud_rate(9600),
+            Err(DeviceError::NotReady)
+        ));
+        assert_eq!(uart.baud_rate(), Some(115200));
+        assert_eq!(regs.read(REG_DATA << 2), 13);
+
+        // the break is cleared when the transmitter does not drain
+        regs.write(REG_LSR << 2, LSR_TRANSMITTER_EMPTY as u32);
+        assert!(matches!(uart.send_break(1000), Err(DeviceError::NotReady)));
+        assert_eq!(regs.read(REG_LCR << 2) & LCR_BREAK as u32, 0);
+    }
+}
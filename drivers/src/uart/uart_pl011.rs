@@ -1,185 +1,338 @@
-//! PL011 UART.
-use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
+//! ARM PrimeCell UART (PL011).
+//!
+//! Reference: <https://developer.arm.com/documentation/ddi0183/latest>
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitflags::bitflags;
+use lock::Mutex;
+
+use crate::io::{Io, Mmio, ReadOnly, WriteOnly};
+use crate::scheme::{impl_event_scheme, uart::UartEvent, Scheme, UartScheme};
 use crate::utils::EventListener;
-use crate::DeviceResult;
-use bitflags::*;
-use core::ptr;
+use crate::{DeviceError, DeviceResult};
+
+use super::{break_chars, wait_for};
+
+/// Baud rate configured at initialization.
+const DEFAULT_BAUD_RATE: u32 = 115200;
 
 bitflags! {
     /// UARTFR
-    struct UartFrFlags: u16 {
-        const TXFE = 1 << 7;
-        const RXFF = 1 << 6;
-        const TXFF = 1 << 5;
-        const RXFE = 1 << 4;
+    struct FlagFlags: u32 {
         const BUSY = 1 << 3;
+        const RX_FIFO_EMPTY = 1 << 4;
+        const TX_FIFO_FULL = 1 << 5;
+        const RX_FIFO_FULL = 1 << 6;
+        const TX_FIFO_EMPTY = 1 << 7;
     }
 }
 
 bitflags! {
-    /// UARTCR
-    struct UartCrFlags: u16 {
-        const RXE = 1 << 9;
-        const TXE = 1 << 8;
-        const UARTEN = 1 << 0;
+    /// UARTLCR_H
+    struct LineCtrlFlags: u32 {
+        const SEND_BREAK = 1;
+        const PARITY_ENABLE = 1 << 1;
+        const EVEN_PARITY = 1 << 2;
+        const TWO_STOP_BITS = 1 << 3;
+        const FIFO_ENABLE = 1 << 4;
+        const WORD_LEN_8 = 0b11 << 5;
+        const STICK_PARITY = 1 << 7;
     }
 }
 
 bitflags! {
-    // UARTIMSC
-    struct UartImscFlags: u16 {
-        const RTIM = 1 << 6;
-        const TXIM = 1 << 5;
-        const RXIM = 1 << 4;
+    /// UARTCR
+    struct CtrlFlags: u32 {
+        const UART_ENABLE = 1;
+        const TX_ENABLE = 1 << 8;
+        const RX_ENABLE = 1 << 9;
     }
 }
 
 bitflags! {
-    // UARTICR
-    struct UartIcrFlags: u16 {
-        const RTIC = 1 << 6;
-        const TXIC = 1 << 5;
-        const RXIC = 1 << 4;
+    /// UARTIMSC, UARTRIS, UARTMIS and UARTICR
+    struct IntFlags: u32 {
+        const RX = 1 << 4;
+        const TX = 1 << 5;
+        const RX_TIMEOUT = 1 << 6;
+        const FRAMING_ERROR = 1 << 7;
+        const PARITY_ERROR = 1 << 8;
+        const BREAK_ERROR = 1 << 9;
+        const OVERRUN_ERROR = 1 << 10;
     }
 }
 
-bitflags! {
-    //UARTMIS
-    struct UartMisFlags: u16 {
-        const TXMIS = 1 << 5;
-        const RXMIS = 1 << 4;
-    }
+#[repr(C)]
+struct Pl011Inner {
+    /// Data register
+    data: Mmio<u32>,
+    /// Receive status / error clear register
+    rx_sts: Mmio<u32>,
+    _reserved0: [u32; 4],
+    /// Flag register
+    flag: ReadOnly<Mmio<u32>>,
+    _reserved1: u32,
+    /// IrDA low-power counter register
+    ilpr: Mmio<u32>,
+    /// Integer baud rate register
+    int_baud: Mmio<u32>,
+    /// Fractional baud rate register
+    frac_baud: Mmio<u32>,
+    /// Line control register
+    line_ctrl: Mmio<u32>,
+    /// Control register
+    ctrl: Mmio<u32>,
+    /// Interrupt FIFO level select register
+    fifo_level: Mmio<u32>,
+    /// Interrupt mask set/clear register
+    int_mask: Mmio<u32>,
+    /// Raw interrupt status register
+    raw_int_sts: ReadOnly<Mmio<u32>>,
+    /// Masked interrupt status register
+    masked_int_sts: ReadOnly<Mmio<u32>>,
+    /// Interrupt clear register
+    int_clear: WriteOnly<Mmio<u32>>,
 }
 
-bitflags! {
-    //UARTLCR_H
-    struct UartLcrhFlags: u16 {
-        const FEN = 1 << 4;
+impl Pl011Inner {
+    /// Initialize the UART, or restore the configuration it had before, e.g.
+    /// set up by the firmware, if it fails.
+    fn init(&mut self, clock_freq: u32, baud: u32) -> DeviceResult {
+        let (ctrl, line_ctrl) = (self.ctrl.read(), self.line_ctrl.read());
+        self.configure(clock_freq, baud).map_err(|err| {
+            self.line_ctrl.write(line_ctrl);
+            self.ctrl.write(ctrl);
+            err
+        })
     }
-}
 
-#[allow(dead_code)]
-pub struct Pl011Uart {
-    inner: Pl011Inner,
-    listener: EventListener,
-}
+    fn configure(&mut self, clock_freq: u32, baud: u32) -> DeviceResult {
+        // Disable the UART before changing its configuration
+        self.ctrl.write(0);
+        wait_for(|| !self.flag().contains(FlagFlags::BUSY))?;
 
-impl Pl011Uart {
-    pub fn new(base: usize) -> Self {
-        Self {
-            inner: {
-                let inner = Pl011Inner::new(base);
-                inner.init();
-                inner
-            },
-            listener: EventListener::new(),
-        }
+        // Flush the transmit FIFO
+        self.line_ctrl.write(0);
+        self.set_baud_rate(clock_freq, baud)?;
+
+        // 8 data bits, no parity, 1 stop bit, FIFO enabled
+        let flags = LineCtrlFlags::WORD_LEN_8 | LineCtrlFlags::FIFO_ENABLE;
+        self.line_ctrl.write(flags.bits());
+
+        // Clear pending interrupts and enable RX and error interrupts
+        self.int_clear.write(0x7ff);
+        let flags = IntFlags::RX
+            | IntFlags::RX_TIMEOUT
+            | IntFlags::FRAMING_ERROR
+            | IntFlags::PARITY_ERROR
+            | IntFlags::BREAK_ERROR
+            | IntFlags::OVERRUN_ERROR;
+        self.int_mask.write(flags.bits());
+
+        // Enable RX, TX, UART
+        let flags = CtrlFlags::RX_ENABLE | CtrlFlags::TX_ENABLE | CtrlFlags::UART_ENABLE;
+        self.ctrl.write(flags.bits());
+        Ok(())
     }
 
-    fn getchar(&self) -> Option<u8> {
-        self.inner.getchar()
+    fn flag(&self) -> FlagFlags {
+        FlagFlags::from_bits_truncate(self.flag.read())
     }
 
-    fn putchar(&self, data: u8) {
-        self.inner.putchar(data);
+    /// Program IBRD and FBRD. The divisor is `clock_freq / (16 * baud)`, with a
+    /// 6-bit fractional part.
+    fn set_baud_rate(&mut self, clock_freq: u32, baud: u32) -> DeviceResult {
+        if baud == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let div = (clock_freq as u64 * 4 + baud as u64 / 2) / baud as u64;
+        let (int_div, frac_div) = (div >> 6, div & 0x3f);
+        if int_div == 0 || int_div > 0xffff {
+            return Err(DeviceError::InvalidParam);
+        }
+        wait_for(|| !self.flag().contains(FlagFlags::BUSY))?;
+        self.int_baud.write(int_div as u32);
+        self.frac_baud.write(frac_div as u32);
+        // The divisors are latched by a write to LCR_H
+        let line_ctrl = self.line_ctrl.read();
+        self.line_ctrl.write(line_ctrl);
+        Ok(())
     }
-}
 
-struct Pl011Inner {
-    base: usize,
-    data_reg: u8,
-    flag_reg: u8,
-    line_ctrl_reg: u8,
-    ctrl_reg: u8,
-    intr_mask_setclr_reg: u8,
-    intr_clr_reg: u8,
-}
+    fn try_recv(&mut self) -> DeviceResult<Option<u8>> {
+        if self.flag().contains(FlagFlags::RX_FIFO_EMPTY) {
+            Ok(None)
+        } else {
+            Ok(Some(self.data.read() as u8))
+        }
+    }
 
-impl Pl011Inner {
-    pub fn new(base: usize) -> Pl011Inner {
-        Pl011Inner {
-            base,
-            data_reg: 0x00,
-            flag_reg: 0x18,
-            line_ctrl_reg: 0x2c,
-            ctrl_reg: 0x30,
-            intr_mask_setclr_reg: 0x38,
-            intr_clr_reg: 0x44,
+    fn send(&mut self, ch: u8) -> DeviceResult {
+        while self.flag().contains(FlagFlags::TX_FIFO_FULL) {}
+        self.data.write(ch as u32);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        for (i, &c) in buf.iter().enumerate() {
+            if self.flag().contains(FlagFlags::TX_FIFO_FULL) {
+                return i;
+            }
+            self.data.write(c as u32);
         }
+        buf.len()
     }
 
-    fn read_reg(&self, register: u8) -> u16 {
-        unsafe { ptr::read_volatile((self.base + register as usize) as *mut u16) }
+    fn write_str(&mut self, s: &str) -> DeviceResult {
+        for b in s.bytes() {
+            match b {
+                b'\n' => {
+                    self.send(b'\r')?;
+                    self.send(b'\n')?;
+                }
+                _ => {
+                    self.send(b)?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn write_reg(&self, register: u8, data: u16) {
-        unsafe {
-            ptr::write_volatile((self.base + register as usize) as *mut u16, data);
+    /// Set BRK of LCR_H while sending `chars` characters, which keeps the
+    /// line low for at least the duration of these characters. BRK is cleared
+    /// even if the transmitter gets stuck.
+    fn send_break(&mut self, chars: u64) -> DeviceResult {
+        wait_for(|| !self.flag().contains(FlagFlags::BUSY))?;
+        let line_ctrl = self.line_ctrl.read();
+        self.line_ctrl
+            .write(line_ctrl | LineCtrlFlags::SEND_BREAK.bits());
+        let mut result = Ok(());
+        for _ in 0..chars {
+            result = wait_for(|| !self.flag().contains(FlagFlags::TX_FIFO_FULL));
+            if result.is_err() {
+                break;
+            }
+            self.data.write(0);
         }
+        let result = result.and_then(|_| wait_for(|| !self.flag().contains(FlagFlags::BUSY)));
+        self.line_ctrl.write(line_ctrl);
+        result
     }
 
-    fn init(&self) {
-        // Enable RX, TX, UART
-        let flags = UartCrFlags::RXE | UartCrFlags::TXE | UartCrFlags::UARTEN;
-        self.write_reg(self.ctrl_reg, flags.bits());
+    /// Read and clear the masked interrupt status.
+    fn ack_interrupt(&mut self) -> IntFlags {
+        let status = IntFlags::from_bits_truncate(self.masked_int_sts.read());
+        self.int_clear.write(status.bits());
+        status
+    }
+}
 
-        // Disable FIFOs (use character mode instead)
-        let mut flags = UartLcrhFlags::from_bits_truncate(self.read_reg(self.line_ctrl_reg));
-        flags.remove(UartLcrhFlags::FEN);
-        self.write_reg(self.line_ctrl_reg, flags.bits());
+/// MMIO driver for ARM PL011 UART.
+pub struct UartPl011 {
+    inner: Mutex<&'static mut Pl011Inner>,
+    listener: EventListener<UartEvent>,
+    clock_freq: u32,
+    baud_rate: AtomicU32,
+}
 
-        // Enable IRQs
-        let flags = UartImscFlags::RXIM;
-        self.write_reg(self.intr_mask_setclr_reg, flags.bits);
+impl_event_scheme!(UartPl011, UartEvent);
 
-        // Clear pending interrupts
-        self.write_reg(self.intr_clr_reg, 0x7ff);
-    }
+/// The PL011 UART with memory-mapped registers, like [`Uart16550Mmio`](super::Uart16550Mmio).
+pub type Pl011Mmio = UartPl011;
 
-    fn line_sts(&self) -> UartFrFlags {
-        UartFrFlags::from_bits_truncate(self.read_reg(self.flag_reg))
+impl UartPl011 {
+    /// Construct a `UartPl011` whose registers start at `base`, and set the
+    /// baud rate to 115200 according to the UART reference clock `clock_freq`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize, clock_freq: u32) -> Self {
+        Self::with_baud_rate(base, clock_freq, DEFAULT_BAUD_RATE)
     }
 
-    fn getchar(&self) -> Option<u8> {
-        if self.line_sts().contains(UartFrFlags::RXFF) {
-            Some(self.read_reg(self.data_reg) as u8)
-        } else {
-            None
+    /// Construct a `UartPl011` whose registers start at `base`, and set the
+    /// baud rate to `baud` according to the UART reference clock `clock_freq`.
+    ///
+    /// If it fails, the UART keeps the configuration it had, and the baud rate
+    /// is reported as unknown.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_baud_rate(base: usize, clock_freq: u32, baud: u32) -> Self {
+        let uart: &mut Pl011Inner = Mmio::<u32>::from_base_as(base);
+        let baud_rate = match uart.init(clock_freq, baud) {
+            Ok(_) => baud,
+            Err(err) => {
+                warn!(
+                    "pl011: failed to set baud rate {} with clock {}Hz, keep the current configuration: {:?}",
+                    baud, clock_freq, err
+                );
+                0
+            }
+        };
+        Self {
+            inner: Mutex::new(uart),
+            listener: EventListener::new(),
+            clock_freq,
+            baud_rate: AtomicU32::new(baud_rate),
         }
     }
-
-    fn putchar(&self, data: u8) {
-        while !self.line_sts().contains(UartFrFlags::TXFE) {}
-        self.write_reg(self.data_reg, data as u16);
-    }
 }
 
-impl Scheme for Pl011Uart {
+impl Scheme for UartPl011 {
     fn name(&self) -> &str {
-        "Pl011 ARM series uart"
+        "uart-pl011"
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.listener.trigger(())
+        let status = self.inner.lock().ack_interrupt();
+        if status.contains(IntFlags::BREAK_ERROR) {
+            self.listener.trigger(UartEvent::Break);
+        } else if status
+            .intersects(IntFlags::FRAMING_ERROR | IntFlags::PARITY_ERROR | IntFlags::OVERRUN_ERROR)
+        {
+            self.listener.trigger(UartEvent::LineError);
+        }
+        if status.intersects(IntFlags::RX | IntFlags::RX_TIMEOUT) {
+            self.listener.trigger(UartEvent::Received);
+        }
     }
 }
 
-impl_event_scheme!(Pl011Uart);
-
-impl UartScheme for Pl011Uart {
+impl UartScheme for UartPl011 {
     fn try_recv(&self) -> DeviceResult<Option<u8>> {
-        Ok(self.getchar())
+        self.inner.lock().try_recv()
     }
 
     fn send(&self, ch: u8) -> DeviceResult {
-        self.putchar(ch);
-        Ok(())
+        self.inner.lock().send(ch)
     }
 
     fn write_str(&self, s: &str) -> DeviceResult {
-        for c in s.bytes() {
-            self.send(c)?;
-        }
+        self.inner.lock().write_str(s)
+    }
+
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        Ok(self.inner.lock().write_bytes(buf))
+    }
+
+    fn send_break(&self, duration_us: u32) -> DeviceResult {
+        let baud = self.baud_rate().ok_or(DeviceError::NotReady)?;
+        self.inner.lock().send_break(break_chars(duration_us, baud))
+    }
+
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        self.inner.lock().set_baud_rate(self.clock_freq, baud)?;
+        self.baud_rate.store(baud, Ordering::Relaxed);
         Ok(())
     }
+
+    fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate.load(Ordering::Relaxed) {
+            0 => None,
+            baud => Some(baud),
+        }
+    }
 }
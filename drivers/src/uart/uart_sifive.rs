@@ -0,0 +1,249 @@
+//! SiFive UART, used in the FU540 and FU740 SoCs.
+//!
+//! Reference: SiFive FU740-C000 Manual, Chapter 15 "Universal Asynchronous
+//! Receiver/Transmitter (UART)".
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitflags::bitflags;
+use lock::Mutex;
+
+use crate::io::{Io, Mmio, ReadOnly};
+use crate::scheme::{impl_event_scheme, uart::UartEvent, Scheme, UartScheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult};
+
+bitflags! {
+    /// TXDATA fields
+    struct TxDataFlags: u32 {
+        const FULL = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// RXDATA fields
+    struct RxDataFlags: u32 {
+        const EMPTY = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// TXCTRL fields
+    struct TxCtrlFlags: u32 {
+        const TXEN = 1;
+        const NSTOP = 1 << 1;
+    }
+}
+
+bitflags! {
+    /// RXCTRL fields
+    struct RxCtrlFlags: u32 {
+        const RXEN = 1;
+    }
+}
+
+bitflags! {
+    /// IE and IP fields
+    struct IntFlags: u32 {
+        const TXWM = 1;
+        const RXWM = 1 << 1;
+    }
+}
+
+/// Mask of the watermark level fields `txcnt` and `rxcnt`.
+const WATERMARK_MASK: u32 = 0b111 << 16;
+
+#[repr(C)]
+struct UartSifiveInner {
+    /// Transmit data register
+    tx_data: Mmio<u32>,
+    /// Receive data register
+    rx_data: ReadOnly<Mmio<u32>>,
+    /// Transmit control register
+    tx_ctrl: Mmio<u32>,
+    /// Receive control register
+    rx_ctrl: Mmio<u32>,
+    /// UART interrupt enable
+    ie: Mmio<u32>,
+    /// UART interrupt pending
+    ip: ReadOnly<Mmio<u32>>,
+    /// Baud rate divisor
+    div: Mmio<u32>,
+}
+
+impl UartSifiveInner {
+    fn init(&mut self) {
+        // Enable transmit with 1 stop bit
+        let tx_ctrl = self.tx_ctrl.read() & !TxCtrlFlags::NSTOP.bits();
+        self.tx_ctrl.write(tx_ctrl | TxCtrlFlags::TXEN.bits());
+
+        // Enable receive, the RX watermark interrupt is pending as long as the
+        // RX FIFO is not empty
+        let rx_ctrl = self.rx_ctrl.read() & !WATERMARK_MASK;
+        self.rx_ctrl.write(rx_ctrl | RxCtrlFlags::RXEN.bits());
+
+        // Enable RX interrupt only
+        self.ie.write(IntFlags::RXWM.bits());
+    }
+
+    /// Program `div`. The baud rate is `clock_freq / (div + 1)`.
+    fn set_baud_rate(&mut self, clock_freq: u32, baud: u32) -> DeviceResult {
+        if baud == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let div = (clock_freq as u64 + baud as u64 / 2) / baud as u64;
+        if div == 0 || div > 0x1_0000 {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.div.write(div as u32 - 1);
+        Ok(())
+    }
+
+    fn pending(&self) -> IntFlags {
+        IntFlags::from_bits_truncate(self.ip.read())
+    }
+
+    fn try_recv(&mut self) -> DeviceResult<Option<u8>> {
+        // Reading RXDATA pops the RX FIFO, so check EMPTY with the same read
+        let data = self.rx_data.read();
+        if RxDataFlags::from_bits_truncate(data).contains(RxDataFlags::EMPTY) {
+            Ok(None)
+        } else {
+            Ok(Some(data as u8))
+        }
+    }
+
+    fn tx_full(&self) -> bool {
+        TxDataFlags::from_bits_truncate(self.tx_data.read()).contains(TxDataFlags::FULL)
+    }
+
+    fn send(&mut self, ch: u8) -> DeviceResult {
+        // Writes are ignored while the TX FIFO is full
+        while self.tx_full() {
+            core::hint::spin_loop();
+        }
+        self.tx_data.write(ch as u32);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        for (i, &c) in buf.iter().enumerate() {
+            if self.tx_full() {
+                return i;
+            }
+            self.tx_data.write(c as u32);
+        }
+        buf.len()
+    }
+
+    fn write_str(&mut self, s: &str) -> DeviceResult {
+        for b in s.bytes() {
+            match b {
+                b'\n' => {
+                    self.send(b'\r')?;
+                    self.send(b'\n')?;
+                }
+                _ => {
+                    self.send(b)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// MMIO driver for SiFive UART.
+pub struct UartSifive {
+    inner: Mutex<&'static mut UartSifiveInner>,
+    listener: EventListener<UartEvent>,
+    /// The input clock, or 0 if unknown.
+    clock_freq: u32,
+    baud_rate: AtomicU32,
+}
+
+impl_event_scheme!(UartSifive, UartEvent);
+
+impl UartSifive {
+    /// Construct a `UartSifive` whose registers start at `base`, and keep the
+    /// baud rate divisor set by the firmware.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn new(base: usize) -> Self {
+        let uart: &mut UartSifiveInner = Mmio::<u32>::from_base_as(base);
+        uart.init();
+        Self {
+            inner: Mutex::new(uart),
+            listener: EventListener::new(),
+            clock_freq: 0,
+            baud_rate: AtomicU32::new(0),
+        }
+    }
+
+    /// Construct a `UartSifive` whose registers start at `base`, and set the
+    /// baud rate to `baud` according to the input clock `clock_freq`. If
+    /// failed, the divisor is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because `base` may be an arbitrary address.
+    pub unsafe fn with_clock(base: usize, clock_freq: u32, baud: u32) -> Self {
+        let mut uart = Self::new(base);
+        uart.clock_freq = clock_freq;
+        if let Err(err) = uart.set_baud_rate(baud) {
+            warn!(
+                "uart-sifive: failed to set baud rate {} with clock {}Hz: {:?}",
+                baud, clock_freq, err
+            );
+        }
+        uart
+    }
+}
+
+impl Scheme for UartSifive {
+    fn name(&self) -> &str {
+        "uart-sifive"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // The listener (e.g. `BufferedUart`) drains RXDATA until it is empty,
+        // which clears the RX watermark interrupt.
+        if self.inner.lock().pending().contains(IntFlags::RXWM) {
+            self.listener.trigger(UartEvent::Received);
+        }
+    }
+}
+
+impl UartScheme for UartSifive {
+    fn try_recv(&self) -> DeviceResult<Option<u8>> {
+        self.inner.lock().try_recv()
+    }
+
+    fn send(&self, ch: u8) -> DeviceResult {
+        self.inner.lock().send(ch)
+    }
+
+    fn write_str(&self, s: &str) -> DeviceResult {
+        self.inner.lock().write_str(s)
+    }
+
+    fn write_bytes(&self, buf: &[u8]) -> DeviceResult<usize> {
+        Ok(self.inner.lock().write_bytes(buf))
+    }
+
+    fn set_baud_rate(&self, baud: u32) -> DeviceResult {
+        if self.clock_freq == 0 {
+            return Err(DeviceError::NotSupported);
+        }
+        self.inner.lock().set_baud_rate(self.clock_freq, baud)?;
+        self.baud_rate.store(baud, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        match self.baud_rate.load(Ordering::Relaxed) {
+            0 => None,
+            baud => Some(baud),
+        }
+    }
+}
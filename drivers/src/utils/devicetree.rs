@@ -1,8 +1,8 @@
 //! Package of [`device_tree`].
 
 use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
-use alloc::vec::Vec;
-use core::ops::Range;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::{convert::TryInto, ops::Range};
 use device_tree::{DeviceTree as DeviceTreeInner, PropError};
 
 pub use device_tree::{util::StringList, Node};
@@ -11,35 +11,332 @@ pub use device_tree::{util::StringList, Node};
 /// properties for any interrupt generating device.
 pub type InterruptsProp = Vec<u32>;
 
+/// The default `#address-cells` if neither the node nor its ancestors specify.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+/// The default `#size-cells` if neither the node nor its ancestors specify.
+const DEFAULT_SIZE_CELLS: u32 = 1;
+/// The maximum `#address-cells` and `#size-cells` supported, as of the PCI bus.
+const MAX_CELLS: u32 = 3;
+
+/// The space code in `phys.hi` of the 32-bit and 64-bit PCI memory spaces.
+const PCI_SPACE_MEM32: u32 = 2;
+const PCI_SPACE_MEM64: u32 = 3;
+
+/// The maximum number of nested interrupt nexus nodes an interrupt may be
+/// routed through.
+const MAX_NEXUS_DEPTH: usize = 8;
+
+/// The magic number at the beginning of the device tree blob.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The size of the header of the device tree blob.
+pub(crate) const FDT_HEADER_SIZE: usize = 40;
+/// The maximum size of the device tree blob, same as Linux.
+const FDT_MAX_SIZE: u32 = 0x20_0000;
+/// The oldest version of the device tree blob that is supported.
+const FDT_FIRST_VERSION: u32 = 16;
+/// The latest version of the device tree blob that is supported.
+const FDT_LAST_VERSION: u32 = 17;
+
+/// Why the device tree blob is considered malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtbError {
+    /// The blob is shorter than its header or `totalsize` says.
+    Truncated,
+    /// The magic number is not `0xd00dfeed`.
+    BadMagic(u32),
+    /// The `totalsize` is smaller than the header or too large.
+    BadTotalSize(u32),
+    /// A block specified by the header is out of `totalsize`.
+    BadOffset,
+    /// The `version` and `last_comp_version` are not supported.
+    BadVersion(u32),
+}
+
+impl From<DtbError> for DeviceError {
+    fn from(_err: DtbError) -> Self {
+        Self::InvalidDtb
+    }
+}
+
+/// Validate the header of the device tree blob, and returns the `totalsize`.
+///
+/// Only the header is needed in `blob` if `check_len` is false.
+fn check_header(blob: &[u8], check_len: bool) -> Result<usize, DtbError> {
+    if blob.len() < FDT_HEADER_SIZE {
+        return Err(DtbError::Truncated);
+    }
+    let field = |i: usize| u32::from_be_bytes(blob[i * 4..i * 4 + 4].try_into().unwrap());
+    let (magic, total_size) = (field(0), field(1));
+    let (off_struct, off_strings, off_rsvmap) = (field(2), field(3), field(4));
+    let (version, last_comp_version) = (field(5), field(6));
+    if magic != FDT_MAGIC {
+        return Err(DtbError::BadMagic(magic));
+    }
+    if (total_size as usize) < FDT_HEADER_SIZE || total_size > FDT_MAX_SIZE {
+        return Err(DtbError::BadTotalSize(total_size));
+    }
+    if check_len && blob.len() < total_size as usize {
+        return Err(DtbError::Truncated);
+    }
+    if version < FDT_FIRST_VERSION || last_comp_version > FDT_LAST_VERSION {
+        return Err(DtbError::BadVersion(version));
+    }
+    // `size_dt_struct` is added in version 17
+    let size_struct = if version >= 17 { field(9) } else { 0 };
+    let size_strings = field(8);
+    let in_blob = |off: u32, size: u32| {
+        (off as usize) >= FDT_HEADER_SIZE && off as u64 + size as u64 <= total_size as u64
+    };
+    if !in_blob(off_struct, size_struct)
+        || !in_blob(off_strings, size_strings)
+        || !in_blob(off_rsvmap, 0)
+    {
+        return Err(DtbError::BadOffset);
+    }
+    Ok(total_size as usize)
+}
+
+/// Parse the memory reservation block, and returns the non-empty `(address,
+/// size)` entries.
+fn parse_mem_rsvmap(blob: &[u8]) -> Vec<(u64, u64)> {
+    let off_rsvmap = u32::from_be_bytes(blob[16..20].try_into().unwrap()) as usize;
+    let entries = blob[off_rsvmap.min(blob.len())..].chunks_exact(16);
+    entries
+        .map(|e| {
+            let addr = u64::from_be_bytes(e[..8].try_into().unwrap());
+            let size = u64::from_be_bytes(e[8..].try_into().unwrap());
+            (addr, size)
+        })
+        .take_while(|&(addr, size)| addr != 0 || size != 0)
+        .filter(|&(_, size)| size != 0)
+        .collect()
+}
+
+/// Sort and merge the overlapping or adjacent `regions`, then remove the
+/// `reserved` ones from them.
+fn subtract_regions(mut regions: Vec<Range<u64>>, reserved: &[Range<u64>]) -> Vec<Range<u64>> {
+    regions.retain(|r| !r.is_empty());
+    regions.sort_unstable_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(regions.len());
+    for r in regions {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    for res in reserved.iter().filter(|r| !r.is_empty()) {
+        let mut ret = Vec::with_capacity(merged.len() + 1);
+        for r in merged {
+            if res.end <= r.start || r.end <= res.start {
+                ret.push(r);
+                continue;
+            }
+            if r.start < res.start {
+                ret.push(r.start..res.start);
+            }
+            if res.end < r.end {
+                ret.push(res.end..r.end);
+            }
+        }
+        merged = ret;
+    }
+    merged
+}
+
 /// A wrapper structure of `device_tree::DeviceTree`.
-pub struct Devicetree(DeviceTreeInner);
+pub struct Devicetree(
+    DeviceTreeInner,
+    /// Entries of the memory reservation block in the header.
+    Vec<(u64, u64)>,
+);
+
+/// Information of a CPU node in `/cpus`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// The CPU ID in `reg`, i.e. the hart ID on RISC-V.
+    pub id: usize,
+    /// Whether the `status` is not `"disabled"`.
+    pub enabled: bool,
+    /// The `riscv,isa` property, e.g. `"rv64imafdc"`.
+    pub isa: Option<String>,
+    /// The `timebase-frequency` of this CPU, or of `/cpus` if not present.
+    pub timebase_frequency: Option<u32>,
+}
+
+/// An entry of the `ranges` property, which maps a range of child bus
+/// addresses into the parent address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddrRange {
+    /// The PCI address space of `child_addr`, i.e. the space code in bits
+    /// 24-25 of `phys.hi`, or `None` if the child bus is not of PCI.
+    pub child_space: Option<u32>,
+    /// The start address in the child bus address space.
+    pub child_addr: u64,
+    /// The start address in the parent address space.
+    pub parent_addr: u64,
+    /// The size of the range.
+    pub size: u64,
+}
+
+/// An entry of the `interrupt-map` property.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterruptMapEntry {
+    /// The unit address of the child node, in `#address-cells` of the nexus.
+    pub child_unit_addr: Vec<u32>,
+    /// The interrupt specifier of the child node, in `#interrupt-cells` of the
+    /// nexus.
+    pub child_spec: Vec<u32>,
+    /// The phandle of the interrupt parent the interrupt is routed to.
+    pub parent: u32,
+    /// The unit address for the interrupt parent, in its `#address-cells`,
+    /// which is used if the parent is also a nexus.
+    pub parent_unit_addr: Vec<u32>,
+    /// The translated interrupt specifier for the interrupt parent.
+    pub parent_spec: Vec<u32>,
+}
+
+/// The `interrupt-map` and `interrupt-map-mask` properties of an interrupt
+/// nexus node, e.g. a PCI host bridge, about the notion: <https://elinux.org/Device_Tree_Usage#Advanced_Interrupt_Mapping>.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterruptMap {
+    /// The `#address-cells` property of the nexus.
+    pub address_cells: u32,
+    /// The `#interrupt-cells` property of the nexus.
+    pub interrupt_cells: u32,
+    /// The `interrupt-map-mask` property, applied to the unit address followed
+    /// by the interrupt specifier before matching. All ones if absent.
+    pub mask: Vec<u32>,
+    /// Entries of the `interrupt-map` property.
+    pub entries: Vec<InterruptMapEntry>,
+}
+
+impl InterruptMap {
+    /// Find the entry matching the child unit address and interrupt specifier,
+    /// and returns the phandle of the interrupt parent and the translated
+    /// interrupt specifier.
+    pub fn translate(&self, unit_addr: &[u32], spec: &[u32]) -> Option<(u32, &[u32])> {
+        self.find(unit_addr, spec)
+            .map(|e| (e.parent, e.parent_spec.as_slice()))
+    }
+
+    /// Find the entry matching the child unit address and interrupt specifier.
+    pub fn find(&self, unit_addr: &[u32], spec: &[u32]) -> Option<&InterruptMapEntry> {
+        let masked = |i: usize, cell: u32| cell & self.mask.get(i).copied().unwrap_or(u32::MAX);
+        let matches = |expected: &[u32], actual: &[u32], offset: usize| {
+            expected.len() == actual.len()
+                && expected
+                    .iter()
+                    .zip(actual)
+                    .enumerate()
+                    .all(|(i, (&e, &a))| masked(offset + i, e) == masked(offset + i, a))
+        };
+        self.entries.iter().find(|e| {
+            matches(&e.child_unit_addr, unit_addr, 0)
+                && matches(&e.child_spec, spec, self.address_cells as usize)
+        })
+    }
+}
 
 /// Some properties inherited from ancestor nodes.
 ///
 /// About the notion: cell, see <https://elinux.org/Device_Tree_Usage#How_Addressing_Works>.
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct InheritProps {
-    /// The `#address-cells` property of its parent node.
+    /// The full path of the node, e.g. `/soc/serial@10000000`.
+    pub path: String,
+    /// The `#address-cells` property of its parent node. If don't have,
+    /// inherit from the nearest ancestor that has.
     pub parent_address_cells: u32,
-    /// The `#size-cells` property of its parent node.
+    /// The `#size-cells` property of its parent node. If don't have, inherit
+    /// from the nearest ancestor that has.
     pub parent_size_cells: u32,
     /// The `interrupt-parent` property of the node. If don't have, inherit from
     /// its parent node.
     pub interrupt_parent: u32,
+    /// The `#interrupt-cells` property of the interrupt parent.
+    pub interrupt_cells: u32,
+    /// The `interrupt-map` of the interrupt parent, if it is an interrupt
+    /// nexus rather than an interrupt controller.
+    pub interrupt_map: Option<Arc<InterruptMap>>,
+    /// The translation from addresses of the parent bus to CPU physical
+    /// addresses, composed from the `ranges` of all ancestor nodes. `None`
+    /// means the identity mapping.
+    pub ranges: Option<Vec<AddrRange>>,
+    /// Whether any ancestor node is disabled by its `status` property, then
+    /// the node is not operational either.
+    pub parent_disabled: bool,
+}
+
+impl InheritProps {
+    /// Translate an address of the parent bus to the CPU physical address.
+    ///
+    /// `space` is the PCI address space of `addr` as [`AddrRange::child_space`],
+    /// only the ranges of the same space are matched, so that the I/O and
+    /// memory spaces at the same PCI address are told apart.
+    pub fn translate(&self, space: Option<u32>, addr: u64) -> Option<u64> {
+        match &self.ranges {
+            None => Some(addr),
+            Some(ranges) => ranges
+                .iter()
+                .find(|r| {
+                    same_pci_space(r.child_space, space)
+                        && addr >= r.child_addr
+                        && addr - r.child_addr < r.size
+                })
+                .map(|r| r.parent_addr + (addr - r.child_addr)),
+        }
+    }
+}
+
+/// Whether two PCI space codes refer to the same address space. The 32-bit
+/// and 64-bit memory spaces are the same one, as in Linux.
+fn same_pci_space(a: Option<u32>, b: Option<u32>) -> bool {
+    let normalize = |space: Option<u32>| match space {
+        Some(PCI_SPACE_MEM64) => Some(PCI_SPACE_MEM32),
+        other => other,
+    };
+    normalize(a) == normalize(b)
 }
 
 impl Devicetree {
     /// Load the device tree blob from the given virtual address.
+    ///
+    /// The header is validated before the whole blob is accessed.
     pub fn from(dtb_base_vaddr: VirtAddr) -> DeviceResult<Self> {
         info!("Loading device tree blob from {:#x}", dtb_base_vaddr);
-        match unsafe { DeviceTreeInner::load_from_raw_pointer(dtb_base_vaddr as *const _) } {
-            Ok(dt) => Ok(Self(dt)),
+        let header =
+            unsafe { core::slice::from_raw_parts(dtb_base_vaddr as *const u8, FDT_HEADER_SIZE) };
+        let total_size = check_header(header, false).map_err(|err| {
+            warn!(
+                "device-tree: invalid DTB header @ {:#x}: {:?}",
+                dtb_base_vaddr, err
+            );
+            err
+        })?;
+        let blob = unsafe { core::slice::from_raw_parts(dtb_base_vaddr as *const u8, total_size) };
+        Self::from_bytes(blob)
+    }
+
+    /// Validate the header of the device tree blob, and returns its
+    /// `totalsize`. Only the first [`FDT_HEADER_SIZE`] bytes are accessed.
+    pub(crate) fn blob_size(header: &[u8]) -> DeviceResult<usize> {
+        check_header(header, false).map_err(|err| {
+            warn!("device-tree: invalid DTB header: {:?}", err);
+            err.into()
+        })
+    }
+
+    /// Load the device tree blob from the given bytes.
+    pub fn from_bytes(blob: &[u8]) -> DeviceResult<Self> {
+        if let Err(err) = check_header(blob, true) {
+            warn!("device-tree: invalid DTB header: {:?}", err);
+            return Err(err.into());
+        }
+        match DeviceTreeInner::load(blob) {
+            Ok(dt) => Ok(Self(dt, parse_mem_rsvmap(blob))),
             Err(err) => {
-                warn!(
-                    "device-tree: failed to load DTB @ {:#x}: {:?}",
-                    dtb_base_vaddr, err
-                );
-                Err(DeviceError::InvalidParam)
+                warn!("device-tree: failed to load DTB: {:?}", err);
+                Err(DeviceError::InvalidDtb)
             }
         }
     }
@@ -49,19 +346,53 @@ impl Devicetree {
         F: FnMut(&Node, &StringList, &InheritProps),
     {
         let mut props = props;
+        if !props.path.ends_with('/') {
+            props.path.push('/');
+        }
+        props.path.push_str(&node.name);
         if let Ok(num) = node.prop_u32("interrupt-parent") {
+            let intc = self.find_by_phandle(num);
             props.interrupt_parent = num;
+            props.interrupt_cells = intc
+                .and_then(|intc| intc.prop_u32("#interrupt-cells").ok())
+                .unwrap_or(0);
+            props.interrupt_map = intc.and_then(|intc| self.interrupt_map(intc));
         }
         if let Ok(comp) = node.prop_str_list("compatible") {
             device_node_op(node, &comp, &props);
         }
 
-        props.parent_address_cells = node.prop_u32("#address-cells").unwrap_or(0);
-        props.parent_size_cells = node.prop_u32("#size-cells").unwrap_or(0);
+        // children without `interrupt-parent` route interrupts through the nexus
+        if let Some(map) = self.interrupt_map(node) {
+            props.interrupt_parent = node.prop_u32("phandle").unwrap_or(0);
+            props.interrupt_cells = map.interrupt_cells;
+            props.interrupt_map = Some(map);
+        }
+
+        let address_cells = node
+            .prop_u32("#address-cells")
+            .unwrap_or(props.parent_address_cells);
+        let size_cells = node
+            .prop_u32("#size-cells")
+            .unwrap_or(props.parent_size_cells);
+        // the root node has no parent bus to translate to
+        if !core::ptr::eq(node, &self.0.root) {
+            match parse_ranges(node, &props, address_cells, size_cells) {
+                Ok(Some(ranges)) => props.ranges = Some(ranges),
+                Ok(None) => {}
+                Err(err) => warn!(
+                    "device-tree: failed to parse ranges of node {:?}: {:?}",
+                    node.name, err
+                ),
+            }
+        }
+        props.parent_address_cells = address_cells;
+        props.parent_size_cells = size_cells;
+        props.parent_disabled |= !is_enabled(node);
 
         // DFS
         for child in node.children.iter() {
-            self.walk_inner(child, props, device_node_op);
+            self.walk_inner(child, props.clone(), device_node_op);
         }
     }
 
@@ -71,89 +402,1652 @@ impl Devicetree {
     where
         F: FnMut(&Node, &StringList, &InheritProps),
     {
-        self.walk_inner(&self.0.root, InheritProps::default(), device_node_op)
+        let props = InheritProps {
+            path: String::new(),
+            parent_address_cells: DEFAULT_ADDRESS_CELLS,
+            parent_size_cells: DEFAULT_SIZE_CELLS,
+            ..Default::default()
+        };
+        self.walk_inner(&self.0.root, props, device_node_op)
+    }
+
+    /// Parse the `interrupt-map` of an interrupt nexus node. Returns `None` if
+    /// the node is not a nexus or the map is malformed.
+    fn interrupt_map(&self, node: &Node) -> Option<Arc<InterruptMap>> {
+        if !node.has_prop("interrupt-map") {
+            return None;
+        }
+        match self.parse_interrupt_map(node) {
+            Ok(map) => Some(Arc::new(map)),
+            Err(err) => {
+                warn!(
+                    "device-tree: failed to parse interrupt-map of node {:?}: {:?}",
+                    node.name, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Parse the `interrupt-map` and `interrupt-map-mask` properties. Each
+    /// entry is `<child-unit-addr child-spec parent parent-unit-addr
+    /// parent-spec>`, where the lengths of the last two depend on the
+    /// `#address-cells` and `#interrupt-cells` of that interrupt parent.
+    pub fn parse_interrupt_map(&self, node: &Node) -> DeviceResult<InterruptMap> {
+        let address_cells = node.prop_u32("#address-cells").unwrap_or(0);
+        let interrupt_cells = node.prop_u32("#interrupt-cells")?;
+        let child_len = (address_cells + interrupt_cells) as usize;
+        let mask = if node.has_prop("interrupt-map-mask") {
+            node.prop_cells("interrupt-map-mask")?
+        } else {
+            Vec::new()
+        };
+
+        let cells = node.prop_cells("interrupt-map")?;
+        let mut rest = cells.as_slice();
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            if rest.len() <= child_len {
+                return Err(DeviceError::InvalidParam);
+            }
+            let (child, parent) = (&rest[..child_len], rest[child_len]);
+            let intc = self.find_by_phandle(parent).ok_or_else(|| {
+                warn!(
+                    "device-tree: interrupt-map of node {:?}: no such node with phandle {:#x}",
+                    node.name, parent
+                );
+                DeviceError::InvalidParam
+            })?;
+            let parent_addr_len = intc.prop_u32("#address-cells").unwrap_or(0) as usize;
+            let parent_spec_len = intc.prop_u32("#interrupt-cells")? as usize;
+            let start = child_len + 1 + parent_addr_len;
+            let end = start + parent_spec_len;
+            if rest.len() < end {
+                return Err(DeviceError::InvalidParam);
+            }
+            entries.push(InterruptMapEntry {
+                child_unit_addr: child[..address_cells as usize].to_vec(),
+                child_spec: child[address_cells as usize..].to_vec(),
+                parent,
+                parent_unit_addr: rest[child_len + 1..start].to_vec(),
+                parent_spec: rest[start..end].to_vec(),
+            });
+            rest = &rest[end..];
+        }
+        Ok(InterruptMap {
+            address_cells,
+            interrupt_cells,
+            mask,
+            entries,
+        })
+    }
+
+    /// Route the interrupts in the form of `interrupts-extended` of the node
+    /// through interrupt nexus nodes, until they reach interrupt controllers.
+    ///
+    /// Interrupts not in the `interrupt-map` are dropped. Interrupts to
+    /// unknown phandles are kept as is, for the caller to report.
+    pub fn resolve_interrupts(&self, node: &Node, interrupts: &[u32]) -> InterruptsProp {
+        let mut ret = Vec::with_capacity(interrupts.len());
+        let mut rest = interrupts;
+        while let [phandle, tail @ ..] = rest {
+            let cells = match self
+                .find_by_phandle(*phandle)
+                .and_then(|parent| parent.prop_u32("#interrupt-cells").ok())
+            {
+                Some(cells) => (cells as usize).min(tail.len()),
+                None => {
+                    ret.extend_from_slice(rest);
+                    break;
+                }
+            };
+            let (spec, next) = tail.split_at(cells);
+            match self.route_interrupt(node, *phandle, spec) {
+                Some((parent, parent_spec)) => {
+                    ret.push(parent);
+                    ret.extend_from_slice(&parent_spec);
+                }
+                None => warn!(
+                    "device-tree: interrupt {:x?} of node {:?} to phandle {:#x} is not routed",
+                    spec, node.name, phandle
+                ),
+            }
+            rest = next;
+        }
+        ret
+    }
+
+    /// Route an interrupt to the interrupt parent `phandle` through nexus
+    /// nodes, and returns the final interrupt parent and specifier.
+    fn route_interrupt(&self, node: &Node, phandle: u32, spec: &[u32]) -> Option<(u32, Vec<u32>)> {
+        let (mut phandle, mut spec) = (phandle, spec.to_vec());
+        let mut unit_addr = node.prop_cells("reg").unwrap_or_default();
+        for _ in 0..MAX_NEXUS_DEPTH {
+            let parent = match self.find_by_phandle(phandle) {
+                Some(parent) => parent,
+                None => return Some((phandle, spec)),
+            };
+            if parent.has_prop("interrupt-controller") || !parent.has_prop("interrupt-map") {
+                return Some((phandle, spec));
+            }
+            let map = self.parse_interrupt_map(parent).ok()?;
+            unit_addr.resize(map.address_cells as usize, 0);
+            let entry = map.find(&unit_addr, &spec)?;
+            phandle = entry.parent;
+            spec = entry.parent_spec.clone();
+            unit_addr = entry.parent_unit_addr.clone();
+        }
+        warn!(
+            "device-tree: interrupt-map of node {:?} is nested too deeply",
+            node.name
+        );
+        None
+    }
+
+    /// Find the node with the given `phandle`.
+    pub fn find_by_phandle(&self, phandle: u32) -> Option<&Node> {
+        fn find(node: &Node, phandle: u32) -> Option<&Node> {
+            if node.prop_u32("phandle").ok() == Some(phandle) {
+                return Some(node);
+            }
+            node.children.iter().find_map(|child| find(child, phandle))
+        }
+        find(&self.0.root, phandle)
+    }
+
+    /// Find all nodes with `compat` as any string of their `compatible`
+    /// property, in the order of DFS.
+    pub fn find_compatible(&self, compat: &str) -> Vec<&Node> {
+        fn find<'a>(node: &'a Node, compat: &str, found: &mut Vec<&'a Node>) {
+            if let Ok(comp) = node.prop_str_list("compatible") {
+                if comp.contains(compat) {
+                    found.push(node);
+                }
+            }
+            for child in node.children.iter() {
+                find(child, compat, found);
+            }
+        }
+        let mut found = Vec::new();
+        find(&self.0.root, compat, &mut found);
+        found
+    }
+
+    /// Find the node with the given full path, e.g. `/soc/serial@10000000`.
+    pub fn node_at_path(&self, path: &str) -> Option<&Node> {
+        if path.starts_with('/') {
+            self.0.find(path)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the properties inherited by the `target` node, which must have
+    /// the `compatible` property, e.g. to parse the `reg` of a node referred
+    /// by phandle.
+    pub fn inherit_props(&self, target: &Node) -> Option<InheritProps> {
+        let mut found = None;
+        self.walk(&mut |node, _, props| {
+            if found.is_none() && core::ptr::eq(node, target) {
+                found = Some(props.clone());
+            }
+        });
+        found
+    }
+
+    /// Find the node with the given full path, or the alias in the `/aliases`
+    /// node.
+    pub fn find_by_path(&self, path: &str) -> Option<&Node> {
+        if path.starts_with('/') {
+            self.node_at_path(path)
+        } else {
+            let path = self.node_at_path("/aliases")?.prop_str(path).ok()?;
+            self.node_at_path(path)
+        }
+    }
+
+    /// Returns all aliases in the `/aliases` node, with the nodes they refer
+    /// to. Aliases to non-existent nodes are ignored.
+    pub fn aliases(&self) -> Vec<(&str, &Node)> {
+        let aliases = match self.0.find("/aliases") {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        aliases
+            .props
+            .iter()
+            .filter_map(|(name, _)| {
+                let path = aliases.prop_str(name).ok()?;
+                if path.starts_with('/') {
+                    Some((name.as_str(), self.0.find(path)?))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the node specified by the `stdout-path` property in the
+    /// `/chosen` node, as the boot console.
+    ///
+    /// The options after `:` (e.g. `"serial0:115200n8"`) are ignored.
+    pub fn stdout_node(&self) -> Option<&Node> {
+        let chosen = self.0.find("/chosen")?;
+        let stdout_path = chosen
+            .prop_str("stdout-path")
+            .or_else(|_| chosen.prop_str("linux,stdout-path"))
+            .ok()?;
+        let path = stdout_path.split(':').next()?;
+        self.find_by_path(path)
     }
 
     /// Returns the `bootargs` property in the `/chosen` node, as the kernel
-    /// command line.
+    /// command line. It ends at the first NUL, and `None` is returned if it is
+    /// absent or empty.
     pub fn bootargs(&self) -> Option<&str> {
-        self.0.find("/chosen")?.prop_str("bootargs").ok()
+        let value = prop_bytes(self.0.find("/chosen")?, "bootargs").ok()?;
+        let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+        core::str::from_utf8(&value[..len])
+            .ok()
+            .filter(|s| !s.is_empty())
     }
 
-    /// Returns the `timebase-frequency` property in the `/cpus` node, as timer
+    /// Returns the `timebase-frequency` property in the `/cpus` node, or the
+    /// one of the first enabled CPU, as the timer frequency.
     pub fn timebase_frequency(&self) -> Option<u32> {
-        self.0.find("/cpus")?.prop_u32("timebase-frequency").ok()
+        if let Ok(freq) = self.0.find("/cpus")?.prop_u32("timebase-frequency") {
+            return Some(freq);
+        }
+        self.cpus()
+            .iter()
+            .filter(|cpu| cpu.enabled)
+            .find_map(|cpu| cpu.timebase_frequency)
+    }
+
+    /// Returns all CPU nodes in `/cpus`, including the disabled ones, in the
+    /// order of the device tree. Other nodes like `cpu-map` are ignored.
+    pub fn cpus(&self) -> Vec<CpuInfo> {
+        let cpus = match self.0.find("/cpus") {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        let address_cells = cpus.prop_u32("#address-cells").unwrap_or(1);
+        let default_timebase = cpus.prop_u32("timebase-frequency").ok();
+        cpus.children
+            .iter()
+            .filter(|node| {
+                node.prop_str("device_type")
+                    .map_or(node.name.starts_with("cpu@"), |t| t == "cpu")
+            })
+            .filter_map(|node| {
+                let reg = node.prop_cells("reg").ok()?;
+                let id = from_cells(&reg, address_cells).ok()? as usize;
+                Some(CpuInfo {
+                    id,
+                    enabled: node.prop_str("status").map_or(true, |s| s != "disabled"),
+                    isa: node.prop_str("riscv,isa").ok().map(String::from),
+                    timebase_frequency: node
+                        .prop_u32("timebase-frequency")
+                        .ok()
+                        .or(default_timebase),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the frequency of the input clock of the node, from its
+    /// `clock-frequency` property, or the `clock-frequency` of the first clock
+    /// in its `clocks` property if it is a `fixed-clock`.
+    pub fn clock_frequency(&self, node: &Node) -> Option<u32> {
+        if let Ok(freq) = node.prop_u32("clock-frequency") {
+            return Some(freq);
+        }
+        let phandle = *node.prop_cells("clocks").ok()?.first()?;
+        let clock = self.find_by_phandle(phandle)?;
+        if clock.prop_str("compatible").ok()? == "fixed-clock" {
+            clock.prop_u32("clock-frequency").ok()
+        } else {
+            None
+        }
     }
 
     /// Returns the `linux,initrd-start` and `linux,initrd-end` properties in
-    /// the `/chosen` node, as the init RAM disk address region.
-    pub fn initrd_region(&self) -> Option<Range<PhysAddr>> {
+    /// the `/chosen` node, as the address and size of the init RAM disk. Both
+    /// 32-bit and 64-bit values are accepted.
+    pub fn initrd(&self) -> Option<(PhysAddr, usize)> {
         let chosen = self.0.find("/chosen")?;
-        let start = chosen.prop_u32("linux,initrd-start").ok()? as _;
-        let end = chosen.prop_u32("linux,initrd-end").ok()? as _;
-        Some(start..end)
+        let start = NodeExt::prop_u64(chosen, "linux,initrd-start").ok()?;
+        let end = NodeExt::prop_u64(chosen, "linux,initrd-end").ok()?;
+        if end < start {
+            return None;
+        }
+        Some((start as PhysAddr, (end - start) as usize))
     }
 
-    /// Returns the physical memory regions specified in the `/memory` nodes.
-    pub fn memory_regions(&self) -> DeviceResult<Vec<Range<PhysAddr>>> {
-        let props = InheritProps {
-            parent_address_cells: self.0.root.prop_u32("#address-cells").unwrap_or(0),
-            parent_size_cells: self.0.root.prop_u32("#size-cells").unwrap_or(0),
+    /// Same as [`initrd`](Self::initrd), as the init RAM disk address region.
+    pub fn initrd_region(&self) -> Option<Range<PhysAddr>> {
+        self.initrd().map(|(start, size)| start..start + size)
+    }
+
+    /// Returns the properties inherited by the children of the root node.
+    fn root_props(&self) -> InheritProps {
+        let root = &self.0.root;
+        InheritProps {
+            parent_address_cells: root
+                .prop_u32("#address-cells")
+                .unwrap_or(DEFAULT_ADDRESS_CELLS),
+            parent_size_cells: root.prop_u32("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS),
             ..Default::default()
-        };
+        }
+    }
 
+    /// Returns the physical memory regions specified in the `/memory` nodes.
+    pub fn memory_regions(&self) -> DeviceResult<Vec<Range<PhysAddr>>> {
+        let props = self.root_props();
         let mut regions = Vec::new();
         for node in &self.0.root.children {
             if node.name.starts_with("memory@")
                 || node.prop_str("device_type").unwrap_or_default() == "memory"
             {
-                let (addr, size) = parse_reg(node, &props)?;
-                regions.push(addr as usize..addr as usize + size as usize)
+                for (addr, size) in parse_reg_all(node, &props)? {
+                    regions.push(addr as usize..addr as usize + size as usize)
+                }
+            }
+        }
+        Ok(regions)
+    }
+
+    /// Returns the physical memory regions reserved by the `/memreserve/`
+    /// entries and the children of the `/reserved-memory` node.
+    ///
+    /// The dynamically allocated reserved regions (without `reg`) are not
+    /// included.
+    pub fn reserved_regions(&self) -> DeviceResult<Vec<Range<u64>>> {
+        let mut regions: Vec<_> = self
+            .1
+            .iter()
+            .map(|&(addr, size)| addr..addr + size)
+            .collect();
+        if let Some(node) = self.0.find("/reserved-memory") {
+            let address_cells = node
+                .prop_u32("#address-cells")
+                .unwrap_or(DEFAULT_ADDRESS_CELLS);
+            let size_cells = node.prop_u32("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS);
+            let props = InheritProps {
+                parent_address_cells: address_cells,
+                parent_size_cells: size_cells,
+                // the children are translated to the root address space
+                ranges: parse_ranges(node, &self.root_props(), address_cells, size_cells)?,
+                ..Default::default()
+            };
+            for child in node.children.iter().filter(|n| n.has_prop("reg")) {
+                for (addr, size) in parse_reg_all(child, &props)? {
+                    regions.push(addr..addr + size);
+                }
             }
         }
         Ok(regions)
     }
+
+    /// Returns the physical memory regions that can be used by the allocator,
+    /// as sorted and coalesced `(address, size)` pairs, i.e.
+    /// [`memory_regions`](Self::memory_regions) without
+    /// [`reserved_regions`](Self::reserved_regions).
+    pub fn usable_memory_regions(&self) -> DeviceResult<Vec<(PhysAddr, usize)>> {
+        let memory = self
+            .memory_regions()?
+            .into_iter()
+            .map(|r| r.start as u64..r.end as u64)
+            .collect();
+        Ok(subtract_regions(memory, &self.reserved_regions()?)
+            .into_iter()
+            .map(|r| (r.start as PhysAddr, (r.end - r.start) as usize))
+            .collect())
+    }
+
+    /// Merge the device tree overlay `blob` into the tree. The properties in
+    /// the `__overlay__` node of each `fragment@N` replace those of the target
+    /// node, and its child nodes are merged recursively or appended.
+    ///
+    /// The target is the node at `target-path`, or the node with the
+    /// `target` phandle, which is resolved by `__fixups__` and the
+    /// `__symbols__` of this tree if it refers to a label. The phandles local
+    /// to the overlay (`__local_fixups__`) are not renumbered.
+    ///
+    /// The fragments before a malformed one are still applied.
+    pub fn apply_overlay(&mut self, blob: &[u8]) -> DeviceResult {
+        if let Err(err) = check_header(blob, true) {
+            warn!("device-tree: invalid overlay header: {:?}", err);
+            return Err(err.into());
+        }
+        let mut root = match DeviceTreeInner::load(blob) {
+            Ok(dt) => dt.root,
+            Err(err) => {
+                warn!("device-tree: failed to load overlay: {:?}", err);
+                return Err(DeviceError::InvalidDtb);
+            }
+        };
+        let fixups = root
+            .children
+            .iter()
+            .position(|n| n.name == "__fixups__")
+            .map(|i| root.children.remove(i));
+        // `__symbols__` and `__local_fixups__` are not fragments
+        for mut fragment in root
+            .children
+            .into_iter()
+            .filter(|n| !n.name.starts_with("__"))
+        {
+            let overlay = match fragment
+                .children
+                .iter()
+                .position(|n| n.name == "__overlay__")
+            {
+                Some(i) => fragment.children.remove(i),
+                None => {
+                    warn!("device-tree: no __overlay__ in {:?}", fragment.name);
+                    return Err(DeviceError::InvalidDtb);
+                }
+            };
+            match self.overlay_target(&fragment, fixups.as_ref()) {
+                Some(target) => merge_node(target, overlay),
+                None => {
+                    warn!(
+                        "device-tree: target of overlay {:?} not found",
+                        fragment.name
+                    );
+                    return Err(DeviceError::InvalidDtb);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the target node of the overlay `fragment` for modification.
+    fn overlay_target(&mut self, fragment: &Node, fixups: Option<&Node>) -> Option<&mut Node> {
+        if let Ok(path) = fragment.prop_str("target-path") {
+            return find_mut_by_path(&mut self.0.root, path);
+        }
+        // the fixup of the first cell of `target`, e.g. `/fragment@0:target:0`
+        let fixup = format!("/{}:target:0", fragment.name);
+        let label = fixups.and_then(|fixups| {
+            fixups.props.iter().find_map(|(label, _)| {
+                let refs = fixups.prop_str_list(label).ok()?;
+                refs.contains(fixup.as_str()).then(|| label)
+            })
+        });
+        match label {
+            Some(label) => {
+                let path = String::from(self.0.find("/__symbols__")?.prop_str(label).ok()?);
+                find_mut_by_path(&mut self.0.root, &path)
+            }
+            None => find_mut_by_phandle(&mut self.0.root, fragment.prop_u32("target").ok()?),
+        }
+    }
+}
+
+/// Find the node at the full `path` under `root` for modification. The unit
+/// address in each component can be omitted if it is unambiguous.
+fn find_mut_by_path<'a>(root: &'a mut Node, path: &str) -> Option<&'a mut Node> {
+    let mut node = root;
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        let index = node
+            .children
+            .iter()
+            .position(|c| c.name == name)
+            .or_else(|| {
+                node.children
+                    .iter()
+                    .position(|c| c.name.split('@').next() == Some(name))
+            })?;
+        node = &mut node.children[index];
+    }
+    Some(node)
+}
+
+/// Find the node with the given `phandle` under `node` for modification.
+fn find_mut_by_phandle(node: &mut Node, phandle: u32) -> Option<&mut Node> {
+    if node.prop_u32("phandle").ok() == Some(phandle) {
+        return Some(node);
+    }
+    node.children
+        .iter_mut()
+        .find_map(|child| find_mut_by_phandle(child, phandle))
+}
+
+/// Merge the properties and child nodes of `overlay` into `target`.
+fn merge_node(target: &mut Node, overlay: Node) {
+    for (name, value) in overlay.props {
+        match target.props.iter_mut().find(|(n, _)| *n == name) {
+            Some(prop) => prop.1 = value,
+            None => target.props.push((name, value)),
+        }
+    }
+    for child in overlay.children {
+        match target.children.iter_mut().find(|c| c.name == child.name) {
+            Some(node) => merge_node(node, child),
+            None => target.children.push(child),
+        }
+    }
+}
+
+/// Returns whether the node is operational according to its `status` property.
+///
+/// A node without `status` is considered to be enabled.
+pub fn is_enabled(node: &Node) -> bool {
+    match node.prop_str("status") {
+        Ok(status) => status == "okay" || status == "ok",
+        Err(_) => true,
+    }
 }
 
-/// Combine `cell_num` of 32-bit integers from `cells` into a 64-bit integer.
+/// Combine `cell_num` of big-endian 32-bit integers from `cells` into a
+/// 64-bit integer. At most 3 cells are supported, and the value must fit in 64
+/// bits.
 fn from_cells(cells: &[u32], cell_num: u32) -> DeviceResult<u64> {
-    if cell_num as usize > cells.len() {
+    if cell_num > MAX_CELLS || cell_num as usize > cells.len() {
+        return Err(DeviceError::InvalidParam);
+    }
+    let cells = &cells[..cell_num as usize];
+    let (high, low) = cells.split_at(cells.len().saturating_sub(2));
+    if high.iter().any(|&c| c != 0) {
         return Err(DeviceError::InvalidParam);
     }
-    let mut value = 0;
-    for &c in &cells[..cell_num as usize] {
-        value = value << 32 | c as u64;
+    Ok(low.iter().fold(0, |value, &c| value << 32 | c as u64))
+}
+
+/// Combine `cell_num` cells of a bus address into a 64-bit integer, as
+/// [`from_cells`].
+///
+/// A 3-cell address is of the PCI bus, whose first cell `phys.hi` holds the
+/// address space and the bus/device/function numbers rather than the address.
+/// The space code in its bits 24-25 is returned along with the address, and
+/// `None` for other buses.
+fn addr_from_cells(cells: &[u32], cell_num: u32) -> DeviceResult<(Option<u32>, u64)> {
+    match cell_num {
+        3 if cells.len() >= 3 => Ok((Some((cells[0] >> 24) & 0x3), from_cells(&cells[1..], 2)?)),
+        _ => Ok((None, from_cells(cells, cell_num)?)),
     }
-    Ok(value)
 }
 
-/// Parse the `reg` property, about `reg`: <https://elinux.org/Device_Tree_Usage#How_Addressing_Works>.
+/// Parse the first `(address, size)` pair of the `reg` property, about `reg`: <https://elinux.org/Device_Tree_Usage#How_Addressing_Works>.
+///
+/// The address is translated to the CPU physical address through the `ranges`
+/// of ancestor nodes.
 pub fn parse_reg(node: &Node, props: &InheritProps) -> DeviceResult<(u64, u64)> {
+    parse_reg_all(node, props)?
+        .first()
+        .copied()
+        .ok_or(DeviceError::InvalidParam)
+}
+
+/// Parse all `(address, size)` pairs of the `reg` property, for devices with
+/// multiple register windows.
+///
+/// The addresses are translated as [`parse_reg`].
+pub fn parse_reg_all(node: &Node, props: &InheritProps) -> DeviceResult<Vec<(u64, u64)>> {
     let cells = node.prop_cells("reg")?;
-    let addr = from_cells(&cells, props.parent_address_cells)?;
-    let size = from_cells(
-        &cells[props.parent_address_cells as usize..],
-        props.parent_size_cells,
-    )?;
-    Ok((addr, size))
+    let entry_len = (props.parent_address_cells + props.parent_size_cells) as usize;
+    if entry_len == 0 || cells.len() % entry_len != 0 {
+        return Err(DeviceError::InvalidParam);
+    }
+    cells
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let (space, addr) = addr_from_cells(entry, props.parent_address_cells)?;
+            let size = from_cells(
+                &entry[props.parent_address_cells as usize..],
+                props.parent_size_cells,
+            )?;
+            let paddr = props
+                .translate(space, addr)
+                .ok_or(DeviceError::InvalidParam)?;
+            Ok((paddr, size))
+        })
+        .collect()
+}
+
+/// Parse the `ranges` property of a bus node, and compose it with the
+/// translation of its ancestors, about `ranges`: <https://elinux.org/Device_Tree_Usage#Ranges_.28Address_Translation.29>.
+///
+/// Returns `None` if there is no `ranges` or it is empty, which means the
+/// addresses of the child bus are not translated.
+fn parse_ranges(
+    node: &Node,
+    props: &InheritProps,
+    address_cells: u32,
+    size_cells: u32,
+) -> DeviceResult<Option<Vec<AddrRange>>> {
+    if !node.has_prop("ranges") {
+        return Ok(None);
+    }
+    let cells = node.prop_cells("ranges")?;
+    if cells.is_empty() {
+        return Ok(None);
+    }
+    let entry_len = (address_cells + props.parent_address_cells + size_cells) as usize;
+    if entry_len == 0 || cells.len() % entry_len != 0 {
+        return Err(DeviceError::InvalidParam);
+    }
+    let mut ranges = Vec::with_capacity(cells.len() / entry_len);
+    for entry in cells.chunks(entry_len) {
+        let (child_space, child_addr) = addr_from_cells(entry, address_cells)?;
+        let entry = &entry[address_cells as usize..];
+        let (parent_space, parent_addr) = addr_from_cells(entry, props.parent_address_cells)?;
+        let entry = &entry[props.parent_address_cells as usize..];
+        let size = from_cells(entry, size_cells)?;
+        match props.translate(parent_space, parent_addr) {
+            Some(parent_addr) => ranges.push(AddrRange {
+                child_space,
+                child_addr,
+                parent_addr,
+                size,
+            }),
+            None => warn!(
+                "device-tree: ranges of node {:?}: {:#x} is not mapped by the parent bus",
+                node.name, parent_addr
+            ),
+        }
+    }
+    Ok(Some(ranges))
 }
 
 /// Returns a `Vec<u32>` according to the `interrupts` or `interrupts-extended`
-/// property, the first element is the interrupt parent.
+/// property, in the form of `interrupts-extended`: each interrupt specifier is
+/// preceded by the phandle of its interrupt parent.
+///
+/// If both properties present, `interrupts-extended` takes precedence.
+///
+/// If the interrupt parent is an interrupt nexus, the `interrupts` are
+/// translated through its `interrupt-map`, and interrupts not in the map are
+/// ignored.
 pub fn parse_interrupts(node: &Node, props: &InheritProps) -> DeviceResult<InterruptsProp> {
     if node.has_prop("interrupts-extended") {
         Ok(node.prop_cells("interrupts-extended")?)
+    } else if let (true, Some(map)) = (node.has_prop("interrupts"), &props.interrupt_map) {
+        parse_mapped_interrupts(node, map)
     } else if node.has_prop("interrupts") && props.interrupt_parent > 0 {
-        let mut ret = node.prop_cells("interrupts")?;
-        ret.insert(0, props.interrupt_parent);
+        let cells = node.prop_cells("interrupts")?;
+        // treat all cells as one specifier if the interrupt parent is unknown
+        let spec_len = match props.interrupt_cells as usize {
+            0 => cells.len().max(1),
+            n => n,
+        };
+        let mut ret = Vec::with_capacity(cells.len() + cells.len() / spec_len);
+        for spec in cells.chunks(spec_len) {
+            ret.push(props.interrupt_parent);
+            ret.extend_from_slice(spec);
+        }
         Ok(ret)
     } else {
         Ok(Vec::new())
     }
 }
 
+/// Translate the `interrupts` property through the `interrupt-map` of the
+/// interrupt nexus, to the form of `interrupts-extended`.
+fn parse_mapped_interrupts(node: &Node, map: &InterruptMap) -> DeviceResult<InterruptsProp> {
+    let cells = node.prop_cells("interrupts")?;
+    // the unit address is the first cells of `reg`, or all zeros if none
+    let mut unit_addr = node.prop_cells("reg").unwrap_or_default();
+    unit_addr.resize(map.address_cells as usize, 0);
+    let mut ret = Vec::new();
+    for spec in cells.chunks(map.interrupt_cells.max(1) as usize) {
+        match map.translate(&unit_addr, spec) {
+            Some((parent, parent_spec)) => {
+                ret.push(parent);
+                ret.extend_from_slice(parent_spec);
+            }
+            None => warn!(
+                "device-tree: interrupt {:x?} of node {:?} is not in the interrupt-map",
+                spec, node.name
+            ),
+        }
+    }
+    Ok(ret)
+}
+
+/// Helpers to decode the big-endian property values of a [`Node`]. The 32-bit
+/// cells are decoded by [`Node::prop_cells`].
+pub trait NodeExt {
+    /// Returns the value of the property `name` as a 64-bit integer, which may
+    /// be in one cell or two cells, e.g. `linux,initrd-start`.
+    ///
+    /// Returns [`DeviceError::InvalidParam`] if there is no such property, or
+    /// it is neither 4 nor 8 bytes.
+    ///
+    /// The inherent [`Node::prop_u64`] only accepts 8 bytes and shadows this
+    /// method, so call it as `NodeExt::prop_u64(node, name)`.
+    fn prop_u64(&self, name: &str) -> DeviceResult<u64>;
+}
+
+impl NodeExt for Node {
+    fn prop_u64(&self, name: &str) -> DeviceResult<u64> {
+        let value = prop_bytes(self, name)?;
+        match value.len() {
+            4 => Ok(u32::from_be_bytes(value.try_into().unwrap()) as u64),
+            8 => Ok(u64::from_be_bytes(value.try_into().unwrap())),
+            _ => Err(DeviceError::InvalidParam),
+        }
+    }
+}
+
+/// Returns the raw value of the property `name`.
+fn prop_bytes<'a>(node: &'a Node, name: &str) -> DeviceResult<&'a [u8]> {
+    node.props
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_slice())
+        .ok_or(DeviceError::InvalidParam)
+}
+
 impl From<PropError> for DeviceError {
     fn from(_err: PropError) -> Self {
         Self::InvalidParam
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use alloc::{string::String, vec};
+
+    /// A minimal flattened device tree blob builder, about the format: <https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html>.
+    #[derive(Default)]
+    pub(crate) struct FdtBuilder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+        rsvmap: Vec<(u64, u64)>,
+    }
+
+    impl FdtBuilder {
+        const FDT_MAGIC: u32 = 0xd00d_feed;
+        const FDT_BEGIN_NODE: u32 = 1;
+        const FDT_END_NODE: u32 = 2;
+        const FDT_PROP: u32 = 3;
+        const FDT_END: u32 = 9;
+        const HEADER_SIZE: usize = 40;
+
+        fn push_u32(buf: &mut Vec<u8>, value: u32) {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+
+        fn align(&mut self) {
+            while self.structs.len() % 4 != 0 {
+                self.structs.push(0);
+            }
+        }
+
+        pub(crate) fn begin_node(&mut self, name: &str) -> &mut Self {
+            Self::push_u32(&mut self.structs, Self::FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.align();
+            self
+        }
+
+        pub(crate) fn end_node(&mut self) -> &mut Self {
+            Self::push_u32(&mut self.structs, Self::FDT_END_NODE);
+            self
+        }
+
+        pub(crate) fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            Self::push_u32(&mut self.structs, Self::FDT_PROP);
+            Self::push_u32(&mut self.structs, value.len() as u32);
+            Self::push_u32(&mut self.structs, name_off);
+            self.structs.extend_from_slice(value);
+            self.align();
+            self
+        }
+
+        pub(crate) fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let mut value = Vec::new();
+            for &c in cells {
+                Self::push_u32(&mut value, c);
+            }
+            self.prop(name, &value)
+        }
+
+        pub(crate) fn prop_str(&mut self, name: &str, s: &str) -> &mut Self {
+            let mut value = String::from(s).into_bytes();
+            value.push(0);
+            self.prop(name, &value)
+        }
+
+        pub(crate) fn mem_reserve(&mut self, addr: u64, size: u64) -> &mut Self {
+            self.rsvmap.push((addr, size));
+            self
+        }
+
+        pub(crate) fn build(&mut self) -> Vec<u8> {
+            Self::push_u32(&mut self.structs, Self::FDT_END);
+            let off_rsvmap = Self::HEADER_SIZE;
+            let off_structs = off_rsvmap + (self.rsvmap.len() + 1) * 16;
+            let off_strings = off_structs + self.structs.len();
+            let total_size = off_strings + self.strings.len();
+
+            let mut blob = Vec::with_capacity(total_size);
+            for value in [
+                Self::FDT_MAGIC,
+                total_size as u32,
+                off_structs as u32,
+                off_strings as u32,
+                off_rsvmap as u32,
+                17, // version
+                16, // last_comp_version
+                0,  // boot_cpuid_phys
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ] {
+                Self::push_u32(&mut blob, value);
+            }
+            for &(addr, size) in self.rsvmap.iter().chain(&[(0, 0)]) {
+                blob.extend_from_slice(&addr.to_be_bytes());
+                blob.extend_from_slice(&size.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    fn load(blob: &[u8]) -> Devicetree {
+        Devicetree::from_bytes(blob).unwrap()
+    }
+
+    fn minimal_blob() -> Vec<u8> {
+        FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .end_node()
+            .build()
+    }
+
+    /// Overwrite the `index`-th field of the header.
+    fn set_header_field(blob: &mut [u8], index: usize, value: u32) {
+        blob[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Returns `(node name, paddr, size)` of all nodes with the `reg` property.
+    fn collect_regs(dt: &Devicetree) -> Vec<(String, u64, u64)> {
+        let mut regs = Vec::new();
+        dt.walk(&mut |node, _comp, props| {
+            if let Ok((paddr, size)) = parse_reg(node, props) {
+                regs.push((node.name.clone(), paddr, size));
+            }
+        });
+        regs
+    }
+
+    #[test]
+    fn test_ranges_translation() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            // soc: 0x0 -> 0x1000_0000, 0x2000_0000 -> 0x1_0000_0000
+            .begin_node("soc")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop_cells(
+                "ranges",
+                &[
+                    0x0,
+                    0x0,
+                    0x1000_0000,
+                    0x100_0000,
+                    0x2000_0000,
+                    0x1,
+                    0x0,
+                    0x10_0000,
+                ],
+            )
+            // bus: 0x0 -> 0x20_0000 (in soc)
+            .begin_node("bus@200000")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop_cells("ranges", &[0x0, 0x20_0000, 0x1_0000])
+            .begin_node("serial@100")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x100, 0x100])
+            .end_node()
+            .end_node()
+            .begin_node("serial@20000100")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x2000_0100, 0x100])
+            .end_node()
+            // out of all ranges of soc
+            .begin_node("serial@30000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x3000_0000, 0x100])
+            .end_node()
+            .end_node()
+            // identity mapping
+            .begin_node("bus@40000000")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop("ranges", &[])
+            .begin_node("serial@40000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x4000_0000, 0x100])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let regs = collect_regs(&load(&blob));
+        assert_eq!(
+            regs,
+            vec![
+                (String::from("serial@100"), 0x1020_0100, 0x100),
+                (String::from("serial@20000100"), 0x1_0000_0100, 0x100),
+                (String::from("serial@40000000"), 0x4000_0000, 0x100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prop_helpers() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("cells", &[0x1234_5678, 0x9abc_def0])
+            .prop_cells("one", &[0x8000_0000])
+            .prop("odd", &[0x1, 0x2, 0x3])
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        let root = &dt.0.root;
+        assert_eq!(
+            NodeExt::prop_u64(root, "cells").unwrap(),
+            0x1234_5678_9abc_def0
+        );
+        assert_eq!(NodeExt::prop_u64(root, "one").unwrap(), 0x8000_0000);
+        assert!(matches!(
+            NodeExt::prop_u64(root, "odd"),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(
+            NodeExt::prop_u64(root, "none"),
+            Err(DeviceError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn test_reg_all() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("ethernet@4500000")
+            .prop_str("compatible", "allwinner,sunxi-gmac")
+            .prop_cells("reg", &[0x0450_0000, 0x1_0000, 0x0300_0030, 0x4])
+            .end_node()
+            .end_node()
+            .build();
+
+        let mut regs = Vec::new();
+        load(&blob).walk(&mut |node, _comp, props| {
+            regs = parse_reg_all(node, props).unwrap();
+            assert_eq!(parse_reg(node, props).unwrap(), regs[0]);
+        });
+        assert_eq!(regs, vec![(0x0450_0000, 0x1_0000), (0x0300_0030, 0x4)]);
+    }
+
+    #[test]
+    fn test_reg_cells() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            // 2/2 cells, above 4 GiB
+            .begin_node("pci@300000000")
+            .prop_str("compatible", "pci-host-ecam-generic")
+            .prop_cells("reg", &[0x3, 0x0, 0x1, 0x0])
+            .end_node()
+            // size-cells is 0
+            .begin_node("cpus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[0])
+            .begin_node("cpu@1")
+            .prop_str("compatible", "riscv")
+            .prop_cells("reg", &[0x1])
+            .end_node()
+            .end_node()
+            // 2/1 cells
+            .begin_node("bus@80000000")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#size-cells", &[1])
+            .prop("ranges", &[])
+            .begin_node("serial@180000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1, 0x8000_0000, 0x100])
+            .end_node()
+            .end_node()
+            // 1/1 cells
+            .begin_node("soc")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop("ranges", &[])
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100])
+            .end_node()
+            // inherit 1/1 cells from the nearest ancestor
+            .begin_node("bus@20000000")
+            .prop_str("compatible", "simple-bus")
+            .prop("ranges", &[])
+            .begin_node("serial@20000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x2000_0000, 0x200])
+            .end_node()
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let regs = collect_regs(&load(&blob));
+        assert_eq!(
+            regs,
+            vec![
+                (String::from("pci@300000000"), 0x3_0000_0000, 0x1_0000_0000),
+                (String::from("cpu@1"), 0x1, 0),
+                (String::from("serial@180000000"), 0x1_8000_0000, 0x100),
+                (String::from("serial@10000000"), 0x1000_0000, 0x100),
+                (String::from("serial@20000000"), 0x2000_0000, 0x200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reg_pci_cells() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            // 32-bit memory space: 0x0 -> 0x4000_0000, I/O space: 0x0 -> 0x3eff_0000
+            .begin_node("pci@30000000")
+            .prop_str("compatible", "pci-host-ecam-generic")
+            .prop_cells("#address-cells", &[3])
+            .prop_cells("#size-cells", &[2])
+            .prop_cells("reg", &[0x0, 0x3000_0000, 0x0, 0x1000_0000])
+            .prop_cells(
+                "ranges",
+                &[
+                    0x0200_0000,
+                    0x0,
+                    0x0,
+                    0x0,
+                    0x4000_0000,
+                    0x0,
+                    0x4000_0000,
+                    0x0100_0000,
+                    0x0,
+                    0x0,
+                    0x0,
+                    0x3eff_0000,
+                    0x0,
+                    0x1_0000,
+                ],
+            )
+            // BAR 0 of device 1, function 0
+            .begin_node("ethernet@1,0")
+            .prop_str("compatible", "pci8086,100e")
+            .prop_cells("reg", &[0x0200_0810, 0x0, 0x1000, 0x0, 0x1000])
+            .end_node()
+            // I/O BAR 0 of device 2, function 0, at the same PCI address
+            .begin_node("ethernet@2,0")
+            .prop_str("compatible", "pci8086,100e")
+            .prop_cells("reg", &[0x0100_1010, 0x0, 0x1000, 0x0, 0x40])
+            .end_node()
+            // the configuration space is not mapped by any range
+            .begin_node("ethernet@3,0")
+            .prop_str("compatible", "pci8086,100e")
+            .prop_cells("reg", &[0x0000_1800, 0x0, 0x0, 0x0, 0x0])
+            .end_node()
+            .end_node()
+            // 1/3 cells
+            .begin_node("bus@10000000")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[3])
+            .prop("ranges", &[])
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x0, 0x0, 0x100])
+            .end_node()
+            // the size does not fit in 64 bits
+            .begin_node("serial@11000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1100_0000, 0x1, 0x0, 0x100])
+            .end_node()
+            .end_node()
+            // too many address cells
+            .begin_node("bus@20000000")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[4])
+            .prop_cells("#size-cells", &[1])
+            .prop("ranges", &[])
+            .begin_node("serial@20000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x0, 0x0, 0x0, 0x2000_0000, 0x100])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        assert_eq!(
+            collect_regs(&dt),
+            vec![
+                (String::from("pci@30000000"), 0x3000_0000, 0x1000_0000),
+                (String::from("ethernet@1,0"), 0x4000_1000, 0x1000),
+                (String::from("ethernet@2,0"), 0x3eff_1000, 0x40),
+                (String::from("serial@10000000"), 0x1000_0000, 0x100),
+            ]
+        );
+        dt.walk(&mut |node, _comp, props| {
+            if node.name == "ethernet@3,0"
+                || node.name == "serial@11000000"
+                || node.name == "serial@20000000"
+            {
+                assert!(matches!(
+                    parse_reg(node, props),
+                    Err(DeviceError::InvalidParam)
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn test_interrupt_map() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .begin_node("interrupt-controller@c000000")
+            .prop_str("compatible", "riscv,plic0")
+            .prop("interrupt-controller", &[])
+            .prop_cells("#address-cells", &[0])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[1])
+            .prop_cells("reg", &[0x0, 0xc00_0000, 0x0, 0x400_0000])
+            .end_node()
+            .begin_node("pci@30000000")
+            .prop_str("compatible", "pci-host-ecam-generic")
+            .prop_cells("#address-cells", &[3])
+            .prop_cells("#size-cells", &[2])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("interrupt-parent", &[1])
+            .prop_cells("interrupt-map-mask", &[0x1800, 0, 0, 7])
+            // slot 1: INTA..INTD -> 33, 34, 35, 32
+            .prop_cells(
+                "interrupt-map",
+                &[
+                    0x800, 0, 0, 1, 1, 33, //
+                    0x800, 0, 0, 2, 1, 34, //
+                    0x800, 0, 0, 3, 1, 35, //
+                    0x800, 0, 0, 4, 1, 32,
+                ],
+            )
+            // slot 1, function 0, INTA
+            .begin_node("ethernet@1,0")
+            .prop_str("compatible", "pci8086,100e")
+            .prop_cells("reg", &[0x800, 0, 0, 0, 0])
+            .prop_cells("interrupts", &[1])
+            .end_node()
+            // slot 1, function 2, INTD: the function number is masked
+            .begin_node("usb@1,2")
+            .prop_str("compatible", "pci1b36,d")
+            .prop_cells("reg", &[0xa00, 0, 0, 0, 0])
+            .prop_cells("interrupts", &[4])
+            .end_node()
+            // slot 2 is not in the map
+            .begin_node("storage@2,0")
+            .prop_str("compatible", "pci1af4,1001")
+            .prop_cells("reg", &[0x1000, 0, 0, 0, 0])
+            .prop_cells("interrupts", &[1])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let mut interrupts = Vec::new();
+        load(&blob).walk(&mut |node, _comp, props| {
+            interrupts.push((node.name.clone(), parse_interrupts(node, props).unwrap()));
+        });
+        assert_eq!(
+            interrupts,
+            vec![
+                (String::from("interrupt-controller@c000000"), vec![]),
+                (String::from("pci@30000000"), vec![]),
+                (String::from("ethernet@1,0"), vec![1, 33]),
+                (String::from("usb@1,2"), vec![1, 32]),
+                (String::from("storage@2,0"), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_node_path() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_str("compatible", "riscv-virtio")
+            .begin_node("soc")
+            .prop_str("compatible", "simple-bus")
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let mut paths = Vec::new();
+        load(&blob).walk(&mut |_node, _comp, props| paths.push(props.path.clone()));
+        assert_eq!(paths, vec!["/", "/soc", "/soc/serial@10000000"]);
+    }
+
+    #[test]
+    fn test_find_compatible() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_str("compatible", "riscv-virtio")
+            .begin_node("soc")
+            .prop_str("compatible", "simple-bus")
+            .begin_node("serial@10000000")
+            .prop("compatible", b"vendor,uart\0ns16550a\0")
+            .end_node()
+            .begin_node("serial@10000100")
+            .prop_str("compatible", "ns16550a")
+            .end_node()
+            .begin_node("serial@10000200")
+            .prop_str("compatible", "ns16550")
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        // any string of `compatible` matches, but not a prefix
+        let names: Vec<_> = dt
+            .find_compatible("ns16550a")
+            .iter()
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(names, ["serial@10000000", "serial@10000100"]);
+        assert!(dt.find_compatible("vendor").is_empty());
+
+        let node = dt.node_at_path("/soc/serial@10000200").unwrap();
+        assert_eq!(node.prop_str("compatible").unwrap(), "ns16550");
+        assert!(dt.node_at_path("/soc/serial@10000300").is_none());
+        assert!(dt.node_at_path("serial0").is_none());
+    }
+
+    #[test]
+    fn test_check_header() {
+        let blob = minimal_blob();
+        assert_eq!(check_header(&blob, true), Ok(blob.len()));
+        assert!(Devicetree::from_bytes(&blob).is_ok());
+
+        // truncated
+        assert_eq!(check_header(&blob[..20], false), Err(DtbError::Truncated));
+        assert_eq!(
+            check_header(&blob[..blob.len() - 4], true),
+            Err(DtbError::Truncated)
+        );
+        assert!(matches!(
+            Devicetree::from_bytes(&blob[..blob.len() - 4]),
+            Err(DeviceError::InvalidDtb)
+        ));
+        // only the header is required
+        assert_eq!(check_header(&blob[..40], false), Ok(blob.len()));
+
+        // corrupted
+        let corrupted = |index: usize, value: u32| {
+            let mut blob = blob.clone();
+            set_header_field(&mut blob, index, value);
+            check_header(&blob, true)
+        };
+        assert_eq!(
+            corrupted(0, 0xdead_beef),
+            Err(DtbError::BadMagic(0xdead_beef))
+        );
+        assert_eq!(corrupted(1, 8), Err(DtbError::BadTotalSize(8)));
+        assert_eq!(
+            corrupted(1, 0xffff_fff0),
+            Err(DtbError::BadTotalSize(0xffff_fff0))
+        );
+        assert_eq!(corrupted(2, 0x1000), Err(DtbError::BadOffset));
+        assert_eq!(corrupted(3, blob.len() as u32), Err(DtbError::BadOffset));
+        assert_eq!(corrupted(4, 0), Err(DtbError::BadOffset));
+        assert_eq!(corrupted(5, 1), Err(DtbError::BadVersion(1)));
+        assert_eq!(corrupted(6, 18), Err(DtbError::BadVersion(17)));
+        assert_eq!(corrupted(9, 0x1000), Err(DtbError::BadOffset));
+    }
+
+    #[test]
+    fn test_nested_interrupt_map() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin_node("interrupt-controller@c000000")
+            .prop_str("compatible", "riscv,plic0")
+            .prop("interrupt-controller", &[])
+            .prop_cells("#address-cells", &[0])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[1])
+            .end_node()
+            // nexus 2 -> PLIC: 1..=4 -> 40..=43
+            .begin_node("bridge@1")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[2])
+            .prop_cells("interrupt-map-mask", &[0, 7])
+            .prop_cells(
+                "interrupt-map",
+                &[0, 1, 1, 40, 0, 2, 1, 41, 0, 3, 1, 42, 0, 4, 1, 43],
+            )
+            .end_node()
+            // nexus 3 -> nexus 2: 1 -> 4, 2 -> 3
+            .begin_node("bridge@2")
+            .prop_str("compatible", "simple-bus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#interrupt-cells", &[1])
+            .prop_cells("phandle", &[3])
+            .prop_cells("interrupt-map-mask", &[0, 7])
+            .prop_cells("interrupt-map", &[0, 1, 2, 0, 4, 0, 2, 2, 0, 3])
+            .end_node()
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("reg", &[0x1000_0000, 0x100])
+            .prop_cells(
+                "interrupts-extended",
+                &[1, 10, 2, 1, 3, 1, 3, 2, 3, 5, 9, 1],
+            )
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        let node = dt.find_by_path("/serial@10000000").unwrap();
+        let interrupts = parse_interrupts(node, &InheritProps::default()).unwrap();
+        assert_eq!(
+            dt.resolve_interrupts(node, &interrupts),
+            // direct, through one nexus, through two nexus, not in the map
+            // (dropped), unknown phandle (kept)
+            vec![1, 10, 1, 40, 1, 43, 1, 42, 9, 1]
+        );
+    }
+
+    #[test]
+    fn test_chosen() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("chosen")
+            .prop("bootargs", b"console=ttyS0 LOG=warn\0\0garbage")
+            .prop_cells("linux,initrd-start", &[0x1, 0x8000_0000])
+            .prop_cells("linux,initrd-end", &[0x1, 0x8010_0000])
+            .end_node()
+            .end_node()
+            .build();
+        let dt = load(&blob);
+        assert_eq!(dt.bootargs(), Some("console=ttyS0 LOG=warn"));
+        assert_eq!(dt.initrd(), Some((0x1_8000_0000, 0x10_0000)));
+
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("chosen")
+            .prop("bootargs", b"\0")
+            .prop_cells("linux,initrd-start", &[0x8400_0000])
+            .prop_cells("linux,initrd-end", &[0x8420_0000])
+            .end_node()
+            .end_node()
+            .build();
+        let dt = load(&blob);
+        assert_eq!(dt.bootargs(), None);
+        assert_eq!(dt.initrd_region(), Some(0x8400_0000..0x8420_0000));
+
+        let dt = load(&minimal_blob());
+        assert_eq!(dt.bootargs(), None);
+        assert_eq!(dt.initrd(), None);
+    }
+
+    #[test]
+    fn test_clock_frequency() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("apb-pclk")
+            .prop_str("compatible", "fixed-clock")
+            .prop_cells("#clock-cells", &[0])
+            .prop_cells("clock-frequency", &[24_000_000])
+            .prop_cells("phandle", &[0x8000])
+            .end_node()
+            .begin_node("pl011@9000000")
+            .prop_str("compatible", "arm,pl011")
+            .prop_cells("clocks", &[0x8000, 0x8000])
+            .end_node()
+            .begin_node("serial@10000000")
+            .prop_str("compatible", "ns16550a")
+            .prop_cells("clock-frequency", &[0x38_4000])
+            .end_node()
+            .begin_node("serial@10001000")
+            .prop_str("compatible", "ns16550a")
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        let freq = |path| dt.clock_frequency(dt.find_by_path(path).unwrap());
+        assert_eq!(freq("/pl011@9000000"), Some(24_000_000));
+        assert_eq!(freq("/serial@10000000"), Some(0x38_4000));
+        assert_eq!(freq("/serial@10001000"), None);
+    }
+
+    #[test]
+    fn test_subtract_regions() {
+        let regions = vec![
+            0x3000..0x4000,
+            0x1000..0x2000,
+            0x2000..0x2800,
+            0x5000..0x5000,
+        ];
+        assert_eq!(
+            subtract_regions(regions, &[0x1800..0x1900, 0x3800..0x5000]),
+            vec![0x1000..0x1800, 0x1900..0x2800, 0x3000..0x3800]
+        );
+    }
+
+    #[test]
+    fn test_usable_memory_regions() {
+        let blob = FdtBuilder::default()
+            .mem_reserve(0x8000_0000, 0x20_0000)
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .begin_node("memory@80000000")
+            .prop_str("device_type", "memory")
+            .prop_cells("reg", &[0x0, 0x8000_0000, 0x0, 0x4000_0000])
+            .end_node()
+            .begin_node("memory@100000000")
+            .prop_str("device_type", "memory")
+            .prop_cells(
+                "reg",
+                &[
+                    0x1,
+                    0x0,
+                    0x0,
+                    0x1000_0000,
+                    0x0,
+                    0xc000_0000,
+                    0x0,
+                    0x4000_0000,
+                ],
+            )
+            .end_node()
+            .begin_node("reserved-memory")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .begin_node("mmode_resv@80000000")
+            .prop_cells("reg", &[0x0, 0x8010_0000, 0x0, 0x20_0000])
+            .prop("no-map", &[])
+            .end_node()
+            .begin_node("linux,cma")
+            .prop_str("compatible", "shared-dma-pool")
+            .prop_cells("reg", &[0x0, 0xbf00_0000, 0x0, 0x100_0000])
+            .end_node()
+            .begin_node("dynamic")
+            .prop_cells("size", &[0x0, 0x10_0000])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        assert_eq!(
+            dt.usable_memory_regions().unwrap(),
+            vec![
+                (0x8030_0000, 0x3ed0_0000),
+                // adjacent banks are merged
+                (0xc000_0000, 0x5000_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reserved_memory_ranges() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            // 32-bit offsets from 0x8000_0000
+            .begin_node("reserved-memory")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop_cells("ranges", &[0x0, 0x0, 0x8000_0000, 0x4000_0000])
+            .begin_node("mmode_resv@80100000")
+            .prop_cells("reg", &[0x10_0000, 0x20_0000])
+            .prop("no-map", &[])
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        assert_eq!(
+            load(&blob).reserved_regions().unwrap(),
+            vec![0x8010_0000..0x8030_0000]
+        );
+    }
+
+    #[test]
+    fn test_cpus() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("cpus")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[0])
+            .begin_node("cpu@0")
+            .prop_str("device_type", "cpu")
+            .prop_cells("reg", &[0])
+            .prop_str("status", "disabled")
+            .prop_str("riscv,isa", "rv64imac")
+            .prop_cells("timebase-frequency", &[1_000_000])
+            .end_node()
+            .begin_node("cpu@1")
+            .prop_str("device_type", "cpu")
+            .prop_cells("reg", &[1])
+            .prop_str("status", "okay")
+            .prop_str("riscv,isa", "rv64imafdc")
+            .prop_cells("timebase-frequency", &[10_000_000])
+            .end_node()
+            .begin_node("cpu@2")
+            .prop_cells("reg", &[2])
+            .end_node()
+            .begin_node("cpu-map")
+            .begin_node("cluster0")
+            .end_node()
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+
+        let dt = load(&blob);
+        let cpus = dt.cpus();
+        assert_eq!(cpus.len(), 3);
+        assert_eq!(cpus[0].id, 0);
+        assert!(!cpus[0].enabled);
+        assert_eq!(cpus[1].isa.as_deref(), Some("rv64imafdc"));
+        assert_eq!(cpus[2].id, 2);
+        assert!(cpus[2].enabled);
+        assert_eq!(cpus[2].timebase_frequency, None);
+        // the first enabled CPU, since `/cpus` has no `timebase-frequency`
+        assert_eq!(dt.timebase_frequency(), Some(10_000_000));
+    }
+
+    #[test]
+    fn test_apply_overlay() {
+        let blob = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("soc")
+            .begin_node("uart@1000")
+            .prop_str("compatible", "ns16550a")
+            .prop_str("status", "okay")
+            .end_node()
+            .end_node()
+            .begin_node("__symbols__")
+            .prop_str("soc", "/soc")
+            .end_node()
+            .end_node()
+            .build();
+        let overlay = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("fragment@0")
+            .prop_str("target-path", "/soc/uart")
+            .begin_node("__overlay__")
+            .prop_str("status", "disabled")
+            .prop_cells("current-speed", &[9600])
+            .end_node()
+            .end_node()
+            .begin_node("fragment@1")
+            .prop_cells("target", &[0xffff_ffff])
+            .begin_node("__overlay__")
+            .begin_node("virtio@2000")
+            .prop_str("compatible", "virtio,mmio")
+            .end_node()
+            .end_node()
+            .end_node()
+            .begin_node("__fixups__")
+            .prop_str("soc", "/fragment@1:target:0")
+            .end_node()
+            .end_node()
+            .build();
+
+        let mut dt = load(&blob);
+        dt.apply_overlay(&overlay).unwrap();
+        let uart = dt.find_by_path("/soc/uart@1000").unwrap();
+        assert!(!is_enabled(uart));
+        assert_eq!(uart.prop_u32("current-speed").ok(), Some(9600));
+        assert_eq!(uart.prop_str("compatible").ok(), Some("ns16550a"));
+        let virtio = dt.find_by_path("/soc/virtio@2000").unwrap();
+        assert_eq!(virtio.prop_str("compatible").ok(), Some("virtio,mmio"));
+
+        let overlay = FdtBuilder::default()
+            .begin_node("")
+            .begin_node("fragment@0")
+            .prop_str("target-path", "/no-such-node")
+            .begin_node("__overlay__")
+            .end_node()
+            .end_node()
+            .end_node()
+            .build();
+        assert!(dt.apply_overlay(&overlay).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! Physically contiguous buffers for DMA.
+
+use alloc::sync::Arc;
+
+use crate::bus::{phys_to_virt, PAGE_SIZE};
+use crate::{DeviceError, DeviceResult, PhysAddr};
+
+struct DmaRegion {
+    paddr: PhysAddr,
+    pages: usize,
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        unsafe { drivers_dma_dealloc(self.paddr, self.pages) };
+    }
+}
+
+/// A physically contiguous buffer for DMA.
+///
+/// Clones refer to the same memory, which is freed after the last one is
+/// dropped, so a driver can keep the buffer alive while the device uses it.
+#[derive(Clone)]
+pub struct DmaBuf {
+    region: Arc<DmaRegion>,
+    len: usize,
+}
+
+impl DmaBuf {
+    /// Allocate a zeroed buffer of `len` bytes.
+    pub fn new(len: usize) -> DeviceResult<Self> {
+        if len == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        let paddr = unsafe { drivers_dma_alloc(pages) };
+        if paddr == 0 {
+            return Err(DeviceError::DmaError);
+        }
+        unsafe { core::ptr::write_bytes(phys_to_virt(paddr) as *mut u8, 0, pages * PAGE_SIZE) };
+        Ok(Self {
+            region: Arc::new(DmaRegion { paddr, pages }),
+            len,
+        })
+    }
+
+    /// Returns the physical address of the buffer.
+    pub fn paddr(&self) -> PhysAddr {
+        self.region.paddr
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the buffer has a length of 0, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the bytes at `offset` to `buf`.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> DeviceResult {
+        self.check_range(offset, buf.len())?;
+        let src = (phys_to_virt(self.paddr()) + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Copy `data` to the bytes at `offset`.
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> DeviceResult {
+        self.check_range(offset, data.len())?;
+        let dst = (phys_to_virt(self.paddr()) + offset) as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> DeviceResult {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(DeviceError::BufferTooSmall),
+        }
+    }
+}
+
+extern "C" {
+    fn drivers_dma_alloc(pages: usize) -> PhysAddr;
+    fn drivers_dma_dealloc(paddr: PhysAddr, pages: usize) -> i32;
+}
@@ -51,7 +51,6 @@ impl<const IRQ_COUNT: usize> IrqManager<IRQ_COUNT> {
         Ok(irq_num)
     }
 
-    #[cfg(not(target_arch = "aarch64"))]
     pub fn unregister_handler(&mut self, irq_num: usize) -> DeviceResult {
         info!("IRQ unregister handler {}", irq_num);
         if !self.allocator.is_alloced(irq_num) {
@@ -74,6 +73,14 @@ impl<const IRQ_COUNT: usize> IrqManager<IRQ_COUNT> {
         }
     }
 
+    /// Returns the IRQ numbers which have handlers.
+    pub fn registered_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.table
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| h.as_ref().map(|_| i))
+    }
+
     pub fn handle(&self, irq_num: usize) -> DeviceResult {
         if let Some(f) = &self.table[irq_num] {
             f();
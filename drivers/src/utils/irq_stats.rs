@@ -0,0 +1,56 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Lock-free counters of how many times each IRQ fires.
+pub struct IrqStats<const IRQ_COUNT: usize> {
+    counts: [AtomicU64; IRQ_COUNT],
+}
+
+impl<const IRQ_COUNT: usize> IrqStats<IRQ_COUNT> {
+    pub fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            counts: [ZERO; IRQ_COUNT],
+        }
+    }
+
+    /// Increase the counter of `irq_num`, ignored if out of range.
+    pub fn inc(&self, irq_num: usize) {
+        if let Some(count) = self.counts.get(irq_num) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the `(irq_num, count)` pairs of the IRQs in `irqs`.
+    pub fn collect(&self, irqs: impl Iterator<Item = usize>) -> Vec<(usize, u64)> {
+        irqs.filter_map(|i| Some((i, self.counts.get(i)?.load(Ordering::Relaxed))))
+            .collect()
+    }
+
+    /// Reset all counters to zero.
+    pub fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_irq_stats() {
+        let stats = IrqStats::<8>::new();
+        stats.inc(1);
+        stats.inc(3);
+        stats.inc(3);
+        stats.inc(8); // out of range
+        assert_eq!(
+            stats.collect([1, 3, 5].iter().copied()),
+            vec![(1, 1), (3, 2), (5, 0)]
+        );
+        stats.reset();
+        assert_eq!(stats.collect(0..8).iter().map(|s| s.1).sum::<u64>(), 0);
+    }
+}
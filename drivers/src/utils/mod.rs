@@ -1,17 +1,26 @@
-//! Event handler and device tree.
+//! Event handler, DMA buffer and device tree.
 
+mod dma_buf;
 mod event_listener;
 mod id_allocator;
 mod irq_manager;
+mod irq_stats;
 
 #[cfg(feature = "graphic")]
 mod graphic_console;
 
+// not every mock is used with every set of features
+#[cfg(test)]
+#[allow(dead_code)]
+pub(crate) mod test_utils;
+
 pub mod devicetree;
 
 pub(super) use id_allocator::IdAllocator;
 pub(super) use irq_manager::IrqManager;
+pub(super) use irq_stats::IrqStats;
 
+pub use dma_buf::DmaBuf;
 pub use event_listener::{EventHandler, EventListener};
 
 #[cfg(feature = "graphic")]
@@ -0,0 +1,152 @@
+//! Mocks of the kernel and the hardware shared by the unit tests.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use core::{cell::RefCell, mem::size_of};
+
+use crate::builder::{IoMapper, MmioAttrs};
+use crate::bus::PAGE_SIZE;
+use crate::{DeviceResult, PhysAddr, VirtAddr};
+
+fn dma_layout(pages: usize) -> Layout {
+    Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+// The DMA memory is allocated from the heap of the host, whose physical
+// addresses are the same as the virtual addresses.
+
+#[no_mangle]
+extern "C" fn drivers_dma_alloc(pages: usize) -> PhysAddr {
+    unsafe { alloc_zeroed(dma_layout(pages)) as PhysAddr }
+}
+
+#[no_mangle]
+extern "C" fn drivers_dma_dealloc(paddr: PhysAddr, pages: usize) -> i32 {
+    unsafe { dealloc(paddr as *mut u8, dma_layout(pages)) };
+    0
+}
+
+#[no_mangle]
+extern "C" fn drivers_phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+    paddr
+}
+
+#[no_mangle]
+extern "C" fn drivers_virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
+    vaddr
+}
+
+/// Registers in the memory of the host, accessed by the byte offset as the
+/// drivers do, which are freed when dropped.
+pub(crate) struct MockRegisters<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T: Copy + Default> MockRegisters<T> {
+    /// Allocate `len` zeroed registers of type `T`.
+    pub fn new(len: usize) -> Self {
+        let regs = vec![T::default(); len].into_boxed_slice();
+        Self {
+            ptr: Box::into_raw(regs) as *mut T,
+            len,
+        }
+    }
+
+    /// Returns the base address passed to the drivers.
+    pub fn base(&self) -> VirtAddr {
+        self.ptr as VirtAddr
+    }
+
+    /// Read the register at the byte `offset`.
+    pub fn read(&self, offset: usize) -> T {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    /// Write the register at the byte `offset`.
+    pub fn write(&self, offset: usize, value: T) {
+        unsafe { self.reg(offset).write_volatile(value) }
+    }
+
+    fn reg(&self, offset: usize) -> *mut T {
+        let idx = offset / size_of::<T>();
+        assert!(
+            idx < self.len,
+            "mock register {:#x} is out of range",
+            offset
+        );
+        unsafe { self.ptr.add(idx) }
+    }
+}
+
+impl<T> Drop for MockRegisters<T> {
+    fn drop(&mut self) {
+        let regs = core::ptr::slice_from_raw_parts_mut(self.ptr, self.len);
+        drop(unsafe { Box::from_raw(regs) });
+    }
+}
+
+/// Maps each region to a new zeroed buffer, and records the mapped and
+/// unmapped regions so that the registers written by drivers can be checked.
+///
+/// The buffers are leaked, since the devices may outlive the mapper. Clones
+/// share the records.
+#[derive(Clone, Default)]
+pub(crate) struct MockIoMapper {
+    regions: Rc<RefCell<Vec<(PhysAddr, VirtAddr, usize, MmioAttrs)>>>,
+    unmapped: Rc<RefCell<Vec<(VirtAddr, usize)>>>,
+    /// Bytes filled in the buffers when mapped.
+    presets: Vec<(PhysAddr, u8)>,
+}
+
+impl MockIoMapper {
+    /// Fill the byte at the physical address when it is mapped, e.g. the
+    /// status registers polled by drivers.
+    pub fn preset(mut self, paddr: PhysAddr, value: u8) -> Self {
+        self.presets.push((paddr, value));
+        self
+    }
+
+    /// Returns the mapped regions as `(paddr, vaddr, size, attrs)`.
+    pub fn regions(&self) -> Vec<(PhysAddr, VirtAddr, usize, MmioAttrs)> {
+        self.regions.borrow().clone()
+    }
+
+    /// Returns the unmapped regions as `(vaddr, len)`.
+    pub fn unmapped(&self) -> Vec<(VirtAddr, usize)> {
+        self.unmapped.borrow().clone()
+    }
+
+    /// Read the 32-bit register at the physical address.
+    pub fn read(&self, paddr: PhysAddr) -> u32 {
+        let regions = self.regions.borrow();
+        let &(base, vaddr, ..) = regions
+            .iter()
+            .find(|&&(base, _, size, _)| (base..base + size).contains(&paddr))
+            .expect("the register is not mapped");
+        unsafe { ((vaddr + paddr - base) as *const u32).read_volatile() }
+    }
+}
+
+impl IoMapper for MockIoMapper {
+    fn query_or_map(&self, paddr: PhysAddr, size: usize) -> Option<VirtAddr> {
+        self.map_mmio(paddr, size, MmioAttrs::default()).ok()
+    }
+
+    fn map_pages(&self, paddr: PhysAddr, size: usize, attrs: MmioAttrs) -> Option<VirtAddr> {
+        let buf = Box::leak(vec![0u32; size / 4].into_boxed_slice());
+        let vaddr = buf.as_mut_ptr() as VirtAddr;
+        for &(addr, value) in &self.presets {
+            if (paddr..paddr + size).contains(&addr) {
+                unsafe { ((vaddr + addr - paddr) as *mut u8).write(value) };
+            }
+        }
+        self.regions.borrow_mut().push((paddr, vaddr, size, attrs));
+        Some(vaddr)
+    }
+
+    fn unmap(&self, vaddr: VirtAddr, len: usize) -> DeviceResult {
+        self.unmapped.borrow_mut().push((vaddr, len));
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use lock::Mutex;
+
+use super::transport::{Transport, VirtQueue, INT_CONFIG_CHANGE};
+use crate::bus::PAGE_SIZE;
+use crate::io::Io;
+use crate::scheme::{impl_event_scheme, BalloonScheme, Scheme};
+use crate::utils::{DmaBuf, EventListener};
+use crate::{DeviceError, DeviceResult, PhysAddr};
+
+/// The host must be told before the deflated pages are used.
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1;
+
+/// PFNs are always in 4 KiB pages.
+const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
+
+/// Indices of the virtqueues.
+const INFLATE_QUEUE: u32 = 0;
+const DEFLATE_QUEUE: u32 = 1;
+
+/// Indices of 32-bit words in the configuration space.
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 1;
+
+/// Number of PFNs in the DMA buffer.
+const PFNS_PER_BUF: usize = PAGE_SIZE / core::mem::size_of::<u32>();
+
+struct VirtIoBalloonInner {
+    transport: Transport,
+    inflate_queue: VirtQueue,
+    deflate_queue: VirtQueue,
+    /// A page of little-endian PFNs.
+    pfns: DmaBuf,
+}
+
+impl VirtIoBalloonInner {
+    /// Send the PFNs of `pages` by the inflate or deflate queue, at most a
+    /// buffer of PFNs at a time, and update `actual` after each transfer.
+    fn send_pfns(&mut self, pages: &[PhysAddr], inflate: bool) -> DeviceResult {
+        if pages.iter().any(|&paddr| paddr % PAGE_SIZE != 0) {
+            return Err(DeviceError::InvalidParam);
+        }
+        for chunk in pages.chunks(PFNS_PER_BUF) {
+            for (i, &paddr) in chunk.iter().enumerate() {
+                let pfn = (paddr >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+                self.pfns
+                    .write_at(i * core::mem::size_of::<u32>(), &pfn.to_le_bytes())?;
+            }
+            let queue = if inflate {
+                &mut self.inflate_queue
+            } else {
+                &mut self.deflate_queue
+            };
+            let len = chunk.len() * core::mem::size_of::<u32>();
+            self.transport
+                .transfer(queue, self.pfns.paddr(), len, false)?;
+
+            let actual = self.transport.config(CONFIG_ACTUAL);
+            let n = chunk.len() as u32;
+            actual.write(if inflate {
+                actual.read().wrapping_add(n)
+            } else {
+                actual.read().saturating_sub(n)
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Driver of the VirtIO memory balloon device.
+pub struct VirtIoBalloon {
+    inner: Mutex<VirtIoBalloonInner>,
+    listener: EventListener,
+}
+
+impl_event_scheme!(VirtIoBalloon);
+
+impl VirtIoBalloon {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        transport.require_config()?;
+        transport.begin_init(|_| VIRTIO_BALLOON_F_MUST_TELL_HOST)?;
+        let inflate_queue = transport.create_queue(INFLATE_QUEUE)?;
+        let deflate_queue = transport.create_queue(DEFLATE_QUEUE)?;
+        transport.finish_init();
+        let pfns = DmaBuf::new(PAGE_SIZE)?;
+        Ok(Self {
+            inner: Mutex::new(VirtIoBalloonInner {
+                transport,
+                inflate_queue,
+                deflate_queue,
+                pfns,
+            }),
+            listener: EventListener::new(),
+        })
+    }
+}
+
+impl Scheme for VirtIoBalloon {
+    fn name(&self) -> &str {
+        "virtio-balloon"
+    }
+
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let status = self.inner.lock().transport.ack_interrupt();
+        if status & INT_CONFIG_CHANGE != 0 {
+            self.listener.trigger(());
+        }
+    }
+}
+
+impl BalloonScheme for VirtIoBalloon {
+    fn target_pages(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.transport.config(CONFIG_NUM_PAGES).read() as usize
+    }
+
+    fn actual_pages(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.transport.config(CONFIG_ACTUAL).read() as usize
+    }
+
+    fn inflate(&self, pages: &[PhysAddr]) -> DeviceResult {
+        self.inner.lock().send_pfns(pages, true)
+    }
+
+    /// Returns after the host has used the PFNs, so the pages are safe to use
+    /// even if `VIRTIO_BALLOON_F_MUST_TELL_HOST` is negotiated.
+    fn deflate(&self, pages: &[PhysAddr]) -> DeviceResult {
+        self.inner.lock().send_pfns(pages, false)
+    }
+}
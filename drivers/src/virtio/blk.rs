@@ -1,43 +1,404 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
 use lock::Mutex;
-use virtio_drivers::{VirtIOBlk as InnerDriver, VirtIOHeader};
 
-use crate::scheme::{BlockScheme, Scheme};
-use crate::DeviceResult;
+use super::transport::{Transport, VirtQueue};
+use crate::io::Io;
+use crate::scheme::{impl_event_scheme, BlockScheme, RequestId, Scheme};
+use crate::utils::{DmaBuf, EventListener, IdAllocator};
+use crate::{DeviceError, DeviceResult};
+
+/// The unit of addressing and transfer.
+const SECTOR_SIZE: usize = 512;
+
+/// Index of the `capacity` field in the configuration space.
+const CONFIG_CAPACITY: usize = 0;
+
+/// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device supports the flush request.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
 
-pub struct VirtIoBlk<'a> {
-    inner: Mutex<InnerDriver<'a>>,
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Descriptors of the request virtqueue, a request takes at most 3 of them.
+const QUEUE_SIZE: u16 = 128;
+const DESC_PER_REQ: usize = 3;
+
+/// Size of the request header, i.e. the type, a reserved field and the sector.
+const HEADER_SIZE: usize = 16;
+
+struct Request {
+    id: RequestId,
+    slot: usize,
+    /// Kept alive until the device is done with it.
+    _buf: Option<DmaBuf>,
+    /// Reaped by a blocking call rather than `poll_completions`.
+    waited: bool,
 }
 
-impl<'a> VirtIoBlk<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
+struct VirtIoBlkInner {
+    transport: Transport,
+    queue: VirtQueue,
+    /// The headers followed by the status bytes, one for each slot.
+    reqs: DmaBuf,
+    slots: IdAllocator,
+    /// Head descriptor index -> request.
+    in_flight: BTreeMap<u16, Request>,
+    completed: Vec<(RequestId, DeviceResult, bool)>,
+    next_id: RequestId,
+}
+
+/// Driver of the VirtIO block device, with multiple requests in flight.
+pub struct VirtIoBlk {
+    inner: Mutex<VirtIoBlkInner>,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+    listener: EventListener,
+}
+
+impl_event_scheme!(VirtIoBlk);
+
+fn status_to_result(status: u8) -> DeviceResult {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err(DeviceError::NotSupported),
+        _ => Err(DeviceError::IoError),
+    }
+}
+
+impl VirtIoBlkInner {
+    fn status_offset(&self, slot: usize) -> usize {
+        HEADER_SIZE * self.slots_count() + slot
+    }
+
+    fn slots_count(&self) -> usize {
+        self.queue.size() as usize / DESC_PER_REQ
+    }
+
+    /// Make the request available to the device and notify it.
+    fn submit(
+        &mut self,
+        ty: u32,
+        sector: u64,
+        buf: Option<&DmaBuf>,
+        waited: bool,
+    ) -> DeviceResult<RequestId> {
+        let slot = self.slots.alloc()?;
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&ty.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        self.reqs.write_at(HEADER_SIZE * slot, &header)?;
+        let status_offset = self.status_offset(slot);
+        self.reqs.write_at(status_offset, &[0xff])?;
+
+        let header = (self.reqs.paddr() + HEADER_SIZE * slot, HEADER_SIZE, false);
+        let status = (self.reqs.paddr() + status_offset, 1, true);
+        let res = match buf {
+            Some(buf) => {
+                let data = (buf.paddr(), buf.len(), ty == VIRTIO_BLK_T_IN);
+                self.queue.add(&[header, data, status])
+            }
+            None => self.queue.add(&[header, status]),
+        };
+        let head = match res {
+            Ok(head) => head,
+            Err(err) => {
+                self.slots.free(slot, 1)?;
+                return Err(err);
+            }
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.in_flight.insert(
+            head,
+            Request {
+                id,
+                slot,
+                _buf: buf.cloned(),
+                waited,
+            },
+        );
+        self.transport.notify(&self.queue);
+        Ok(id)
+    }
+
+    /// Move the requests used by the device to `completed`, and returns
+    /// whether there are any.
+    fn reap(&mut self) -> bool {
+        let mut reaped = false;
+        while let Some((head, _)) = self.queue.pop_used() {
+            let req = match self.in_flight.remove(&head) {
+                Some(req) => req,
+                None => continue,
+            };
+            let mut status = [0u8];
+            let res = self
+                .reqs
+                .read_at(self.status_offset(req.slot), &mut status)
+                .and_then(|_| status_to_result(status[0]));
+            self.slots.free(req.slot, 1).ok();
+            self.completed.push((req.id, res, req.waited));
+            reaped = true;
+        }
+        reaped
+    }
+
+    /// Take the result of the request `id` if it has completed.
+    fn take_completed(&mut self, id: RequestId) -> Option<DeviceResult> {
+        let pos = self.completed.iter().position(|c| c.0 == id)?;
+        Some(self.completed.remove(pos).1)
+    }
+}
+
+impl VirtIoBlk {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        transport.require_config()?;
+        let features =
+            transport.begin_init(|offered| offered & (VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH))?;
+        let queue = transport.create_queue_with_size(0, QUEUE_SIZE)?;
+        let slots_count = queue.size() as usize / DESC_PER_REQ;
+        if slots_count == 0 {
+            return Err(DeviceError::NotSupported);
+        }
+        transport.finish_init();
+        let capacity = (transport.config(CONFIG_CAPACITY + 1).read() as u64) << 32
+            | transport.config(CONFIG_CAPACITY).read() as u64;
         Ok(Self {
-            inner: Mutex::new(InnerDriver::new(header)?),
+            inner: Mutex::new(VirtIoBlkInner {
+                transport,
+                queue,
+                reqs: DmaBuf::new((HEADER_SIZE + 1) * slots_count)?,
+                slots: IdAllocator::new(0..slots_count)?,
+                in_flight: BTreeMap::new(),
+                completed: Vec::new(),
+                next_id: 0,
+            }),
+            capacity,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            can_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+            listener: EventListener::new(),
         })
     }
+
+    /// Check the request of `len` bytes at `sector` before submitting it.
+    fn check_request(&self, sector: u64, len: usize, write: bool) -> DeviceResult {
+        if write && self.read_only {
+            return Err(DeviceError::NotSupported);
+        }
+        let sectors = (len / SECTOR_SIZE) as u64;
+        let end = sector.checked_add(sectors);
+        if len % SECTOR_SIZE != 0 || end.map_or(true, |end| end > self.capacity) {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Submit a request and wait for it, retrying while the queue is full.
+    fn submit_and_wait(&self, ty: u32, sector: u64, buf: Option<&DmaBuf>) -> DeviceResult {
+        let id = loop {
+            let mut inner = self.inner.lock();
+            match inner.submit(ty, sector, buf, true) {
+                Err(DeviceError::NoResources) => inner.reap(),
+                res => break res?,
+            };
+            drop(inner);
+            core::hint::spin_loop();
+        };
+        loop {
+            let mut inner = self.inner.lock();
+            inner.reap();
+            if let Some(res) = inner.take_completed(id) {
+                return res;
+            }
+            drop(inner);
+            core::hint::spin_loop();
+        }
+    }
 }
 
-impl<'a> Scheme for VirtIoBlk<'a> {
+impl Scheme for VirtIoBlk {
     fn name(&self) -> &str {
         "virtio-blk"
     }
 
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
     fn handle_irq(&self, _irq_num: usize) {
-        self.inner.lock().ack_interrupt();
+        let completed = {
+            let mut inner = self.inner.lock();
+            inner.transport.ack_interrupt();
+            inner.reap()
+        };
+        if completed {
+            self.listener.trigger(());
+        }
     }
 }
 
-impl<'a> BlockScheme for VirtIoBlk<'a> {
+impl BlockScheme for VirtIoBlk {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
-        self.inner.lock().read_block(block_id, buf)?;
-        Ok(())
+        self.check_request(block_id as u64, buf.len(), false)?;
+        let dma = DmaBuf::new(buf.len())?;
+        self.submit_and_wait(VIRTIO_BLK_T_IN, block_id as u64, Some(&dma))?;
+        dma.read_at(0, buf)
     }
 
     fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
-        self.inner.lock().write_block(block_id, buf)?;
-        Ok(())
+        self.check_request(block_id as u64, buf.len(), true)?;
+        let dma = DmaBuf::new(buf.len())?;
+        dma.write_at(0, buf)?;
+        self.submit_and_wait(VIRTIO_BLK_T_OUT, block_id as u64, Some(&dma))
+    }
+
+    fn submit_read(&self, sector: u64, buf: DmaBuf) -> DeviceResult<RequestId> {
+        self.check_request(sector, buf.len(), false)?;
+        self.inner
+            .lock()
+            .submit(VIRTIO_BLK_T_IN, sector, Some(&buf), false)
+    }
+
+    fn submit_write(&self, sector: u64, buf: DmaBuf) -> DeviceResult<RequestId> {
+        self.check_request(sector, buf.len(), true)?;
+        self.inner
+            .lock()
+            .submit(VIRTIO_BLK_T_OUT, sector, Some(&buf), false)
     }
 
+    fn poll_completions(&self) -> Vec<(RequestId, DeviceResult)> {
+        let mut inner = self.inner.lock();
+        inner.reap();
+        let (polled, waited): (Vec<_>, _) = core::mem::take(&mut inner.completed)
+            .into_iter()
+            .partition(|c| !c.2);
+        inner.completed = waited;
+        polled.into_iter().map(|(id, res, _)| (id, res)).collect()
+    }
+
+    /// Only if the device offers `VIRTIO_BLK_F_FLUSH`, the writes are
+    /// guaranteed to be durable.
     fn flush(&self) -> DeviceResult {
-        Ok(())
+        if !self.can_flush {
+            return Err(DeviceError::NotSupported);
+        }
+        self.submit_and_wait(VIRTIO_BLK_T_FLUSH, 0, None)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+    use virtio_drivers::VirtIOHeader;
+
+    const PAGE_SIZE: usize = 0x1000;
+    const QUEUE_NOTIFY: usize = 0x50 / 4;
+    const QUEUE_PFN: usize = 0x40 / 4;
+
+    /// The registers of a legacy virtio-blk device in the memory.
+    fn mock_header(features: u32, capacity: u64) -> *mut u32 {
+        let regs = Box::leak(Box::new([0u32; 0x200 / 4]));
+        regs[0x00 / 4] = 0x7472_6976; // magic
+        regs[0x04 / 4] = 1; // version
+        regs[0x08 / 4] = 2; // block device
+        regs[0x10 / 4] = features;
+        regs[0x34 / 4] = 16; // QueueNumMax
+        regs[0x100 / 4] = capacity as u32;
+        regs[0x100 / 4 + 1] = (capacity >> 32) as u32;
+        regs.as_mut_ptr()
+    }
+
+    fn read_reg(base: *mut u32, index: usize) -> u32 {
+        unsafe { base.add(index).read_volatile() }
+    }
+
+    /// Complete the `n`-th available request as the device, with the 16
+    /// descriptors of the legacy queue at `QueuePFN`.
+    fn complete(base: *mut u32, n: usize, used_idx: u16) {
+        let queue = read_reg(base, QUEUE_PFN) as usize * PAGE_SIZE;
+        let avail_ring = (queue + 16 * 16 + 4) as *const u16;
+        let used = (queue + PAGE_SIZE) as *mut u16;
+        unsafe {
+            let head = avail_ring.add(n).read_volatile();
+            // the status is the last descriptor of the chain
+            let mut desc = queue + 16 * head as usize;
+            while (desc as *const u16).add(6).read_volatile() & 1 != 0 {
+                desc = queue + 16 * (desc as *const u16).add(7).read_volatile() as usize;
+            }
+            let status = (desc as *const u64).read_volatile() as *mut u8;
+            status.write_volatile(VIRTIO_BLK_S_OK);
+            let elem = used.add(2 + 4 * (used_idx as usize % 16)) as *mut u32;
+            elem.write_volatile(head as u32);
+            used.add(1).write_volatile(used_idx + 1);
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let base = mock_header(1 << 5, 0x1_0000_0800);
+        let header = unsafe { &mut *(base as *mut VirtIOHeader) };
+        let blk = VirtIoBlk::new(header).unwrap();
+        assert!(blk.is_read_only());
+        assert_eq!(blk.capacity(), 0x1_0000_0800);
+
+        let buf = [0u8; 512];
+        assert!(matches!(
+            blk.write_block(0, &buf),
+            Err(DeviceError::NotSupported)
+        ));
+        // nothing was submitted
+        assert_eq!(read_reg(base, QUEUE_NOTIFY), 0);
+    }
+
+    #[test]
+    fn test_multiple_requests() {
+        let base = mock_header(0, 8);
+        let header = unsafe { &mut *(base as *mut VirtIOHeader) };
+        let blk = VirtIoBlk::new(header).unwrap();
+
+        // at most 5 requests of 3 descriptors in flight
+        let ids: Vec<_> = (0..5)
+            .map(|i| blk.submit_read(i, DmaBuf::new(512).unwrap()).unwrap())
+            .collect();
+        assert!(matches!(
+            blk.submit_read(5, DmaBuf::new(512).unwrap()),
+            Err(DeviceError::NoResources)
+        ));
+        assert!(blk.poll_completions().is_empty());
+
+        // completed out of order
+        complete(base, 3, 0);
+        complete(base, 1, 1);
+        let done = blk.poll_completions();
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[0].0, ids[3]);
+        assert_eq!(done[1].0, ids[1]);
+        assert!(done.iter().all(|(_, res)| res.is_ok()));
+
+        // the freed descriptors can be used again
+        assert!(blk.submit_read(5, DmaBuf::new(512).unwrap()).is_ok());
+        assert!(matches!(
+            blk.submit_read(6, DmaBuf::new(1000).unwrap()),
+            Err(DeviceError::InvalidParam)
+        ));
     }
 }
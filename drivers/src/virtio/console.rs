@@ -3,21 +3,25 @@ use core::fmt::{Result, Write};
 use lock::Mutex;
 use virtio_drivers::{VirtIOConsole as InnerDriver, VirtIOHeader};
 
+use super::reset;
 use crate::prelude::DeviceResult;
-use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
+use crate::scheme::{impl_event_scheme, uart::UartEvent, Scheme, UartScheme};
 use crate::utils::EventListener;
 
 pub struct VirtIoConsole<'a> {
     inner: Mutex<InnerDriver<'a>>,
-    listener: EventListener,
+    header_base: usize,
+    listener: EventListener<UartEvent>,
 }
 
-impl_event_scheme!(VirtIoConsole<'_>);
+impl_event_scheme!(VirtIoConsole<'_>, UartEvent);
 
 impl<'a> VirtIoConsole<'a> {
     pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
+        let header_base = header as *mut _ as usize;
         Ok(Self {
             inner: Mutex::new(InnerDriver::new(header)?),
+            header_base,
             listener: EventListener::new(),
         })
     }
@@ -28,9 +32,15 @@ impl<'a> Scheme for VirtIoConsole<'a> {
         "virtio-console"
     }
 
+    fn shutdown(&self) -> DeviceResult {
+        let _inner = self.inner.lock();
+        reset(self.header_base);
+        Ok(())
+    }
+
     fn handle_irq(&self, _irq_num: usize) {
         self.inner.lock().ack_interrupt().unwrap();
-        self.listener.trigger(());
+        self.listener.trigger(UartEvent::Received);
     }
 }
 
@@ -1,68 +1,441 @@
+use alloc::vec::Vec;
 use lock::Mutex;
-use virtio_drivers::{VirtIOGpu as InnerDriver, VirtIOHeader};
 
-use crate::prelude::{ColorFormat, DisplayInfo, FrameBuffer};
+use super::transport::{Transport, VirtQueue, INT_CONFIG_CHANGE};
+use crate::bus::{phys_to_virt, PAGE_SIZE};
+use crate::io::Io;
+use crate::prelude::{ColorFormat, DisplayInfo, FrameBuffer, Rectangle, CURSOR_SIZE};
 use crate::scheme::{DisplayScheme, Scheme};
-use crate::DeviceResult;
+use crate::utils::DmaBuf;
+use crate::{DeviceError, DeviceResult};
 
-pub struct VirtIoGpu<'a> {
-    info: DisplayInfo,
-    inner: Mutex<InnerDriver<'a>>,
-}
+/// Indices of the virtqueues.
+const CONTROL_QUEUE: u32 = 0;
+const CURSOR_QUEUE: u32 = 1;
+
+/// Indices of the fields in the configuration space.
+const CONFIG_EVENTS_READ: usize = 0;
+const CONFIG_EVENTS_CLEAR: usize = 1;
+
+/// The display configuration has changed.
+const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1;
+
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
+const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x0301;
+
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
 
+/// `B8G8R8A8`, i.e. [`ColorFormat::ARGB8888`] in little-endian.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+
+/// Size of `virtio_gpu_ctrl_hdr`, in 32-bit words.
+const HEADER_WORDS: usize = 6;
+/// Size of a `virtio_gpu_display_one`, in 32-bit words.
+const DISPLAY_ONE_WORDS: usize = 6;
+/// Number of the `virtio_gpu_display_one` in the response of
+/// `GET_DISPLAY_INFO`.
+const MAX_SCANOUTS: usize = 16;
+
+/// The requests are at the start of the command page, and the responses are
+/// at the second half.
+const RESP_OFFSET: usize = PAGE_SIZE / 2;
+
+/// The scanout to show the frame buffer.
+const SCANOUT_ID: u32 = 0;
+/// Two resources for the frame buffer are used in turn, so the new one can be
+/// set up before releasing the old one.
+const FB_RESOURCE_IDS: [u32; 2] = [0xbabe, 0xbabf];
+const CURSOR_RESOURCE_ID: u32 = 0xdade;
+
+/// Size of a cursor image in bytes.
+const CURSOR_IMG_SIZE: usize = (CURSOR_SIZE * CURSOR_SIZE * 4) as usize;
 const CURSOR_HOT_X: u32 = 13;
 const CURSOR_HOT_Y: u32 = 11;
 static CURSOR_IMG: &[u8] = include_bytes!("../display/resource/cursor.bin"); // 64 x 64 x 4
 
-impl<'a> VirtIoGpu<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
-        let mut gpu = InnerDriver::new(header)?;
-        let fb = gpu.setup_framebuffer()?;
-        let fb_base_vaddr = fb.as_ptr() as usize;
-        let fb_size = fb.len();
-        let (width, height) = gpu.resolution();
-        let info = DisplayInfo {
+const FORMAT: ColorFormat = ColorFormat::ARGB8888;
+
+struct VirtIoGpuInner {
+    transport: Transport,
+    control_queue: VirtQueue,
+    /// The device may not have a cursor queue.
+    cursor_queue: Option<VirtQueue>,
+    /// A page for the request and the response of a command.
+    cmd_buf: DmaBuf,
+    /// The backing memory of the current frame buffer resource.
+    fb: Option<DmaBuf>,
+    /// All frame buffers ever allocated. They are never freed, since the
+    /// pointers from [`DisplayScheme::fb`] and the mappings of them may still
+    /// be in use after the resolution is changed.
+    fb_pool: Vec<DmaBuf>,
+    fb_resource_id: u32,
+    /// The backing memory of the cursor resource.
+    cursor: DmaBuf,
+    cursor_pos: (u32, u32),
+    cursor_hot: (u32, u32),
+    info: DisplayInfo,
+}
+
+impl VirtIoGpuInner {
+    /// Send the command `ty` followed by `args` on the control queue, wait for
+    /// the response of `resp_ty`, and copy its payload after the header to
+    /// `resp`.
+    fn command(&mut self, ty: u32, args: &[u32], resp_ty: u32, resp: &mut [u32]) -> DeviceResult {
+        let mut req = [0u32; HEADER_WORDS + 8];
+        let req = &mut req[..HEADER_WORDS + args.len()];
+        req[0] = ty;
+        req[HEADER_WORDS..].copy_from_slice(args);
+        for (i, word) in req.iter().enumerate() {
+            self.cmd_buf.write_at(i * 4, &word.to_le_bytes())?;
+        }
+
+        let paddr = self.cmd_buf.paddr();
+        let resp_len = (HEADER_WORDS + resp.len()) * 4;
+        let head = self.control_queue.add(&[
+            (paddr, req.len() * 4, false),
+            (paddr + RESP_OFFSET, resp_len, true),
+        ])?;
+        self.transport.notify(&self.control_queue);
+        loop {
+            match self.control_queue.pop_used() {
+                Some((id, _)) if id == head => break,
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+
+        let mut word = [0; 4];
+        self.cmd_buf.read_at(RESP_OFFSET, &mut word)?;
+        if u32::from_le_bytes(word) != resp_ty {
+            warn!(
+                "virtio-gpu: command {:#x} failed with {:#x}",
+                ty,
+                u32::from_le_bytes(word)
+            );
+            return Err(DeviceError::IoError);
+        }
+        for (i, r) in resp.iter_mut().enumerate() {
+            self.cmd_buf
+                .read_at(RESP_OFFSET + (HEADER_WORDS + i) * 4, &mut word)?;
+            *r = u32::from_le_bytes(word);
+        }
+        Ok(())
+    }
+
+    fn command_nodata(&mut self, ty: u32, args: &[u32]) -> DeviceResult {
+        self.command(ty, args, VIRTIO_GPU_RESP_OK_NODATA, &mut [])
+    }
+
+    /// Returns the preferred resolution of the scanout.
+    fn display_info(&mut self) -> DeviceResult<(u32, u32)> {
+        let mut pmodes = [0; DISPLAY_ONE_WORDS * MAX_SCANOUTS];
+        self.command(
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+            &[],
+            VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+            &mut pmodes,
+        )?;
+        // `x, y, width, height, enabled, flags`
+        let pmode = &pmodes[SCANOUT_ID as usize * DISPLAY_ONE_WORDS..];
+        match (pmode[2], pmode[3]) {
+            (0, _) | (_, 0) => Err(DeviceError::NotReady),
+            res => Ok(res),
+        }
+    }
+
+    /// Create a 2D resource `id` of `width` x `height`, backed by `backing`.
+    fn create_resource(
+        &mut self,
+        id: u32,
+        width: u32,
+        height: u32,
+        backing: &DmaBuf,
+    ) -> DeviceResult {
+        self.command_nodata(
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+            &[id, VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM, width, height],
+        )?;
+        // a single `virtio_gpu_mem_entry`
+        let addr = backing.paddr() as u64;
+        let res = self.command_nodata(
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            &[
+                id,
+                1,
+                addr as u32,
+                (addr >> 32) as u32,
+                backing.len() as u32,
+                0,
+            ],
+        );
+        if res.is_err() {
+            self.command_nodata(VIRTIO_GPU_CMD_RESOURCE_UNREF, &[id, 0])?;
+        }
+        res
+    }
+
+    fn destroy_resource(&mut self, id: u32) -> DeviceResult {
+        self.command_nodata(VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING, &[id, 0])?;
+        self.command_nodata(VIRTIO_GPU_CMD_RESOURCE_UNREF, &[id, 0])
+    }
+
+    /// Copy the `rect` of the resource `id` to the host, from `offset` in the
+    /// backing of the resource, i.e. the top-left pixel of the `rect`.
+    fn transfer_to_host(&mut self, id: u32, rect: &Rectangle, offset: u64) -> DeviceResult {
+        self.command_nodata(
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            &[
+                rect.x,
+                rect.y,
+                rect.width,
+                rect.height,
+                offset as u32,
+                (offset >> 32) as u32,
+                id,
+                0,
+            ],
+        )
+    }
+
+    /// Allocate a frame buffer of `width` x `height`, and show it on the
+    /// scanout instead of the current one.
+    fn setup_framebuffer(&mut self, width: u32, height: u32) -> DeviceResult {
+        if width == 0 || height == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let stride = FORMAT.row_bytes(width);
+        let size = stride as usize * height as usize;
+        // reuse the smallest buffer which is large enough
+        let fb = match self
+            .fb_pool
+            .iter()
+            .filter(|buf| buf.len() >= size)
+            .min_by_key(|buf| buf.len())
+        {
+            Some(buf) => buf.clone(),
+            None => {
+                let buf = DmaBuf::new(size)?;
+                self.fb_pool.push(buf.clone());
+                buf
+            }
+        };
+        let old_id = self.fb_resource_id;
+        let id = if old_id == FB_RESOURCE_IDS[0] {
+            FB_RESOURCE_IDS[1]
+        } else {
+            FB_RESOURCE_IDS[0]
+        };
+        self.create_resource(id, width, height, &fb)?;
+        if let Err(e) = self.command_nodata(
+            VIRTIO_GPU_CMD_SET_SCANOUT,
+            &[0, 0, width, height, SCANOUT_ID, id],
+        ) {
+            self.destroy_resource(id)?;
+            return Err(e);
+        }
+        if self.fb.is_some() {
+            self.destroy_resource(old_id)?;
+        }
+
+        self.info = DisplayInfo {
             width,
             height,
-            format: ColorFormat::ARGB8888,
-            fb_base_vaddr,
-            fb_size,
+            stride,
+            format: FORMAT,
+            fb_base_vaddr: phys_to_virt(fb.paddr()),
+            fb_size: size,
         };
-        gpu.setup_cursor(
-            CURSOR_IMG,
-            width / 2,
-            height / 2,
-            CURSOR_HOT_X,
-            CURSOR_HOT_Y,
-        )?;
+        self.fb = Some(fb);
+        self.fb_resource_id = id;
+        Ok(())
+    }
+
+    /// Update the `rect` of the frame buffer on screen, only the changed
+    /// pixels are copied to the host.
+    fn flush_rect(&mut self, rect: &Rectangle) -> DeviceResult {
+        let rect = rect.clamp(self.info.width, self.info.height)?;
+        let id = self.fb_resource_id;
+        let offset = rect.y as u64 * self.info.stride as u64 + FORMAT.row_bytes(rect.x) as u64;
+        self.transfer_to_host(id, &rect, offset)?;
+        self.command_nodata(
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH,
+            &[rect.x, rect.y, rect.width, rect.height, id, 0],
+        )
+    }
+
+    /// Send the cursor command `ty` with the current position and image.
+    /// The device does not respond to the commands on the cursor queue.
+    fn cursor_command(&mut self, ty: u32) -> DeviceResult {
+        let queue = self
+            .cursor_queue
+            .as_mut()
+            .ok_or(DeviceError::NotSupported)?;
+        let (x, y) = self.cursor_pos;
+        let (hot_x, hot_y) = self.cursor_hot;
+        // `virtio_gpu_ctrl_hdr`, `virtio_gpu_cursor_pos`, `resource_id`,
+        // `hot_x`, `hot_y`, `padding`
+        let req: [u32; HEADER_WORDS + 8] = [
+            ty,
+            0,
+            0,
+            0,
+            0,
+            0,
+            SCANOUT_ID,
+            x,
+            y,
+            0,
+            CURSOR_RESOURCE_ID,
+            hot_x,
+            hot_y,
+            0,
+        ];
+        for (i, word) in req.iter().enumerate() {
+            self.cmd_buf.write_at(i * 4, &word.to_le_bytes())?;
+        }
+        let head = queue.add(&[(self.cmd_buf.paddr(), req.len() * 4, false)])?;
+        self.transport.notify(queue);
+        loop {
+            match queue.pop_used() {
+                Some((id, _)) if id == head => return Ok(()),
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Replace the image of the cursor resource, and show it.
+    fn set_cursor_image(&mut self, img: &[u8], hot_x: u32, hot_y: u32) -> DeviceResult {
+        if self.cursor_queue.is_none() {
+            return Err(DeviceError::NotSupported);
+        }
+        if img.len() != CURSOR_IMG_SIZE || hot_x >= CURSOR_SIZE || hot_y >= CURSOR_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.cursor.write_at(0, img)?;
+        let rect = Rectangle {
+            x: 0,
+            y: 0,
+            width: CURSOR_SIZE,
+            height: CURSOR_SIZE,
+        };
+        self.transfer_to_host(CURSOR_RESOURCE_ID, &rect, 0)?;
+        self.cursor_hot = (hot_x, hot_y);
+        self.cursor_command(VIRTIO_GPU_CMD_UPDATE_CURSOR)
+    }
+
+    fn move_cursor(&mut self, x: u32, y: u32) -> DeviceResult {
+        if self.cursor_queue.is_none() {
+            return Err(DeviceError::NotSupported);
+        }
+        self.cursor_pos = (x, y);
+        self.cursor_command(VIRTIO_GPU_CMD_MOVE_CURSOR)
+    }
+}
+
+/// Driver of the VirtIO GPU device, showing a 2D frame buffer on the first
+/// scanout.
+pub struct VirtIoGpu {
+    inner: Mutex<VirtIoGpuInner>,
+}
+
+impl VirtIoGpu {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        transport.require_config()?;
+        transport.begin_init(|_| 0)?;
+        let control_queue = transport.create_queue(CONTROL_QUEUE)?;
+        let cursor_queue = transport.create_queue(CURSOR_QUEUE).ok();
+        transport.finish_init();
+
+        let cursor = DmaBuf::new(CURSOR_IMG_SIZE)?;
+        let mut inner = VirtIoGpuInner {
+            transport,
+            control_queue,
+            cursor_queue,
+            cmd_buf: DmaBuf::new(PAGE_SIZE)?,
+            fb: None,
+            fb_pool: Vec::new(),
+            fb_resource_id: 0,
+            cursor: cursor.clone(),
+            cursor_pos: (0, 0),
+            cursor_hot: (0, 0),
+            info: DisplayInfo {
+                width: 0,
+                height: 0,
+                stride: 0,
+                format: FORMAT,
+                fb_base_vaddr: 0,
+                fb_size: 0,
+            },
+        };
+
+        let (width, height) = inner.display_info()?;
+        inner.setup_framebuffer(width, height)?;
+        if inner.cursor_queue.is_some() {
+            inner.create_resource(CURSOR_RESOURCE_ID, CURSOR_SIZE, CURSOR_SIZE, &cursor)?;
+            inner.cursor_pos = (width / 2, height / 2);
+            inner.set_cursor_image(CURSOR_IMG, CURSOR_HOT_X, CURSOR_HOT_Y)?;
+        }
         Ok(Self {
-            info,
-            inner: Mutex::new(gpu),
+            inner: Mutex::new(inner),
         })
     }
 }
 
-impl<'a> Scheme for VirtIoGpu<'a> {
+impl Scheme for VirtIoGpu {
     fn name(&self) -> &str {
         "virtio-gpu"
     }
 
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
     fn handle_irq(&self, _irq_num: usize) {
-        self.inner.lock().ack_interrupt();
+        let inner = &mut *self.inner.lock();
+        if inner.transport.ack_interrupt() & INT_CONFIG_CHANGE != 0 {
+            let events = inner.transport.config(CONFIG_EVENTS_READ).read();
+            if events & VIRTIO_GPU_EVENT_DISPLAY != 0 {
+                info!("virtio-gpu: display configuration changed");
+            }
+            inner.transport.config(CONFIG_EVENTS_CLEAR).write(events);
+        }
     }
 }
 
-impl<'a> DisplayScheme for VirtIoGpu<'a> {
+impl DisplayScheme for VirtIoGpu {
     #[inline]
     fn info(&self) -> DisplayInfo {
-        self.info
+        self.inner.lock().info
+    }
+
+    fn set_resolution(&self, width: u32, height: u32) -> DeviceResult {
+        self.inner.lock().setup_framebuffer(width, height)
     }
 
     #[inline]
     fn fb(&self) -> FrameBuffer {
-        unsafe {
-            FrameBuffer::from_raw_parts_mut(self.info.fb_base_vaddr as *mut u8, self.info.fb_size)
-        }
+        let info = self.info();
+        unsafe { FrameBuffer::from_raw_parts_mut(info.fb_base_vaddr as *mut u8, info.fb_size) }
+    }
+
+    fn set_cursor_image(&self, img: &[u8], hot_x: u32, hot_y: u32) -> DeviceResult {
+        self.inner.lock().set_cursor_image(img, hot_x, hot_y)
+    }
+
+    fn move_cursor(&self, x: u32, y: u32) -> DeviceResult {
+        self.inner.lock().move_cursor(x, y)
     }
 
     #[inline]
@@ -71,7 +444,22 @@ impl<'a> DisplayScheme for VirtIoGpu<'a> {
     }
 
     fn flush(&self) -> DeviceResult {
-        self.inner.lock().flush()?;
-        Ok(())
+        let inner = &mut *self.inner.lock();
+        let screen = Rectangle {
+            x: 0,
+            y: 0,
+            width: inner.info.width,
+            height: inner.info.height,
+        };
+        inner.flush_rect(&screen)
+    }
+
+    fn flush_rect(&self, x: u32, y: u32, width: u32, height: u32) -> DeviceResult {
+        self.inner.lock().flush_rect(&Rectangle {
+            x,
+            y,
+            width,
+            height,
+        })
     }
 }
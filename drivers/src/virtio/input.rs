@@ -1,78 +1,398 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
 
 use lock::Mutex;
-use virtio_drivers::{InputConfigSelect, VirtIOHeader, VirtIOInput as InnerDriver};
 
-use crate::prelude::{CapabilityType, InputCapability, InputEvent, InputEventType};
+use super::transport::{Transport, VirtQueue};
+use crate::input::input_event_codes::{abs::*, ev::*, key::*, led::*, rel::*, rep::*, syn::*};
+use crate::io::Io;
+use crate::prelude::{
+    CapabilityType, InputCapability, InputEvent, InputEventType, KeyLeds, TypedInputEvent,
+};
 use crate::scheme::{impl_event_scheme, InputScheme, Scheme};
-use crate::utils::EventListener;
-use crate::DeviceResult;
+use crate::utils::{DmaBuf, EventListener};
+use crate::{DeviceError, DeviceResult};
 
-pub struct VirtIoInput<'a> {
-    inner: Mutex<InnerDriver<'a>>,
+const EVENT_QUEUE: u32 = 0;
+const STATUS_QUEUE: u32 = 1;
+
+/// Descriptors of the event virtqueue, each for an event buffer.
+const EVENT_QUEUE_SIZE: u16 = 64;
+
+/// Size of `struct virtio_input_event`, i.e. the type, code and value.
+const EVENT_SIZE: usize = 8;
+
+/// Index of the `select`, `subsel` and `size` fields in the configuration
+/// space.
+const CONFIG_SELECT: usize = 0;
+/// Index of the data in the configuration space.
+const CONFIG_DATA: usize = 2;
+const CONFIG_DATA_SIZE: usize = 128;
+
+const CONFIG_ID_NAME: u8 = 0x01;
+const CONFIG_PROP_BITS: u8 = 0x10;
+const CONFIG_EV_BITS: u8 = 0x11;
+
+/// At most so many decoded events are kept for [`InputScheme::poll_event`],
+/// the oldest ones are dropped.
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// Whether the `EV_KEY` code is a button rather than a key of keyboards.
+fn is_button(code: u16) -> bool {
+    matches!(
+        code,
+        BTN_MISC..=BTN_GEAR_UP | BTN_DPAD_UP..=BTN_DPAD_RIGHT | BTN_TRIGGER_HAPPY..=BTN_TRIGGER_HAPPY40
+    )
+}
+
+/// Collects the raw events of a frame, and decodes them at `SYN_REPORT`.
+#[derive(Default)]
+struct EventDecoder {
+    keys: Vec<TypedInputEvent>,
+    rel: Option<(i32, i32)>,
+    /// The last absolute position, as only the changed axes are reported.
+    abs: (i32, i32),
+    abs_changed: bool,
+    pending: VecDeque<TypedInputEvent>,
+}
+
+impl EventDecoder {
+    fn update(&mut self, e: &InputEvent) {
+        match e.event_type {
+            InputEventType::Key => {
+                let (code, pressed) = (e.code, e.value != 0);
+                self.keys.push(if is_button(code) {
+                    TypedInputEvent::Button { code, pressed }
+                } else {
+                    TypedInputEvent::Key { code, pressed }
+                });
+            }
+            InputEventType::RelAxis => {
+                let rel = self.rel.get_or_insert((0, 0));
+                match e.code {
+                    REL_X => rel.0 += e.value,
+                    REL_Y => rel.1 += e.value,
+                    _ => {}
+                }
+            }
+            InputEventType::AbsAxis => match e.code {
+                ABS_X => {
+                    self.abs.0 = e.value;
+                    self.abs_changed = true;
+                }
+                ABS_Y => {
+                    self.abs.1 = e.value;
+                    self.abs_changed = true;
+                }
+                _ => {}
+            },
+            InputEventType::Syn if e.code == SYN_REPORT => self.end_frame(),
+            _ => {}
+        }
+    }
+
+    /// The motion goes first, so that the buttons act at the new position.
+    fn end_frame(&mut self) {
+        if let Some((dx, dy)) = self.rel.take() {
+            self.push(TypedInputEvent::RelMotion { dx, dy });
+        }
+        if core::mem::take(&mut self.abs_changed) {
+            let (x, y) = self.abs;
+            self.push(TypedInputEvent::AbsMotion { x, y });
+        }
+        for e in core::mem::take(&mut self.keys) {
+            self.push(e);
+        }
+    }
+
+    fn push(&mut self, e: TypedInputEvent) {
+        if self.pending.len() == MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(e);
+    }
+}
+
+struct VirtIoInputInner {
+    transport: Transport,
+    event_queue: VirtQueue,
+    status_queue: VirtQueue,
+    /// Buffers for the events from the device, one for each descriptor.
+    events: DmaBuf,
+    /// Head descriptor index -> event buffer.
+    event_heads: BTreeMap<u16, usize>,
+    /// The buffer for an event sent to the device.
+    status: DmaBuf,
+}
+
+impl VirtIoInputInner {
+    fn add_event_buf(&mut self, index: usize) -> DeviceResult {
+        let paddr = self.events.paddr() + index * EVENT_SIZE;
+        let head = self.event_queue.add(&[(paddr, EVENT_SIZE, true)])?;
+        self.event_heads.insert(head, index);
+        Ok(())
+    }
+
+    /// Take the events written by the device, and give the buffers back.
+    fn pop_events(&mut self) -> DeviceResult<Vec<InputEvent>> {
+        let mut events = Vec::new();
+        while let Some((head, _)) = self.event_queue.pop_used() {
+            let index = match self.event_heads.remove(&head) {
+                Some(index) => index,
+                None => continue,
+            };
+            let mut buf = [0; EVENT_SIZE];
+            self.events.read_at(index * EVENT_SIZE, &mut buf)?;
+            self.add_event_buf(index)?;
+            let event_type = u16::from_le_bytes([buf[0], buf[1]]);
+            if let Ok(event_type) = InputEventType::try_from(event_type) {
+                events.push(InputEvent {
+                    event_type,
+                    code: u16::from_le_bytes([buf[2], buf[3]]),
+                    value: i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+                });
+            }
+        }
+        if !events.is_empty() {
+            self.transport.notify(&self.event_queue);
+        }
+        Ok(events)
+    }
+
+    /// Send an event to the device by the status virtqueue, e.g. to turn on
+    /// an LED, and wait for the device to take it.
+    fn send_status(&mut self, event_type: u16, code: u16, value: i32) -> DeviceResult {
+        let mut buf = [0; EVENT_SIZE];
+        buf[0..2].copy_from_slice(&event_type.to_le_bytes());
+        buf[2..4].copy_from_slice(&code.to_le_bytes());
+        buf[4..8].copy_from_slice(&value.to_le_bytes());
+        self.status.write_at(0, &buf)?;
+        let paddr = self.status.paddr();
+        self.transport
+            .transfer(&mut self.status_queue, paddr, EVENT_SIZE, false)?;
+        Ok(())
+    }
+
+    /// Select the configuration `select` and `subsel`, copy the data to `buf`
+    /// and returns its size.
+    fn query_config(&mut self, select: u8, subsel: u8, buf: &mut [u8]) -> usize {
+        self.transport
+            .config(CONFIG_SELECT)
+            .write(select as u32 | (subsel as u32) << 8);
+        let size = ((self.transport.config(CONFIG_SELECT).read() >> 16) as u8 as usize)
+            .min(CONFIG_DATA_SIZE)
+            .min(buf.len());
+        for (i, chunk) in buf[..size].chunks_mut(4).enumerate() {
+            let word = self.transport.config(CONFIG_DATA + i).read().to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+        size
+    }
+
+    /// Whether the device supports the events of `event_type`.
+    fn has_event_type(&mut self, event_type: u16) -> bool {
+        let mut bitmap = [0u8; CONFIG_DATA_SIZE];
+        self.query_config(CONFIG_EV_BITS, event_type as u8, &mut bitmap) > 0
+    }
+}
+
+/// Driver of the VirtIO input device, e.g. keyboards, mice and tablets.
+pub struct VirtIoInput {
+    inner: Mutex<VirtIoInputInner>,
+    decoder: Mutex<EventDecoder>,
     listener: EventListener<InputEvent>,
 }
 
-impl<'a> VirtIoInput<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
-        let inner = Mutex::new(InnerDriver::new(header)?);
+impl VirtIoInput {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        transport.require_config()?;
+        // no device specific features
+        transport.begin_init(|_| 0)?;
+        let event_queue = transport.create_queue_with_size(EVENT_QUEUE, EVENT_QUEUE_SIZE)?;
+        let status_queue = transport.create_queue(STATUS_QUEUE)?;
+        transport.finish_init();
+
+        let event_bufs = event_queue.size() as usize;
+        let mut inner = VirtIoInputInner {
+            transport,
+            event_queue,
+            status_queue,
+            events: DmaBuf::new(event_bufs * EVENT_SIZE)?,
+            event_heads: BTreeMap::new(),
+            status: DmaBuf::new(EVENT_SIZE)?,
+        };
+        for i in 0..event_bufs {
+            inner.add_event_buf(i)?;
+        }
+        inner.transport.notify(&inner.event_queue);
         Ok(Self {
-            inner,
+            inner: Mutex::new(inner),
+            decoder: Mutex::new(EventDecoder::default()),
             listener: EventListener::new(),
         })
     }
+
+    /// Turn on the keyboard LEDs in `leds`, and turn off the others.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the device has no LEDs.
+    pub fn set_leds(&self, leds: KeyLeds) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        if !inner.has_event_type(EV_LED) {
+            return Err(DeviceError::NotSupported);
+        }
+        for (led, code) in [
+            (KeyLeds::NUM, LED_NUML),
+            (KeyLeds::CAPS, LED_CAPSL),
+            (KeyLeds::SCROLL, LED_SCROLLL),
+        ]
+        .iter()
+        .copied()
+        {
+            inner.send_status(EV_LED, code, leds.contains(led) as i32)?;
+        }
+        inner.send_status(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Set the delay before the pressed key starts repeating, and the period
+    /// between the repeated keys, both in milliseconds.
+    ///
+    /// Returns [`DeviceError::NotSupported`] if the device does not repeat
+    /// keys.
+    pub fn set_repeat(&self, delay_ms: u32, period_ms: u32) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        if !inner.has_event_type(EV_REP) {
+            return Err(DeviceError::NotSupported);
+        }
+        inner.send_status(EV_REP, REP_DELAY, delay_ms as i32)?;
+        inner.send_status(EV_REP, REP_PERIOD, period_ms as i32)?;
+        inner.send_status(EV_SYN, SYN_REPORT, 0)
+    }
 }
 
-impl_event_scheme!(VirtIoInput<'_>, InputEvent);
+impl_event_scheme!(VirtIoInput, InputEvent);
 
-impl<'a> Scheme for VirtIoInput<'a> {
+impl Scheme for VirtIoInput {
     fn name(&self) -> &str {
         "virtio-input"
     }
 
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
     fn handle_irq(&self, _irq_num: usize) {
-        let mut inner = self.inner.lock();
-        inner.ack_interrupt();
-        while let Some(e) = inner.pop_pending_event() {
-            if let Ok(event_type) = InputEventType::try_from(e.event_type) {
-                self.listener.trigger(InputEvent {
-                    event_type,
-                    code: e.code,
-                    value: e.value as i32,
-                });
+        let events = {
+            let mut inner = self.inner.lock();
+            inner.transport.ack_interrupt();
+            inner.pop_events()
+        };
+        match events {
+            Ok(events) => {
+                for event in events {
+                    self.decoder.lock().update(&event);
+                    self.listener.trigger(event);
+                }
             }
+            Err(err) => warn!("virtio-input: failed to handle events: {:?}", err),
         }
     }
 }
 
-impl<'a> InputScheme for VirtIoInput<'a> {
+impl InputScheme for VirtIoInput {
     fn capability(&self, cap_type: CapabilityType) -> InputCapability {
         let mut inner = self.inner.lock();
-        let mut bitmap = [0u8; 128];
+        let mut bitmap = [0u8; CONFIG_DATA_SIZE];
         match cap_type {
             CapabilityType::InputProp => {
-                let size = inner.query_config_select(InputConfigSelect::PropBits, 0, &mut bitmap);
-                InputCapability::from_bitmap(&bitmap[..size as usize])
+                let size = inner.query_config(CONFIG_PROP_BITS, 0, &mut bitmap);
+                InputCapability::from_bitmap(&bitmap[..size])
             }
             CapabilityType::Event => {
                 let mut cap = InputCapability::empty();
-                for i in 0..crate::input::input_event_codes::ev::EV_CNT {
-                    let size =
-                        inner.query_config_select(InputConfigSelect::EvBits, i as u8, &mut bitmap);
-                    if size > 0 {
+                for i in 0..EV_CNT {
+                    if inner.has_event_type(i) {
                         cap.set(i);
                     }
                 }
                 cap
             }
             _ => {
-                let size = inner.query_config_select(
-                    InputConfigSelect::EvBits,
-                    cap_type as u8,
-                    &mut bitmap,
-                );
-                InputCapability::from_bitmap(&bitmap[..size as usize])
+                let size = inner.query_config(CONFIG_EV_BITS, cap_type as u8, &mut bitmap);
+                InputCapability::from_bitmap(&bitmap[..size])
             }
         }
     }
+
+    /// Events are decoded at the end of each frame.
+    fn poll_event(&self) -> Option<TypedInputEvent> {
+        self.decoder.lock().pending.pop_front()
+    }
+
+    /// Returns the name in the configuration space, e.g. `QEMU Virtio
+    /// Keyboard`.
+    fn device_name(&self) -> String {
+        let mut buf = [0u8; CONFIG_DATA_SIZE];
+        let size = self.inner.lock().query_config(CONFIG_ID_NAME, 0, &mut buf);
+        String::from_utf8_lossy(&buf[..size]).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn raw(event_type: InputEventType, code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            event_type,
+            code,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_decode_events() {
+        use InputEventType::*;
+        let mut decoder = EventDecoder::default();
+        for e in &[
+            raw(RelAxis, REL_X, 3),
+            raw(RelAxis, REL_Y, -2),
+            raw(Key, BTN_LEFT, 1),
+            raw(Syn, SYN_REPORT, 0),
+            raw(Key, KEY_A, 1),
+            raw(Syn, SYN_REPORT, 0),
+            raw(AbsAxis, ABS_X, 100),
+            raw(AbsAxis, ABS_Y, 200),
+            raw(Syn, SYN_REPORT, 0),
+            // only the changed axis is reported
+            raw(AbsAxis, ABS_Y, 300),
+            raw(Syn, SYN_REPORT, 0),
+        ] {
+            decoder.update(e);
+        }
+        let events: Vec<_> = decoder.pending.drain(..).collect();
+        assert_eq!(
+            events,
+            [
+                TypedInputEvent::RelMotion { dx: 3, dy: -2 },
+                TypedInputEvent::Button {
+                    code: BTN_LEFT,
+                    pressed: true
+                },
+                TypedInputEvent::Key {
+                    code: KEY_A,
+                    pressed: true
+                },
+                TypedInputEvent::AbsMotion { x: 100, y: 200 },
+                TypedInputEvent::AbsMotion { x: 100, y: 300 },
+            ]
+        );
+
+        // nothing is decoded before the end of the frame
+        decoder.update(&raw(Key, KEY_A, 0));
+        assert!(decoder.pending.is_empty());
+    }
 }
@@ -1,20 +1,69 @@
 //! Packaging of [`virtio-drivers` library](https://github.com/rcore-os/virtio-drivers).
 
+mod balloon;
 mod blk;
 mod console;
 mod gpu;
 mod input;
+mod net;
+mod pci;
+mod rng;
+mod transport;
+mod vsock;
 
+pub use balloon::VirtIoBalloon;
 pub use blk::VirtIoBlk;
 pub use console::VirtIoConsole;
 pub use gpu::VirtIoGpu;
 pub use input::VirtIoInput;
+pub use net::VirtIoNet;
+pub use pci::{pci_device_type, PciTransport, VIRTIO_PCI_VENDOR_ID};
+pub use rng::VirtIoRng;
+pub use transport::Transport;
 pub use virtio_drivers::VirtIOHeader;
+pub use vsock::VirtIoVsock;
 
+use crate::io::{Io, Mmio};
 use crate::DeviceError;
 use core::convert::From;
 use virtio_drivers::Error;
 
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const MMIO_MAGIC: usize = 0x000 / 4;
+const MMIO_VERSION: usize = 0x004 / 4;
+const MMIO_DEVICE_ID: usize = 0x008 / 4;
+const MMIO_STATUS: usize = 0x070 / 4;
+
+/// Register layout of a VirtIO MMIO device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioVersion {
+    /// The legacy interface (version 1), with page-based virtqueue addresses.
+    Legacy,
+    /// The modern interface of VirtIO 1.0 (version 2).
+    Modern,
+}
+
+/// Returns the register layout of the VirtIO MMIO device at `base`, or `None`
+/// if there is no VirtIO device (e.g. the placeholders with device ID 0).
+pub fn mmio_version(base: usize) -> Option<MmioVersion> {
+    let regs = unsafe { Mmio::<u32>::from_base(base) };
+    if regs.add(MMIO_MAGIC).read() != MMIO_MAGIC_VALUE || regs.add(MMIO_DEVICE_ID).read() == 0 {
+        return None;
+    }
+    match regs.add(MMIO_VERSION).read() {
+        1 => Some(MmioVersion::Legacy),
+        2 => Some(MmioVersion::Modern),
+        _ => None,
+    }
+}
+
+/// Reset the device of the drivers from `virtio_drivers`, which do not expose
+/// the status register.
+fn reset(header_base: usize) {
+    let regs = unsafe { Mmio::<u32>::from_base(header_base) };
+    regs.add(MMIO_STATUS).write(0);
+}
+
 impl From<Error> for DeviceError {
     fn from(err: Error) -> Self {
         match err {
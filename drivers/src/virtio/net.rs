@@ -0,0 +1,365 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lock::Mutex;
+use smoltcp::wire::{EthernetAddress, IpCidr};
+use virtio_drivers::VirtIOHeader;
+
+use super::transport::{Transport, VirtQueue};
+use crate::bus::PAGE_SIZE;
+use crate::io::Io;
+use crate::scheme::{impl_event_scheme, LinkStatus, NetScheme, Scheme};
+use crate::utils::{DmaBuf, EventListener, IdAllocator};
+use crate::{DeviceError, DeviceResult};
+
+/// Indices of the virtqueues.
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// The device has a MAC address in its configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// The device reports the link state in its configuration space.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+/// Set in the `status` field if the link is up.
+const VIRTIO_NET_S_LINK_UP: u32 = 1;
+/// Index of the word holding the last 2 bytes of the MAC address, followed
+/// by the `status` field, in the configuration space.
+const CONFIG_MAC_STATUS: usize = 1;
+
+/// Size of the header before each frame, without `VIRTIO_NET_F_MRG_RXBUF`.
+/// The modern interface adds the `num_buffers` field.
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+/// The largest Ethernet frame without the FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// Number of buffers of each virtqueue, each of them takes a page and a
+/// descriptor.
+const QUEUE_BUFS: u16 = 16;
+
+/// Generate a unicast, locally administered MAC address from the address of
+/// the device, which is unique in the system.
+fn local_mac(header: &VirtIOHeader) -> EthernetAddress {
+    let id = (header as *const _ as usize as u64).to_be_bytes();
+    EthernetAddress([0x02, 0x00, id[4], id[5], id[6], id[7]])
+}
+
+struct VirtIoNetInner {
+    transport: Transport,
+    rx_queue: VirtQueue,
+    tx_queue: VirtQueue,
+    /// A page for each receive buffer.
+    rx_bufs: DmaBuf,
+    /// Head descriptor index -> receive buffer.
+    rx_heads: BTreeMap<u16, usize>,
+    /// A page for each frame being sent.
+    tx_bufs: DmaBuf,
+    tx_slots: IdAllocator,
+    /// Head descriptor index -> send buffer.
+    tx_heads: BTreeMap<u16, usize>,
+    header_size: usize,
+}
+
+impl VirtIoNetInner {
+    /// Make the receive buffer `index` available to the device.
+    fn add_rx_buf(&mut self, index: usize) -> DeviceResult {
+        let paddr = self.rx_bufs.paddr() + index * PAGE_SIZE;
+        let head = self
+            .rx_queue
+            .add(&[(paddr, self.header_size + MAX_FRAME_SIZE, true)])?;
+        self.rx_heads.insert(head, index);
+        Ok(())
+    }
+
+    /// Free the send buffers taken by the device.
+    fn reap_tx(&mut self) -> DeviceResult {
+        while let Some((head, _)) = self.tx_queue.pop_used() {
+            if let Some(slot) = self.tx_heads.remove(&head) {
+                self.tx_slots.free(slot, 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a received frame into `buf`, and give the buffer back to the
+    /// device.
+    fn recv(&mut self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let (head, written) = self.rx_queue.pop_used().ok_or(DeviceError::Again)?;
+        let index = self.rx_heads.remove(&head).ok_or(DeviceError::IoError)?;
+        let len = written.saturating_sub(self.header_size).min(MAX_FRAME_SIZE);
+        let res = if len > buf.len() {
+            Err(DeviceError::InvalidParam)
+        } else {
+            let offset = index * PAGE_SIZE + self.header_size;
+            self.rx_bufs.read_at(offset, &mut buf[..len]).map(|_| len)
+        };
+        self.add_rx_buf(index)?;
+        self.transport.notify(&self.rx_queue);
+        res
+    }
+
+    /// Copy `frame` into a free send buffer after a zeroed header, i.e. no
+    /// offloading, and make it available to the device.
+    fn send(&mut self, frame: &[u8]) -> DeviceResult {
+        self.reap_tx()?;
+        let slot = self.tx_slots.alloc().map_err(|_| DeviceError::Again)?;
+        let offset = slot * PAGE_SIZE;
+        self.tx_bufs
+            .write_at(offset, &[0; HEADER_SIZE][..self.header_size])?;
+        self.tx_bufs.write_at(offset + self.header_size, frame)?;
+        let paddr = self.tx_bufs.paddr() + offset;
+        let len = self.header_size + frame.len();
+        let head = match self.tx_queue.add(&[(paddr, len, false)]) {
+            Ok(head) => head,
+            Err(_) => {
+                self.tx_slots.free(slot, 1)?;
+                return Err(DeviceError::Again);
+            }
+        };
+        self.tx_heads.insert(head, slot);
+        self.transport.notify(&self.tx_queue);
+        Ok(())
+    }
+
+    fn read_mac(&self) -> EthernetAddress {
+        let low = self.transport.config(0).read().to_le_bytes();
+        let high = self
+            .transport
+            .config(CONFIG_MAC_STATUS)
+            .read()
+            .to_le_bytes();
+        EthernetAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+    }
+}
+
+/// Driver of the VirtIO network device.
+///
+/// All receive buffers are made available to the device in advance, so
+/// frames arrive without a pending `recv`, and are copied out of them.
+pub struct VirtIoNet {
+    inner: Mutex<VirtIoNetInner>,
+    mac: EthernetAddress,
+    has_status: bool,
+    link_up: AtomicBool,
+    listener: EventListener,
+}
+
+impl_event_scheme!(VirtIoNet);
+
+impl VirtIoNet {
+    /// Initialize the device with an RX and a TX virtqueue.
+    ///
+    /// If `VIRTIO_NET_F_MAC` is not negotiated, a locally administered
+    /// MAC address is generated.
+    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
+        let local_mac = local_mac(header);
+        let mut transport = Transport::from(header);
+        transport.require_config()?;
+        let features =
+            transport.begin_init(|offered| offered & (VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS))?;
+        let rx_queue = transport.create_queue_with_size(RX_QUEUE, QUEUE_BUFS)?;
+        let tx_queue = transport.create_queue_with_size(TX_QUEUE, QUEUE_BUFS)?;
+        transport.finish_init();
+
+        let rx_bufs = rx_queue.size() as usize;
+        let tx_bufs = tx_queue.size() as usize;
+        let mut inner = VirtIoNetInner {
+            header_size: if transport.is_legacy() {
+                LEGACY_HEADER_SIZE
+            } else {
+                HEADER_SIZE
+            },
+            transport,
+            rx_queue,
+            tx_queue,
+            rx_bufs: DmaBuf::new(rx_bufs * PAGE_SIZE)?,
+            rx_heads: BTreeMap::new(),
+            tx_bufs: DmaBuf::new(tx_bufs * PAGE_SIZE)?,
+            tx_slots: IdAllocator::new(0..tx_bufs)?,
+            tx_heads: BTreeMap::new(),
+        };
+        for i in 0..rx_bufs {
+            inner.add_rx_buf(i)?;
+        }
+        inner.transport.notify(&inner.rx_queue);
+
+        let mac = if features & VIRTIO_NET_F_MAC != 0 {
+            inner.read_mac()
+        } else {
+            warn!("virtio-net: no MAC address offered, use {}", local_mac);
+            local_mac
+        };
+        let net = Self {
+            inner: Mutex::new(inner),
+            mac,
+            has_status: features & VIRTIO_NET_F_STATUS != 0,
+            link_up: AtomicBool::new(false),
+            listener: EventListener::new(),
+        };
+        net.link_up.store(net.read_link_up(), Ordering::Relaxed);
+        Ok(net)
+    }
+
+    /// The link is always up if `VIRTIO_NET_F_STATUS` is not negotiated, in
+    /// which case the `status` field does not exist.
+    fn read_link_up(&self) -> bool {
+        if !self.has_status {
+            return true;
+        }
+        let word = self.inner.lock().transport.config(CONFIG_MAC_STATUS).read();
+        (word >> 16) & VIRTIO_NET_S_LINK_UP != 0
+    }
+}
+
+impl Scheme for VirtIoNet {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let received = {
+            let mut inner = self.inner.lock();
+            inner.transport.ack_interrupt();
+            inner.rx_queue.can_pop()
+        };
+        // The configuration change interrupt is not told apart by the driver
+        let up = self.read_link_up();
+        let link_changed = self.link_up.swap(up, Ordering::Relaxed) != up;
+        if received || link_changed {
+            self.listener.trigger(());
+        }
+    }
+}
+
+impl NetScheme for VirtIoNet {
+    /// A frame larger than `buf` is dropped.
+    fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        self.inner.lock().recv(buf)
+    }
+
+    /// The frame is copied, so `frame` can be reused once it returns.
+    /// Returns [`DeviceError::InvalidParam`] if `frame` is larger than
+    /// an Ethernet frame.
+    fn send(&self, frame: &[u8]) -> DeviceResult {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.inner.lock().send(frame)
+    }
+
+    fn get_mac(&self) -> EthernetAddress {
+        self.mac
+    }
+
+    /// The speed is not reported, as `VIRTIO_NET_F_SPEED_DUPLEX` is not
+    /// negotiated.
+    fn link_status(&self) -> LinkStatus {
+        LinkStatus {
+            up: self.read_link_up(),
+            speed_mbps: 0,
+            full_duplex: true,
+        }
+    }
+
+    fn get_ifname(&self) -> String {
+        String::from(self.name())
+    }
+
+    fn get_ip_address(&self) -> Vec<IpCidr> {
+        Vec::new()
+    }
+
+    fn poll(&self) -> DeviceResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheme::EventScheme;
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// The registers of a legacy virtio-net device in the memory.
+    fn mock_header(features: u32) -> *mut u32 {
+        let regs = Box::leak(Box::new([0u32; 0x200 / 4]));
+        regs[0x00 / 4] = 0x7472_6976; // magic
+        regs[0x04 / 4] = 1; // version
+        regs[0x08 / 4] = 1; // network card
+        regs[0x10 / 4] = features;
+        regs[0x34 / 4] = QUEUE_BUFS as u32; // QueueNumMax
+        regs[0x100 / 4] = u32::from_le_bytes([MAC[0], MAC[1], MAC[2], MAC[3]]);
+        regs[0x104 / 4] = u32::from_le_bytes([MAC[4], MAC[5], 0, 0]);
+        regs.as_mut_ptr()
+    }
+
+    /// Receive `frame` into the `n`-th available buffer as the device.
+    fn receive(net: &VirtIoNet, n: usize, used_idx: u16, frame: &[u8]) {
+        let queue = net.inner.lock().rx_queue.desc_paddr();
+        let size = QUEUE_BUFS as usize;
+        let avail_ring = (queue + 16 * size + 4) as *const u16;
+        let used = (queue + PAGE_SIZE) as *mut u16;
+        unsafe {
+            let head = avail_ring.add(n % size).read_volatile();
+            let buf = ((queue + 16 * head as usize) as *const u64).read_volatile() as *mut u8;
+            core::ptr::write_bytes(buf, 0, LEGACY_HEADER_SIZE);
+            let data = buf.add(LEGACY_HEADER_SIZE);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), data, frame.len());
+            let elem = used.add(2 + 4 * (used_idx as usize % size)) as *mut u32;
+            elem.write_volatile(head as u32);
+            elem.add(1)
+                .write_volatile((LEGACY_HEADER_SIZE + frame.len()) as u32);
+            used.add(1).write_volatile(used_idx + 1);
+        }
+    }
+
+    #[test]
+    fn test_recv() {
+        let base = mock_header(VIRTIO_NET_F_MAC as u32);
+        let net = VirtIoNet::new(unsafe { &mut *(base as *mut VirtIOHeader) }).unwrap();
+        assert_eq!(net.mac_address(), MAC);
+        let events = Arc::new(AtomicUsize::new(0));
+        let cloned = events.clone();
+        net.subscribe(
+            Box::new(move |_| {
+                cloned.fetch_add(1, Ordering::Relaxed);
+            }),
+            false,
+        );
+
+        let mut buf = [0; MAX_FRAME_SIZE];
+        assert!(matches!(net.recv(&mut buf), Err(DeviceError::Again)));
+        net.handle_irq(0);
+        assert_eq!(events.load(Ordering::Relaxed), 0);
+
+        // the frames arrive without a pending `recv`
+        receive(&net, 0, 0, b"frame 0");
+        receive(&net, 1, 1, b"the second frame");
+        net.handle_irq(0);
+        assert_eq!(events.load(Ordering::Relaxed), 1);
+        assert_eq!(net.recv(&mut buf).unwrap(), 7);
+        assert_eq!(&buf[..7], b"frame 0");
+        // too large for the buffer, and dropped
+        assert!(matches!(
+            net.recv(&mut buf[..8]),
+            Err(DeviceError::InvalidParam)
+        ));
+        assert!(matches!(net.recv(&mut buf), Err(DeviceError::Again)));
+
+        // the buffers are given back to the device
+        receive(&net, QUEUE_BUFS as usize, 2, b"frame 2");
+        assert_eq!(net.recv(&mut buf).unwrap(), 7);
+        assert_eq!(&buf[..7], b"frame 2");
+    }
+}
@@ -0,0 +1,331 @@
+//! The VirtIO PCI transport of VirtIO 1.0, which finds the register blocks by
+//! the vendor specific capabilities in the configuration space. Interrupts
+//! are delivered by INTx, MSI-X is not used.
+
+use alloc::collections::BTreeMap;
+
+use virtio_drivers::DeviceType;
+
+use super::transport::{Transport, TransportOps, VirtQueue};
+use crate::bus::pci_ecam::{PciBar, PciFunction};
+use crate::io::{Io, Mmio};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+/// The vendor ID of VirtIO PCI devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
+const PCI_STATUS_WORD: usize = 0x04;
+const PCI_STATUS_CAP_LIST: u32 = 1 << (16 + 4);
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_CAP_ID_VNDR: u32 = 0x09;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u32 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u32 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u32 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u32 = 4;
+
+/// A malformed capability list may loop.
+const MAX_CAPS: usize = 48;
+
+// offsets of the common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// Returns the type of the VirtIO device of the PCI function, or `None` if it
+/// is not a VirtIO device or the type is unknown.
+pub fn pci_device_type(func: &PciFunction) -> Option<DeviceType> {
+    if func.vendor_id != VIRTIO_PCI_VENDOR_ID {
+        return None;
+    }
+    // the transitional devices have their own IDs
+    let id = match func.device_id {
+        0x1000 => 1,
+        0x1001 => 2,
+        0x1002 => 5,
+        0x1003 => 3,
+        0x1005 => 4,
+        id @ 0x1040..=0x107f => id - 0x1040,
+        _ => return None,
+    };
+    Some(match id {
+        1 => DeviceType::Network,
+        2 => DeviceType::Block,
+        3 => DeviceType::Console,
+        4 => DeviceType::EntropySource,
+        5 => DeviceType::MemoryBallooning,
+        16 => DeviceType::GPU,
+        18 => DeviceType::Input,
+        19 => DeviceType::Socket,
+        _ => return None,
+    })
+}
+
+/// The VirtIO PCI transport.
+pub struct PciTransport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_off_multiplier: u32,
+    isr: VirtAddr,
+    /// The device specific configuration, which is absent if the device has
+    /// no configuration, e.g. the entropy source.
+    device: Option<VirtAddr>,
+    /// Queue index -> notification address.
+    notify_addrs: BTreeMap<u32, VirtAddr>,
+}
+
+impl PciTransport {
+    /// Find the register blocks of the VirtIO device `func`, and map the BARs
+    /// they are in with `map`.
+    pub fn new(
+        func: &PciFunction,
+        mut map: impl FnMut(PhysAddr, usize) -> Option<VirtAddr>,
+    ) -> DeviceResult<Self> {
+        let config = unsafe { Mmio::<u32>::from_base(func.config_vaddr) };
+        if config.add(PCI_STATUS_WORD / 4).read() & PCI_STATUS_CAP_LIST == 0 {
+            return Err(DeviceError::NotSupported);
+        }
+
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_off_multiplier = 0;
+        let mut ptr = config.add(PCI_CAPABILITY_LIST / 4).read() as usize & 0xfc;
+        for _ in 0..MAX_CAPS {
+            if ptr == 0 {
+                break;
+            }
+            let cap = config.add(ptr / 4);
+            let header = cap.read();
+            let next = (header >> 8) as usize & 0xfc;
+            let cfg_type = header >> 24;
+            if header & 0xff != PCI_CAP_ID_VNDR {
+                ptr = next;
+                continue;
+            }
+            let bar = cap.add(1).read() as usize & 0xff;
+            let (offset, length) = (cap.add(2).read() as usize, cap.add(3).read() as usize);
+            let slot = match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG => &mut common,
+                VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                    notify_off_multiplier = cap.add(4).read();
+                    &mut notify
+                }
+                VIRTIO_PCI_CAP_ISR_CFG => &mut isr,
+                VIRTIO_PCI_CAP_DEVICE_CFG => &mut device,
+                _ => {
+                    ptr = next;
+                    continue;
+                }
+            };
+            // the first capability of each type is preferred
+            if slot.is_none() {
+                match func.bars.get(bar).copied().flatten() {
+                    Some(PciBar::Memory { paddr, size, .. }) if offset + length <= size => {
+                        let vaddr = map(paddr, size).ok_or(DeviceError::NoResources)?;
+                        *slot = Some(vaddr + offset);
+                    }
+                    _ => warn!(
+                        "virtio-pci: capability {} in BAR{} {:#x}+{:#x} is not accessible",
+                        cfg_type, bar, offset, length
+                    ),
+                }
+            }
+            ptr = next;
+        }
+
+        match (common, notify, isr) {
+            (Some(common), Some(notify), Some(isr)) => Ok(Self {
+                common,
+                notify,
+                notify_off_multiplier,
+                isr,
+                device,
+                notify_addrs: BTreeMap::new(),
+            }),
+            // the legacy interface in I/O space is not supported
+            _ => Err(DeviceError::NotSupported),
+        }
+    }
+
+    fn common<T>(&self, offset: usize) -> &'static mut Mmio<T> {
+        unsafe { Mmio::<T>::from_base(self.common + offset) }
+    }
+
+    fn select_queue(&mut self, index: u32) {
+        self.common::<u16>(COMMON_QUEUE_SELECT).write(index as u16);
+    }
+}
+
+impl From<PciTransport> for Transport {
+    fn from(transport: PciTransport) -> Self {
+        Transport::new(transport)
+    }
+}
+
+impl TransportOps for PciTransport {
+    fn status(&self) -> u32 {
+        self.common::<u8>(COMMON_DEVICE_STATUS).read() as u32
+    }
+
+    fn set_status(&mut self, status: u32) {
+        self.common::<u8>(COMMON_DEVICE_STATUS).write(status as u8);
+    }
+
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let mut offered = 0;
+        for sel in 0..2 {
+            self.common::<u32>(COMMON_DEVICE_FEATURE_SELECT).write(sel);
+            offered |= (self.common::<u32>(COMMON_DEVICE_FEATURE).read() as u64) << (sel * 32);
+        }
+        offered
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        for sel in 0..2 {
+            self.common::<u32>(COMMON_DRIVER_FEATURE_SELECT).write(sel);
+            self.common::<u32>(COMMON_DRIVER_FEATURE)
+                .write((features >> (sel * 32)) as u32);
+        }
+    }
+
+    fn max_queue_size(&mut self, index: u32) -> u32 {
+        if index >= self.common::<u16>(COMMON_NUM_QUEUES).read() as u32 {
+            return 0;
+        }
+        self.select_queue(index);
+        self.common::<u16>(COMMON_QUEUE_SIZE).read() as u32
+    }
+
+    fn activate_queue(&mut self, queue: &VirtQueue) {
+        self.select_queue(queue.index());
+        self.common::<u16>(COMMON_QUEUE_SIZE).write(queue.size());
+        for (offset, paddr) in [
+            (COMMON_QUEUE_DESC, queue.desc_paddr()),
+            (COMMON_QUEUE_DRIVER, queue.avail_paddr()),
+            (COMMON_QUEUE_DEVICE, queue.used_paddr()),
+        ]
+        .iter()
+        .copied()
+        {
+            self.common::<u32>(offset).write(paddr as u32);
+            self.common::<u32>(offset + 4)
+                .write((paddr as u64 >> 32) as u32);
+        }
+        let notify_off = self.common::<u16>(COMMON_QUEUE_NOTIFY_OFF).read() as usize;
+        self.notify_addrs.insert(
+            queue.index(),
+            self.notify + notify_off * self.notify_off_multiplier as usize,
+        );
+        self.common::<u16>(COMMON_QUEUE_ENABLE).write(1);
+    }
+
+    fn notify(&mut self, index: u32) {
+        if let Some(&addr) = self.notify_addrs.get(&index) {
+            unsafe { Mmio::<u16>::from_base(addr) }.write(index as u16);
+        }
+    }
+
+    /// Reading the ISR status also clears it and deasserts the INTx.
+    fn ack_interrupt(&mut self) -> u32 {
+        unsafe { Mmio::<u8>::from_base(self.isr) }.read() as u32
+    }
+
+    /// The drivers check [`has_config`](TransportOps::has_config) first.
+    fn config(&self, index: usize) -> &'static mut Mmio<u32> {
+        let device = self
+            .device
+            .expect("virtio-pci: no device specific configuration");
+        unsafe { Mmio::<u32>::from_base(device + index * 4) }
+    }
+
+    fn has_config(&self) -> bool {
+        self.device.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::boxed::Box;
+
+    /// A configuration space with the capabilities in BAR4, as QEMU does.
+    fn mock_function(bar: &mut [u32], with_device_cfg: bool) -> PciFunction {
+        let config = Box::leak(Box::new([0u32; 0x1000 / 4]));
+        config[PCI_STATUS_WORD / 4] = PCI_STATUS_CAP_LIST;
+        config[PCI_CAPABILITY_LIST / 4] = 0x40;
+        let caps: &[(u32, usize)] = if with_device_cfg {
+            &[(1, 0), (3, 0x1000), (4, 0x2000), (2, 0x3000)]
+        } else {
+            &[(1, 0), (3, 0x1000), (2, 0x3000)]
+        };
+        for (i, &(cfg_type, offset)) in caps.iter().enumerate() {
+            let ptr = 0x40 + i * 0x14;
+            let next = if i + 1 < caps.len() { ptr + 0x14 } else { 0 };
+            let cap = &mut config[ptr / 4..ptr / 4 + 5];
+            cap[0] = cfg_type << 24 | 0x14 << 16 | (next as u32) << 8 | PCI_CAP_ID_VNDR;
+            cap[1] = 4;
+            cap[2] = offset as u32;
+            cap[3] = 0x1000;
+            cap[4] = 4; // notify_off_multiplier
+        }
+        let mut bars = [None; 6];
+        bars[4] = Some(PciBar::Memory {
+            paddr: bar.as_mut_ptr() as usize,
+            size: bar.len() * 4,
+            prefetchable: true,
+        });
+        PciFunction {
+            bus: 0,
+            device: 1,
+            function: 0,
+            vendor_id: VIRTIO_PCI_VENDOR_ID,
+            device_id: 0x1042,
+            class: 1,
+            subclass: 0,
+            prog_if: 0,
+            bars,
+            interrupt_pin: 1,
+            config_vaddr: config.as_ptr() as usize,
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let bar = Box::leak(Box::new([0u32; 0x4000 / 4]));
+        let base = bar.as_ptr() as usize;
+        let func = mock_function(bar, true);
+        assert!(matches!(pci_device_type(&func), Some(DeviceType::Block)));
+
+        let transport = PciTransport::new(&func, |paddr, _| Some(paddr)).unwrap();
+        assert_eq!(transport.common, base);
+        assert_eq!(transport.isr, base + 0x1000);
+        assert_eq!(transport.device, Some(base + 0x2000));
+        assert_eq!(transport.notify, base + 0x3000);
+        assert_eq!(transport.notify_off_multiplier, 4);
+        assert_eq!(transport.config(1) as *mut _ as usize, base + 0x2004);
+
+        // the device specific configuration is optional, e.g. for virtio-rng
+        let bar = Box::leak(Box::new([0u32; 0x4000 / 4]));
+        let func = mock_function(bar, false);
+        let transport = PciTransport::new(&func, |paddr, _| Some(paddr)).unwrap();
+        assert_eq!(transport.device, None);
+        assert!(!transport.has_config());
+        assert!(matches!(
+            Transport::from(transport).require_config(),
+            Err(DeviceError::NotSupported)
+        ));
+    }
+}
@@ -0,0 +1,70 @@
+use lock::Mutex;
+
+use super::transport::{Transport, VirtQueue};
+use crate::bus::PAGE_SIZE;
+use crate::scheme::{RngScheme, Scheme};
+use crate::utils::DmaBuf;
+use crate::DeviceResult;
+
+struct VirtIoRngInner {
+    transport: Transport,
+    queue: VirtQueue,
+    buf: DmaBuf,
+}
+
+/// Driver of the VirtIO entropy device, which fills buffers with random bytes
+/// by the only request virtqueue.
+pub struct VirtIoRng {
+    inner: Mutex<VirtIoRngInner>,
+}
+
+impl VirtIoRng {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        // no device specific features
+        transport.begin_init(|_| 0)?;
+        let queue = transport.create_queue(0)?;
+        transport.finish_init();
+        let buf = DmaBuf::new(PAGE_SIZE)?;
+        Ok(Self {
+            inner: Mutex::new(VirtIoRngInner {
+                transport,
+                queue,
+                buf,
+            }),
+        })
+    }
+}
+
+impl Scheme for VirtIoRng {
+    fn name(&self) -> &str {
+        "virtio-rng"
+    }
+
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        self.inner.lock().transport.ack_interrupt();
+    }
+}
+
+impl RngScheme for VirtIoRng {
+    /// Fill at most a page of `buf` at a time, and block until the device
+    /// completes the request.
+    fn fill(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let len = buf.len().min(PAGE_SIZE);
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let filled = inner
+            .transport
+            .transfer(&mut inner.queue, inner.buf.paddr(), len, true)?;
+        inner.buf.read_at(0, &mut buf[..filled])?;
+        Ok(filled)
+    }
+}
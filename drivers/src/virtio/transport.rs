@@ -0,0 +1,455 @@
+//! A minimal VirtIO transport for the devices not supported by
+//! `virtio_drivers`, over MMIO with both the legacy and the modern register
+//! layouts, or over PCI (see [`super::pci`]).
+
+use alloc::boxed::Box;
+use core::sync::atomic::{fence, Ordering};
+
+use virtio_drivers::VirtIOHeader;
+
+use crate::bus::{phys_to_virt, PAGE_SIZE};
+use crate::io::{Io, Mmio};
+use crate::utils::DmaBuf;
+use crate::{DeviceError, DeviceResult};
+
+const MMIO_VERSION: usize = 0x004 / 4;
+const MMIO_DEVICE_FEATURES: usize = 0x010 / 4;
+const MMIO_DEVICE_FEATURES_SEL: usize = 0x014 / 4;
+const MMIO_DRIVER_FEATURES: usize = 0x020 / 4;
+const MMIO_DRIVER_FEATURES_SEL: usize = 0x024 / 4;
+const MMIO_GUEST_PAGE_SIZE: usize = 0x028 / 4;
+const MMIO_QUEUE_SEL: usize = 0x030 / 4;
+const MMIO_QUEUE_NUM_MAX: usize = 0x034 / 4;
+const MMIO_QUEUE_NUM: usize = 0x038 / 4;
+const MMIO_QUEUE_ALIGN: usize = 0x03c / 4;
+const MMIO_QUEUE_PFN: usize = 0x040 / 4;
+const MMIO_QUEUE_READY: usize = 0x044 / 4;
+const MMIO_QUEUE_NOTIFY: usize = 0x050 / 4;
+const MMIO_INTERRUPT_STATUS: usize = 0x060 / 4;
+const MMIO_INTERRUPT_ACK: usize = 0x064 / 4;
+const MMIO_STATUS: usize = 0x070 / 4;
+const MMIO_QUEUE_DESC: usize = 0x080 / 4;
+const MMIO_QUEUE_AVAIL: usize = 0x090 / 4;
+const MMIO_QUEUE_USED: usize = 0x0a0 / 4;
+const MMIO_CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+/// `VIRTIO_F_VERSION_1`, required by the modern interface.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// The configuration change notification bit in the interrupt status.
+pub(super) const INT_CONFIG_CHANGE: u32 = 2;
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+
+/// Only one request is in flight at a time by default.
+const DEFAULT_QUEUE_SIZE: u16 = 2;
+
+/// The descriptor table and the available ring in the first page, the used
+/// ring in the second one (aligned to a page as the legacy interface requires).
+const QUEUE_PAGES: usize = 2;
+
+/// The most descriptors fit in `QUEUE_PAGES` pages.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+#[repr(C)]
+struct Descriptor {
+    addr: Mmio<u64>,
+    len: Mmio<u32>,
+    flags: Mmio<u16>,
+    next: Mmio<u16>,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: Mmio<u32>,
+    len: Mmio<u32>,
+}
+
+/// A split virtqueue, whose free descriptors are linked by `next`.
+pub(super) struct VirtQueue {
+    index: u32,
+    size: u16,
+    /// The pages of the rings, freed with the queue.
+    mem: DmaBuf,
+    desc: &'static mut [Descriptor],
+    avail_idx: &'static mut Mmio<u16>,
+    avail_ring: &'static mut [Mmio<u16>],
+    used_idx: &'static mut Mmio<u16>,
+    used_ring: &'static mut [UsedElem],
+    free_head: u16,
+    num_free: u16,
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    fn new(index: u32, size: u16) -> DeviceResult<Self> {
+        let mem = DmaBuf::new(QUEUE_PAGES * PAGE_SIZE)?;
+        let vaddr = phys_to_virt(mem.paddr());
+        let avail = vaddr + size as usize * core::mem::size_of::<Descriptor>();
+        let used = vaddr + PAGE_SIZE;
+        let len = size as usize;
+        // skip the `flags` fields of the rings
+        let mut queue = unsafe {
+            Self {
+                index,
+                size,
+                mem,
+                desc: core::slice::from_raw_parts_mut(vaddr as _, len),
+                avail_idx: Mmio::<u16>::from_base(avail + 2),
+                avail_ring: core::slice::from_raw_parts_mut((avail + 4) as _, len),
+                used_idx: Mmio::<u16>::from_base(used + 2),
+                used_ring: core::slice::from_raw_parts_mut((used + 4) as _, len),
+                free_head: 0,
+                num_free: size,
+                last_used_idx: 0,
+            }
+        };
+        for (i, desc) in queue.desc.iter_mut().enumerate() {
+            desc.next.write(i as u16 + 1);
+        }
+        Ok(queue)
+    }
+
+    pub(super) fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub(super) fn desc_paddr(&self) -> usize {
+        self.mem.paddr()
+    }
+
+    pub(super) fn avail_paddr(&self) -> usize {
+        self.mem.paddr() + self.size as usize * core::mem::size_of::<Descriptor>()
+    }
+
+    pub(super) fn used_paddr(&self) -> usize {
+        self.mem.paddr() + PAGE_SIZE
+    }
+
+    /// Returns the number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Make a chain of the buffers `(paddr, len, writable)` available to the
+    /// device, where the buffers are written by the device if `writable`, or
+    /// read otherwise. Returns the descriptor index of the head, which
+    /// identifies the chain until it is used.
+    ///
+    /// Returns [`DeviceError::NoResources`] if there are not enough free
+    /// descriptors.
+    pub fn add(&mut self, bufs: &[(usize, usize, bool)]) -> DeviceResult<u16> {
+        if bufs.is_empty() {
+            return Err(DeviceError::InvalidParam);
+        }
+        if bufs.len() > self.num_free as usize {
+            return Err(DeviceError::NoResources);
+        }
+        let head = self.free_head;
+        for (i, &(paddr, len, writable)) in bufs.iter().enumerate() {
+            let desc = &mut self.desc[self.free_head as usize];
+            desc.addr.write(paddr as u64);
+            desc.len.write(len as u32);
+            let mut flags = if writable { VRING_DESC_F_WRITE } else { 0 };
+            if i + 1 < bufs.len() {
+                flags |= VRING_DESC_F_NEXT;
+            }
+            desc.flags.write(flags);
+            self.free_head = desc.next.read();
+        }
+        self.num_free -= bufs.len() as u16;
+
+        let avail_idx = self.avail_idx.read();
+        self.avail_ring[(avail_idx % self.size) as usize].write(head);
+        fence(Ordering::SeqCst);
+        self.avail_idx.write(avail_idx.wrapping_add(1));
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether there are chains used by the device and not taken yet.
+    pub fn can_pop(&self) -> bool {
+        self.used_idx.read() != self.last_used_idx
+    }
+
+    /// Take a chain used by the device, and returns the descriptor index of
+    /// its head and the number of bytes written by the device.
+    pub fn pop_used(&mut self) -> Option<(u16, usize)> {
+        if self.used_idx.read() == self.last_used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = &self.used_ring[(self.last_used_idx % self.size) as usize];
+        let (head, len) = (elem.id.read() as u16, elem.len.read() as usize);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.free_chain(head);
+        Some((head, len))
+    }
+
+    /// Put the chain starting at `head` back to the free list.
+    fn free_chain(&mut self, head: u16) {
+        let free_head = self.free_head;
+        let mut i = head;
+        loop {
+            self.num_free += 1;
+            let desc = &mut self.desc[i as usize];
+            if desc.flags.read() & VRING_DESC_F_NEXT == 0 {
+                desc.next.write(free_head);
+                break;
+            }
+            i = desc.next.read();
+        }
+        self.free_head = head;
+    }
+}
+
+/// Operations which differ between the MMIO and PCI transports.
+pub(super) trait TransportOps: Send {
+    fn status(&self) -> u32;
+    fn set_status(&mut self, status: u32);
+    /// Whether it is the legacy interface, without `VIRTIO_F_VERSION_1`.
+    fn is_legacy(&self) -> bool;
+    fn device_features(&mut self) -> u64;
+    fn set_driver_features(&mut self, features: u64);
+    /// Returns the maximum size of the virtqueue `index`, or 0 if it is not
+    /// available.
+    fn max_queue_size(&mut self, index: u32) -> u32;
+    /// Tell the device the size and the addresses of the virtqueue.
+    fn activate_queue(&mut self, queue: &VirtQueue);
+    fn notify(&mut self, index: u32);
+    fn ack_interrupt(&mut self) -> u32;
+    fn config(&self, index: usize) -> &'static mut Mmio<u32>;
+
+    /// Whether the device specific configuration space exists.
+    fn has_config(&self) -> bool {
+        true
+    }
+}
+
+/// The VirtIO MMIO transport.
+struct MmioTransport {
+    regs: &'static mut Mmio<u32>,
+    legacy: bool,
+}
+
+impl MmioTransport {
+    fn new(header: &'static mut VirtIOHeader) -> Self {
+        let regs = unsafe { Mmio::<u32>::from_base(header as *mut _ as usize) };
+        let legacy = regs.add(MMIO_VERSION).read() == 1;
+        Self { regs, legacy }
+    }
+}
+
+impl TransportOps for MmioTransport {
+    fn status(&self) -> u32 {
+        self.regs.add(MMIO_STATUS).read()
+    }
+
+    fn set_status(&mut self, status: u32) {
+        self.regs.add(MMIO_STATUS).write(status);
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let mut offered = 0;
+        for sel in 0..2 {
+            self.regs.add(MMIO_DEVICE_FEATURES_SEL).write(sel);
+            offered |= (self.regs.add(MMIO_DEVICE_FEATURES).read() as u64) << (sel * 32);
+        }
+        offered
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        for sel in 0..2 {
+            self.regs.add(MMIO_DRIVER_FEATURES_SEL).write(sel);
+            self.regs
+                .add(MMIO_DRIVER_FEATURES)
+                .write((features >> (sel * 32)) as u32);
+        }
+        if self.legacy {
+            self.regs.add(MMIO_GUEST_PAGE_SIZE).write(PAGE_SIZE as u32);
+        }
+    }
+
+    fn max_queue_size(&mut self, index: u32) -> u32 {
+        self.regs.add(MMIO_QUEUE_SEL).write(index);
+        self.regs.add(MMIO_QUEUE_NUM_MAX).read()
+    }
+
+    fn activate_queue(&mut self, queue: &VirtQueue) {
+        self.regs.add(MMIO_QUEUE_SEL).write(queue.index);
+        self.regs.add(MMIO_QUEUE_NUM).write(queue.size as u32);
+        if self.legacy {
+            self.regs.add(MMIO_QUEUE_ALIGN).write(PAGE_SIZE as u32);
+            self.regs
+                .add(MMIO_QUEUE_PFN)
+                .write((queue.desc_paddr() / PAGE_SIZE) as u32);
+        } else {
+            for (reg, paddr) in [
+                (MMIO_QUEUE_DESC, queue.desc_paddr()),
+                (MMIO_QUEUE_AVAIL, queue.avail_paddr()),
+                (MMIO_QUEUE_USED, queue.used_paddr()),
+            ]
+            .iter()
+            .copied()
+            {
+                self.regs.add(reg).write(paddr as u32);
+                self.regs.add(reg + 1).write((paddr as u64 >> 32) as u32);
+            }
+            self.regs.add(MMIO_QUEUE_READY).write(1);
+        }
+    }
+
+    fn notify(&mut self, index: u32) {
+        self.regs.add(MMIO_QUEUE_NOTIFY).write(index);
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        let status = self.regs.add(MMIO_INTERRUPT_STATUS).read();
+        self.regs.add(MMIO_INTERRUPT_ACK).write(status);
+        status
+    }
+
+    fn config(&self, index: usize) -> &'static mut Mmio<u32> {
+        self.regs.add(MMIO_CONFIG / 4 + index)
+    }
+}
+
+/// The transport of a VirtIO device, i.e. the MMIO registers or the PCI
+/// capabilities by which the driver finds and configures the device.
+pub struct Transport(Box<dyn TransportOps>);
+
+impl From<&'static mut VirtIOHeader> for Transport {
+    fn from(header: &'static mut VirtIOHeader) -> Self {
+        Self(Box::new(MmioTransport::new(header)))
+    }
+}
+
+impl Transport {
+    pub(super) fn new(ops: impl TransportOps + 'static) -> Self {
+        Self(Box::new(ops))
+    }
+
+    /// Reset the device and negotiate the features, `negotiate` selects the
+    /// device specific features to use from the offered ones. Returns the
+    /// negotiated features.
+    pub(super) fn begin_init(&mut self, negotiate: impl FnOnce(u64) -> u64) -> DeviceResult<u64> {
+        let ops = &mut self.0;
+        ops.set_status(0);
+        ops.set_status(STATUS_ACKNOWLEDGE);
+        ops.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = ops.device_features();
+        let mut features = negotiate(offered) & offered & 0xff_ffff;
+        if !ops.is_legacy() {
+            features |= F_VERSION_1;
+        }
+        ops.set_driver_features(features);
+        if !ops.is_legacy() {
+            let status = ops.status();
+            ops.set_status(status | STATUS_FEATURES_OK);
+            if ops.status() & STATUS_FEATURES_OK == 0 {
+                return Err(DeviceError::NotSupported);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Whether it is the legacy interface, without `VIRTIO_F_VERSION_1`.
+    pub(super) fn is_legacy(&self) -> bool {
+        self.0.is_legacy()
+    }
+
+    /// Set the device to be ready after all virtqueues are created.
+    pub(super) fn finish_init(&mut self) {
+        let status = self.0.status();
+        self.0.set_status(status | STATUS_DRIVER_OK);
+    }
+
+    /// Reset the device, which stops using all virtqueues after that.
+    pub(super) fn reset(&mut self) {
+        self.0.set_status(0);
+    }
+
+    /// Create the virtqueue `index` and tell the device about it.
+    pub(super) fn create_queue(&mut self, index: u32) -> DeviceResult<VirtQueue> {
+        self.create_queue_with_size(index, DEFAULT_QUEUE_SIZE)
+    }
+
+    /// Create the virtqueue `index` with at most `size` descriptors, limited
+    /// by the device and rounded down to a power of two.
+    pub(super) fn create_queue_with_size(
+        &mut self,
+        index: u32,
+        size: u16,
+    ) -> DeviceResult<VirtQueue> {
+        let max = self.0.max_queue_size(index);
+        if max < DEFAULT_QUEUE_SIZE as u32 {
+            return Err(DeviceError::NotSupported);
+        }
+        let size = size
+            .clamp(DEFAULT_QUEUE_SIZE, MAX_QUEUE_SIZE)
+            .min(max.min(u16::MAX as u32) as u16);
+        let size = 1 << (15 - size.leading_zeros());
+        let queue = VirtQueue::new(index, size)?;
+        self.0.activate_queue(&queue);
+        Ok(queue)
+    }
+
+    /// Tell the device there are new buffers available in the `queue`.
+    pub(super) fn notify(&mut self, queue: &VirtQueue) {
+        self.0.notify(queue.index);
+    }
+
+    /// Submit the buffer `paddr..paddr + len` to the `queue`, and wait for the
+    /// device to use it. The buffer is written by the device if `writable`,
+    /// or read otherwise. Returns the number of bytes written by the device.
+    pub(super) fn transfer(
+        &mut self,
+        queue: &mut VirtQueue,
+        paddr: usize,
+        len: usize,
+        writable: bool,
+    ) -> DeviceResult<usize> {
+        let head = queue.add(&[(paddr, len, writable)])?;
+        self.notify(queue);
+        loop {
+            match queue.pop_used() {
+                Some((id, written)) if id == head => return Ok(written.min(len)),
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Acknowledge the interrupt, and returns the interrupt status.
+    pub(super) fn ack_interrupt(&mut self) -> u32 {
+        self.0.ack_interrupt()
+    }
+
+    /// Returns the `index`-th 32-bit word of the device configuration space.
+    pub(super) fn config(&self, index: usize) -> &'static mut Mmio<u32> {
+        self.0.config(index)
+    }
+
+    /// Returns [`DeviceError::NotSupported`] if the device configuration space
+    /// is missing, which is allowed for the devices without configuration,
+    /// e.g. the entropy source. Checked by the drivers using
+    /// [`config`](Self::config).
+    pub(super) fn require_config(&self) -> DeviceResult {
+        if self.0.has_config() {
+            Ok(())
+        } else {
+            Err(DeviceError::NotSupported)
+        }
+    }
+}
@@ -0,0 +1,642 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+
+use lock::Mutex;
+
+use super::transport::{Transport, VirtQueue};
+use crate::bus::PAGE_SIZE;
+use crate::io::Io;
+use crate::scheme::{impl_event_scheme, Scheme, VsockConnId, VsockScheme};
+use crate::utils::{DmaBuf, EventListener};
+use crate::{DeviceError, DeviceResult};
+
+/// Indices of the virtqueues.
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+const EVENT_QUEUE: u32 = 2;
+
+/// Index of the 64-bit `guest_cid` field in the configuration space.
+const CONFIG_GUEST_CID: usize = 0;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// Flags of the SHUTDOWN packets, the sender will not receive or send.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+const VIRTIO_VSOCK_SHUTDOWN_ALL: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+/// The only event, all connections are dropped, e.g. after a live migration.
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+/// Size of the packet header.
+const HEADER_SIZE: usize = 44;
+/// Each packet takes a page, the header followed by the payload.
+const MAX_PAYLOAD: usize = PAGE_SIZE - HEADER_SIZE;
+
+/// Number of receive buffers, each of them takes 2 descriptors.
+const RX_BUFS: usize = 16;
+/// Size of an event.
+const EVENT_SIZE: usize = 4;
+
+/// Size of the receive buffer of each connection, i.e. the credit given to
+/// the peer.
+const CONN_BUF_SIZE: u32 = 64 * 1024;
+/// Tell the peer about the consumed bytes after this many.
+const CREDIT_UPDATE_THRESHOLD: u32 = CONN_BUF_SIZE / 4;
+
+/// Local ports are allocated from here.
+const FIRST_EPHEMERAL_PORT: u32 = 49152;
+/// How many times to poll for the response of the peer before giving up.
+const CONNECT_TIMEOUT: usize = 1_000_000;
+
+/// The header of packets, in little-endian on the wire.
+#[derive(Debug, Default, Clone, Copy)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    ty: u16,
+    op: u16,
+    flags: u32,
+    /// Size of the receive buffer of the sender.
+    buf_alloc: u32,
+    /// Bytes consumed by the sender in total.
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.ty.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; HEADER_SIZE]) -> Self {
+        let u64_at = |i: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&buf[i..i + 8]);
+            u64::from_le_bytes(b)
+        };
+        let u32_at = |i: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&buf[i..i + 4]);
+            u32::from_le_bytes(b)
+        };
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            ty: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    /// Returns the header of a RST packet in reply to this one.
+    fn reset_reply(&self) -> Self {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RST,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// The REQUEST is sent, waiting for the RESPONSE.
+    Connecting,
+    Connected,
+    /// Refused, reset or shut down by either side.
+    Closed,
+}
+
+struct Connection {
+    peer_cid: u64,
+    peer_port: u32,
+    state: ConnState,
+    /// Received data not taken by `recv` yet.
+    rx: VecDeque<u8>,
+    /// The SHUTDOWN flags sent by the peer.
+    peer_shutdown: u32,
+    /// Bytes taken by `recv` in total.
+    fwd_cnt: u32,
+    /// The `fwd_cnt` last told to the peer.
+    last_fwd_cnt: u32,
+    /// Bytes sent in total.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(peer_cid: u64, peer_port: u32) -> Self {
+        Self {
+            peer_cid,
+            peer_port,
+            state: ConnState::Connecting,
+            rx: VecDeque::new(),
+            peer_shutdown: 0,
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Returns the free space in the receive buffer of the peer, as the
+    /// bytes that can be sent without overrunning it.
+    fn peer_free(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+}
+
+struct VirtIoVsockInner {
+    transport: Transport,
+    rx_queue: VirtQueue,
+    tx_queue: VirtQueue,
+    event_queue: VirtQueue,
+    /// A page for each receive buffer.
+    rx_bufs: DmaBuf,
+    /// Head descriptor index -> receive buffer.
+    rx_heads: BTreeMap<u16, usize>,
+    /// A page for the packet being sent.
+    tx_buf: DmaBuf,
+    events: DmaBuf,
+    /// Head descriptor index -> event buffer.
+    event_heads: BTreeMap<u16, usize>,
+    guest_cid: u64,
+    /// Local port -> connection.
+    conns: BTreeMap<VsockConnId, Connection>,
+    next_port: u32,
+}
+
+impl VirtIoVsockInner {
+    fn read_guest_cid(&self) -> u64 {
+        let low = self.transport.config(CONFIG_GUEST_CID).read() as u64;
+        let high = self.transport.config(CONFIG_GUEST_CID + 1).read() as u64;
+        high << 32 | low
+    }
+
+    /// Make the receive buffer `index` available to the device, with the
+    /// header and the payload in separate descriptors.
+    fn add_rx_buf(&mut self, index: usize) -> DeviceResult {
+        let paddr = self.rx_bufs.paddr() + index * PAGE_SIZE;
+        let head = self.rx_queue.add(&[
+            (paddr, HEADER_SIZE, true),
+            (paddr + HEADER_SIZE, MAX_PAYLOAD, true),
+        ])?;
+        self.rx_heads.insert(head, index);
+        Ok(())
+    }
+
+    fn add_event_buf(&mut self, index: usize) -> DeviceResult {
+        let paddr = self.events.paddr() + index * EVENT_SIZE;
+        let head = self.event_queue.add(&[(paddr, EVENT_SIZE, true)])?;
+        self.event_heads.insert(head, index);
+        Ok(())
+    }
+
+    /// Send a packet and wait for the device to take it.
+    fn send_packet(&mut self, mut header: PacketHeader, payload: &[u8]) -> DeviceResult {
+        header.len = payload.len() as u32;
+        self.tx_buf.write_at(0, &header.to_bytes())?;
+        self.tx_buf.write_at(HEADER_SIZE, payload)?;
+        let paddr = self.tx_buf.paddr();
+        let head = if payload.is_empty() {
+            self.tx_queue.add(&[(paddr, HEADER_SIZE, false)])?
+        } else {
+            self.tx_queue.add(&[
+                (paddr, HEADER_SIZE, false),
+                (paddr + HEADER_SIZE, payload.len(), false),
+            ])?
+        };
+        self.transport.notify(&self.tx_queue);
+        loop {
+            match self.tx_queue.pop_used() {
+                Some((id, _)) if id == head => return Ok(()),
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Send a packet of the connection `port`, with our credit in the header.
+    fn send_conn_packet(
+        &mut self,
+        port: VsockConnId,
+        op: u16,
+        flags: u32,
+        payload: &[u8],
+    ) -> DeviceResult {
+        let conn = self.conns.get_mut(&port).ok_or(DeviceError::InvalidParam)?;
+        conn.last_fwd_cnt = conn.fwd_cnt;
+        let header = PacketHeader {
+            src_cid: self.guest_cid,
+            dst_cid: conn.peer_cid,
+            src_port: port,
+            dst_port: conn.peer_port,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: CONN_BUF_SIZE,
+            fwd_cnt: conn.fwd_cnt,
+            ..Default::default()
+        };
+        self.send_packet(header, payload)
+    }
+
+    /// Handle a received packet, and returns the header of the reply if any.
+    fn handle_packet(&mut self, header: &PacketHeader, payload: &[u8]) -> Option<PacketHeader> {
+        let reset = if header.op == VIRTIO_VSOCK_OP_RST {
+            None
+        } else {
+            Some(header.reset_reply())
+        };
+        if header.dst_cid != self.guest_cid || header.ty != VIRTIO_VSOCK_TYPE_STREAM {
+            return reset;
+        }
+        // not listening, so no connection is accepted
+        let conn = match self.conns.get_mut(&header.dst_port) {
+            Some(conn) if conn.peer_cid == header.src_cid && conn.peer_port == header.src_port => {
+                conn
+            }
+            _ => return reset,
+        };
+        conn.peer_buf_alloc = header.buf_alloc;
+        conn.peer_fwd_cnt = header.fwd_cnt;
+        match header.op {
+            VIRTIO_VSOCK_OP_RESPONSE if conn.state == ConnState::Connecting => {
+                conn.state = ConnState::Connected;
+            }
+            VIRTIO_VSOCK_OP_RW if conn.state == ConnState::Connected => {
+                // the peer should never send more than our credit
+                let room = (CONN_BUF_SIZE as usize).saturating_sub(conn.rx.len());
+                if payload.len() > room {
+                    warn!(
+                        "virtio-vsock: port {} overran the receive buffer, {} bytes dropped",
+                        header.dst_port,
+                        payload.len() - room
+                    );
+                }
+                conn.rx.extend(&payload[..payload.len().min(room)]);
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.peer_shutdown |= header.flags & VIRTIO_VSOCK_SHUTDOWN_ALL;
+                if conn.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_ALL {
+                    conn.state = ConnState::Closed;
+                    return reset;
+                }
+            }
+            VIRTIO_VSOCK_OP_RST => conn.state = ConnState::Closed,
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                conn.last_fwd_cnt = conn.fwd_cnt;
+                return Some(PacketHeader {
+                    src_cid: header.dst_cid,
+                    dst_cid: header.src_cid,
+                    src_port: header.dst_port,
+                    dst_port: header.src_port,
+                    ty: VIRTIO_VSOCK_TYPE_STREAM,
+                    op: VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                    buf_alloc: CONN_BUF_SIZE,
+                    fwd_cnt: conn.fwd_cnt,
+                    ..Default::default()
+                });
+            }
+            // the credit of the peer is updated by any packet
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            VIRTIO_VSOCK_OP_REQUEST => return reset,
+            op => warn!("virtio-vsock: unexpected op {} in {:?}", op, conn.state),
+        }
+        None
+    }
+
+    /// All connections are dropped by the device, and the guest CID may be
+    /// changed.
+    fn transport_reset(&mut self) {
+        self.guest_cid = self.read_guest_cid();
+        for conn in self.conns.values_mut() {
+            conn.state = ConnState::Closed;
+        }
+    }
+
+    /// Handle the received packets and events, and returns whether there are
+    /// any.
+    fn poll(&mut self) -> DeviceResult<bool> {
+        let mut polled = false;
+        let mut payload = vec![0; MAX_PAYLOAD];
+        while let Some((head, written)) = self.rx_queue.pop_used() {
+            let index = match self.rx_heads.remove(&head) {
+                Some(index) => index,
+                None => continue,
+            };
+            polled = true;
+            let offset = index * PAGE_SIZE;
+            let mut header = [0; HEADER_SIZE];
+            self.rx_bufs.read_at(offset, &mut header)?;
+            let header = PacketHeader::from_bytes(&header);
+            let len = (header.len as usize)
+                .min(written.saturating_sub(HEADER_SIZE))
+                .min(MAX_PAYLOAD);
+            self.rx_bufs
+                .read_at(offset + HEADER_SIZE, &mut payload[..len])?;
+            self.add_rx_buf(index)?;
+            if let Some(reply) = self.handle_packet(&header, &payload[..len]) {
+                self.send_packet(reply, &[])?;
+            }
+        }
+        if polled {
+            self.transport.notify(&self.rx_queue);
+        }
+
+        let mut events = false;
+        while let Some((head, _)) = self.event_queue.pop_used() {
+            let index = match self.event_heads.remove(&head) {
+                Some(index) => index,
+                None => continue,
+            };
+            let mut id = [0; EVENT_SIZE];
+            self.events.read_at(index * EVENT_SIZE, &mut id)?;
+            if u32::from_le_bytes(id) == VIRTIO_VSOCK_EVENT_TRANSPORT_RESET {
+                warn!("virtio-vsock: transport reset, all connections are dropped");
+                self.transport_reset();
+            }
+            self.add_event_buf(index)?;
+            events = true;
+        }
+        if events {
+            self.transport.notify(&self.event_queue);
+        }
+        Ok(polled || events)
+    }
+
+    /// Returns an unused local port.
+    fn alloc_port(&mut self) -> DeviceResult<VsockConnId> {
+        for _ in FIRST_EPHEMERAL_PORT..=u16::MAX as u32 {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX as u32 {
+                FIRST_EPHEMERAL_PORT
+            } else {
+                port + 1
+            };
+            if !self.conns.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+        Err(DeviceError::NoResources)
+    }
+
+    fn conn(&mut self, port: VsockConnId) -> DeviceResult<&mut Connection> {
+        self.conns.get_mut(&port).ok_or(DeviceError::InvalidParam)
+    }
+}
+
+/// Driver of the VirtIO socket device, for the stream sockets to the host.
+///
+/// Only outgoing connections are supported.
+pub struct VirtIoVsock {
+    inner: Mutex<VirtIoVsockInner>,
+    listener: EventListener,
+}
+
+impl_event_scheme!(VirtIoVsock);
+
+impl VirtIoVsock {
+    pub fn new(transport: impl Into<Transport>) -> DeviceResult<Self> {
+        let mut transport = transport.into();
+        transport.require_config()?;
+        // no device specific features, i.e. no SEQPACKET
+        transport.begin_init(|_| 0)?;
+        let rx_queue = transport.create_queue_with_size(RX_QUEUE, (RX_BUFS * 2) as u16)?;
+        let tx_queue = transport.create_queue(TX_QUEUE)?;
+        let event_queue = transport.create_queue(EVENT_QUEUE)?;
+        transport.finish_init();
+
+        let rx_bufs = rx_queue.size() as usize / 2;
+        let event_bufs = event_queue.size() as usize;
+        let mut inner = VirtIoVsockInner {
+            transport,
+            rx_queue,
+            tx_queue,
+            event_queue,
+            rx_bufs: DmaBuf::new(rx_bufs * PAGE_SIZE)?,
+            rx_heads: BTreeMap::new(),
+            tx_buf: DmaBuf::new(PAGE_SIZE)?,
+            events: DmaBuf::new(event_bufs * EVENT_SIZE)?,
+            event_heads: BTreeMap::new(),
+            guest_cid: 0,
+            conns: BTreeMap::new(),
+            next_port: FIRST_EPHEMERAL_PORT,
+        };
+        inner.guest_cid = inner.read_guest_cid();
+        for i in 0..rx_bufs {
+            inner.add_rx_buf(i)?;
+        }
+        for i in 0..event_bufs {
+            inner.add_event_buf(i)?;
+        }
+        inner.transport.notify(&inner.rx_queue);
+        inner.transport.notify(&inner.event_queue);
+        info!("virtio-vsock: guest CID {}", inner.guest_cid);
+        Ok(Self {
+            inner: Mutex::new(inner),
+            listener: EventListener::new(),
+        })
+    }
+}
+
+impl Scheme for VirtIoVsock {
+    fn name(&self) -> &str {
+        "virtio-vsock"
+    }
+
+    fn shutdown(&self) -> DeviceResult {
+        self.inner.lock().transport.reset();
+        Ok(())
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let polled = {
+            let mut inner = self.inner.lock();
+            inner.transport.ack_interrupt();
+            inner.poll()
+        };
+        match polled {
+            Ok(true) => self.listener.trigger(()),
+            Ok(false) => {}
+            Err(err) => warn!("virtio-vsock: failed to handle packets: {:?}", err),
+        }
+    }
+}
+
+impl VsockScheme for VirtIoVsock {
+    fn guest_cid(&self) -> u64 {
+        self.inner.lock().guest_cid
+    }
+
+    /// Returns [`DeviceError::IoError`] if the peer refuses the connection, or
+    /// [`DeviceError::NotReady`] if it does not respond in time, in which case
+    /// the request is reset.
+    fn connect(&self, cid: u64, port: u32) -> DeviceResult<VsockConnId> {
+        let mut inner = self.inner.lock();
+        let local_port = inner.alloc_port()?;
+        inner.conns.insert(local_port, Connection::new(cid, port));
+        if let Err(err) = inner.send_conn_packet(local_port, VIRTIO_VSOCK_OP_REQUEST, 0, &[]) {
+            inner.conns.remove(&local_port);
+            return Err(err);
+        }
+        for _ in 0..CONNECT_TIMEOUT {
+            inner.poll()?;
+            match inner.conn(local_port)?.state {
+                ConnState::Connecting => core::hint::spin_loop(),
+                ConnState::Connected => return Ok(local_port),
+                ConnState::Closed => {
+                    inner.conns.remove(&local_port);
+                    return Err(DeviceError::IoError);
+                }
+            }
+        }
+        // tell the peer to drop the request, a late response is reset too
+        inner
+            .send_conn_packet(local_port, VIRTIO_VSOCK_OP_RST, 0, &[])
+            .ok();
+        inner.conns.remove(&local_port);
+        Err(DeviceError::NotReady)
+    }
+
+    /// Returns [`DeviceError::IoError`] if the connection is closed.
+    fn send(&self, conn: VsockConnId, buf: &[u8]) -> DeviceResult<usize> {
+        let mut inner = self.inner.lock();
+        inner.poll()?;
+        let c = inner.conn(conn)?;
+        if c.state != ConnState::Connected || c.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+            return Err(DeviceError::IoError);
+        }
+        let len = buf.len().min(c.peer_free() as usize).min(MAX_PAYLOAD);
+        if len == 0 {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            inner.send_conn_packet(conn, VIRTIO_VSOCK_OP_CREDIT_REQUEST, 0, &[])?;
+            return Err(DeviceError::NotReady);
+        }
+        inner.send_conn_packet(conn, VIRTIO_VSOCK_OP_RW, 0, &buf[..len])?;
+        let c = inner.conn(conn)?;
+        c.tx_cnt = c.tx_cnt.wrapping_add(len as u32);
+        Ok(len)
+    }
+
+    fn recv(&self, conn: VsockConnId, buf: &mut [u8]) -> DeviceResult<usize> {
+        let mut inner = self.inner.lock();
+        inner.poll()?;
+        let c = inner.conn(conn)?;
+        if c.rx.is_empty() {
+            return if c.state == ConnState::Closed
+                || c.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+            {
+                Ok(0)
+            } else {
+                Err(DeviceError::NotReady)
+            };
+        }
+        let len = buf.len().min(c.rx.len());
+        for (dst, src) in buf.iter_mut().zip(c.rx.drain(..len)) {
+            *dst = src;
+        }
+        c.fwd_cnt = c.fwd_cnt.wrapping_add(len as u32);
+        // give the credit back before the peer runs out of it
+        if c.state == ConnState::Connected
+            && c.fwd_cnt.wrapping_sub(c.last_fwd_cnt) >= CREDIT_UPDATE_THRESHOLD
+        {
+            inner.send_conn_packet(conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[])?;
+        }
+        Ok(len)
+    }
+
+    fn close(&self, conn: VsockConnId) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        if inner.conn(conn)?.state != ConnState::Closed {
+            inner.send_conn_packet(
+                conn,
+                VIRTIO_VSOCK_OP_SHUTDOWN,
+                VIRTIO_VSOCK_SHUTDOWN_ALL,
+                &[],
+            )?;
+        }
+        inner.conns.remove(&conn);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = PacketHeader {
+            src_cid: 3,
+            dst_cid: 2,
+            src_port: FIRST_EPHEMERAL_PORT,
+            dst_port: 1234,
+            len: 5,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RW,
+            flags: 0,
+            buf_alloc: CONN_BUF_SIZE,
+            fwd_cnt: 42,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[30], VIRTIO_VSOCK_OP_RW as u8);
+        let parsed = PacketHeader::from_bytes(&bytes);
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        let reply = parsed.reset_reply();
+        assert_eq!((reply.dst_cid, reply.dst_port), (3, FIRST_EPHEMERAL_PORT));
+        assert_eq!(reply.op, VIRTIO_VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn test_peer_credit() {
+        let mut conn = Connection::new(2, 1234);
+        assert_eq!(conn.peer_free(), 0);
+        conn.peer_buf_alloc = 1000;
+        conn.tx_cnt = 600;
+        conn.peer_fwd_cnt = 100;
+        assert_eq!(conn.peer_free(), 500);
+        // the counters wrap around
+        conn.tx_cnt = 50;
+        conn.peer_fwd_cnt = u32::MAX - 49;
+        assert_eq!(conn.peer_free(), 900);
+    }
+}
@@ -19,6 +19,7 @@ pub struct KernelConfig {
 
 pub const PHYS_MEMORY_BASE: usize = 0x4000_0000;
 pub const UART_SIZE: usize = 0x1000;
+pub const UART_CLOCK_FREQ: u32 = 24_000_000;
 pub const VIRTIO_BASE: usize = 0x0a00_0000;
 pub const VIRTIO_SIZE: usize = 0x100;
 pub const PA_1TB_BITS: usize = 40;
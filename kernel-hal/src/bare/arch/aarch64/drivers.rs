@@ -1,18 +1,18 @@
 use crate::arch::timer::set_next_trigger;
 use crate::drivers;
 use crate::hal_fn::mem::phys_to_virt;
-use crate::imp::config::VIRTIO_BASE;
+use crate::imp::config::{UART_CLOCK_FREQ, VIRTIO_BASE};
 use crate::KCONFIG;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use zcore_drivers::irq::gic_400;
 use zcore_drivers::scheme::IrqScheme;
-use zcore_drivers::uart::{BufferedUart, Pl011Uart};
+use zcore_drivers::uart::{BufferedUart, UartPl011};
 use zcore_drivers::virtio::{VirtIOHeader, VirtIoBlk};
 use zcore_drivers::Device;
 
 pub fn init_early() {
-    let uart = Pl011Uart::new(phys_to_virt(KCONFIG.uart_base));
+    let uart = unsafe { UartPl011::new(phys_to_virt(KCONFIG.uart_base), UART_CLOCK_FREQ) };
     let uart = Arc::new(uart);
     let gic = gic_400::init(
         phys_to_virt(KCONFIG.gic_base + 0x1_0000),
@@ -1,39 +1,69 @@
 use alloc::boxed::Box;
 use alloc::format;
+use alloc::vec::Vec;
 
-use zcore_drivers::builder::{DevicetreeDriverBuilder, IoMapper};
+use lock::Mutex;
+use zcore_drivers::builder::{DevicetreeDriverBuilder, IoMapper, MmioAttrs, ProbedDevices};
 use zcore_drivers::irq::riscv::ScauseIntCode;
 use zcore_drivers::uart::BufferedUart;
-use zcore_drivers::{Device, DeviceResult};
+use zcore_drivers::{Device, DeviceError, DeviceResult};
 
 use crate::common::vm::GenericPageTable;
 use crate::{drivers, mem::phys_to_virt, CachePolicy, MMUFlags, PhysAddr, VirtAddr};
 
+/// A region mapped by [`IoMapperImpl`], and the number of devices using it.
+struct MappedRegion {
+    vaddr: VirtAddr,
+    size: usize,
+    refs: usize,
+}
+
+/// Regions mapped by [`IoMapperImpl`] rather than at boot, which are unmapped
+/// once no device uses them.
+static MAPPED_REGIONS: Mutex<Vec<MappedRegion>> = Mutex::new(Vec::new());
+
 struct IoMapperImpl;
 
 impl IoMapper for IoMapperImpl {
     fn query_or_map(&self, paddr: PhysAddr, size: usize) -> Option<VirtAddr> {
+        self.map_mmio(paddr, size, MmioAttrs::Device).ok()
+    }
+
+    fn map_pages(&self, paddr: PhysAddr, size: usize, attrs: MmioAttrs) -> Option<VirtAddr> {
         let vaddr = phys_to_virt(paddr);
         let mut pt = super::vm::kernel_page_table().lock();
         if let Ok((paddr_mapped, _, _)) = pt.query(vaddr) {
             if paddr_mapped == paddr {
+                let mut regions = MAPPED_REGIONS.lock();
+                if let Some(r) = regions
+                    .iter_mut()
+                    .find(|r| vaddr >= r.vaddr && vaddr - r.vaddr < r.size)
+                {
+                    r.refs += 1;
+                }
                 Some(vaddr)
             } else {
                 warn!(
-                    "IoMapper::query_or_map: not linear mapping: vaddr={:#x}, paddr={:#x}",
+                    "IoMapper::map_pages: not linear mapping: vaddr={:#x}, paddr={:#x}",
                     vaddr, paddr_mapped
                 );
                 None
             }
         } else {
             let size = crate::addr::align_up(size);
+            let policy = match attrs {
+                MmioAttrs::Device => CachePolicy::UncachedDevice,
+                MmioAttrs::Uncached => CachePolicy::Uncached,
+                MmioAttrs::WriteCombining => CachePolicy::WriteCombining,
+                MmioAttrs::Cached => CachePolicy::Cached,
+            };
             let flags = MMUFlags::READ
                 | MMUFlags::WRITE
                 | MMUFlags::HUGE_PAGE
-                | MMUFlags::from_bits_truncate(CachePolicy::UncachedDevice as usize);
+                | MMUFlags::from_bits_truncate(policy as usize);
             if let Err(err) = pt.map_cont(vaddr, size, paddr, flags) {
                 warn!(
-                    "IoMapper::query_or_map: failed to map {:#x?} => {:#x}, flags={:?}: {:?}",
+                    "IoMapper::map_pages: failed to map {:#x?} => {:#x}, flags={:?}: {:?}",
                     vaddr..vaddr + size,
                     paddr,
                     flags,
@@ -41,25 +71,72 @@ impl IoMapper for IoMapperImpl {
                 );
                 None
             } else {
+                MAPPED_REGIONS.lock().push(MappedRegion {
+                    vaddr,
+                    size,
+                    refs: 1,
+                });
                 Some(vaddr)
             }
         }
     }
+
+    /// Unmap the region containing `vaddr` once no device uses it. Regions
+    /// mapped at boot are left as is.
+    fn unmap(&self, vaddr: VirtAddr, _len: usize) -> DeviceResult {
+        let mut regions = MAPPED_REGIONS.lock();
+        let idx = match regions
+            .iter()
+            .position(|r| vaddr >= r.vaddr && vaddr - r.vaddr < r.size)
+        {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        regions[idx].refs -= 1;
+        if regions[idx].refs > 0 {
+            return Ok(());
+        }
+        let r = regions.swap_remove(idx);
+        // `map_pages` locks the page table first, do not hold both here
+        drop(regions);
+        let mut pt = super::vm::kernel_page_table().lock();
+        pt.unmap_cont(r.vaddr, r.size).map_err(|err| {
+            warn!(
+                "IoMapper::unmap: failed to unmap {:#x?}: {:?}",
+                r.vaddr..r.vaddr + r.size,
+                err
+            );
+            DeviceError::IoError
+        })
+    }
 }
 
 /// Initialize device drivers.
 pub(super) fn init() -> DeviceResult {
     // prase DTB and probe devices
-    let dev_list =
-        DevicetreeDriverBuilder::new(phys_to_virt(crate::KCONFIG.dtb_paddr), IoMapperImpl)?
-            .build()?;
-    // add drivers
-    for dev in dev_list.into_iter() {
-        if let Device::Uart(uart) = dev {
-            drivers::add_device(Device::Uart(BufferedUart::new(uart)));
-        } else {
-            drivers::add_device(dev);
+    let ProbedDevices {
+        mut devices,
+        console,
+        irq_errors,
+        ..
+    } = DevicetreeDriverBuilder::new(phys_to_virt(crate::KCONFIG.dtb_paddr), IoMapperImpl)?
+        .filter_from_cmdline(&super::cmdline())
+        .build()?;
+    for (path, err) in irq_errors {
+        warn!("failed to register interrupts of {}: {:?}", path, err);
+    }
+    // add drivers, the console UART goes first since the first one is used
+    let console = console.map(|i| devices.remove(i));
+    for named in console.into_iter().chain(devices) {
+        info!(
+            "device {:?} from {} ({:?}), MMIO {:x?}",
+            named.names, named.path, named.compatible, named.mmio
+        );
+        let mut named = named;
+        if let Device::Uart(uart) = named.device {
+            named.device = Device::Uart(BufferedUart::new(uart));
         }
+        drivers::add_named_device(named);
     }
 
     #[cfg(not(any(
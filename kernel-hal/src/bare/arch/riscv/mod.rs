@@ -38,15 +38,22 @@ pub fn primary_init_early() {
         info!("Load kernel cmdline from DTB: {:?}", cmdline);
         CMDLINE.init_once_by(cmdline.into());
     }
+    let cpus = dt.cpus();
+    info!("Load CPUs from DTB: {:#x?}", cpus);
     if let Some(time_freq) = dt.timebase_frequency() {
-        info!("Load CPU clock frequency from DTB: {} Hz", time_freq);
-        super::cpu::CPU_FREQ_MHZ.init_once_by((time_freq / 1_000_000) as u16);
+        info!("Load timebase frequency from DTB: {} Hz", time_freq);
+        timer::TIMEBASE_FREQ.init_once_by(time_freq as u64);
+        cpu::CPU_FREQ_MHZ.init_once_by((time_freq / 1_000_000).max(1) as u16);
     }
     if let Some(initrd_region) = dt.initrd_region() {
         info!("Load initrd regions from DTB: {:#x?}", initrd_region);
         INITRD_REGION.init_once_by(Some(initrd_region));
     }
-    if let Ok(regions) = dt.memory_regions() {
+    if let Ok(regions) = dt.usable_memory_regions() {
+        let regions = regions
+            .into_iter()
+            .map(|(start, size)| start..start + size)
+            .collect::<Vec<_>>();
         info!("Load memory regions from DTB: {:#x?}", regions);
         MEMORY_REGIONS.init_once_by(regions);
     }
@@ -69,7 +76,8 @@ pub fn secondary_init() {
     drivers::intc_init().unwrap();
     let plic = crate::drivers::all_irq()
         .find("riscv-plic")
-        .expect("IRQ device 'riscv-plic' not initialized!");
+        .or_else(|| crate::drivers::all_irq().find("riscv-aplic"))
+        .expect("IRQ device 'riscv-plic' or 'riscv-aplic' not initialized!");
     info!(
         "cpu {} enable plic: {:?}",
         crate::cpu::cpu_id(),
@@ -1,12 +1,16 @@
 use core::time::Duration;
 
+use crate::utils::init_once::InitOnce;
+
+/// Frequency of the `time` CSR in Hz, from `timebase-frequency` in the DTB.
+pub(super) static TIMEBASE_FREQ: InitOnce<u64> = InitOnce::new_with_default(10_000_000); // QEMU virt
+
 fn get_cycle() -> u64 {
     riscv::register::time::read() as u64
 }
 
 pub(super) fn timer_set_next() {
-    let cycles =
-        super::cpu::cpu_frequency() as u64 * 1_000_000 / super::super::timer::TICKS_PER_SEC;
+    let cycles = *TIMEBASE_FREQ / super::super::timer::TICKS_PER_SEC;
     sbi_rt::set_timer(get_cycle() + cycles);
 }
 
@@ -15,5 +19,5 @@ pub(super) fn init() {
 }
 
 pub(crate) fn timer_now() -> Duration {
-    Duration::from_nanos(get_cycle() * 1000 / super::cpu::cpu_frequency() as u64)
+    Duration::from_nanos((get_cycle() as u128 * 1_000_000_000 / *TIMEBASE_FREQ as u128) as u64)
 }
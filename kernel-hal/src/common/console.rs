@@ -11,7 +11,14 @@ static SERIAL_WRITER: Mutex<SerialWriter> = Mutex::new(SerialWriter);
 impl Write for SerialWriter {
     fn write_str(&mut self, s: &str) -> Result {
         if let Some(uart) = drivers::all_uart().first() {
-            uart.write_str(s).unwrap();
+            // Errors are ignored, the output is lost on a stuck line rather
+            // than panicking, which may be in the panic handler already.
+            uart.write_str(s).ok();
+            // The bytes queued in the TX buffer can not be sent by the
+            // interrupt handler if the interrupts are disabled.
+            if !crate::interrupt::intr_get() {
+                uart.flush().ok();
+            }
         } else {
             crate::hal_fn::console::console_write_early(s);
         }
@@ -53,6 +60,14 @@ pub fn serial_write_fmt(fmt: Arguments) {
     SERIAL_WRITER.lock().write_fmt(fmt).unwrap();
 }
 
+/// Waits until all data written into the serial are sent.
+pub fn serial_flush() {
+    if let Some(uart) = drivers::all_uart().first() {
+        // It is called by the panic handler, do not panic again
+        uart.flush().ok();
+    }
+}
+
 /// Writes a string slice into the graphic console.
 #[allow(unused_variables)]
 pub fn graphic_console_write_str(s: &str) {
@@ -1,122 +1,104 @@
 //! Device drivers.
 
-use alloc::{sync::Arc, vec::Vec};
 use core::convert::From;
 
-use lock::{RwLock, RwLockReadGuard};
-
 use zcore_drivers::scheme::{
-    BlockScheme, DisplayScheme, InputScheme, IrqScheme, NetScheme, Scheme, UartScheme,
+    BalloonScheme, BlockScheme, DisplayScheme, GpioScheme, InputScheme, IrqScheme, NetScheme,
+    PowerScheme, RngScheme, RtcScheme, TimerScheme, UartScheme, VsockScheme,
 };
 use zcore_drivers::{Device, DeviceError};
 
 /// Re-exported modules from crate [`zcore_drivers`].
 pub use zcore_drivers::{prelude, scheme};
 
-/// A wrapper of a device array with the same [`Scheme`].
-pub struct DeviceList<T: Scheme + ?Sized>(RwLock<Vec<Arc<T>>>);
-
-impl<T: Scheme + ?Sized> DeviceList<T> {
-    fn add(&self, dev: Arc<T>) {
-        self.0.write().push(dev);
-    }
-
-    /// Convert self into a vector.
-    pub fn as_vec(&self) -> RwLockReadGuard<'_, Vec<Arc<T>>> {
-        self.0.read()
-    }
-
-    /// Returns the device at given position, or `None` if out of bounds.
-    pub fn try_get(&self, idx: usize) -> Option<Arc<T>> {
-        self.0.read().get(idx).cloned()
-    }
-
-    /// Returns the device with the given name, or `None` if not found.
-    pub fn find(&self, name: &str) -> Option<Arc<T>> {
-        self.0.read().iter().find(|d| d.name() == name).cloned()
-    }
-
-    /// Returns the first device of this device array, or `None` if it is empty.
-    pub fn first(&self) -> Option<Arc<T>> {
-        self.try_get(0)
-    }
+pub use zcore_drivers::manager::{DeviceList, DeviceManager};
 
-    /// Returns the first device of this device array.
-    ///
-    /// # Panic
-    ///
-    /// Panics if the array is empty.
-    pub fn first_unwrap(&self) -> Arc<T> {
-        self.first()
-            .unwrap_or_else(|| panic!("device not initialized: {}", core::any::type_name::<T>()))
-    }
+lazy_static! {
+    static ref DEVICES: DeviceManager = DeviceManager::new();
 }
 
-impl<T: Scheme + ?Sized> Default for DeviceList<T> {
-    fn default() -> Self {
-        Self(RwLock::new(Vec::new()))
-    }
+pub(crate) fn add_device(dev: Device) {
+    DEVICES.add(dev)
 }
 
-#[derive(Default)]
-struct AllDeviceList {
-    block: DeviceList<dyn BlockScheme>,
-    display: DeviceList<dyn DisplayScheme>,
-    input: DeviceList<dyn InputScheme>,
-    irq: DeviceList<dyn IrqScheme>,
-    net: DeviceList<dyn NetScheme>,
-    uart: DeviceList<dyn UartScheme>,
-}
-
-impl AllDeviceList {
-    pub fn add_device(&self, dev: Device) {
-        match dev {
-            Device::Block(d) => self.block.add(d),
-            Device::Display(d) => self.display.add(d),
-            Device::Input(d) => self.input.add(d),
-            Device::Irq(d) => self.irq.add(d),
-            Device::Net(d) => self.net.add(d),
-            Device::Uart(d) => self.uart.add(d),
-        }
-    }
+/// Add a device probed from the device tree, which can be removed by name.
+#[cfg(all(
+    not(feature = "libos"),
+    any(target_arch = "riscv32", target_arch = "riscv64")
+))]
+pub(crate) fn add_named_device(named: zcore_drivers::builder::NamedDevice) {
+    DEVICES.add_named(named)
 }
 
-lazy_static! {
-    static ref DEVICES: AllDeviceList = AllDeviceList::default();
+/// Returns the manager of all devices, e.g. to look up or remove a device by
+/// name.
+pub fn device_manager() -> &'static DeviceManager {
+    &DEVICES
 }
 
-pub(crate) fn add_device(dev: Device) {
-    DEVICES.add_device(dev)
+/// Returns all devices which implement the [`BalloonScheme`].
+pub fn all_balloon() -> &'static DeviceList<dyn BalloonScheme> {
+    DEVICES.balloons()
 }
 
 /// Returns all devices which implement the [`BlockScheme`].
 pub fn all_block() -> &'static DeviceList<dyn BlockScheme> {
-    &DEVICES.block
+    DEVICES.blocks()
 }
 
 /// Returns all devices which implement the [`DisplayScheme`].
 pub fn all_display() -> &'static DeviceList<dyn DisplayScheme> {
-    &DEVICES.display
+    DEVICES.displays()
+}
+
+/// Returns all devices which implement the [`GpioScheme`].
+pub fn all_gpio() -> &'static DeviceList<dyn GpioScheme> {
+    DEVICES.gpios()
 }
 
 /// Returns all devices which implement the [`InputScheme`].
 pub fn all_input() -> &'static DeviceList<dyn InputScheme> {
-    &DEVICES.input
+    DEVICES.inputs()
 }
 
 /// Returns all devices which implement the [`IrqScheme`].
 pub fn all_irq() -> &'static DeviceList<dyn IrqScheme> {
-    &DEVICES.irq
+    DEVICES.irqs()
 }
 
 /// Returns all devices which implement the [`NetScheme`].
 pub fn all_net() -> &'static DeviceList<dyn NetScheme> {
-    &DEVICES.net
+    DEVICES.nets()
+}
+
+/// Returns all devices which implement the [`PowerScheme`].
+pub fn all_power() -> &'static DeviceList<dyn PowerScheme> {
+    DEVICES.powers()
+}
+
+/// Returns all devices which implement the [`RngScheme`].
+pub fn all_rng() -> &'static DeviceList<dyn RngScheme> {
+    DEVICES.rngs()
+}
+
+/// Returns all devices which implement the [`RtcScheme`].
+pub fn all_rtc() -> &'static DeviceList<dyn RtcScheme> {
+    DEVICES.rtcs()
+}
+
+/// Returns all devices which implement the [`TimerScheme`].
+pub fn all_timer() -> &'static DeviceList<dyn TimerScheme> {
+    DEVICES.timers()
 }
 
 /// Returns all devices which implement the [`UartScheme`].
 pub fn all_uart() -> &'static DeviceList<dyn UartScheme> {
-    &DEVICES.uart
+    DEVICES.uarts()
+}
+
+/// Returns all devices which implement the [`VsockScheme`].
+pub fn all_vsock() -> &'static DeviceList<dyn VsockScheme> {
+    DEVICES.vsocks()
 }
 
 impl From<DeviceError> for crate::HalError {
@@ -12,7 +12,6 @@ use alloc::vec::Vec;
 use alloc::sync::Arc;
 
 use alloc::string::String;
-use lock::Mutex;
 
 use crate::drivers::add_device;
 use crate::drivers::all_net;
@@ -57,10 +56,7 @@ pub fn init() {
         .neighbor_cache(neighbor_cache)
         .finalize();
 
-    let loopback_iface = LoopbackInterface {
-        iface: Arc::new(Mutex::new(iface)),
-        name,
-    };
+    let loopback_iface = LoopbackInterface::new(iface, name);
     // loopback_iface
     let dev = Device::Net(Arc::new(loopback_iface));
     add_device(dev);
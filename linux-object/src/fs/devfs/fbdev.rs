@@ -121,7 +121,7 @@ impl From<DisplayInfo> for FbFixScreeninfo {
             smem_len: info.fb_size as u32,
             fb_type: FbType::PackedPixels,
             visual: FbVisual::TrueColor,
-            line_length: info.width * info.format.bytes() as u32,
+            line_length: info.stride,
             mmio_start: 0,
             mmio_len: 0,
             accel: FB_ACCEL_NONE,
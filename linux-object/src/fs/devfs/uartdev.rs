@@ -29,18 +29,7 @@ impl INode for UartDev {
             buf.len()
         );
 
-        let mut len = 0;
-        for b in buf.iter_mut() {
-            match self.port.try_recv() {
-                Ok(Some(b_)) => {
-                    *b = b_;
-                    len += 1;
-                }
-                Ok(None) => break,
-                Err(e) => return Err(convert_error(e)),
-            }
-        }
-        Ok(len)
+        self.port.recv_slice(buf).map_err(convert_error)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
@@ -50,10 +39,17 @@ impl INode for UartDev {
             buf.len()
         );
 
-        for b in buf {
-            self.port.send(*b).map_err(convert_error)?;
+        let mut written = 0;
+        while written < buf.len() {
+            match self.port.send_slice(&buf[written..]) {
+                Ok(0) => break,
+                Ok(n) => written += n,
+                // The TX buffer is full, return the bytes written so far
+                Err(DeviceError::Again) if written > 0 => break,
+                Err(e) => return Err(convert_error(e)),
+            }
         }
-        Ok(buf.len())
+        Ok(written)
     }
 
     fn poll(&self) -> Result<PollStatus> {
@@ -100,10 +96,12 @@ fn convert_error(e: DeviceError) -> FsError {
         DeviceError::NotSupported => FsError::NotSupported,
         DeviceError::NotReady => FsError::Busy,
         DeviceError::InvalidParam => FsError::InvalidParam,
+        DeviceError::Again => FsError::Again,
         DeviceError::BufferTooSmall
         | DeviceError::DmaError
         | DeviceError::IoError
         | DeviceError::AlreadyExists
-        | DeviceError::NoResources => FsError::DeviceError,
+        | DeviceError::NoResources
+        | DeviceError::InvalidDtb => FsError::DeviceError,
     }
 }
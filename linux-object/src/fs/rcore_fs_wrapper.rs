@@ -8,6 +8,7 @@ extern crate rcore_fs;
 use kernel_hal::drivers::scheme::BlockScheme;
 use lock::RwLock;
 use rcore_fs::dev::{BlockDevice, DevError, Device, Result};
+use zcore_drivers::DeviceError;
 
 /// A naive LRU cache layer for `BlockDevice`, re-exported from `rcore-fs`.
 pub use rcore_fs::dev::block_cache::BlockCache;
@@ -62,6 +63,10 @@ impl BlockDevice for Block {
     }
 
     fn sync(&self) -> Result<()> {
-        self.0.flush().map_err(|_| DevError)
+        match self.0.flush() {
+            // nothing more can be done if the device can not flush
+            Ok(()) | Err(DeviceError::NotSupported) => Ok(()),
+            Err(_) => Err(DevError),
+        }
     }
 }
@@ -7,6 +7,7 @@ use log::*;
 fn panic(info: &PanicInfo) -> ! {
     println!("\n\npanic cpu={}\n{}", kernel_hal::cpu::cpu_id(), info);
     error!("\n\n{info}");
+    kernel_hal::console::serial_flush();
 
     if cfg!(feature = "baremetal-test") {
         kernel_hal::cpu::reset();